@@ -0,0 +1,113 @@
+//! Benchmarks of the steps every request goes through, `cargo bench` comparing them
+//! with the previous run.
+
+use bytes::Bytes;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use http_server_starter_rust::{
+    ComparePath, HttpCode, Request, RequestBuffer, Response, Route, Router,
+};
+
+const GET: &[u8] = b"GET /echo/hello?lang=en HTTP/1.1\r\n\
+    Host: localhost:4221\r\n\
+    User-Agent: Mozilla/5.0 (X11; Linux x86_64; rv:120.0) Gecko/20100101 Firefox/120.0\r\n\
+    Accept: text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8\r\n\
+    Accept-Language: en-US,en;q=0.5\r\n\
+    Accept-Encoding: gzip, deflate, br\r\n\
+    Connection: keep-alive\r\n\
+    Cookie: session=8f14e45fceea167a5a36dedd4bea2543; theme=dark\r\n\
+    Cache-Control: max-age=0\r\n\r\n";
+
+const POST: &[u8] = b"POST /files/notes.txt HTTP/1.1\r\n\
+    Host: localhost:4221\r\n\
+    User-Agent: curl/8.4.0\r\n\
+    Accept: */*\r\n\
+    Content-Type: application/octet-stream\r\n\
+    Content-Length: 64\r\n\r\n\
+    0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+fn parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+    for (name, req) in [("get", GET), ("post", POST)] {
+        group.throughput(Throughput::Bytes(req.len() as u64));
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let mut buf = RequestBuffer::from(black_box(req).iter().copied());
+                Request::parse(&mut buf).unwrap()
+            })
+        });
+    }
+    // As connections parse heads, in place in the bytes received
+    group.throughput(Throughput::Bytes(GET.len() as u64));
+    group.bench_function("get_head", |b| {
+        b.iter(|| Request::parse_head(Bytes::from_static(black_box(GET))).unwrap())
+    });
+    group.finish();
+}
+
+/// Header lookups and copies, as middlewares and extractors do them on every request.
+fn headers(c: &mut Criterion) {
+    let mut group = c.benchmark_group("headers");
+    let req = Request::parse_head(Bytes::from_static(GET)).unwrap();
+    group.bench_function("lookup", |b| {
+        b.iter(|| black_box(&req).header(black_box("cookie")))
+    });
+    group.bench_function("lookup_missing", |b| {
+        b.iter(|| black_box(&req).header(black_box("X-Request-Id")))
+    });
+    group.bench_function("clone", |b| b.iter(|| black_box(&req).headers().to_owned()));
+    group.finish();
+}
+
+/// Router of `n` routes with a parameter each, only the last one matching
+/// `/resource{n-1}/42`.
+fn router(n: usize) -> Router {
+    let mut router = Router::default();
+    for i in 0..n {
+        router.add_route(Route::get(
+            format!("/resource{}/{{id}}", i),
+            |_: Request| Response::from(HttpCode::Ok),
+            ComparePath::Exact,
+        ));
+    }
+    router
+}
+
+fn route(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let mut group = c.benchmark_group("route");
+    for n in [10, 100, 1000] {
+        let router = router(n);
+        let last = Request::builder()
+            .path(format!("/resource{}/42", n - 1))
+            .build();
+        group.bench_with_input(BenchmarkId::new("last", n), &last, |b, req| {
+            b.iter(|| runtime.block_on(router.route(req.clone())))
+        });
+        let missing = Request::builder().path("/missing").build();
+        group.bench_with_input(BenchmarkId::new("missing", n), &missing, |b, req| {
+            b.iter(|| runtime.block_on(router.route(req.clone())))
+        });
+    }
+    group.finish();
+}
+
+fn serialize(c: &mut Criterion) {
+    let mut group = c.benchmark_group("serialize");
+    let mut res = Response::from("Hello, World!".repeat(64));
+    res.header("Content-Type", "text/plain; charset=utf-8");
+    res.header("Cache-Control", "public, max-age=60");
+    res.header("ETag", "\"5d41402abc4b2a76b9719d911017c592\"");
+    res.append_header("Set-Cookie", "session=abc; Path=/; HttpOnly");
+    res.append_header("Set-Cookie", "theme=dark; Path=/");
+    group.bench_function("text", |b| b.iter(|| black_box(res.clone()).into_bytes()));
+    let empty = Response::from(HttpCode::NoContent);
+    group.bench_function("empty", |b| {
+        b.iter(|| black_box(empty.clone()).into_bytes())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parse, headers, route, serialize);
+criterion_main!(benches);
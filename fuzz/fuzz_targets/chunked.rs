@@ -0,0 +1,29 @@
+//! Arbitrary bytes as a chunked body received over several reads, which must decode the
+//! same way whatever the reads it is split in, and never past its limit.
+//!
+//! The first byte sets how many bytes each read gets, the rest being the body.
+//!
+//! ```sh
+//! cargo +nightly fuzz run chunked fuzz/corpus/chunked
+//! ```
+
+#![no_main]
+
+use http_server_starter_rust::decode_chunked;
+use libfuzzer_sys::fuzz_target;
+
+const MAX: usize = 1024;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&read_size, body)) = data.split_first() else {
+        return;
+    };
+    let whole = decode_chunked([body], MAX);
+    let split = decode_chunked(body.chunks(usize::from(read_size).max(1)), MAX);
+    assert_eq!(whole, split);
+    if let Ok(Some((decoded, used))) = whole {
+        assert!(used <= body.len());
+        // The body and its framing, then the trailer section and the empty line ending it
+        assert!(decoded.len() <= MAX && used <= 2 * MAX + 2);
+    }
+});
@@ -0,0 +1,23 @@
+//! Arbitrary bytes as a request head and body, which must be parsed or refused without
+//! panicking, along with what handlers read off the request.
+//!
+//! ```sh
+//! cargo +nightly fuzz run parse_request fuzz/corpus/parse_request
+//! ```
+
+#![no_main]
+
+use http_server_starter_rust::{Request, RequestBuffer};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(req) = Request::parse(&mut RequestBuffer::from(data.iter().copied())) else {
+        return;
+    };
+    let _ = req.query_pairs();
+    let _ = req.form_pairs();
+    let _ = req.cookie("session");
+    let _ = req.keep_alive();
+    let _ = req.negotiate(&["text/html", "application/json"]);
+    let _ = req.preferred_language(&["en", "fr"]);
+});
@@ -0,0 +1,106 @@
+//! Route registration attributes for the HTTP server.
+//!
+//! `#[get("/echo/{msg}")]` on a handler generates, next to the function, a module of
+//! the same name whose `route()` builds the matching `Route`. `routes![echo, ...]`
+//! collects those routes into a `Vec`.
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
+use syn::{parse_macro_input, Error, Ident, ItemFn, LitStr, Path, Token};
+
+/// Arguments of a route attribute: the path, optionally followed by `prefix` to
+/// match every path starting with it.
+struct RouteArgs {
+    path: LitStr,
+    prefix: bool,
+}
+
+impl Parse for RouteArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let path = input.parse::<LitStr>()?;
+        if !path.value().starts_with('/') {
+            return Err(Error::new(path.span(), "route paths must start with `/`"));
+        }
+
+        let mut prefix = false;
+        if input.parse::<Option<Token![,]>>()?.is_some() && !input.is_empty() {
+            let flag = input.parse::<Ident>()?;
+            if flag != "prefix" {
+                return Err(Error::new(flag.span(), "expected `prefix`"));
+            }
+            prefix = true;
+            input.parse::<Option<Token![,]>>()?;
+        }
+
+        Ok(RouteArgs { path, prefix })
+    }
+}
+
+fn route(method: &str, args: TokenStream, item: TokenStream) -> TokenStream {
+    let RouteArgs { path, prefix } = parse_macro_input!(args as RouteArgs);
+    let handler = parse_macro_input!(item as ItemFn);
+
+    let name = &handler.sig.ident;
+    let vis = &handler.vis;
+    let constructor = Ident::new(method, Span::call_site());
+    let compare_path = if prefix {
+        quote!(crate::ComparePath::Prefix)
+    } else {
+        quote!(crate::ComparePath::Exact)
+    };
+
+    quote! {
+        #handler
+
+        #[doc(hidden)]
+        #[allow(non_snake_case)]
+        #vis mod #name {
+            pub fn route() -> crate::Route {
+                crate::Route::#constructor(#path, super::#name, #compare_path)
+            }
+        }
+    }
+    .into()
+}
+
+/// Registers the handler for `GET` requests on the given path.
+#[proc_macro_attribute]
+pub fn get(args: TokenStream, item: TokenStream) -> TokenStream {
+    route("get", args, item)
+}
+
+/// Registers the handler for `POST` requests on the given path.
+#[proc_macro_attribute]
+pub fn post(args: TokenStream, item: TokenStream) -> TokenStream {
+    route("post", args, item)
+}
+
+/// Registers the handler for `PUT` requests on the given path.
+#[proc_macro_attribute]
+pub fn put(args: TokenStream, item: TokenStream) -> TokenStream {
+    route("put", args, item)
+}
+
+/// Registers the handler for `DELETE` requests on the given path.
+#[proc_macro_attribute]
+pub fn delete(args: TokenStream, item: TokenStream) -> TokenStream {
+    route("delete", args, item)
+}
+
+/// Registers the handler for `PATCH` requests on the given path.
+#[proc_macro_attribute]
+pub fn patch(args: TokenStream, item: TokenStream) -> TokenStream {
+    route("patch", args, item)
+}
+
+/// Collects the routes of handlers annotated with a route attribute.
+#[proc_macro]
+pub fn routes(input: TokenStream) -> TokenStream {
+    let handlers =
+        parse_macro_input!(input with Punctuated::<Path, Token![,]>::parse_terminated).into_iter();
+
+    quote!(vec![#(#handlers::route()),*]).into()
+}
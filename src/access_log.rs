@@ -0,0 +1,460 @@
+//! Access logging in the Common and Combined Log Formats, each line followed by the
+//! time spent answering the request in milliseconds and, when set, the request id.
+//!
+//! Log files are written from a background thread and can be rotated by size or age,
+//! see [`Rotation`].
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use tracing::error;
+
+use super::date::{civil_from_days, MONTHS};
+use super::middleware::{Middleware, Next};
+use super::request_id::RequestId;
+use super::router::BoxFuture;
+use super::{Request, Response};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    Common,
+    #[default]
+    Combined,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "common" => Ok(LogFormat::Common),
+            "combined" => Ok(LogFormat::Combined),
+            _ => Err(format!("Invalid access log format: {}", s)),
+        }
+    }
+}
+
+/// Middleware writing one line per request.
+#[derive(Clone)]
+pub struct AccessLog {
+    format: LogFormat,
+    out: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl AccessLog {
+    pub fn new<W>(out: W, format: LogFormat) -> Self
+    where
+        W: Write + Send + 'static,
+    {
+        AccessLog {
+            format,
+            out: Arc::new(Mutex::new(Box::new(out))),
+        }
+    }
+
+    pub fn stdout(format: LogFormat) -> Self {
+        AccessLog::new(io::stdout(), format)
+    }
+
+    /// Appends to the file at `path`, creating it if needed.
+    pub fn file<P>(path: P, format: LogFormat) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        AccessLog::rotating(path, format, Rotation::default())
+    }
+
+    /// Appends to the file at `path`, moving it aside as specified by `rotation`.
+    /// Lines are written by a background thread, so requests never wait on the disk.
+    pub fn rotating<P>(path: P, format: LogFormat, rotation: Rotation) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let file = RotatingFile::open(path.as_ref().to_path_buf(), rotation)?;
+        Ok(AccessLog::new(BackgroundWriter::spawn(file)?, format))
+    }
+
+    fn log(&self, entry: &Entry, res: &Response, elapsed_ms: f64) {
+        let mut line = format!(
+            "{} - - [{}] \"{}\" {} {}",
+            entry.host,
+            clf_time(entry.time),
+            entry.request_line,
+            res.code().as_u16(),
+            match res.body_len() {
+                None | Some(0) => String::from("-"),
+                Some(len) => len.to_string(),
+            },
+        );
+        if self.format == LogFormat::Combined {
+            line += &format!(" \"{}\" \"{}\"", entry.referer, entry.user_agent);
+        }
+        line += &format!(" {:.3}", elapsed_ms);
+        if let Some(id) = &entry.request_id {
+            line += &format!(" {}", id);
+        }
+
+        line.push('\n');
+
+        // Written at once so that lines are never split, nor interleaved by the writers
+        // forwarding them to a background thread
+        let mut out = self.out.lock().unwrap();
+        if let Err(e) = out.write_all(line.as_bytes()).and_then(|_| out.flush()) {
+            error!("Failed to write access log: {}", e);
+        }
+    }
+}
+
+/// When a log file is moved aside for a new one. Rotated files are renamed with a
+/// numeric suffix, `access.log.1` being the most recent, and only the last few are
+/// kept. The default never rotates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rotation {
+    max_size: Option<u64>,
+    max_age: Option<Duration>,
+    keep: usize,
+}
+
+impl Default for Rotation {
+    fn default() -> Self {
+        Rotation {
+            max_size: None,
+            max_age: None,
+            keep: DEFAULT_KEEP,
+        }
+    }
+}
+
+impl Rotation {
+    /// Rotates before a line would make the file larger than `bytes`.
+    pub fn with_max_size(mut self, bytes: u64) -> Self {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    /// Rotates files once they have been written to for `age`.
+    pub fn with_max_age(mut self, age: Duration) -> Self {
+        self.max_age = Some(age);
+        self
+    }
+
+    /// Number of rotated files kept, 5 by default.
+    pub fn with_keep(mut self, keep: usize) -> Self {
+        self.keep = keep;
+        self
+    }
+}
+
+const DEFAULT_KEEP: usize = 5;
+/// Longest time a line stays buffered before reaching the file.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Log file rotated by a [`Rotation`]. Every write is expected to be whole lines.
+struct RotatingFile {
+    path: PathBuf,
+    rotation: Rotation,
+    file: BufWriter<File>,
+    size: u64,
+    opened: Instant,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, rotation: Rotation) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            rotation,
+            file: BufWriter::new(file),
+            size,
+            opened: Instant::now(),
+        })
+    }
+
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    /// Whether to rotate before writing `len` more bytes. Empty files never are.
+    fn should_rotate(&self, len: usize) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        let too_large = self
+            .rotation
+            .max_size
+            .is_some_and(|max| self.size + len as u64 > max);
+        let too_old = self
+            .rotation
+            .max_age
+            .is_some_and(|age| self.opened.elapsed() >= age);
+        too_large || too_old
+    }
+
+    /// Shifts the rotated files by one, dropping the oldest, then starts a new file.
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.rotation.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(self.rotated_path(self.rotation.keep));
+            for n in (1..self.rotation.keep).rev() {
+                let _ = fs::rename(self.rotated_path(n), self.rotated_path(n + 1));
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        *self = RotatingFile::open(self.path.clone(), self.rotation)?;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.should_rotate(buf.len()) {
+            self.rotate()?;
+        }
+        self.file.write_all(buf)?;
+        self.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Writer handing its bytes over to a thread writing them to `out`, flushed once no
+/// line came for a while. Dropping it waits for the pending lines to be written.
+struct BackgroundWriter {
+    tx: Option<Sender<Vec<u8>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl BackgroundWriter {
+    fn spawn<W>(mut out: W) -> io::Result<Self>
+    where
+        W: Write + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel::<Vec<u8>>();
+        let thread = thread::Builder::new()
+            .name(String::from("access-log"))
+            .spawn(move || {
+                let mut dirty = false;
+                loop {
+                    let written = match rx.recv_timeout(FLUSH_INTERVAL) {
+                        Ok(bytes) => {
+                            dirty = true;
+                            out.write_all(&bytes)
+                        }
+                        Err(RecvTimeoutError::Timeout) if dirty => {
+                            dirty = false;
+                            out.flush()
+                        }
+                        Err(RecvTimeoutError::Timeout) => Ok(()),
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    };
+                    if let Err(e) = written {
+                        error!("Failed to write access log: {}", e);
+                    }
+                }
+                if let Err(e) = out.flush() {
+                    error!("Failed to write access log: {}", e);
+                }
+            })?;
+        Ok(BackgroundWriter {
+            tx: Some(tx),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Write for BackgroundWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let tx = self.tx.as_ref().ok_or(io::ErrorKind::BrokenPipe)?;
+        tx.send(buf.to_vec())
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    /// Lines are flushed by the background thread.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for BackgroundWriter {
+    fn drop(&mut self) {
+        drop(self.tx.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// What is logged about the request, captured before it is handed down the chain.
+struct Entry {
+    host: String,
+    time: SystemTime,
+    request_line: String,
+    referer: String,
+    user_agent: String,
+    request_id: Option<RequestId>,
+}
+
+impl Middleware for AccessLog {
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<Response> {
+        let target = match req.query() {
+            Some(query) => format!("{}?{}", req.path(), query),
+            None => req.path().to_string(),
+        };
+        let entry = Entry {
+            host: req
+                .peer_addr()
+                .map_or_else(|| String::from("-"), |addr| addr.ip().to_string()),
+            time: SystemTime::now(),
+            request_line: format!(
+                "{} {} {}",
+                req.method().as_str(),
+                target,
+                req.version().as_str()
+            ),
+            referer: req.header("Referer").unwrap_or("-").to_string(),
+            user_agent: req.header("User-Agent").unwrap_or("-").to_string(),
+            request_id: req.extensions().get::<RequestId>().cloned(),
+        };
+
+        let log = self.clone();
+        Box::pin(async move {
+            let start = Instant::now();
+            let res = next.run(req).await;
+            log.log(&entry, &res, start.elapsed().as_secs_f64() * 1000.0);
+            res
+        })
+    }
+}
+
+/// Formats `time` as `10/Oct/2000:13:55:36 +0000`.
+fn clf_time(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let secs = secs % 86400;
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{middleware, ConnectionInfo, RequestBuffer};
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_clf_time() {
+        let time = UNIX_EPOCH + Duration::from_secs(971_186_136);
+        assert_eq!(clf_time(time), "10/Oct/2000:13:55:36 +0000");
+        assert_eq!(clf_time(UNIX_EPOCH), "01/Jan/1970:00:00:00 +0000");
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = std::env::temp_dir().join(format!("access-log-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+        let rotation = Rotation::default().with_max_size(10).with_keep(2);
+
+        let mut file = RotatingFile::open(path.clone(), rotation).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n", "fifth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        file.flush().unwrap();
+        // A line larger than the limit still goes to a file of its own
+        let read = |path: PathBuf| fs::read_to_string(path).unwrap();
+        assert_eq!(read(path.clone()), "fifth\n");
+        assert_eq!(read(dir.join("access.log.1")), "fourth\n");
+        assert_eq!(read(dir.join("access.log.2")), "third\n");
+        assert!(!dir.join("access.log.3").exists());
+
+        // Lines are appended to an existing file until it reaches the limit
+        let mut file = RotatingFile::open(path.clone(), rotation).unwrap();
+        file.write_all(b"six\n").unwrap();
+        file.flush().unwrap();
+        assert_eq!(read(path.clone()), "fifth\nsix\n");
+
+        let rotation = Rotation::default().with_max_age(Duration::ZERO);
+        let mut file = RotatingFile::open(path.clone(), rotation).unwrap();
+        file.write_all(b"seventh\n").unwrap();
+        file.flush().unwrap();
+        assert_eq!(read(path), "seventh\n");
+        assert_eq!(read(dir.join("access.log.1")), "fifth\nsix\n");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_background_writer() {
+        let buf = Buffer::default();
+        let mut writer = BackgroundWriter::spawn(buf.clone()).unwrap();
+        writer.write_all(b"one\n").unwrap();
+        writer.write_all(b"two\n").unwrap();
+        // Dropping the writer waits for the pending lines
+        drop(writer);
+        assert_eq!(buf.0.lock().unwrap().as_slice(), b"one\ntwo\n");
+    }
+
+    #[tokio::test]
+    async fn test_access_log() {
+        let buf = Buffer::default();
+        let access_log = AccessLog::new(buf.clone(), LogFormat::Combined);
+
+        let mut req = Request::parse(&mut RequestBuffer::from(
+            "GET /echo/abc?x=1 HTTP/1.1\r\nUser-Agent: curl/8.0\r\n\r\n".bytes(),
+        ))
+        .unwrap();
+        req.set_connection_info(ConnectionInfo {
+            peer_addr: Some("10.0.0.7:51234".parse().unwrap()),
+            ..Default::default()
+        });
+        middleware::send_request(access_log, req, |_| async { Response::from("abc") }).await;
+
+        let line = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        assert!(line.starts_with("10.0.0.7 - - ["));
+        assert!(
+            line.contains("] \"GET /echo/abc?x=1 HTTP/1.1\" 200 3 \"-\" \"curl/8.0\" "),
+            "{}",
+            line
+        );
+        assert!(line.ends_with('\n'));
+    }
+}
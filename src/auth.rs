@@ -0,0 +1,388 @@
+//! Authentication middlewares. Requests authenticated with Basic credentials carry
+//! an [`AuthUser`] extension naming the user, those authenticated with a JWT carry
+//! the verified claims.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+#[cfg(feature = "jwt")]
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::Arc;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+#[cfg(feature = "jwt")]
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+#[cfg(feature = "jwt")]
+use serde::de::DeserializeOwned;
+use sha1::{Digest, Sha1};
+
+use super::middleware::{Middleware, Next};
+use super::router::BoxFuture;
+use super::{HttpCode, Request, Response};
+
+/// Name of the authenticated user, stored in the request extensions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthUser(pub String);
+
+/// Source of the accepted user names and passwords.
+pub trait Credentials: Send + Sync + 'static {
+    fn verify(&self, user: &str, password: &str) -> bool;
+}
+
+/// Plain text passwords by user name.
+impl Credentials for HashMap<String, String> {
+    fn verify(&self, user: &str, password: &str) -> bool {
+        self.get(user)
+            .is_some_and(|expected| constant_time_eq(expected.as_bytes(), password.as_bytes()))
+    }
+}
+
+/// Users of an htpasswd file, with `{SHA}` passwords.
+#[derive(Debug, Default)]
+pub struct Htpasswd {
+    /// Base64 SHA-1 digest of the password of each user.
+    users: HashMap<String, String>,
+}
+
+impl Htpasswd {
+    pub fn load<P>(path: P) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        Htpasswd::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Parses `user:{SHA}digest` lines, skipping blank lines and comments. Any other
+    /// scheme, such as bcrypt, MD5 or crypt, fails rather than letting its hash be
+    /// taken for a plain text password.
+    pub fn parse(content: &str) -> io::Result<Self> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let users = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let (user, hash) = line
+                    .split_once(':')
+                    .ok_or_else(|| invalid(format!("malformed entry {:?}", line)))?;
+                let digest = hash.strip_prefix("{SHA}").ok_or_else(|| {
+                    invalid(format!(
+                        "unsupported hash scheme for user {}, expected {{SHA}}",
+                        user
+                    ))
+                })?;
+                Ok((user.to_string(), digest.to_string()))
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Htpasswd { users })
+    }
+}
+
+impl Credentials for Htpasswd {
+    fn verify(&self, user: &str, password: &str) -> bool {
+        let Some(expected) = self.users.get(user) else {
+            return false;
+        };
+        let digest = BASE64.encode(Sha1::digest(password.as_bytes()));
+        constant_time_eq(expected.as_bytes(), digest.as_bytes())
+    }
+}
+
+/// Middleware requiring HTTP Basic credentials, answering 401 otherwise.
+#[derive(Clone)]
+pub struct BasicAuth {
+    realm: String,
+    credentials: Arc<dyn Credentials>,
+}
+
+impl BasicAuth {
+    pub fn new<C>(realm: impl Into<String>, credentials: C) -> Self
+    where
+        C: Credentials,
+    {
+        BasicAuth {
+            realm: realm.into(),
+            credentials: Arc::new(credentials),
+        }
+    }
+
+    fn authenticate(&self, req: &Request) -> Option<String> {
+        let (scheme, encoded) = req.header("Authorization")?.trim().split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("Basic") {
+            return None;
+        }
+
+        let decoded = String::from_utf8(BASE64.decode(encoded.trim()).ok()?).ok()?;
+        let (user, password) = decoded.split_once(':')?;
+        self.credentials
+            .verify(user, password)
+            .then(|| user.to_string())
+    }
+}
+
+impl Middleware for BasicAuth {
+    fn handle(&self, mut req: Request, next: Next) -> BoxFuture<Response> {
+        match self.authenticate(&req) {
+            Some(user) => {
+                req.extensions_mut().insert(AuthUser(user));
+                next.run(req)
+            }
+            None => {
+                let mut res = Response::from(HttpCode::Unauthorized);
+                res.header(
+                    "WWW-Authenticate",
+                    format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.realm),
+                );
+                Box::pin(async move { res })
+            }
+        }
+    }
+}
+
+/// Middleware requiring a valid `Authorization: Bearer` JWT, whose claims are
+/// deserialized into `C` and stored in the request extensions.
+///
+/// Expired tokens are rejected, as well as tokens for another audience or issuer
+/// when those are configured.
+#[cfg(feature = "jwt")]
+pub struct JwtAuth<C = serde_json::Value> {
+    key: Arc<DecodingKey>,
+    validation: Arc<Validation>,
+    claims: PhantomData<fn() -> C>,
+}
+
+#[cfg(feature = "jwt")]
+impl<C> JwtAuth<C>
+where
+    C: DeserializeOwned + Clone + Send + Sync + 'static,
+{
+    fn new(key: DecodingKey, algorithms: &[Algorithm]) -> Self {
+        let mut validation = Validation::new(algorithms[0]);
+        validation.algorithms = algorithms.to_vec();
+        JwtAuth {
+            key: Arc::new(key),
+            validation: Arc::new(validation),
+            claims: PhantomData,
+        }
+    }
+
+    /// Accepts tokens signed with HS256, HS384 or HS512 using `secret`.
+    pub fn hmac(secret: &[u8]) -> Self {
+        let algorithms = [Algorithm::HS256, Algorithm::HS384, Algorithm::HS512];
+        JwtAuth::new(DecodingKey::from_secret(secret), &algorithms)
+    }
+
+    /// Accepts tokens signed with RS256, RS384, RS512 or PS256 by the PEM public key.
+    pub fn rsa_pem(pem: &[u8]) -> jsonwebtoken::errors::Result<Self> {
+        let algorithms = [
+            Algorithm::RS256,
+            Algorithm::RS384,
+            Algorithm::RS512,
+            Algorithm::PS256,
+        ];
+        Ok(JwtAuth::new(DecodingKey::from_rsa_pem(pem)?, &algorithms))
+    }
+
+    /// Accepts tokens signed with ES256 or ES384 by the PEM public key.
+    pub fn ec_pem(pem: &[u8]) -> jsonwebtoken::errors::Result<Self> {
+        let algorithms = [Algorithm::ES256, Algorithm::ES384];
+        Ok(JwtAuth::new(DecodingKey::from_ec_pem(pem)?, &algorithms))
+    }
+
+    pub fn with_audience(mut self, audience: &str) -> Self {
+        Arc::make_mut(&mut self.validation).set_audience(&[audience]);
+        self
+    }
+
+    pub fn with_issuer(mut self, issuer: &str) -> Self {
+        Arc::make_mut(&mut self.validation).set_issuer(&[issuer]);
+        self
+    }
+
+    /// Tolerance on the expiry time, 60 seconds by default.
+    pub fn with_leeway(mut self, secs: u64) -> Self {
+        Arc::make_mut(&mut self.validation).leeway = secs;
+        self
+    }
+
+    fn authenticate(&self, req: &Request) -> Result<C, String> {
+        let header = req
+            .header("Authorization")
+            .ok_or_else(|| String::from("missing credentials"))?;
+        let token = match header.trim().split_once(' ') {
+            Some((scheme, token)) if scheme.eq_ignore_ascii_case("Bearer") => token.trim(),
+            _ => return Err(String::from("not a bearer token")),
+        };
+
+        jsonwebtoken::decode(token, &self.key, &self.validation)
+            .map(|data| data.claims)
+            .map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(feature = "jwt")]
+impl<C> Clone for JwtAuth<C> {
+    fn clone(&self) -> Self {
+        JwtAuth {
+            key: self.key.clone(),
+            validation: self.validation.clone(),
+            claims: PhantomData,
+        }
+    }
+}
+
+#[cfg(feature = "jwt")]
+impl<C> Middleware for JwtAuth<C>
+where
+    C: DeserializeOwned + Clone + Send + Sync + 'static,
+{
+    fn handle(&self, mut req: Request, next: Next) -> BoxFuture<Response> {
+        match self.authenticate(&req) {
+            Ok(claims) => {
+                req.extensions_mut().insert(claims);
+                next.run(req)
+            }
+            Err(reason) => {
+                let mut res = Response::from(HttpCode::Unauthorized);
+                let challenge = match req.header("Authorization") {
+                    Some(_) => format!(
+                        "Bearer error=\"invalid_token\", error_description=\"{}\"",
+                        reason.replace('"', "'")
+                    ),
+                    None => String::from("Bearer"),
+                };
+                res.header("WWW-Authenticate", challenge);
+                Box::pin(async move { res })
+            }
+        }
+    }
+}
+
+/// Compares secrets in a time independent of where they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extract::Extension;
+    use crate::{middleware, ComparePath, Route, Router};
+
+    async fn send<M>(auth: M, authorization: Option<&str>) -> Response
+    where
+        M: Middleware,
+    {
+        let mut router = Router::default();
+        router.add_route(Route::get(
+            "/user",
+            |Extension(AuthUser(user)): Extension<AuthUser>| Response::from(user),
+            ComparePath::Exact,
+        ));
+        #[cfg(feature = "jwt")]
+        router.add_route(Route::get(
+            "/claims",
+            |Extension(claims): Extension<serde_json::Value>| {
+                Response::from(claims["sub"].to_string())
+            },
+            ComparePath::Exact,
+        ));
+        let router = Arc::new(router);
+
+        let path = if authorization.is_some_and(|a| a.starts_with("Bearer")) {
+            "/claims"
+        } else {
+            "/user"
+        };
+        let mut req = format!("GET {} HTTP/1.1\r\n", path);
+        if let Some(authorization) = authorization {
+            req += &format!("Authorization: {}\r\n", authorization);
+        }
+        req += "\r\n";
+
+        middleware::send(auth, &req, move |req| {
+            let router = router.clone();
+            async move { router.route(req).await }
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_basic_auth() {
+        let users = HashMap::from([(String::from("ada"), String::from("lovelace"))]);
+        let auth = BasicAuth::new("files", users);
+
+        let credentials = format!("Basic {}", BASE64.encode("ada:lovelace"));
+        let res = send(auth.clone(), Some(&credentials)).await;
+        assert!(res.into_bytes().ends_with(b"\r\n\r\nada"));
+
+        let wrong = format!("Basic {}", BASE64.encode("ada:babbage"));
+        for authorization in [None, Some(wrong.as_str()), Some("Digest token")] {
+            let res = send(auth.clone(), authorization).await;
+            assert_eq!(
+                res.header_value("WWW-Authenticate"),
+                Some("Basic realm=\"files\", charset=\"UTF-8\"")
+            );
+            assert!(res
+                .into_bytes()
+                .starts_with(b"HTTP/1.1 401 Unauthorized\r\n"));
+        }
+    }
+
+    #[test]
+    fn test_htpasswd() {
+        let htpasswd = Htpasswd::parse(
+            "# users\n\
+             ada:{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=\n",
+        )
+        .unwrap();
+        assert!(htpasswd.verify("ada", "password"));
+        assert!(!htpasswd.verify("ada", "passwore"));
+        assert!(!htpasswd.verify("bob", "password"));
+
+        // Hashes of other schemes are not passwords to compare with
+        for entry in [
+            "bob:plain",
+            "eve:$2y$05$abcdefghijklmnopqrstuv",
+            "mallory:$apr1$salt$hash",
+            "oscar:rqXBDk5nbqUxY",
+            "trent",
+        ] {
+            assert!(Htpasswd::parse(entry).is_err(), "{} was accepted", entry);
+        }
+    }
+
+    #[cfg(feature = "jwt")]
+    #[tokio::test]
+    async fn test_jwt_auth() {
+        use jsonwebtoken::{EncodingKey, Header};
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let token = |claims: serde_json::Value| {
+            let key = EncodingKey::from_secret(b"secret");
+            let token = jsonwebtoken::encode(&Header::default(), &claims, &key).unwrap();
+            format!("Bearer {}", token)
+        };
+        let auth = JwtAuth::<serde_json::Value>::hmac(b"secret").with_audience("files");
+
+        let valid = token(serde_json::json!({ "sub": "ada", "aud": "files", "exp": now + 60 }));
+        let res = send(auth.clone(), Some(&valid)).await;
+        assert!(res.into_bytes().ends_with(b"\r\n\r\n\"ada\""));
+
+        let expired = token(serde_json::json!({ "sub": "ada", "aud": "files", "exp": now - 3600 }));
+        let other_audience =
+            token(serde_json::json!({ "sub": "ada", "aud": "admin", "exp": now + 60 }));
+        let forged = valid.replace("Bearer ey", "Bearer eY");
+        for authorization in [expired, other_audience, forged] {
+            let res = send(auth.clone(), Some(&authorization)).await;
+            let challenge = res.header_value("WWW-Authenticate").unwrap();
+            assert!(challenge.starts_with("Bearer error=\"invalid_token\""));
+            assert!(res.into_bytes().starts_with(b"HTTP/1.1 401 "));
+        }
+    }
+}
@@ -0,0 +1,34 @@
+//! Bounds on the size of request bodies.
+
+use super::error::AppError;
+use super::middleware::{Middleware, Next};
+use super::router::BoxFuture;
+use super::{Request, Response};
+
+/// Middleware answering 413 to requests whose body is over `max` bytes.
+///
+/// Connections check the limit of the route as soon as the request head is
+/// received, so oversized uploads are turned down without being buffered.
+#[derive(Debug, Clone, Copy)]
+pub struct BodyLimit {
+    max: usize,
+}
+
+impl BodyLimit {
+    pub fn new(max: usize) -> Self {
+        BodyLimit { max }
+    }
+}
+
+impl Middleware for BodyLimit {
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<Response> {
+        if req.content_length() > self.max || req.body().len() > self.max {
+            return Box::pin(async { Response::from(AppError::PayloadTooLarge) });
+        }
+        next.run(req)
+    }
+
+    fn body_limit(&self) -> Option<usize> {
+        Some(self.max)
+    }
+}
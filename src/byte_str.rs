@@ -0,0 +1,138 @@
+//! Text sharing the buffer it was received in.
+
+use std::borrow::Borrow;
+use std::fmt;
+use std::ops::Deref;
+use std::str::Utf8Error;
+
+use bytes::Bytes;
+
+/// UTF-8 text held in [`Bytes`], so that the path and headers of a request point into
+/// the buffer the connection received them in rather than being copied out of it.
+/// Cloning it only counts one more reference to that buffer.
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ByteStr(Bytes);
+
+impl ByteStr {
+    pub const fn from_static(text: &'static str) -> Self {
+        ByteStr(Bytes::from_static(text.as_bytes()))
+    }
+
+    /// Text of `bytes`, failing when they are not UTF-8.
+    pub fn from_utf8(bytes: Bytes) -> Result<Self, Utf8Error> {
+        std::str::from_utf8(&bytes)?;
+        Ok(ByteStr(bytes))
+    }
+
+    /// Text of `text`, which must be a slice of `self`, sharing its buffer.
+    pub fn slice_ref(&self, text: &str) -> Self {
+        ByteStr(self.0.slice_ref(text.as_bytes()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        // SAFETY: the bytes were checked to be UTF-8 when this was built, and slices
+        // only ever get taken at the bounds of a `str`
+        unsafe { std::str::from_utf8_unchecked(&self.0) }
+    }
+
+    pub fn as_bytes(&self) -> &Bytes {
+        &self.0
+    }
+}
+
+impl Deref for ByteStr {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl AsRef<str> for ByteStr {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl Borrow<str> for ByteStr {
+    fn borrow(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl fmt::Debug for ByteStr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Display for ByteStr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self.as_str(), f)
+    }
+}
+
+impl From<String> for ByteStr {
+    fn from(text: String) -> Self {
+        ByteStr(Bytes::from(text.into_bytes()))
+    }
+}
+
+impl From<&str> for ByteStr {
+    fn from(text: &str) -> Self {
+        ByteStr(Bytes::copy_from_slice(text.as_bytes()))
+    }
+}
+
+impl From<&String> for ByteStr {
+    fn from(text: &String) -> Self {
+        ByteStr::from(text.as_str())
+    }
+}
+
+impl From<ByteStr> for String {
+    fn from(text: ByteStr) -> Self {
+        text.as_str().to_string()
+    }
+}
+
+impl PartialEq<str> for ByteStr {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for ByteStr {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl PartialEq<String> for ByteStr {
+    fn eq(&self, other: &String) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<ByteStr> for &str {
+    fn eq(&self, other: &ByteStr) -> bool {
+        *self == other.as_str()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slice_ref() {
+        let head = ByteStr::from_utf8(Bytes::from_static(b"Host: localhost")).unwrap();
+        let (name, value) = head.split_once(": ").unwrap();
+        let (name, value) = (head.slice_ref(name), head.slice_ref(value));
+        assert_eq!((name.as_str(), value.as_str()), ("Host", "localhost"));
+        // Both point into the head instead of a copy of it
+        assert_eq!(value.as_bytes().as_ptr(), head.as_bytes()[6..].as_ptr());
+
+        assert!(ByteStr::from_utf8(Bytes::from_static(b"\xff")).is_err());
+    }
+}
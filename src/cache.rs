@@ -0,0 +1,412 @@
+//! In-memory caching of responses, keyed by method, target and the request headers
+//! named in the response `Vary` header.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::middleware::{Middleware, Next};
+use super::router::BoxFuture;
+use super::{HttpCode, Method, Request, Response};
+
+const DEFAULT_MAX_ENTRIES: usize = 1024;
+const DEFAULT_MAX_BYTES: usize = 64 * 1024 * 1024;
+
+/// Responses kept in memory, evicting the least recently used ones once over its
+/// bounds. Cloning it shares the entries.
+#[derive(Clone)]
+pub struct ResponseCache {
+    inner: Arc<Mutex<Entries>>,
+    max_entries: usize,
+    max_bytes: usize,
+}
+
+#[derive(Default)]
+struct Entries {
+    /// Variants of each method and target, told apart by their `Vary` header values.
+    variants: HashMap<String, Vec<Entry>>,
+    len: usize,
+    bytes: usize,
+    /// Counter giving the recency of entries.
+    clock: u64,
+}
+
+struct Entry {
+    vary: Vec<(String, Option<String>)>,
+    response: Response,
+    stored: Instant,
+    expires: Instant,
+    last_used: u64,
+}
+
+impl Entry {
+    fn matches(&self, req: &Request) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| req.header(name) == value.as_deref())
+    }
+}
+
+impl Default for ResponseCache {
+    fn default() -> Self {
+        ResponseCache {
+            inner: Arc::default(),
+            max_entries: DEFAULT_MAX_ENTRIES,
+            max_bytes: DEFAULT_MAX_BYTES,
+        }
+    }
+}
+
+impl ResponseCache {
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries;
+        self
+    }
+
+    /// Bound on the total size of the cached bodies.
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Looks up a fresh response for `req`, with its `Age` header set.
+    fn get(&self, key: &str, req: &Request) -> Option<Response> {
+        let now = Instant::now();
+        let mut entries = self.inner.lock().unwrap();
+        entries.clock += 1;
+        let clock = entries.clock;
+
+        let variants = entries.variants.get_mut(key)?;
+        let entry = variants.iter_mut().find(|entry| entry.matches(req))?;
+        if entry.expires <= now {
+            return None;
+        }
+        entry.last_used = clock;
+
+        let mut res = entry.response.clone();
+        res.header(
+            "Age",
+            now.duration_since(entry.stored).as_secs().to_string(),
+        );
+        Some(res)
+    }
+
+    fn insert(
+        &self,
+        key: String,
+        vary: Vec<(String, Option<String>)>,
+        res: &Response,
+        ttl: Duration,
+    ) {
+        let size = res.content().len();
+        if size > self.max_bytes || self.max_entries == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut entries = self.inner.lock().unwrap();
+        entries.clock += 1;
+        let entry = Entry {
+            vary,
+            response: res.clone(),
+            stored: now,
+            expires: now + ttl,
+            last_used: entries.clock,
+        };
+
+        let Entries {
+            variants,
+            len,
+            bytes,
+            ..
+        } = &mut *entries;
+        let slot = variants.entry(key).or_default();
+        match slot.iter_mut().find(|e| e.vary == entry.vary) {
+            Some(old) => {
+                *bytes -= old.response.content().len();
+                *old = entry;
+            }
+            None => {
+                slot.push(entry);
+                *len += 1;
+            }
+        }
+        *bytes += size;
+
+        entries.evict(self.max_entries, self.max_bytes, now);
+    }
+
+    /// Drops every variant of `key`, e.g. once the resource was modified.
+    fn remove(&self, key: &str) {
+        let mut entries = self.inner.lock().unwrap();
+        if let Some(removed) = entries.variants.remove(key) {
+            entries.len -= removed.len();
+            entries.bytes -= removed
+                .iter()
+                .map(|e| e.response.content().len())
+                .sum::<usize>();
+        }
+    }
+}
+
+impl Entries {
+    fn evict(&mut self, max_entries: usize, max_bytes: usize, now: Instant) {
+        if self.len <= max_entries && self.bytes <= max_bytes {
+            return;
+        }
+
+        // Expired entries go first, then the least recently used ones
+        self.retain(|entry| entry.expires > now);
+        while self.len > max_entries || self.bytes > max_bytes {
+            let Some(oldest) = self
+                .variants
+                .values()
+                .flatten()
+                .map(|entry| entry.last_used)
+                .min()
+            else {
+                break;
+            };
+            self.retain(|entry| entry.last_used != oldest);
+        }
+    }
+
+    fn retain<F>(&mut self, mut keep: F)
+    where
+        F: FnMut(&Entry) -> bool,
+    {
+        let (mut len, mut bytes) = (0, 0);
+        self.variants.retain(|_, variants| {
+            variants.retain(|entry| keep(entry));
+            len += variants.len();
+            bytes += variants
+                .iter()
+                .map(|e| e.response.content().len())
+                .sum::<usize>();
+            !variants.is_empty()
+        });
+        self.len = len;
+        self.bytes = bytes;
+    }
+}
+
+/// Middleware answering GET requests from a [`ResponseCache`] while the stored
+/// response is fresh. Only 200 responses with an in-memory body are stored, unless
+/// they carry `Cache-Control: no-store` or `private`, a `Set-Cookie` header, or
+/// `Vary: *`.
+///
+/// HEAD requests are answered from the cached GET response, without being stored
+/// themselves. Successful POST, PUT, PATCH and DELETE requests drop the cached
+/// responses of their target, other methods leave them be. Giving routes clones of
+/// the middleware with different TTLs lets them share the cache bounds.
+#[derive(Clone)]
+pub struct Cache {
+    cache: ResponseCache,
+    ttl: Duration,
+}
+
+impl Cache {
+    pub fn new(ttl: Duration) -> Self {
+        Cache {
+            cache: ResponseCache::default(),
+            ttl,
+        }
+    }
+
+    pub fn with_cache(mut self, cache: ResponseCache) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    pub fn cache(&self) -> &ResponseCache {
+        &self.cache
+    }
+}
+
+fn target(req: &Request) -> String {
+    match req.query() {
+        Some(query) => format!("{}?{}", req.path(), query),
+        None => req.path().to_string(),
+    }
+}
+
+fn is_storable(res: &Response) -> bool {
+    let cache_control = res.header_value("Cache-Control").unwrap_or_default();
+    res.code() == HttpCode::Ok
+        && !res.is_stream()
+        && res.header_value("Set-Cookie").is_none()
+        && res.header_value("Vary").map(str::trim) != Some("*")
+        && !cache_control.split(',').any(|directive| {
+            let directive = directive.trim();
+            directive.eq_ignore_ascii_case("no-store") || directive.eq_ignore_ascii_case("private")
+        })
+}
+
+impl Middleware for Cache {
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<Response> {
+        let key = format!("GET {}", target(&req));
+
+        match req.method() {
+            Method::Get | Method::Head => {}
+            Method::Post | Method::Put | Method::Patch | Method::Delete => {
+                let cache = self.cache.clone();
+                return Box::pin(async move {
+                    let res = next.run(req).await;
+                    if res.code().as_u16() < 400 {
+                        cache.remove(&key);
+                    }
+                    res
+                });
+            }
+            // Safe methods do not change the target
+            _ => return next.run(req),
+        }
+
+        // The client asks for a response validated by the server
+        let no_cache = req
+            .header("Cache-Control")
+            .is_some_and(|value| value.contains("no-cache"));
+        if !no_cache {
+            // The connection leaves the body out of answers to HEAD requests
+            if let Some(res) = self.cache.get(&key, &req) {
+                return Box::pin(async move { res });
+            }
+        }
+        // Responses to HEAD requests may lack the body a GET would get
+        if *req.method() == Method::Head {
+            return next.run(req);
+        }
+
+        let cache = self.cache.clone();
+        let ttl = self.ttl;
+        let headers = req.headers().to_vec();
+        Box::pin(async move {
+            let res = next.run(req).await;
+            if is_storable(&res) {
+                let vary = res
+                    .header_value("Vary")
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .map(|name| {
+                        let value = headers
+                            .iter()
+                            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                            .map(|(_, v)| v.to_string());
+                        (name.to_ascii_lowercase(), value)
+                    })
+                    .collect();
+                cache.insert(key, vary, &res, ttl);
+            }
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::middleware;
+
+    /// Answers with the path and the number of calls before this one.
+    async fn send(cache: &Cache, calls: &Arc<AtomicUsize>, req: &str) -> Response {
+        let calls = calls.clone();
+        middleware::send(cache.clone(), req, move |req: Request| {
+            let n = calls.fetch_add(1, Ordering::SeqCst);
+            let mut res = Response::from(format!("{} {}", req.path(), n));
+            res.header("Vary", "Accept-Encoding");
+            async move { res }
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_cache() {
+        let cache = Cache::new(Duration::from_secs(60));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let gzip = "GET /a HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n";
+
+        let res = send(&cache, &calls, gzip).await;
+        assert_eq!(res.content(), b"/a 0");
+        let res = send(&cache, &calls, gzip).await;
+        assert_eq!(res.content(), b"/a 0");
+        assert_eq!(res.header_value("Age"), Some("0"));
+
+        // Another variant, another target, then a forced revalidation
+        let res = send(&cache, &calls, "GET /a HTTP/1.1\r\n\r\n").await;
+        assert_eq!(res.content(), b"/a 1");
+        let res = send(&cache, &calls, "GET /a?x=1 HTTP/1.1\r\n\r\n").await;
+        assert_eq!(res.content(), b"/a 2");
+        let req = "GET /a HTTP/1.1\r\nAccept-Encoding: gzip\r\nCache-Control: no-cache\r\n\r\n";
+        let res = send(&cache, &calls, req).await;
+        assert_eq!(res.content(), b"/a 3");
+        assert_eq!(cache.cache().len(), 3);
+
+        send(&cache, &calls, "POST /a HTTP/1.1\r\n\r\n").await;
+        assert_eq!(cache.cache().len(), 1);
+        let res = send(&cache, &calls, gzip).await;
+        assert_eq!(res.content(), b"/a 5");
+    }
+
+    #[tokio::test]
+    async fn test_safe_methods() {
+        let cache = Cache::new(Duration::from_secs(60));
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        // Not stored, then answered from the GET response
+        let res = send(&cache, &calls, "HEAD /a HTTP/1.1\r\n\r\n").await;
+        assert_eq!(res.content(), b"/a 0");
+        assert_eq!(cache.cache().len(), 0);
+        send(&cache, &calls, "GET /a HTTP/1.1\r\n\r\n").await;
+        let res = send(&cache, &calls, "HEAD /a HTTP/1.1\r\n\r\n").await;
+        assert_eq!(res.content(), b"/a 1");
+
+        // None of these drop the cached response
+        for method in ["HEAD", "OPTIONS", "TRACE"] {
+            send(&cache, &calls, &format!("{} /a HTTP/1.1\r\n\r\n", method)).await;
+        }
+        assert_eq!(cache.cache().len(), 1);
+        let res = send(&cache, &calls, "GET /a HTTP/1.1\r\n\r\n").await;
+        assert_eq!(res.content(), b"/a 1");
+
+        send(&cache, &calls, "PATCH /a HTTP/1.1\r\n\r\n").await;
+        assert_eq!(cache.cache().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_ttl_and_eviction() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let cache = Cache::new(Duration::ZERO);
+        send(&cache, &calls, "GET /a HTTP/1.1\r\n\r\n").await;
+        let res = send(&cache, &calls, "GET /a HTTP/1.1\r\n\r\n").await;
+        assert_eq!(res.content(), b"/a 1");
+
+        let cache = Cache::new(Duration::from_secs(60))
+            .with_cache(ResponseCache::default().with_max_entries(2));
+        for path in ["/a", "/b", "/a", "/c"] {
+            send(&cache, &calls, &format!("GET {} HTTP/1.1\r\n\r\n", path)).await;
+        }
+        assert_eq!(cache.cache().len(), 2);
+        let calls_before = calls.load(Ordering::SeqCst);
+        send(&cache, &calls, "GET /a HTTP/1.1\r\n\r\n").await;
+        assert_eq!(calls.load(Ordering::SeqCst), calls_before);
+        send(&cache, &calls, "GET /b HTTP/1.1\r\n\r\n").await;
+        assert_eq!(calls.load(Ordering::SeqCst), calls_before + 1);
+    }
+}
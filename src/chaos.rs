@@ -0,0 +1,309 @@
+//! Transport wrapper injecting faults, to check how handlers and the server's error
+//! paths behave over a slow or unreliable network.
+//!
+//! Faults are drawn from a seeded generator, so that a failing run can be replayed
+//! with the seed it used.
+//!
+//! ```no_run
+//! # async fn run(router: http_server_starter_rust::SharedRouter) {
+//! use std::time::Duration;
+//! use http_server_starter_rust::{Chaos, ChaosOptions, Connection, ConnectionOptions};
+//!
+//! let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+//! let (stream, _) = listener.accept().await.unwrap();
+//! let options = ChaosOptions {
+//!     latency: Duration::from_millis(20),
+//!     jitter: Duration::from_millis(80),
+//!     max_write: Some(16),
+//!     disconnect_rate: 0.01,
+//!     ..Default::default()
+//! };
+//! Connection::new(Chaos::new(stream, options), ConnectionOptions::default())
+//!     .serve(&router)
+//!     .await;
+//! # }
+//! ```
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+use super::transport::Transport;
+
+/// Faults injected by [`Chaos`], none by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ChaosOptions {
+    /// Delay before every read and write goes through.
+    pub latency: Duration,
+    /// Random delay added to the latency, up to this much.
+    pub jitter: Duration,
+    /// Most bytes accepted by a write, a random number of them up to this being
+    /// written at once.
+    pub max_write: Option<usize>,
+    /// Chance of every read and write to fail as if the peer had reset the
+    /// connection, from 0 to 1. Everything fails afterwards.
+    pub disconnect_rate: f64,
+    /// Seed of the faults drawn.
+    pub seed: u64,
+}
+
+/// Transport passing reads and writes through to `T`, with the faults of its
+/// [`ChaosOptions`].
+#[derive(Debug)]
+pub struct Chaos<T> {
+    inner: T,
+    options: ChaosOptions,
+    rng: SplitMix64,
+    read_delay: Option<Pin<Box<Sleep>>>,
+    write_delay: Option<Pin<Box<Sleep>>>,
+    disconnected: bool,
+}
+
+impl<T: Transport> Chaos<T> {
+    pub fn new(inner: T, options: ChaosOptions) -> Self {
+        Chaos {
+            inner,
+            options,
+            rng: SplitMix64(options.seed),
+            read_delay: None,
+            write_delay: None,
+            disconnected: false,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Waits for the delay of the next read or write, then has it fail when the
+    /// connection is to be dropped.
+    fn poll_fault(&mut self, cx: &mut Context<'_>, write: bool) -> Poll<io::Result<()>> {
+        let (latency, jitter) = (self.options.latency, self.options.jitter);
+        if !latency.is_zero() || !jitter.is_zero() {
+            let slot = match write {
+                true => &mut self.write_delay,
+                false => &mut self.read_delay,
+            };
+            let sleep = slot.get_or_insert_with(|| {
+                let delay = latency + jitter.mul_f64(self.rng.next_f64());
+                Box::pin(tokio::time::sleep(delay))
+            });
+            ready!(sleep.as_mut().poll(cx));
+        }
+
+        if !self.disconnected && self.rng.next_f64() < self.options.disconnect_rate {
+            self.disconnected = true;
+        }
+        match self.disconnected {
+            true => Poll::Ready(Err(io::ErrorKind::ConnectionReset.into())),
+            false => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+impl<T: Transport> AsyncRead for Chaos<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        ready!(self.poll_fault(cx, false))?;
+        let read = ready!(Pin::new(&mut self.inner).poll_read(cx, buf));
+        self.read_delay = None;
+        Poll::Ready(read)
+    }
+}
+
+impl<T: Transport> AsyncWrite for Chaos<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.poll_fault(cx, true))?;
+        let len = match self.options.max_write {
+            Some(max) if buf.len() > 1 => {
+                let max = max.clamp(1, buf.len());
+                1 + (self.rng.next_u64() % max as u64) as usize
+            }
+            _ => buf.len(),
+        };
+        let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &buf[..len]));
+        self.write_delay = None;
+        Poll::Ready(written)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Small generator with a well spread output whatever the seed, zero included.
+#[derive(Debug)]
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Number from 0 included to 1 excluded.
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MockTransport;
+    use crate::{
+        ComparePath, Connection, ConnectionOptions, Request, Response, Route, Router, SharedRouter,
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::time::Instant;
+
+    /// Transport counting the writes it receives.
+    #[derive(Default)]
+    struct Writes(Vec<usize>);
+
+    impl AsyncRead for Writes {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            _: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for Writes {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.0.push(buf.len());
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_partial_writes() {
+        let options = ChaosOptions {
+            max_write: Some(4),
+            seed: 7,
+            ..Default::default()
+        };
+        let mut chaos = Chaos::new(Writes::default(), options);
+        chaos.write_all(&[0; 100]).await.unwrap();
+        let writes = chaos.into_inner().0;
+        assert_eq!(writes.iter().sum::<usize>(), 100);
+        assert!(writes.iter().all(|&len| (1..=4).contains(&len)));
+
+        // The same seed gives the same faults
+        let mut chaos = Chaos::new(Writes::default(), options);
+        chaos.write_all(&[0; 100]).await.unwrap();
+        assert_eq!(chaos.into_inner().0, writes);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_latency() {
+        let options = ChaosOptions {
+            latency: Duration::from_millis(100),
+            jitter: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let transport = MockTransport::default().receive(b"ping");
+        let mut chaos = Chaos::new(transport, options);
+
+        let started = Instant::now();
+        let mut buf = Vec::new();
+        chaos.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"ping");
+        // A read for the data, another one for the end of the stream
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(200), "{:?}", elapsed);
+        assert!(elapsed <= Duration::from_millis(300), "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn test_disconnect() {
+        let options = ChaosOptions {
+            disconnect_rate: 1.0,
+            ..Default::default()
+        };
+        let mut chaos = Chaos::new(Writes::default(), options);
+        let err = chaos.write_all(b"data").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        let err = chaos.read(&mut [0; 4]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        assert!(chaos.into_inner().0.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_connection() {
+        let mut router = Router::default();
+        router.add_route(Route::post(
+            "/",
+            |req: Request| Response::from(req.body().to_vec()),
+            ComparePath::Exact,
+        ));
+        let router = SharedRouter::from(router);
+        let body = "a".repeat(1000);
+        let req = format!(
+            "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 1000\r\n\r\n{}",
+            body
+        );
+
+        for seed in 0..10 {
+            let options = ChaosOptions {
+                jitter: Duration::from_millis(10),
+                max_write: Some(64),
+                seed,
+                ..Default::default()
+            };
+            let transport = MockTransport::default().receive(req.as_bytes());
+            let written = transport.written();
+            Connection::new(Chaos::new(transport, options), ConnectionOptions::default())
+                .serve(&router)
+                .await;
+            let res = written.text();
+            assert!(res.starts_with("HTTP/1.1 200 OK\r\n"), "{}", seed);
+            assert!(res.ends_with(&body), "{}", seed);
+        }
+
+        // The client going away is not an error of the server
+        let options = ChaosOptions {
+            disconnect_rate: 1.0,
+            ..Default::default()
+        };
+        let transport = MockTransport::default().receive(req.as_bytes());
+        let written = transport.written();
+        Connection::new(Chaos::new(transport, options), ConnectionOptions::default())
+            .serve(&router)
+            .await;
+        assert!(written.bytes().is_empty());
+    }
+}
@@ -0,0 +1,226 @@
+//! Request bodies sent with the chunked transfer coding, decoded as they are received
+//! and handed out once complete so that handlers see them as any other body.
+
+use std::mem;
+
+use super::HttpCode;
+
+/// Length of the longest chunk size or trailer line, extensions included.
+const MAX_LINE_SIZE: usize = 4096;
+
+/// Decoder of a chunked body, keeping its place between reads so that the bytes already
+/// received are not gone through again.
+#[derive(Debug)]
+pub(crate) struct Decoder {
+    state: State,
+    /// Number of bytes of the encoded body gone through so far.
+    pos: usize,
+    /// Where the trailer section starts, once the last chunk is through.
+    trailers: usize,
+    body: Vec<u8>,
+    max: usize,
+    max_trailers: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Expecting a chunk size line.
+    Size,
+    /// Inside a chunk, with this many bytes of it left.
+    Data(usize),
+    /// Expecting the CRLF ending a chunk.
+    DataEnd,
+    /// Expecting trailer lines, until the empty one ending the body.
+    Trailers,
+}
+
+impl Decoder {
+    /// Decoder of a body refused with a 413 once its chunks, size lines and extensions
+    /// included, are over `max` bytes, and with a 431 once its trailer section is over
+    /// `max_trailers` bytes.
+    pub(crate) fn new(max: usize, max_trailers: usize) -> Self {
+        Decoder {
+            state: State::Size,
+            pos: 0,
+            trailers: 0,
+            body: Vec::new(),
+            max,
+            max_trailers,
+        }
+    }
+
+    /// Goes on decoding the chunked body at the start of `buf`, which holds everything
+    /// received of it so far. Gives the body along with the number of bytes it took once
+    /// complete, `None` until then. Malformed bodies give the status to answer them with,
+    /// a 413 as soon as a chunk would take the body over the limit. Extensions and
+    /// trailer fields are ignored, though they count against the limits as they stay in
+    /// `buf` until the body is complete.
+    pub(crate) fn decode(&mut self, buf: &[u8]) -> Result<Option<(Vec<u8>, usize)>, HttpCode> {
+        loop {
+            let rest = &buf[self.pos..];
+            match self.state {
+                State::Size => {
+                    let Some(line) = line(rest)? else {
+                        return Ok(None);
+                    };
+                    let size = chunk_size(line)?;
+                    self.pos += line.len() + 2;
+                    if self.pos > self.max || size > self.max - self.pos {
+                        return Err(HttpCode::PayloadTooLarge);
+                    }
+                    self.state = match size {
+                        0 => {
+                            self.trailers = self.pos;
+                            State::Trailers
+                        }
+                        size => State::Data(size),
+                    };
+                }
+                State::Data(left) => {
+                    if rest.is_empty() {
+                        return Ok(None);
+                    }
+                    let data = &rest[..left.min(rest.len())];
+                    self.body.extend_from_slice(data);
+                    self.pos += data.len();
+                    self.state = match left - data.len() {
+                        0 => State::DataEnd,
+                        left => State::Data(left),
+                    };
+                }
+                State::DataEnd => match rest.get(..2) {
+                    None => return Ok(None),
+                    Some(b"\r\n") => {
+                        self.pos += 2;
+                        self.state = State::Size;
+                    }
+                    Some(_) => return Err(HttpCode::BadRequest),
+                },
+                State::Trailers => {
+                    let Some(line) = line(rest)? else {
+                        return Ok(None);
+                    };
+                    self.pos += line.len() + 2;
+                    if line.is_empty() {
+                        return Ok(Some((mem::take(&mut self.body), self.pos)));
+                    }
+                    if self.pos - self.trailers > self.max_trailers {
+                        return Err(HttpCode::RequestHeaderFieldsTooLarge);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Decodes a chunked body received in `reads`, each one adding to the bytes before
+/// it, with a decoder limited to `max` bytes of body and of trailers. Gives what the
+/// last decoding gave, the same as decoding all of it at once. Only there for fuzzing.
+#[doc(hidden)]
+pub fn decode_chunked<'a>(
+    reads: impl IntoIterator<Item = &'a [u8]>,
+    max: usize,
+) -> Result<Option<(Vec<u8>, usize)>, HttpCode> {
+    let mut decoder = Decoder::new(max, max);
+    let mut buf = Vec::new();
+    for read in reads {
+        buf.extend_from_slice(read);
+        if let Some(decoded) = decoder.decode(&buf)? {
+            return Ok(Some(decoded));
+        }
+    }
+    Ok(None)
+}
+
+/// Line at the start of `buf` without its CRLF, `None` until it is complete.
+fn line(buf: &[u8]) -> Result<Option<&[u8]>, HttpCode> {
+    match buf.windows(2).position(|w| w == b"\r\n") {
+        Some(end) if end <= MAX_LINE_SIZE => Ok(Some(&buf[..end])),
+        None if buf.len() <= MAX_LINE_SIZE => Ok(None),
+        _ => Err(HttpCode::BadRequest),
+    }
+}
+
+/// Size in a chunk size line, refused when it overflows like an overlong
+/// `Content-Length` is.
+fn chunk_size(line: &[u8]) -> Result<usize, HttpCode> {
+    let size = line.split(|&b| b == b';').next().unwrap_or_default();
+    let size = std::str::from_utf8(size)
+        .map_err(|_| HttpCode::BadRequest)?
+        .trim_end_matches([' ', '\t']);
+    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(HttpCode::BadRequest);
+    }
+    usize::from_str_radix(size, 16).map_err(|_| HttpCode::BadRequest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(buf: &[u8], max: usize) -> Result<Option<(Vec<u8>, usize)>, HttpCode> {
+        Decoder::new(max, 100).decode(buf)
+    }
+
+    #[test]
+    fn test_decode() {
+        let buf = b"5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\nExpires: never\r\n\r\nGET";
+        let (body, used) = decode(buf, 100).unwrap().unwrap();
+        assert_eq!(body, b"hello world");
+        assert_eq!(&buf[used..], b"GET");
+
+        // Incomplete at any point, whether decoded at once or as it is received
+        let mut decoder = Decoder::new(100, 100);
+        for end in 0..buf.len() - 3 {
+            assert_eq!(decode(&buf[..end], 100), Ok(None), "{}", end);
+            assert_eq!(decoder.decode(&buf[..end]), Ok(None), "{}", end);
+        }
+        let (body, used) = decoder.decode(buf).unwrap().unwrap();
+        assert_eq!(body, b"hello world");
+        assert_eq!(&buf[used..], b"GET");
+
+        assert_eq!(decode(buf, 10), Err(HttpCode::PayloadTooLarge));
+        for invalid in [
+            &b"x\r\n"[..],
+            b"\r\n",
+            b"-5\r\nhello\r\n",
+            b"5\r\nhelloworld\r\n",
+            b"10000000000000000\r\n",
+        ] {
+            assert_eq!(
+                decode(invalid, 100),
+                Err(HttpCode::BadRequest),
+                "{:?}",
+                invalid
+            );
+        }
+        assert_eq!(decode(&[b'1'; 5000], 100), Err(HttpCode::BadRequest));
+    }
+
+    #[test]
+    fn test_decode_limit() {
+        // Refused on the size line taking the body over the limit, before its data
+        let mut decoder = Decoder::new(12, 100);
+        assert_eq!(decoder.decode(b"5\r\nhello\r\n"), Ok(None));
+        assert_eq!(
+            decoder.decode(b"5\r\nhello\r\n1\r\n"),
+            Err(HttpCode::PayloadTooLarge)
+        );
+
+        // Size lines count, however little data they carry
+        let ext = format!("1;{}\r\na\r\n", "x".repeat(100));
+        let mut decoder = Decoder::new(300, 100);
+        assert_eq!(decoder.decode(ext.repeat(2).as_bytes()), Ok(None));
+        assert_eq!(
+            decoder.decode(ext.repeat(3).as_bytes()),
+            Err(HttpCode::PayloadTooLarge)
+        );
+
+        // And so do trailers, against their own limit
+        let buf = format!("0\r\n{}", "X-A: b\r\n".repeat(20));
+        assert_eq!(
+            Decoder::new(300, 100).decode(buf.as_bytes()),
+            Err(HttpCode::RequestHeaderFieldsTooLarge)
+        );
+    }
+}
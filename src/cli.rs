@@ -0,0 +1,332 @@
+//! Command line of the server binary.
+//!
+//! Every flag can be set through an `HTTP_SERVER_*` environment variable as well, e.g.
+//! `HTTP_SERVER_PORT` or `HTTP_SERVER_ACCESS_LOG`, and through the `--config` file.
+//! When a setting is given several ways, the command line wins over the environment,
+//! which wins over the file, which wins over the defaults.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use std::ffi::OsString;
+
+use clap::{Command, CommandFactory, FromArgMatches, Parser};
+use http_server_starter_rust::ip_filter::Cidr;
+use http_server_starter_rust::static_files::SymlinkPolicy;
+use http_server_starter_rust::{LogFormat, RuntimeFlavor, TraceFormat};
+
+use crate::load_test::{LoadTest, Mix};
+
+/// Prefix of the environment variables standing for flags.
+const ENV_PREFIX: &str = "HTTP_SERVER_";
+
+#[derive(Debug, Clone, PartialEq, Parser)]
+#[command(version, about = "HTTP/1.1 server serving the CodeCrafters routes")]
+pub struct Cli {
+    /// TOML file holding the settings not given on the command line
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    /// Address to listen on
+    #[arg(long, default_value_t = IpAddr::V4(Ipv4Addr::LOCALHOST))]
+    pub address: IpAddr,
+    /// Port to listen on
+    #[arg(long, default_value_t = 4221)]
+    pub port: u16,
+    /// How requests are scheduled: current-thread, multi-thread or thread-per-core
+    #[arg(long, default_value = "multi-thread")]
+    pub runtime: RuntimeFlavor,
+    /// Worker threads of the multi-thread runtime, one per core by default
+    #[arg(long)]
+    pub workers: Option<NonZeroUsize>,
+
+    /// Directory served and written under /files
+    #[arg(long, value_parser = files_directory, help_heading = "Files")]
+    pub directory: Option<PathBuf>,
+    /// List the content of directories
+    #[arg(long, requires = "directory", help_heading = "Files")]
+    pub listing: bool,
+    /// Symbolic links followed: never, within-root or always
+    #[arg(long, requires = "directory", help_heading = "Files")]
+    pub symlinks: Option<SymlinkPolicy>,
+    /// Bytes of file content kept in memory
+    #[arg(long, requires = "directory", help_heading = "Files")]
+    pub file_cache: Option<usize>,
+    /// Seconds file responses are cached for
+    #[arg(long, requires = "directory", help_heading = "Files")]
+    pub cache_ttl: Option<u64>,
+    /// htpasswd file of the users allowed to access the files
+    #[arg(long, requires = "directory", help_heading = "Files")]
+    pub htpasswd: Option<PathBuf>,
+    /// Size of the largest file upload, in bytes
+    #[arg(long, requires = "directory", help_heading = "Files")]
+    pub max_upload: Option<usize>,
+    /// Read-only directory served under a path prefix, e.g. /assets=./public
+    #[arg(long = "mount", value_name = "PREFIX=DIR", help_heading = "Files")]
+    pub mounts: Vec<Mount>,
+
+    /// Verbosity of the diagnostics, as RUST_LOG directives, e.g. debug or
+    /// info,parser=trace. router, parser and files stand for those parts of the server
+    #[arg(long, help_heading = "Logging")]
+    pub log_level: Option<String>,
+    /// Diagnostics format: compact, pretty or json
+    #[arg(long, default_value = "compact", help_heading = "Logging")]
+    pub log_format: TraceFormat,
+    /// Access log file, stdout when unset
+    #[arg(long, help_heading = "Logging")]
+    pub access_log: Option<PathBuf>,
+    /// Access log format: common or combined
+    #[arg(long, default_value = "combined", help_heading = "Logging")]
+    pub access_log_format: LogFormat,
+    /// Size in bytes past which the access log file is rotated
+    #[arg(long, requires = "access_log", help_heading = "Logging")]
+    pub access_log_max_size: Option<u64>,
+    /// Age in seconds past which the access log file is rotated
+    #[arg(long, requires = "access_log", help_heading = "Logging")]
+    pub access_log_max_age: Option<u64>,
+    /// Milliseconds past which requests are logged as slow
+    #[arg(long, help_heading = "Logging")]
+    pub slow_request_ms: Option<u64>,
+
+    /// Seconds allowed to handle a request
+    #[arg(long, help_heading = "Limits")]
+    pub request_timeout: Option<u64>,
+    /// Seconds allowed to receive a request head
+    #[arg(long, help_heading = "Limits")]
+    pub header_timeout: Option<u64>,
+    /// Size of the largest request body, in bytes
+    #[arg(long, help_heading = "Limits")]
+    pub max_body_size: Option<usize>,
+    /// Requests allowed per second and client
+    #[arg(long, help_heading = "Limits")]
+    pub rate_limit: Option<u32>,
+    /// Only accept clients from these CIDR ranges
+    #[arg(long, value_delimiter = ',', help_heading = "Limits")]
+    pub allow: Vec<Cidr>,
+    /// Refuse clients from these CIDR ranges
+    #[arg(long, value_delimiter = ',', help_heading = "Limits")]
+    pub deny: Vec<Cidr>,
+
+    /// Send responses uncompressed
+    #[cfg(feature = "compression")]
+    #[arg(long, help_heading = "Compression")]
+    pub no_compression: bool,
+    /// Size in bytes under which responses are not compressed
+    #[cfg(feature = "compression")]
+    #[arg(long, conflicts_with = "no_compression", help_heading = "Compression")]
+    pub compression_min_size: Option<usize>,
+
+    /// Serve /healthz and /readyz
+    #[arg(long, help_heading = "Operations")]
+    pub health: bool,
+    /// Path serving Prometheus metrics, e.g. /metrics
+    #[cfg(feature = "metrics")]
+    #[arg(long, help_heading = "Operations")]
+    pub metrics: Option<String>,
+    /// Serve the /_debug report
+    #[arg(long, help_heading = "Operations")]
+    pub debug: bool,
+    /// Serve the /_debug report on this address instead
+    #[arg(long, help_heading = "Operations")]
+    pub debug_addr: Option<SocketAddr>,
+    /// Act as a forward proxy for CONNECT tunnels
+    #[arg(long, help_heading = "Operations")]
+    pub proxy: bool,
+    /// Honour X-HTTP-Method-Override on POST requests
+    #[arg(long, help_heading = "Operations")]
+    pub method_override: bool,
+
+    /// Run in the background, detached from the terminal
+    #[cfg(unix)]
+    #[arg(long, help_heading = "Daemon")]
+    pub daemon: bool,
+    /// File the output goes to when running in the background
+    #[cfg(unix)]
+    #[arg(long, requires = "daemon", help_heading = "Daemon")]
+    pub log_file: Option<PathBuf>,
+    /// File to write the pid to, removed on exit
+    #[cfg(unix)]
+    #[arg(long, help_heading = "Daemon")]
+    pub pid_file: Option<PathBuf>,
+
+    /// Send requests to the server at this address instead of serving, then report
+    /// the throughput and latency
+    #[arg(long, value_name = "ADDR", help_heading = "Load test")]
+    pub selftest: Option<SocketAddr>,
+    /// Connections sending requests at the same time
+    #[arg(long, requires = "selftest", help_heading = "Load test")]
+    pub concurrency: Option<NonZeroUsize>,
+    /// Requests sent in all
+    #[arg(long, requires = "selftest", help_heading = "Load test")]
+    pub requests: Option<NonZeroUsize>,
+    /// Share of each kind of request: echo, files or upload, e.g. echo=8,files=1,upload=1
+    #[arg(long, requires = "selftest", help_heading = "Load test")]
+    pub mix: Option<Mix>,
+    /// Size of the bodies uploaded, in bytes
+    #[arg(long, requires = "selftest", help_heading = "Load test")]
+    pub upload_size: Option<usize>,
+
+    /// Address to serve HTTP/3 on
+    #[cfg(feature = "http3")]
+    #[arg(long, requires_all = ["tls_cert", "tls_key"], help_heading = "TLS")]
+    pub http3: Option<SocketAddr>,
+    /// PEM certificate chain
+    #[cfg(feature = "http3")]
+    #[arg(long, requires = "tls_key", help_heading = "TLS")]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key
+    #[cfg(feature = "http3")]
+    #[arg(long, requires = "tls_cert", help_heading = "TLS")]
+    pub tls_key: Option<PathBuf>,
+}
+
+impl Cli {
+    /// Command parsing the flags and their environment variables.
+    pub fn command() -> Command {
+        <Cli as CommandFactory>::command().mut_args(|arg| {
+            let env = format!("{}{}", ENV_PREFIX, arg.get_id().as_str().to_uppercase());
+            arg.env(env)
+        })
+    }
+
+    /// Parses `args`, taking the flags missing from the environment.
+    pub fn try_parse_from<I, T>(args: I) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let matches = Self::command().try_get_matches_from(args)?;
+        Self::from_arg_matches(&matches)
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        SocketAddr::new(self.address, self.port)
+    }
+
+    /// Whether the server runs in the background, its output going to a file.
+    pub fn detached(&self) -> bool {
+        #[cfg(unix)]
+        return self.daemon;
+        #[cfg(not(unix))]
+        false
+    }
+
+    /// Load test asked for with `--selftest`, if any.
+    pub fn load_test(&self) -> Option<LoadTest> {
+        Some(LoadTest {
+            addr: self.selftest?,
+            concurrency: self.concurrency.map_or(16, NonZeroUsize::get),
+            requests: self.requests.map_or(10_000, NonZeroUsize::get),
+            mix: self.mix.clone().unwrap_or_default(),
+            upload_size: self.upload_size.unwrap_or(1024),
+        })
+    }
+
+    pub fn runtime(&self) -> RuntimeFlavor {
+        match (self.runtime, self.workers) {
+            (RuntimeFlavor::MultiThread { .. }, Some(workers)) => RuntimeFlavor::MultiThread {
+                workers: Some(workers.get()),
+            },
+            (runtime, _) => runtime,
+        }
+    }
+}
+
+/// Directory mounted with `--mount`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mount {
+    pub prefix: String,
+    pub dir: PathBuf,
+}
+
+impl FromStr for Mount {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (prefix, dir) = s
+            .split_once('=')
+            .filter(|(prefix, _)| prefix.starts_with('/'))
+            .ok_or_else(|| format!("Invalid mount, expected PREFIX=DIR: {}", s))?;
+        Ok(Mount {
+            prefix: prefix.to_string(),
+            dir: files_directory(dir)?,
+        })
+    }
+}
+
+/// Canonical path of the files root, which must be a readable directory rather than
+/// failing on every request.
+fn files_directory(dir: &str) -> Result<PathBuf, String> {
+    std::fs::canonicalize(dir)
+        .and_then(|dir| std::fs::read_dir(&dir).map(|_| dir))
+        .map_err(|e| format!("Invalid directory {}: {}", dir, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cli() {
+        Cli::command().debug_assert();
+
+        let cli = Cli::try_parse_from(["server"]).unwrap();
+        assert_eq!(cli.addr(), "127.0.0.1:4221".parse().unwrap());
+        assert_eq!(cli.runtime(), RuntimeFlavor::MultiThread { workers: None });
+
+        let cli = Cli::try_parse_from([
+            "server",
+            "--port",
+            "8080",
+            "--workers",
+            "4",
+            "--allow",
+            "10.0.0.0/8,::1",
+        ])
+        .unwrap();
+        assert_eq!(cli.addr().port(), 8080);
+        assert_eq!(
+            cli.runtime(),
+            RuntimeFlavor::MultiThread { workers: Some(4) }
+        );
+        assert_eq!(cli.allow.len(), 2);
+
+        for args in [
+            &["server", "--port", "http"][..],
+            &["server", "--workers", "0"],
+            &["server", "--listing"],
+            &["server", "--directory", "/nonexistent"],
+            &["server", "--runtime", "fibers"],
+            &["server", "--mount", "assets=/tmp"],
+        ] {
+            assert!(Cli::try_parse_from(args).is_err(), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn test_env() {
+        let env = |name| {
+            let command = Cli::command();
+            let arg = command
+                .get_arguments()
+                .find(|arg| arg.get_long() == Some(name))
+                .unwrap();
+            arg.get_env().unwrap().to_str().unwrap().to_string()
+        };
+        assert_eq!(env("port"), "HTTP_SERVER_PORT");
+        assert_eq!(env("mount"), "HTTP_SERVER_MOUNTS");
+        assert_eq!(
+            env("access-log-max-size"),
+            "HTTP_SERVER_ACCESS_LOG_MAX_SIZE"
+        );
+
+        // Not read by the other tests
+        std::env::set_var("HTTP_SERVER_SLOW_REQUEST_MS", "250");
+        let cli = Cli::try_parse_from(["server"]).unwrap();
+        assert_eq!(cli.slow_request_ms, Some(250));
+        let cli = Cli::try_parse_from(["server", "--slow-request-ms", "100"]).unwrap();
+        assert_eq!(cli.slow_request_ms, Some(100));
+        std::env::remove_var("HTTP_SERVER_SLOW_REQUEST_MS");
+    }
+}
@@ -0,0 +1,393 @@
+//! Response compression negotiated through `Accept-Encoding`. gzip is always
+//! available, brotli and zstd are enabled by the cargo features of the same name.
+//!
+//! Request bodies sent with a gzip or deflate `Content-Encoding` are decompressed by
+//! the [`Decompression`] middleware.
+
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+use flate2::read::{GzDecoder, ZlibDecoder};
+use flate2::write::GzEncoder;
+use flate2::Compression as GzLevel;
+use tracing::warn;
+
+use super::middleware::{Middleware, Next};
+use super::router::BoxFuture;
+use super::{AppError, Request, Response};
+
+pub use super::negotiate::parse_accept_encoding;
+
+/// Default limit of a decompressed request body.
+const DEFAULT_MAX_DECOMPRESSED_SIZE: usize = 8 * 1024 * 1024;
+
+/// Content types compressed by default; other types are usually already compressed.
+const DEFAULT_CONTENT_TYPES: [&str; 6] = [
+    "text/",
+    "application/json",
+    "application/javascript",
+    "application/xml",
+    "application/wasm",
+    "image/svg+xml",
+];
+
+/// Content codings the server can produce, from the most to the least preferred.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    #[cfg(feature = "brotli")]
+    Brotli,
+    #[cfg(feature = "zstd")]
+    Zstd,
+    Gzip,
+}
+
+impl Encoding {
+    const ALL: &'static [Encoding] = &[
+        #[cfg(feature = "brotli")]
+        Encoding::Brotli,
+        #[cfg(feature = "zstd")]
+        Encoding::Zstd,
+        Encoding::Gzip,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "brotli")]
+            Encoding::Brotli => "br",
+            #[cfg(feature = "zstd")]
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// Picks the encoding with the highest quality value in an `Accept-Encoding`
+    /// header, the server preference breaking ties.
+    pub fn negotiate(header: &str) -> Option<Encoding> {
+        let accepted = parse_accept_encoding(header);
+        let quality = |encoding: &Encoding| {
+            let explicit = accepted
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(encoding.as_str()));
+            let wildcard = accepted.iter().find(|(name, _)| *name == "*");
+            explicit.or(wildcard).map_or(0.0, |&(_, q)| q)
+        };
+
+        let mut best: Option<(Encoding, f32)> = None;
+        for encoding in Encoding::ALL {
+            let q = quality(encoding);
+            if q > 0.0 && best.map_or(true, |(_, best_q)| q > best_q) {
+                best = Some((*encoding, q));
+            }
+        }
+        best.map(|(encoding, _)| encoding)
+    }
+
+    fn encode(&self, body: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            #[cfg(feature = "brotli")]
+            Encoding::Brotli => {
+                let mut out = Vec::new();
+                let mut encoder = brotli::CompressorWriter::new(&mut out, 4096, 5, 22);
+                encoder.write_all(body)?;
+                drop(encoder);
+                Ok(out)
+            }
+            #[cfg(feature = "zstd")]
+            Encoding::Zstd => zstd::encode_all(body, 0),
+            Encoding::Gzip => gzip(body),
+        }
+    }
+}
+
+/// Middleware compressing response bodies with the best encoding the client accepts.
+#[derive(Clone)]
+pub struct Compression {
+    min_size: usize,
+    content_types: Arc<[String]>,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression {
+            min_size: 0,
+            content_types: DEFAULT_CONTENT_TYPES
+                .iter()
+                .map(|t| t.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl Compression {
+    /// Bodies smaller than `min_size` bytes are sent as is.
+    pub fn with_min_size(mut self, min_size: usize) -> Self {
+        self.min_size = min_size;
+        self
+    }
+
+    /// Content types worth compressing, entries ending with `/` matching every subtype.
+    pub fn with_content_types<I, S>(mut self, content_types: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.content_types = content_types.into_iter().map(Into::into).collect();
+        self
+    }
+
+    fn should_compress(&self, res: &Response) -> bool {
+        // Compressing a range would turn it into a range of another representation
+        if res.is_stream()
+            || res.content().len() < self.min_size
+            || res.header_value("Content-Encoding").is_some()
+            || res.header_value("Content-Range").is_some()
+        {
+            return false;
+        }
+
+        let ty = res.header_value("Content-Type").unwrap_or_default();
+        let mime = ty.split(';').next().unwrap_or_default().trim();
+        self.content_types
+            .iter()
+            .any(|allowed| match allowed.ends_with('/') {
+                true => mime.starts_with(allowed.as_str()),
+                false => mime.eq_ignore_ascii_case(allowed),
+            })
+    }
+}
+
+impl Middleware for Compression {
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<Response> {
+        let encoding = req.header("Accept-Encoding").and_then(Encoding::negotiate);
+        let compression = self.clone();
+
+        Box::pin(async move {
+            let mut res = next.run(req).await;
+            if compression.should_compress(&res) {
+                res.add_vary("Accept-Encoding");
+                if let Some(encoding) = encoding {
+                    match encoding.encode(res.content()) {
+                        Ok(body) => {
+                            res.header("Content-Encoding", encoding.as_str());
+                            res.header("Content-Length", body.len().to_string());
+                            *res.content_mut() = body;
+                        }
+                        Err(e) => warn!("Failed to compress response: {}", e),
+                    }
+                }
+            }
+            res
+        })
+    }
+}
+
+/// Middleware decompressing gzip and deflate request bodies before they reach the
+/// handlers. Bodies inflating past the size limit are rejected with a 413.
+#[derive(Clone, Copy)]
+pub struct Decompression {
+    max_size: usize,
+}
+
+impl Default for Decompression {
+    fn default() -> Self {
+        Decompression {
+            max_size: DEFAULT_MAX_DECOMPRESSED_SIZE,
+        }
+    }
+}
+
+impl Decompression {
+    pub fn with_max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    fn decompress(&self, req: &mut Request) -> Result<(), AppError> {
+        let Some(encoding) = req.header("Content-Encoding").map(str::to_ascii_lowercase) else {
+            return Ok(());
+        };
+
+        let body = req.take_body();
+        let decoder: Box<dyn Read> = match encoding.trim() {
+            "identity" => {
+                req.set_body(body);
+                return Ok(());
+            }
+            "gzip" | "x-gzip" => Box::new(GzDecoder::new(&body[..])),
+            "deflate" => Box::new(ZlibDecoder::new(&body[..])),
+            _ => return Err(AppError::UnsupportedMediaType(encoding)),
+        };
+
+        // Reading one byte past the limit tells a body of exactly the limit from a bigger one
+        let mut decoded = Vec::new();
+        decoder
+            .take(self.max_size as u64 + 1)
+            .read_to_end(&mut decoded)
+            .map_err(|e| AppError::BadRequest(format!("Invalid {} body: {}", encoding, e)))?;
+        if decoded.len() > self.max_size {
+            return Err(AppError::PayloadTooLarge);
+        }
+
+        req.remove_header("Content-Encoding");
+        req.set_header("Content-Length", decoded.len().to_string());
+        req.set_body(decoded);
+        Ok(())
+    }
+}
+
+impl Middleware for Decompression {
+    fn handle(&self, mut req: Request, next: Next) -> BoxFuture<Response> {
+        match self.decompress(&mut req) {
+            Ok(()) => next.run(req),
+            Err(err) => Box::pin(async move { Response::from(err) }),
+        }
+    }
+}
+
+fn gzip(body: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), GzLevel::default());
+    encoder.write_all(body)?;
+    encoder.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::write::ZlibEncoder;
+
+    use super::*;
+    use crate::{middleware, RequestBuffer};
+
+    async fn respond(compression: Compression, req: &str, ty: &str, body: &str) -> Response {
+        let (ty, body) = (ty.to_string(), body.to_string());
+        middleware::send(compression, req, move |_| {
+            let mut res = Response::from(body.clone());
+            res.header("Content-Type", ty.clone());
+            res.header("Content-Length", body.len().to_string());
+            async move { res }
+        })
+        .await
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(
+            Encoding::negotiate("invalid-encoding, gzip"),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(Encoding::negotiate("gzip;q=0"), None);
+        assert_eq!(Encoding::negotiate("identity"), None);
+        assert_eq!(
+            Encoding::negotiate("*;q=0.1, gzip;q=0.5"),
+            Some(Encoding::Gzip)
+        );
+
+        #[cfg(feature = "brotli")]
+        {
+            assert_eq!(Encoding::negotiate("gzip, br"), Some(Encoding::Brotli));
+            assert_eq!(Encoding::negotiate("gzip, br;q=0.8"), Some(Encoding::Gzip));
+        }
+        #[cfg(feature = "zstd")]
+        assert_eq!(
+            Encoding::negotiate("gzip;q=0.5, zstd;q=0.9"),
+            Some(Encoding::Zstd)
+        );
+    }
+
+    #[cfg(feature = "brotli")]
+    #[tokio::test]
+    async fn test_brotli() {
+        let req = "GET / HTTP/1.1\r\nAccept-Encoding: br\r\n\r\n";
+        let res = respond(Compression::default(), req, "text/plain", "hello").await;
+        assert_eq!(res.header_value("Content-Encoding"), Some("br"));
+
+        let mut body = Vec::new();
+        brotli::BrotliDecompress(&mut res.content(), &mut body).unwrap();
+        assert_eq!(body, b"hello");
+    }
+
+    #[cfg(feature = "zstd")]
+    #[tokio::test]
+    async fn test_zstd() {
+        let req = "GET / HTTP/1.1\r\nAccept-Encoding: zstd\r\n\r\n";
+        let res = respond(Compression::default(), req, "text/plain", "hello").await;
+        assert_eq!(res.header_value("Content-Encoding"), Some("zstd"));
+        assert_eq!(zstd::decode_all(res.content()).unwrap(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_gzip() {
+        let gzip_req = "GET / HTTP/1.1\r\nAccept-Encoding: deflate, gzip\r\n\r\n";
+        let res = respond(Compression::default(), gzip_req, "text/plain", "hello").await;
+        assert_eq!(res.header_value("Content-Encoding"), Some("gzip"));
+        assert_eq!(res.header_value("Vary"), Some("Accept-Encoding"));
+        assert_eq!(
+            res.header_value("Content-Length"),
+            Some(res.content().len().to_string().as_str())
+        );
+        let mut body = String::new();
+        GzDecoder::new(res.content())
+            .read_to_string(&mut body)
+            .unwrap();
+        assert_eq!(body, "hello");
+
+        let plain_req = "GET / HTTP/1.1\r\n\r\n";
+        let res = respond(Compression::default(), plain_req, "text/plain", "hello").await;
+        assert!(res.header_value("Content-Encoding").is_none());
+        assert_eq!(res.header_value("Vary"), Some("Accept-Encoding"));
+
+        let res = respond(Compression::default(), gzip_req, "image/png", "hello").await;
+        assert!(res.header_value("Content-Encoding").is_none());
+
+        let compression = Compression::default().with_min_size(1024);
+        let res = respond(compression, gzip_req, "text/plain", "hello").await;
+        assert_eq!(res.content(), b"hello");
+    }
+
+    async fn upload(decompression: Decompression, encoding: &str, body: &[u8]) -> Response {
+        let mut req = Request::parse(&mut RequestBuffer::from(
+            format!(
+                "POST /files/a HTTP/1.1\r\nContent-Encoding: {}\r\n\r\n",
+                encoding
+            )
+            .bytes(),
+        ))
+        .unwrap();
+        req.set_body(body.to_vec());
+        middleware::send_request(decompression, req, |req: Request| async move {
+            Response::from(format!(
+                "{} {}",
+                req.content_length(),
+                String::from_utf8_lossy(req.body())
+            ))
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_decompression() {
+        let body = gzip(b"hello world").unwrap();
+        let res = upload(Decompression::default(), "gzip", &body).await;
+        assert_eq!(res.content(), b"11 hello world");
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), GzLevel::default());
+        encoder.write_all(b"deflated").unwrap();
+        let res = upload(
+            Decompression::default(),
+            "deflate",
+            &encoder.finish().unwrap(),
+        )
+        .await;
+        assert_eq!(res.content(), b"8 deflated");
+
+        let bomb = gzip(&[0; 4096]).unwrap();
+        let res = upload(Decompression::default().with_max_size(4095), "gzip", &bomb).await;
+        assert!(res.into_bytes().starts_with(b"HTTP/1.1 413 "));
+        let res = upload(Decompression::default().with_max_size(4096), "gzip", &bomb).await;
+        assert!(res.content().starts_with(b"4096 "));
+
+        let res = upload(Decompression::default(), "gzip", b"not gzip").await;
+        assert!(res.into_bytes().starts_with(b"HTTP/1.1 400 "));
+        let res = upload(Decompression::default(), "compress", b"data").await;
+        assert!(res.into_bytes().starts_with(b"HTTP/1.1 415 "));
+    }
+}
@@ -0,0 +1,325 @@
+//! `--config` file: a TOML file standing for the flags not given on the command line.
+//!
+//! ```toml
+//! [server]
+//! port = 8080
+//! workers = 4
+//!
+//! [files]
+//! directory = "/srv/files"
+//! listing = true
+//!
+//! [mounts]
+//! "/assets" = "./public"
+//!
+//! [timeouts]
+//! header = 5
+//!
+//! [logging]
+//! level = "info,http_server_starter_rust=debug"
+//! access_log = "/var/log/http/access.log"
+//! ```
+//!
+//! Every setting is read as the flag it stands for, with the same validation, unless
+//! that flag is given on the command line or in the environment. Relative paths are
+//! relative to the working directory.
+
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, Command};
+use toml::{Table, Value};
+
+use super::cli::Cli;
+
+/// How a setting translates to a flag.
+#[derive(Clone, Copy)]
+enum Flag {
+    /// The flag taking the value, or set when `true`.
+    Is(&'static str),
+    /// The flag set when the setting is `false`.
+    Not(&'static str),
+}
+
+/// Settings of each section of the file.
+const SECTIONS: &[(&str, &[(&str, Flag)])] = &[
+    (
+        "server",
+        &[
+            ("address", Flag::Is("address")),
+            ("port", Flag::Is("port")),
+            ("runtime", Flag::Is("runtime")),
+            ("workers", Flag::Is("workers")),
+        ],
+    ),
+    (
+        "files",
+        &[
+            ("directory", Flag::Is("directory")),
+            ("listing", Flag::Is("listing")),
+            ("symlinks", Flag::Is("symlinks")),
+            ("cache_size", Flag::Is("file-cache")),
+            ("cache_ttl", Flag::Is("cache-ttl")),
+            ("htpasswd", Flag::Is("htpasswd")),
+            ("max_upload", Flag::Is("max-upload")),
+        ],
+    ),
+    (
+        "compression",
+        &[
+            ("enabled", Flag::Not("no-compression")),
+            ("min_size", Flag::Is("compression-min-size")),
+        ],
+    ),
+    (
+        "limits",
+        &[
+            ("max_body_size", Flag::Is("max-body-size")),
+            ("rate_limit", Flag::Is("rate-limit")),
+            ("allow", Flag::Is("allow")),
+            ("deny", Flag::Is("deny")),
+        ],
+    ),
+    (
+        "timeouts",
+        &[
+            ("request", Flag::Is("request-timeout")),
+            ("header", Flag::Is("header-timeout")),
+        ],
+    ),
+    (
+        "logging",
+        &[
+            ("level", Flag::Is("log-level")),
+            ("format", Flag::Is("log-format")),
+            ("access_log", Flag::Is("access-log")),
+            ("access_log_format", Flag::Is("access-log-format")),
+            ("access_log_max_size", Flag::Is("access-log-max-size")),
+            ("access_log_max_age", Flag::Is("access-log-max-age")),
+            ("slow_request_ms", Flag::Is("slow-request-ms")),
+        ],
+    ),
+    (
+        "operations",
+        &[
+            ("health", Flag::Is("health")),
+            ("metrics", Flag::Is("metrics")),
+            ("debug", Flag::Is("debug")),
+            ("debug_addr", Flag::Is("debug-addr")),
+            ("proxy", Flag::Is("proxy")),
+            ("method_override", Flag::Is("method-override")),
+        ],
+    ),
+    (
+        "daemon",
+        &[
+            ("enabled", Flag::Is("daemon")),
+            ("log_file", Flag::Is("log-file")),
+            ("pid_file", Flag::Is("pid-file")),
+        ],
+    ),
+    (
+        "tls",
+        &[
+            ("http3", Flag::Is("http3")),
+            ("cert", Flag::Is("tls-cert")),
+            ("key", Flag::Is("tls-key")),
+        ],
+    ),
+];
+
+/// Parses the command line, completed by the `--config` file if any. Exits on errors,
+/// as clap does.
+pub fn parse() -> Cli {
+    try_parse().unwrap_or_else(|(e, config)| {
+        let _ = e.print();
+        if let Some(path) = config {
+            eprintln!("\nSettings were read from {} as well.", path.display());
+        }
+        std::process::exit(e.exit_code())
+    })
+}
+
+/// Parses the settings again, e.g. after the `--config` file changed.
+pub fn reparse() -> Result<Cli, String> {
+    try_parse().map_err(|(e, config)| {
+        let message = e.render().to_string();
+        let message = message.lines().next().unwrap_or_default();
+        let message = message.trim_start_matches("error: ").to_string();
+        match config {
+            Some(path) => format!("{} (settings read from {})", message, path.display()),
+            None => message,
+        }
+    })
+}
+
+/// Errors come with the `--config` file the settings were read from.
+fn try_parse() -> Result<Cli, (clap::Error, Option<PathBuf>)> {
+    let args = std::env::args_os().collect::<Vec<_>>();
+    // Only looking for the file and the flags given, the full validation comes after
+    let matches = Cli::command().ignore_errors(true).get_matches_from(&args);
+    let Some(path) = matches.get_one::<PathBuf>("config") else {
+        return Cli::try_parse_from(args).map_err(|e| (e, None));
+    };
+
+    let mut command = Cli::command();
+    let settings = load(path, &command, &matches).map_err(|e| {
+        let message = format!("invalid config file {}: {}", path.display(), e);
+        (command.error(ErrorKind::InvalidValue, message), None)
+    })?;
+    let mut merged = args.clone();
+    merged.splice(1..1, settings.into_iter().map(OsString::from));
+    Cli::try_parse_from(merged).map_err(|e| (e, Some(path.clone())))
+}
+
+/// Reads the file at `path` as flags, leaving out those given otherwise.
+fn load(path: &Path, command: &Command, matches: &ArgMatches) -> Result<Vec<String>, String> {
+    let contents = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let given = |id: &str| {
+        matches
+            .value_source(id)
+            .is_some_and(|source| source != ValueSource::DefaultValue)
+    };
+    flags(&contents, command, given)
+}
+
+/// Flags standing for the settings in `contents`, except those `given` already.
+fn flags<F>(contents: &str, command: &Command, given: F) -> Result<Vec<String>, String>
+where
+    F: Fn(&str) -> bool,
+{
+    let table = contents.parse::<Table>().map_err(|e| e.to_string())?;
+    let mut flags = Vec::new();
+    for (section, settings) in &table {
+        let settings = settings
+            .as_table()
+            .ok_or_else(|| format!("[{}] is not a section", section))?;
+        if section == "mounts" {
+            if !given("mounts") {
+                for (prefix, dir) in settings {
+                    let dir = dir
+                        .as_str()
+                        .ok_or_else(|| format!("mounts.\"{}\" is not a path", prefix))?;
+                    flags.extend([String::from("--mount"), format!("{}={}", prefix, dir)]);
+                }
+            }
+            continue;
+        }
+
+        let known = SECTIONS
+            .iter()
+            .find(|(name, _)| name == section)
+            .ok_or_else(|| format!("unknown section [{}]", section))?
+            .1;
+        for (key, value) in settings {
+            let name = format!("{}.{}", section, key);
+            let flag = known
+                .iter()
+                .find(|(known, _)| known == key)
+                .map(|(_, flag)| *flag)
+                .ok_or_else(|| format!("unknown setting {}", name))?;
+            let long = match flag {
+                Flag::Is(long) | Flag::Not(long) => long,
+            };
+            let arg = command
+                .get_arguments()
+                .find(|arg| arg.get_long() == Some(long))
+                .ok_or_else(|| format!("{} is not supported by this build", name))?;
+            if given(arg.get_id().as_str()) {
+                continue;
+            }
+
+            let set = format!("--{}", long);
+            match (flag, value) {
+                (Flag::Is(_), Value::Boolean(true)) | (Flag::Not(_), Value::Boolean(false))
+                    if !arg.get_action().takes_values() =>
+                {
+                    flags.push(set)
+                }
+                (_, Value::Boolean(_)) if !arg.get_action().takes_values() => {}
+                (Flag::Is(_), Value::Array(values)) => {
+                    for value in values {
+                        flags.extend([set.clone(), scalar(value, &name)?]);
+                    }
+                }
+                (Flag::Is(_), value) if arg.get_action().takes_values() => {
+                    flags.extend([set, scalar(value, &name)?]);
+                }
+                _ => return Err(format!("{} expects true or false", name)),
+            }
+        }
+    }
+    Ok(flags)
+}
+
+fn scalar(value: &Value, name: &str) -> Result<String, String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Integer(n) => Ok(n.to_string()),
+        Value::Float(n) => Ok(n.to_string()),
+        _ => Err(format!("{} expects a string or a number", name)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flags() {
+        let command = Cli::command();
+        let contents = r#"
+            [server]
+            port = 8080
+
+            [files]
+            listing = true
+            cache_size = 1048576
+
+            [mounts]
+            "/assets" = "./public"
+
+            [limits]
+            deny = ["10.0.0.0/8", "::1"]
+
+            [operations]
+            health = false
+        "#;
+        let args = flags(contents, &command, |id| id == "port").unwrap();
+        assert_eq!(
+            args,
+            [
+                "--file-cache",
+                "1048576",
+                "--listing",
+                "--deny",
+                "10.0.0.0/8",
+                "--deny",
+                "::1",
+                "--mount",
+                "/assets=./public",
+            ]
+        );
+
+        // Settings of the features left out are refused
+        let compression = flags("[compression]\nenabled = false", &command, |_| false);
+        if cfg!(feature = "compression") {
+            assert_eq!(compression.unwrap(), ["--no-compression"]);
+        } else {
+            assert!(compression.is_err());
+        }
+
+        for invalid in [
+            "port = 80",
+            "[server]\nhost = \"localhost\"",
+            "[cache]\nttl = 5",
+            "[files]\nlisting = \"yes\"",
+            "[server]\nport = true",
+            "[mounts]\n\"/assets\" = 1",
+        ] {
+            assert!(flags(invalid, &command, |_| false).is_err(), "{}", invalid);
+        }
+    }
+}
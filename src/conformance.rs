@@ -0,0 +1,211 @@
+//! Requests RFC 9110 and RFC 9112 tell servers how to answer, most of them refused so
+//! that this server cannot read a request differently than a proxy in front of it.
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use super::{
+    ComparePath, Connection, ConnectionOptions, HttpCode, Request, Response, Route, SharedRouter,
+};
+
+/// Status line of the first response to `req`, along with its body.
+async fn send(req: &[u8]) -> (String, String) {
+    let mut router = crate::Router::default();
+    router.add_route(Route::get(
+        "/",
+        |_: Request| Response::from(HttpCode::Ok),
+        ComparePath::Exact,
+    ));
+    router.add_route(Route::post(
+        "/",
+        |req: Request| Response::from(req.body().to_vec()),
+        ComparePath::Exact,
+    ));
+    let router = SharedRouter::from(router);
+    let options = ConnectionOptions {
+        max_head_size: 1024,
+        max_body_size: 1024,
+        ..Default::default()
+    };
+
+    let (mut client, server) = tokio::io::duplex(4096);
+    let handle = tokio::spawn(async move { Connection::new(server, options).serve(&router).await });
+    client.write_all(req).await.unwrap();
+    client.shutdown().await.unwrap();
+    let mut res = String::new();
+    client.read_to_string(&mut res).await.unwrap();
+    handle.await.unwrap();
+
+    let (head, body) = res.split_once("\r\n\r\n").unwrap_or_default();
+    let status = head.lines().next().unwrap_or_default().to_string();
+    (status, body.to_string())
+}
+
+async fn status(req: &[u8]) -> String {
+    send(req).await.0
+}
+
+const OK: &str = "HTTP/1.1 200 OK";
+const BAD_REQUEST: &str = "HTTP/1.1 400 Bad Request";
+
+/// RFC 9112 section 5.2: obs-fold is refused outside of message/http.
+#[tokio::test]
+async fn test_obs_fold() {
+    for req in [
+        &b"GET / HTTP/1.1\r\nHost: localhost\r\nX-Folded: a\r\n b\r\n\r\n"[..],
+        b"GET / HTTP/1.1\r\nHost: localhost\r\nX-Folded: a\r\n\tb\r\n\r\n",
+        b"GET / HTTP/1.1\r\n Host: localhost\r\n\r\n",
+    ] {
+        assert_eq!(status(req).await, BAD_REQUEST, "{:?}", req);
+    }
+}
+
+/// RFC 9112 section 5.1: no whitespace is allowed between a field name and the colon.
+#[tokio::test]
+async fn test_field_name() {
+    for req in [
+        &b"GET / HTTP/1.1\r\nHost : localhost\r\n\r\n"[..],
+        b"GET / HTTP/1.1\r\nHost: localhost\r\nX Y: z\r\n\r\n",
+        b"GET / HTTP/1.1\r\nHost: localhost\r\n: empty\r\n\r\n",
+    ] {
+        assert_eq!(status(req).await, BAD_REQUEST, "{:?}", req);
+    }
+    let req = b"GET / HTTP/1.1\r\nHost:localhost\r\nX-Padded: \t a \t\r\n\r\n";
+    assert_eq!(status(req).await, OK);
+}
+
+/// RFC 9112 section 3.2: HTTP/1.1 requests carry exactly one Host header.
+#[tokio::test]
+async fn test_host() {
+    assert_eq!(status(b"GET / HTTP/1.1\r\n\r\n").await, BAD_REQUEST);
+    let req = b"GET / HTTP/1.1\r\nHost: a\r\nhost: b\r\n\r\n";
+    assert_eq!(status(req).await, BAD_REQUEST);
+    // An empty one stands for a missing authority
+    assert_eq!(status(b"GET / HTTP/1.1\r\nHost:\r\n\r\n").await, OK);
+    // HTTP/1.0 predates it
+    assert_eq!(status(b"GET / HTTP/1.0\r\n\r\n").await, OK);
+}
+
+/// RFC 9112 section 6.3: differing Content-Length values are unrecoverable errors.
+#[tokio::test]
+async fn test_content_length() {
+    let req = b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 3\r\n\
+                Content-Length: 5\r\n\r\nhello";
+    assert_eq!(status(req).await, BAD_REQUEST);
+    let req = b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5, 5\r\n\r\nhello";
+    assert_eq!(status(req).await, BAD_REQUEST);
+    // The same value repeated leaves no doubt
+    let req = b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\
+                Content-Length: 5\r\n\r\nhello";
+    assert_eq!(send(req).await, (OK.to_string(), String::from("hello")));
+}
+
+/// RFC 9112 section 2.2: bare CR is refused, and bare LF, which recipients may take as
+/// a line ending, is refused as well.
+#[tokio::test]
+async fn test_line_endings() {
+    for req in [
+        &b"GET / HTTP/1.1\nHost: localhost\n\n"[..],
+        b"GET / HTTP/1.1\r\nHost: localhost\nX-Smuggled: 1\r\n\r\n",
+        b"GET / HTTP/1.1\r\nHost: localhost\rX-Smuggled: 1\r\n\r\n",
+        b"GET /\r HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        b"GET / HTTP/1.1\r\nHost: local\0host\r\n\r\n",
+    ] {
+        assert_eq!(status(req).await, BAD_REQUEST, "{:?}", req);
+    }
+}
+
+/// RFC 9112 section 7.1: chunked bodies are decoded, whatever the size of the chunks
+/// and with or without trailer fields.
+#[tokio::test]
+async fn test_chunked() {
+    let req = b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n\
+                5\r\nhello\r\n1;ext=\"x\"\r\n \r\nA\r\nchunked!!!\r\n0\r\n\r\n";
+    assert_eq!(
+        send(req).await,
+        (OK.to_string(), String::from("hello chunked!!!"))
+    );
+    let req = b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: Chunked\r\n\r\n\
+                2\r\nhi\r\n0\r\nX-Checksum: 1\r\n\r\n";
+    assert_eq!(send(req).await, (OK.to_string(), String::from("hi")));
+}
+
+/// RFC 9112 section 6.1: unknown transfer codings get a 501, and framing that leaves
+/// the length of the body to guess is refused.
+#[tokio::test]
+async fn test_transfer_encoding() {
+    let req = b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: gzip, chunked\r\n\r\n\
+                0\r\n\r\n";
+    assert_eq!(status(req).await, "HTTP/1.1 501 Not Implemented");
+    for req in [
+        &b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\
+           Content-Length: 5\r\n\r\n0\r\n\r\n"[..],
+        b"POST / HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n",
+        b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n\
+          5\r\nhello!\r\n0\r\n\r\n",
+        b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n",
+    ] {
+        assert_eq!(status(req).await, BAD_REQUEST, "{:?}", req);
+    }
+}
+
+/// RFC 9112 section 7.1: chunk sizes are checked before the chunk is received, those
+/// overflowing being refused as overlong lengths are.
+#[tokio::test]
+async fn test_chunk_size() {
+    let too_large = "HTTP/1.1 413 Payload Too Large";
+    for size in ["401", "ffffffffffffffff"] {
+        let req = format!(
+            "POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n{}\r\n",
+            size
+        );
+        assert_eq!(status(req.as_bytes()).await, too_large, "{}", size);
+    }
+    // Over the limit once added up
+    let req = format!(
+        "POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n\
+         200\r\n{}\r\n201\r\n",
+        "a".repeat(0x200)
+    );
+    assert_eq!(status(req.as_bytes()).await, too_large);
+
+    let req = b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n\
+                10000000000000000\r\n";
+    assert_eq!(status(req).await, BAD_REQUEST);
+}
+
+/// RFC 9112 section 7.1.1: chunk extensions count against the limit of the body, so
+/// that small chunks carrying long ones cannot grow it past that limit.
+#[tokio::test]
+async fn test_chunk_extensions() {
+    let chunk = format!("1;ext={}\r\na\r\n", "x".repeat(200));
+    let req = format!(
+        "POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n{}0\r\n\r\n",
+        chunk.repeat(4)
+    );
+    assert_eq!(
+        send(req.as_bytes()).await,
+        (OK.to_string(), String::from("aaaa"))
+    );
+    let req = format!(
+        "POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n{}",
+        chunk.repeat(10)
+    );
+    assert_eq!(
+        status(req.as_bytes()).await,
+        "HTTP/1.1 413 Payload Too Large"
+    );
+}
+
+/// RFC 9112 section 7.1.2: the trailer section is limited as the header section is.
+#[tokio::test]
+async fn test_trailer_section() {
+    let req = format!(
+        "POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n\
+         2\r\nhi\r\n0\r\n{}",
+        "X-Trailer: value\r\n".repeat(100)
+    );
+    assert_eq!(
+        status(req.as_bytes()).await,
+        "HTTP/1.1 431 Request Header Fields Too Large"
+    );
+}
@@ -0,0 +1,1308 @@
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::{Buf, Bytes, BytesMut};
+use itertools::Itertools;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::time::Instant;
+use tracing::{debug, field, info_span, trace, warn, Instrument};
+
+use super::chunked;
+use super::early_hints::{EarlyHints, Hints};
+use super::hooks::{DisconnectEvent, Hooks, RequestEvent, ResponseEvent};
+use super::logging::PARSER_TARGET;
+use super::middleware::{self, Middlewares, Next};
+use super::router::{BoxFuture, MatchedPath};
+use super::transport::Transport;
+use super::upgrade::{Io, UpgradeFn, Upgraded};
+use super::{
+    HttpCode, HttpVersion, Method, Metrics, Request, Response, SharedRouter, Shutdown, StateMap,
+};
+
+const MAX_BUFFER_SIZE: usize = 2048;
+/// Capacity past which a buffer grown by a large request or response is freed once
+/// done with, rather than kept for the life of the connection.
+const MAX_KEPT_BUFFER_SIZE: usize = 128 * 1024;
+const DEFAULT_MAX_HEAD_SIZE: usize = 16 * 1024;
+const DEFAULT_HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_MAX_BODY_SIZE: usize = 64 * 1024 * 1024;
+
+/// A persistent client connection, answering requests one after the other until
+/// the client closes it or asks for it to be closed.
+pub struct Connection<S> {
+    stream: S,
+    buf: BytesMut,
+    /// Responses are serialized into it, reused from one to the next.
+    write_buf: Vec<u8>,
+    /// Request whose head was split off the buffer, waiting for the rest of its body.
+    pending: Option<Request>,
+    /// Decoder of the chunked body being received, kept from one read to the next.
+    chunked: Option<chunked::Decoder>,
+    options: ConnectionOptions,
+    shutdown: Shutdown,
+    info: ConnectionInfo,
+    metrics: Arc<Metrics>,
+    states: StateMap,
+    middlewares: Middlewares,
+    hooks: Hooks,
+    served: usize,
+    into_upgraded: Option<fn(S) -> Box<dyn Io>>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IdleAction {
+    /// Close the connection without a word.
+    #[default]
+    Close,
+    /// Send a 408 before closing, letting the client know it should not reuse it.
+    RequestTimeout,
+}
+
+/// Transport level details of the connection a request was received on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    pub peer_addr: Option<SocketAddr>,
+    pub local_addr: Option<SocketAddr>,
+    pub tls: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionOptions {
+    /// Maximum time allowed to receive a complete request head, 10 seconds by default.
+    pub header_timeout: Option<Duration>,
+    /// Size of the largest request head accepted, request line included, 16 KiB by
+    /// default. Trailer sections of chunked bodies are held to it as well.
+    pub max_head_size: usize,
+    /// Size of the largest request body accepted on any route, 64 MiB by default.
+    /// Chunked bodies count with their framing.
+    pub max_body_size: usize,
+    /// Whether connections may be reused for several requests.
+    pub keep_alive: bool,
+    /// Time an idle connection is kept open waiting for the next request.
+    pub keep_alive_timeout: Option<Duration>,
+    /// What to do with connections idle past the keep-alive timeout.
+    pub idle_action: IdleAction,
+    /// Number of requests served on a connection before it is closed.
+    pub max_requests: Option<usize>,
+    /// Time past which answering a request logs a warning, from its first byte
+    /// received to the last byte of its response written.
+    pub slow_request_threshold: Option<Duration>,
+}
+
+impl<S> Connection<S>
+where
+    S: Transport,
+{
+    pub fn new(stream: S, options: ConnectionOptions) -> Self {
+        Connection {
+            stream,
+            buf: BytesMut::with_capacity(MAX_BUFFER_SIZE),
+            write_buf: Vec::new(),
+            pending: None,
+            chunked: None,
+            options,
+            shutdown: Shutdown::default(),
+            info: ConnectionInfo::default(),
+            metrics: Arc::default(),
+            states: StateMap::default(),
+            middlewares: Middlewares::from([]),
+            hooks: Hooks::default(),
+            served: 0,
+            into_upgraded: None,
+        }
+    }
+
+    /// Once `shutdown` is triggered, the connection is closed as soon as it is idle.
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    pub fn with_info(mut self, info: ConnectionInfo) -> Self {
+        self.info = info;
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Application states made available to every request of the connection.
+    pub fn with_states(mut self, states: StateMap) -> Self {
+        self.states = states;
+        self
+    }
+
+    /// Middlewares wrapping the router for every request of the connection.
+    pub fn with_middlewares(mut self, middlewares: Middlewares) -> Self {
+        self.middlewares = middlewares;
+        self
+    }
+
+    /// Callbacks run as the connection and its requests progress.
+    pub fn with_hooks(mut self, hooks: Hooks) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    /// Lets handlers take the connection over with a 101 response, see
+    /// [`Response::on_upgrade`].
+    pub fn with_upgrades(mut self) -> Self
+    where
+        S: Send + 'static,
+    {
+        self.into_upgraded = Some(|stream| Box::new(stream));
+        self
+    }
+
+    pub async fn serve(self, router: &SharedRouter) {
+        let span = info_span!("connection", peer = self.info.peer_addr.map(field::display));
+        let metrics = self.metrics.clone();
+        let _open = metrics.open_connection();
+        let (hooks, info) = (self.hooks.clone(), self.info);
+        let opened = Instant::now();
+        async move {
+            hooks.connect(info).await;
+            let requests = self.serve_requests(router).await;
+            let event = DisconnectEvent {
+                connection: info,
+                requests,
+                duration: opened.elapsed(),
+            };
+            hooks.disconnect(event).await;
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// Answers requests until the connection is done with, returning how many were.
+    async fn serve_requests(mut self, router: &SharedRouter) -> usize {
+        while let Some((mut req, received)) = self.read_request(router).await {
+            let parsed = Instant::now();
+            self.served += 1;
+            let keep_alive = self.options.keep_alive
+                && req.keep_alive()
+                && !self.shutdown.is_triggered()
+                && !self
+                    .options
+                    .max_requests
+                    .is_some_and(|max| self.served >= max);
+            let version = req.version();
+            let method = req.method().clone();
+            let path = (self.hooks.has_request() || self.hooks.has_response())
+                .then(|| req.path().to_string());
+            if let Some(path) = path.clone().filter(|_| self.hooks.has_request()) {
+                let event = RequestEvent {
+                    connection: self.info,
+                    method: method.clone(),
+                    path,
+                    parse: parsed - received,
+                };
+                self.hooks.request(event).await;
+            }
+
+            let (hints, hints_rx) = EarlyHints::channel();
+            if version == HttpVersion::V1_1 {
+                req.extensions_mut().insert(hints);
+            }
+            let span = info_span!(
+                "request",
+                method = method.as_str(),
+                path = req.path(),
+                route = field::Empty,
+                request_id = field::Empty,
+                trace_id = field::Empty,
+                status = field::Empty,
+            );
+            let summary = self
+                .options
+                .slow_request_threshold
+                .map(|_| RequestSummary::new(&req));
+            let route = span.in_scope(|| self.route(router, req));
+            let mut res = self.respond(route, hints_rx).instrument(span.clone()).await;
+            let handled = Instant::now();
+            let status = res.code().as_u16();
+            span.record("status", status);
+            let matched = path
+                .as_ref()
+                .and_then(|_| res.extensions().get::<MatchedPath>().cloned());
+            if let Some(upgrade) = res.take_upgrade(&method) {
+                let served = self.served;
+                self.upgrade(res, upgrade).await;
+                return served;
+            }
+            if keep_alive {
+                if version == HttpVersion::V1_0 {
+                    res.header("Connection", "keep-alive");
+                }
+                if let Some(params) = self.keep_alive_params() {
+                    res.header("Keep-Alive", params);
+                }
+            } else {
+                res.header("Connection", "close");
+            }
+            // The client does not read a body, which would otherwise be taken for the start
+            // of the next response
+            if method == Method::Head {
+                res.strip_body();
+            }
+            let written = self.write_response(res).instrument(span.clone()).await;
+            let timings = Timings {
+                parse: parsed - received,
+                handler: handled - parsed,
+                write: handled.elapsed(),
+            };
+            span.in_scope(|| {
+                let total = timings.total();
+                debug!(elapsed = ?total, "Request served");
+                if let Some(summary) = summary.filter(|_| self.is_slow(total)) {
+                    summary.warn(&timings);
+                }
+            });
+            if let Some(path) = path.filter(|_| self.hooks.has_response()) {
+                let event = ResponseEvent {
+                    connection: self.info,
+                    method,
+                    path,
+                    route: matched.map(|MatchedPath(route)| route),
+                    status,
+                    parse: timings.parse,
+                    handler: timings.handler,
+                    write: timings.write,
+                    written,
+                };
+                self.hooks.response(event).await;
+            }
+            if !written || !keep_alive {
+                break;
+            }
+        }
+
+        // Half-close so the client reads a clean end of stream before the socket is dropped
+        let _ = self.stream.shutdown().await;
+        self.served
+    }
+
+    fn is_slow(&self, elapsed: Duration) -> bool {
+        self.options
+            .slow_request_threshold
+            .is_some_and(|threshold| elapsed > threshold)
+    }
+
+    /// Writes the 101 response then hands the connection over to `upgrade`.
+    async fn upgrade(mut self, res: Response, upgrade: UpgradeFn) {
+        let Some(into_upgraded) = self.into_upgraded else {
+            warn!("Connection upgrades are not supported on this transport");
+            let mut res = Response::from(HttpCode::NotImplemented);
+            res.header("Connection", "close");
+            self.write_response(res).await;
+            let _ = self.stream.shutdown().await;
+            return;
+        };
+
+        if self.write_response(res).await {
+            upgrade(Upgraded::new(into_upgraded(self.stream), self.buf)).await;
+        }
+    }
+
+    fn route(&self, router: &SharedRouter, req: Request) -> BoxFuture<Response> {
+        let router = router.load();
+        if self.middlewares.is_empty() {
+            return Box::pin(async move { router.route(req).await });
+        }
+
+        let endpoint = move |req| -> BoxFuture<Response> {
+            let router = router.clone();
+            Box::pin(async move { router.route(req).await })
+        };
+        Next::new(self.middlewares.clone(), endpoint).run(req)
+    }
+
+    /// Waits for the response, writing the early hints sent in the meantime.
+    async fn respond(
+        &mut self,
+        mut route: BoxFuture<Response>,
+        mut hints: UnboundedReceiver<Hints>,
+    ) -> Response {
+        let res = loop {
+            tokio::select! {
+                biased;
+                Some(headers) = hints.recv() => self.write_hints(headers).await,
+                res = &mut route => break res,
+            }
+        };
+        // Sent while the handler was completing
+        while let Ok(headers) = hints.try_recv() {
+            self.write_hints(headers).await;
+        }
+        res
+    }
+
+    async fn write_hints(&mut self, headers: Hints) {
+        let mut res = Response::from(HttpCode::EarlyHints);
+        for (key, value) in headers {
+            res.append_header(key, value);
+        }
+        self.write_response(res).await;
+    }
+
+    /// Reads the next request, or `None` once the client closed the connection.
+    ///
+    /// Bytes following the request are kept in the buffer, so requests pipelined in
+    /// the same segment are handed out by the following calls.
+    ///
+    /// Clients failing to send the whole request head before the header timeout are
+    /// answered with a 408 and disconnected, while idle connections are closed after the
+    /// keep-alive timeout as configured by the idle action.
+    ///
+    /// Bodies over the limit of the middlewares or over the maximum body size are
+    /// answered with a 413 as soon as the head is received, then the connection is
+    /// closed rather than reading them. Chunked bodies are refused as soon as they are
+    /// decoded past it.
+    /// Malformed requests are answered with a 400 and the connection closed as well, as
+    /// are heads over the size limit with a 431.
+    async fn read_request(&mut self, router: &SharedRouter) -> Option<(Request, Instant)> {
+        if self.served > 0 && self.buf.is_empty() {
+            if let Some(timeout) = self.options.keep_alive_timeout {
+                match tokio::time::timeout(timeout, self.fill_buf()).await {
+                    Ok(Some(n)) if n > 0 => {}
+                    Ok(_) => return None,
+                    Err(_) => {
+                        self.metrics.record_idle_timeout();
+                        if self.options.idle_action == IdleAction::RequestTimeout {
+                            self.reject(HttpCode::RequestTimeout).await;
+                        }
+                        return None;
+                    }
+                }
+            }
+        }
+
+        let mut deadline = self.options.header_timeout.map(|t| Instant::now() + t);
+        let mut head_checked = false;
+        // Requests are timed from their first byte, not from the wait preceding it
+        let mut received = (!self.buf.is_empty()).then(Instant::now);
+
+        loop {
+            match self.parse_request(router) {
+                Ok(Some(req)) => return Some((req, received.unwrap_or_else(Instant::now))),
+                Ok(None) => {}
+                Err(code) => {
+                    self.metrics.record_parse_error();
+                    self.reject(code).await;
+                    return None;
+                }
+            }
+            if let Some(req) = &self.pending {
+                deadline = None;
+                if !head_checked && req.content_length() > self.body_limit(router, req.clone()) {
+                    self.metrics.record_parse_error();
+                    self.reject(HttpCode::PayloadTooLarge).await;
+                    return None;
+                }
+                head_checked = true;
+            }
+
+            let read = match deadline {
+                Some(deadline) => match tokio::time::timeout_at(deadline, self.fill_buf()).await {
+                    Ok(read) => read,
+                    Err(_) => {
+                        self.metrics.record_header_timeout();
+                        self.reject(HttpCode::RequestTimeout).await;
+                        return None;
+                    }
+                },
+                None => self.fill_buf().await,
+            };
+
+            if read? == 0 {
+                // Closing between requests is fine, not in the middle of one
+                if !self.buf.is_empty() || self.pending.is_some() {
+                    self.metrics.record_client_disconnect();
+                }
+                return None;
+            }
+            received.get_or_insert_with(Instant::now);
+        }
+    }
+
+    /// Reads more bytes into the buffer, returning how many were read. Gives up with
+    /// `None` on read errors or when the server shuts down while the connection is idle.
+    async fn fill_buf(&mut self) -> Option<usize> {
+        let read = tokio::select! {
+            read = self.stream.read_buf(&mut self.buf) => read,
+            _ = self.shutdown.wait(), if self.buf.is_empty() && self.pending.is_none() => {
+                return None
+            }
+        };
+
+        match read {
+            Ok(n) => {
+                self.metrics.record_received(n);
+                Some(n)
+            }
+            Err(e) if is_disconnect(&e) => {
+                self.metrics.record_client_disconnect();
+                None
+            }
+            Err(e) => {
+                warn!("Failed to receive data: {}", e);
+                None
+            }
+        }
+    }
+
+    fn keep_alive_params(&self) -> Option<String> {
+        let timeout = self
+            .options
+            .keep_alive_timeout
+            .map(|t| format!("timeout={}", t.as_secs()));
+        let max = self
+            .options
+            .max_requests
+            .map(|max| format!("max={}", max - self.served));
+
+        let params = timeout.into_iter().chain(max).join(", ");
+        (!params.is_empty()).then_some(params)
+    }
+
+    fn head_len(&self) -> Option<usize> {
+        self.buf
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .map(|i| i + 4)
+    }
+
+    /// Size of the largest body accepted for `req`, the smallest of the limits of the
+    /// middlewares, of the route and of the connection.
+    fn body_limit(&self, router: &SharedRouter, mut req: Request) -> usize {
+        // The route is the one the request reaches once the middlewares rewrote it
+        for middleware in self.middlewares.iter() {
+            middleware.rewrite_head(&mut req);
+        }
+        middleware::body_limit(&self.middlewares)
+            .into_iter()
+            .chain(router.load().body_limit(&req))
+            .fold(self.options.max_body_size, usize::min)
+    }
+
+    /// Takes the next request out of the buffer, `None` until it is complete. Malformed
+    /// requests give the status to answer them with.
+    ///
+    /// The request keeps slices of the buffer it was received in rather than copies:
+    /// the head is split off as soon as it is complete, the body once it is.
+    fn parse_request(&mut self, router: &SharedRouter) -> Result<Option<Request>, HttpCode> {
+        let mut req = match self.pending.take() {
+            Some(req) => req,
+            None => match self.parse_head()? {
+                Some(req) => req,
+                None => return Ok(None),
+            },
+        };
+
+        let body = if req.header("Transfer-Encoding").is_none() {
+            let len = req.content_length();
+            if self.buf.len() < len {
+                self.pending = Some(req);
+                return Ok(None);
+            }
+            self.buf.split_to(len).freeze()
+        } else {
+            let decoder = match &mut self.chunked {
+                Some(decoder) => decoder,
+                None => {
+                    let max = self.body_limit(router, req.clone());
+                    let max_trailers = self.options.max_head_size;
+                    self.chunked
+                        .insert(chunked::Decoder::new(max, max_trailers))
+                }
+            };
+            let decoded = decoder
+                .decode(&self.buf)
+                .map_err(|code| invalid("malformed chunked body", code))?;
+            let Some((body, used)) = decoded else {
+                self.pending = Some(req);
+                return Ok(None);
+            };
+            self.chunked = None;
+            self.buf.advance(used);
+            // Handlers see the body as if it had been sent with a length
+            req.remove_header("Transfer-Encoding");
+            req.set_header("Content-Length", body.len().to_string());
+            Bytes::from(body)
+        };
+
+        req.set_body(body);
+        req.set_connection_info(self.info);
+        req.set_states(self.states.clone());
+        Ok(Some(req))
+    }
+
+    /// Splits the next request head off the buffer and parses it, `None` until it is
+    /// complete.
+    fn parse_head(&mut self) -> Result<Option<Request>, HttpCode> {
+        let head_len = self.head_len();
+        // Heads ending lines with a bare LF would otherwise never be complete
+        if has_bare_line_feed(&self.buf[..head_len.unwrap_or(self.buf.len())]) {
+            return Err(invalid("bare LF line ending", HttpCode::BadRequest));
+        }
+        let Some(head_len) = head_len else {
+            if self.buf.len() > self.options.max_head_size {
+                return Err(HttpCode::RequestHeaderFieldsTooLarge);
+            }
+            return Ok(None);
+        };
+        if head_len > self.options.max_head_size {
+            return Err(HttpCode::RequestHeaderFieldsTooLarge);
+        }
+
+        let head = self.buf.split_to(head_len).freeze();
+        let req = Request::parse_head(head).map_err(|e| invalid(e, HttpCode::BadRequest))?;
+        if req.version() == HttpVersion::V1_1 && req.header("Host").is_none() {
+            return Err(invalid("missing Host header", HttpCode::BadRequest));
+        }
+        trace!(
+            target: PARSER_TARGET,
+            method = req.method().as_str(),
+            path = req.path(),
+            head = head_len,
+            body = req.content_length(),
+            "Parsed request head"
+        );
+        match req.header("Transfer-Encoding") {
+            None => {}
+            // Either could be what another server in front of this one went by
+            Some(_) if req.header("Content-Length").is_some() => {
+                let reason = "both Transfer-Encoding and Content-Length";
+                return Err(invalid(reason, HttpCode::BadRequest));
+            }
+            Some(_) if req.version() == HttpVersion::V1_0 => {
+                let reason = "Transfer-Encoding in an HTTP/1.0 request";
+                return Err(invalid(reason, HttpCode::BadRequest));
+            }
+            Some(coding) if !coding.eq_ignore_ascii_case("chunked") => {
+                let reason = format!("unsupported transfer coding {:?}", coding);
+                return Err(invalid(reason, HttpCode::NotImplemented));
+            }
+            Some(_) => {}
+        }
+        Ok(Some(req))
+    }
+
+    /// Answers with `code` and closes the connection.
+    async fn reject(&mut self, code: HttpCode) {
+        let mut res = Response::from(code);
+        res.header("Connection", "close");
+        self.write_response(res).await;
+    }
+
+    /// Writes the whole response, returning whether the connection is still usable.
+    async fn write_response(&mut self, res: Response) -> bool {
+        self.metrics.record_response(res.code().as_u16());
+        let mut out = Counted::new(&mut self.stream);
+        let written = match res.write_buffered(&mut out, &mut self.write_buf).await {
+            Ok(()) => out.flush().await,
+            Err(e) => Err(e),
+        };
+        self.metrics.record_sent(out.written);
+        self.shrink_buffers();
+
+        match written {
+            Ok(()) => true,
+            // The client went away, there is nobody left to report this to
+            Err(e) if is_disconnect(&e) => {
+                self.metrics.record_client_disconnect();
+                false
+            }
+            Err(e) => {
+                warn!("Failed to send data: {}", e);
+                false
+            }
+        }
+    }
+
+    fn shrink_buffers(&mut self) {
+        if self.buf.is_empty() && self.buf.capacity() > MAX_KEPT_BUFFER_SIZE {
+            self.buf = BytesMut::with_capacity(MAX_BUFFER_SIZE);
+        }
+        if self.write_buf.capacity() > MAX_KEPT_BUFFER_SIZE {
+            self.write_buf = Vec::new();
+        }
+    }
+}
+
+/// Time spent on each step of answering a request.
+struct Timings {
+    /// From the first byte received to the complete request.
+    parse: Duration,
+    handler: Duration,
+    write: Duration,
+}
+
+impl Timings {
+    fn total(&self) -> Duration {
+        self.parse + self.handler + self.write
+    }
+}
+
+/// What is reported about a request answered slowly, captured before it is handed
+/// to the handler.
+struct RequestSummary {
+    request_line: String,
+    user_agent: String,
+    body_len: usize,
+}
+
+impl RequestSummary {
+    fn new(req: &Request) -> Self {
+        let target = match req.query() {
+            Some(query) => format!("{}?{}", req.path(), query),
+            None => req.path().to_string(),
+        };
+        RequestSummary {
+            request_line: format!(
+                "{} {} {}",
+                req.method().as_str(),
+                target,
+                req.version().as_str()
+            ),
+            user_agent: req.header("User-Agent").unwrap_or("-").to_string(),
+            body_len: req.body().len(),
+        }
+    }
+
+    fn warn(&self, timings: &Timings) {
+        warn!(
+            parse = ?timings.parse,
+            handler = ?timings.handler,
+            write = ?timings.write,
+            user_agent = %self.user_agent,
+            body_len = self.body_len,
+            "Slow request \"{}\" took {:?}",
+            self.request_line,
+            timings.total(),
+        );
+    }
+}
+
+/// Writer counting the bytes written through it.
+struct Counted<'a, W> {
+    inner: &'a mut W,
+    written: usize,
+}
+
+impl<'a, W> Counted<'a, W> {
+    fn new(inner: &'a mut W) -> Self {
+        Counted { inner, written: 0 }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Counted<'_, W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut *self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            self.written += n;
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Logs why a request is refused, giving the status to answer it with.
+fn invalid(reason: impl std::fmt::Display, code: HttpCode) -> HttpCode {
+    debug!(target: PARSER_TARGET, "Invalid request: {}", reason);
+    code
+}
+
+/// Whether a line of `head` ends with a LF alone rather than CRLF.
+fn has_bare_line_feed(head: &[u8]) -> bool {
+    head.iter()
+        .enumerate()
+        .any(|(i, &b)| b == b'\n' && (i == 0 || head[i - 1] != b'\r'))
+}
+
+fn is_disconnect(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::UnexpectedEof
+    )
+}
+
+impl Default for ConnectionOptions {
+    fn default() -> Self {
+        ConnectionOptions {
+            header_timeout: Some(DEFAULT_HEADER_TIMEOUT),
+            max_head_size: DEFAULT_MAX_HEAD_SIZE,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            keep_alive: true,
+            keep_alive_timeout: None,
+            idle_action: IdleAction::default(),
+            max_requests: None,
+            slow_request_threshold: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::Middleware;
+    use crate::transport::MockTransport;
+    use crate::{BodyLimit, ComparePath, HttpCode, MethodOverride, NormalizePath, Route, Router};
+
+    fn echo_body(req: Request) -> Response {
+        Response::from(req.body().to_vec())
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_requests() {
+        let mut router = Router::default();
+        router.add_route(Route::post("/", echo_body, ComparePath::Exact));
+        router.add_route(Route::get(
+            "/",
+            |_: Request| Response::from(HttpCode::Ok),
+            ComparePath::Exact,
+        ));
+
+        let (mut client, server) = tokio::io::duplex(MAX_BUFFER_SIZE);
+        let router = SharedRouter::from(router);
+        let handle = tokio::spawn(async move {
+            Connection::new(server, ConnectionOptions::default())
+                .serve(&router)
+                .await
+        });
+
+        client
+            .write_all(
+                b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nfirst\
+                  GET / HTTP/1.1\r\nHost: localhost\r\n\r\n\
+                  POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 6\r\n\
+                  Connection: close\r\n\r\nsecond",
+            )
+            .await
+            .unwrap();
+
+        let mut res = String::new();
+        client.read_to_string(&mut res).await.unwrap();
+        handle.await.unwrap();
+
+        let bodies = res
+            .split("HTTP/1.1 ")
+            .skip(1)
+            .map(|r| r.split("\r\n\r\n").nth(1).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(bodies, vec!["first", "", "second"]);
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_head_request() {
+        let mut router = Router::default();
+        let hello = |_: Request| Response::from("hello");
+        router.add_route(Route::get("/", hello, ComparePath::Exact));
+
+        let (mut client, server) = tokio::io::duplex(MAX_BUFFER_SIZE);
+        let router = SharedRouter::from(router);
+        let handle = tokio::spawn(async move {
+            Connection::new(server, ConnectionOptions::default())
+                .serve(&router)
+                .await
+        });
+
+        client
+            .write_all(
+                b"HEAD / HTTP/1.1\r\nHost: localhost\r\n\r\n\
+                  GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            )
+            .await
+            .unwrap();
+
+        let mut res = String::new();
+        client.read_to_string(&mut res).await.unwrap();
+        handle.await.unwrap();
+
+        // The length of the body a GET would get, without the body itself
+        let (head, get) = res.split_once("\r\n\r\n").unwrap();
+        assert_eq!(head, "HTTP/1.1 200 OK\r\nContent-Length: 5");
+        assert!(get.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(get.ends_with("\r\n\r\nhello"));
+    }
+
+    #[tokio::test]
+    async fn test_body_limit() {
+        let mut router = Router::default();
+        router.add_route(
+            Route::post("/", echo_body, ComparePath::Exact).with_middleware(BodyLimit::new(5)),
+        );
+        let router = SharedRouter::from(router);
+
+        let (mut client, server) = tokio::io::duplex(MAX_BUFFER_SIZE);
+        let handle = tokio::spawn(async move {
+            Connection::new(server, ConnectionOptions::default())
+                .serve(&router)
+                .await
+        });
+
+        // Only the head of the second request is sent, the limit is enforced without its body
+        client
+            .write_all(
+                b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nfirst\
+                  POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 1000000\r\n\r\n",
+            )
+            .await
+            .unwrap();
+
+        let mut res = String::new();
+        client.read_to_string(&mut res).await.unwrap();
+        handle.await.unwrap();
+
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(res.contains("\r\n\r\nfirstHTTP/1.1 413 Payload Too Large\r\n"));
+        assert!(res.contains("Connection: close\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_chunked_body_limit() {
+        let mut router = Router::default();
+        router.add_route(
+            Route::post("/", echo_body, ComparePath::Exact).with_middleware(BodyLimit::new(16)),
+        );
+        let router = SharedRouter::from(router);
+
+        let (mut client, server) = tokio::io::duplex(MAX_BUFFER_SIZE);
+        let handle = tokio::spawn(async move {
+            Connection::new(server, ConnectionOptions::default())
+                .serve(&router)
+                .await
+        });
+
+        // The second body is refused once decoded past the limit, without its end
+        client
+            .write_all(
+                b"POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n\
+                  5\r\nfirst\r\n0\r\n\r\n\
+                  POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n\
+                  3\r\nsec\r\n3\r\nond\r\n3\r\n",
+            )
+            .await
+            .unwrap();
+
+        let mut res = String::new();
+        client.read_to_string(&mut res).await.unwrap();
+        handle.await.unwrap();
+
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(res.contains("\r\n\r\nfirstHTTP/1.1 413 Payload Too Large\r\n"));
+        assert!(res.contains("Connection: close\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_body_limit_before_rewrites() {
+        let mut router = Router::default();
+        router.add_route(
+            Route::put("/files", echo_body, ComparePath::Exact).with_middleware(BodyLimit::new(5)),
+        );
+        router.add_route(Route::post("/", echo_body, ComparePath::Exact));
+        let router = SharedRouter::from(router);
+        let options = ConnectionOptions {
+            max_body_size: 100,
+            ..Default::default()
+        };
+
+        for req in [
+            "POST //files HTTP/1.1\r\nHost: localhost\r\n\
+             X-HTTP-Method-Override: PUT\r\nContent-Length: 6\r\n\r\n",
+            "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 101\r\n\r\n",
+        ] {
+            let router = router.clone();
+            let (mut client, server) = tokio::io::duplex(MAX_BUFFER_SIZE);
+            let middlewares = Middlewares::from([
+                Arc::new(NormalizePath::default()) as Arc<dyn Middleware>,
+                Arc::new(MethodOverride),
+            ]);
+            let handle = tokio::spawn(async move {
+                Connection::new(server, options)
+                    .with_middlewares(middlewares)
+                    .serve(&router)
+                    .await
+            });
+
+            client.write_all(req.as_bytes()).await.unwrap();
+            let mut res = String::new();
+            client.read_to_string(&mut res).await.unwrap();
+            handle.await.unwrap();
+            assert!(
+                res.starts_with("HTTP/1.1 413 Payload Too Large\r\n"),
+                "{:?}",
+                req
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_malformed_requests() {
+        for req in [
+            "GET /\r\n\r\n",
+            "BR[EW / HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            "GET / HTTP/1.1\r\nHost: localhost\r\nNo colon\r\n\r\n",
+            "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: +5\r\n\r\n",
+            "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 9223372036854775807999\r\n\r\n",
+        ] {
+            let router = SharedRouter::default();
+            let (mut client, server) = tokio::io::duplex(MAX_BUFFER_SIZE);
+            let handle = tokio::spawn(async move {
+                Connection::new(server, ConnectionOptions::default())
+                    .serve(&router)
+                    .await
+            });
+
+            client.write_all(req.as_bytes()).await.unwrap();
+            let mut res = String::new();
+            client.read_to_string(&mut res).await.unwrap();
+            handle.await.unwrap();
+            assert!(res.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{:?}", req);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connection_metrics() {
+        let metrics = Arc::new(Metrics::default());
+        for req in [
+            "GET / HTTP/1.1\r\nHost: localhost\r\n\r\nBR[EW / HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 10\r\n\r\nabc",
+        ] {
+            let router = SharedRouter::default();
+            let (mut client, server) = tokio::io::duplex(MAX_BUFFER_SIZE);
+            let conn =
+                Connection::new(server, ConnectionOptions::default()).with_metrics(metrics.clone());
+            let handle = tokio::spawn(async move { conn.serve(&router).await });
+
+            client.write_all(req.as_bytes()).await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut res = String::new();
+            client.read_to_string(&mut res).await.unwrap();
+            handle.await.unwrap();
+        }
+
+        assert_eq!(metrics.responses(4), 2);
+        assert_eq!(metrics.responses(2), 0);
+        assert_eq!(metrics.parse_errors(), 1);
+        // The second client left before sending the whole body
+        assert_eq!(metrics.client_disconnects(), 1);
+        assert!(metrics.bytes_received() > 0);
+        assert!(metrics.bytes_sent() > 0);
+        assert_eq!(metrics.open_connections(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_hooks() {
+        let mut router = Router::default();
+        router.add_route(Route::get(
+            "/echo/{msg}",
+            |_: Request| Response::from(HttpCode::Ok),
+            ComparePath::Exact,
+        ));
+        let router = SharedRouter::from(router);
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let log = |events: &Arc<std::sync::Mutex<Vec<String>>>| {
+            let events = events.clone();
+            move |event: String| {
+                events.lock().unwrap().push(event);
+                async {}
+            }
+        };
+        let (connect, request, response, disconnect) =
+            (log(&events), log(&events), log(&events), log(&events));
+        let hooks = Hooks::default()
+            .on_connect(move |info| connect(format!("connect {:?}", info.peer_addr)))
+            .on_request(move |e| request(format!("request {} {}", e.method.as_str(), e.path)))
+            .on_response(move |e| {
+                response(format!("response {} {:?} {}", e.status, e.route, e.written))
+            })
+            .on_disconnect(move |e| disconnect(format!("disconnect {}", e.requests)));
+
+        let (mut client, server) = tokio::io::duplex(MAX_BUFFER_SIZE);
+        let conn = Connection::new(server, ConnectionOptions::default()).with_hooks(hooks);
+        let handle = tokio::spawn(async move { conn.serve(&router).await });
+        client
+            .write_all(
+                b"GET /echo/hi HTTP/1.1\r\nHost: localhost\r\n\r\n\
+                  GET /none HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut res = String::new();
+        client.read_to_string(&mut res).await.unwrap();
+        handle.await.unwrap();
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                "connect None",
+                "request GET /echo/hi",
+                "response 200 Some(\"/echo/{msg}\") true",
+                "request GET /none",
+                "response 404 None true",
+                "disconnect 2",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_huge_content_length() {
+        let router = SharedRouter::default();
+        let (mut client, server) = tokio::io::duplex(MAX_BUFFER_SIZE);
+        let handle = tokio::spawn(async move {
+            Connection::new(server, ConnectionOptions::default())
+                .serve(&router)
+                .await
+        });
+
+        let req = format!(
+            "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n",
+            usize::MAX
+        );
+        client.write_all(req.as_bytes()).await.unwrap();
+        let mut res = String::new();
+        client.read_to_string(&mut res).await.unwrap();
+        handle.await.unwrap();
+        assert!(res.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_max_head_size() {
+        let router = SharedRouter::default();
+        let options = ConnectionOptions {
+            max_head_size: 64,
+            ..Default::default()
+        };
+        let (mut client, server) = tokio::io::duplex(MAX_BUFFER_SIZE);
+        let handle =
+            tokio::spawn(async move { Connection::new(server, options).serve(&router).await });
+
+        // The head never ends, the limit is enforced on what was received so far
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n")
+            .await
+            .unwrap();
+        client.write_all(&[b'a'; 100]).await.unwrap();
+        let mut res = String::new();
+        client.read_to_string(&mut res).await.unwrap();
+        handle.await.unwrap();
+        assert!(res.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_header_timeout() {
+        let router = SharedRouter::default();
+        // The default timeout of 10 seconds, on the paused clock
+        let transport = MockTransport::default()
+            .receive(b"GET / HTTP/1.1\r\nHost: loc")
+            .pause(Duration::from_secs(9))
+            .receive(b"al")
+            .pause(Duration::from_secs(60));
+        let written = transport.written();
+        let started = Instant::now();
+        Connection::new(transport, ConnectionOptions::default())
+            .serve(&router)
+            .await;
+
+        assert!(written
+            .text()
+            .starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+        assert_eq!(started.elapsed(), DEFAULT_HEADER_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn test_max_requests() {
+        let mut router = Router::default();
+        router.add_route(Route::get(
+            "/",
+            |_: Request| Response::from(HttpCode::Ok),
+            ComparePath::Exact,
+        ));
+        let options = ConnectionOptions {
+            keep_alive_timeout: Some(Duration::from_secs(5)),
+            max_requests: Some(2),
+            ..Default::default()
+        };
+
+        let router = SharedRouter::from(router);
+        let (mut client, server) = tokio::io::duplex(MAX_BUFFER_SIZE);
+        let handle =
+            tokio::spawn(async move { Connection::new(server, options).serve(&router).await });
+
+        client
+            .write_all(
+                b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n\
+                  GET / HTTP/1.1\r\nHost: localhost\r\n\r\n\
+                  GET / HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            )
+            .await
+            .unwrap();
+
+        let mut res = String::new();
+        client.read_to_string(&mut res).await.unwrap();
+        handle.await.unwrap();
+
+        let responses = res.split("HTTP/1.1 ").skip(1).collect::<Vec<_>>();
+        assert_eq!(responses.len(), 2);
+        assert!(responses[0].contains("Keep-Alive: timeout=5, max=1\r\n"));
+        assert!(responses[1].contains("Connection: close\r\n"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_timeout() {
+        let options = ConnectionOptions {
+            keep_alive_timeout: Some(Duration::from_secs(5)),
+            idle_action: IdleAction::RequestTimeout,
+            ..Default::default()
+        };
+        let metrics = Arc::new(Metrics::default());
+
+        let router = SharedRouter::default();
+        // Requests within the timeout keep the connection open
+        let transport = MockTransport::default()
+            .receive(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .pause(Duration::from_secs(4))
+            .receive(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .pause(Duration::from_secs(60));
+        let written = transport.written();
+        Connection::new(transport, options)
+            .with_metrics(metrics.clone())
+            .serve(&router)
+            .await;
+
+        let res = written.text();
+        let responses = res.split("HTTP/1.1 ").skip(1).collect::<Vec<_>>();
+        assert_eq!(responses.len(), 3);
+        assert!(responses[2].starts_with("408 Request Timeout\r\n"));
+        assert_eq!(metrics.idle_timeouts(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_partial_reads() {
+        let mut router = Router::default();
+        router.add_route(Route::post("/", echo_body, ComparePath::Exact));
+        let router = SharedRouter::from(router);
+
+        let reqs = b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nfirst\
+                     POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n\
+                     6\r\nsecond\r\n0\r\n\r\n";
+        // One byte at a time, then every request along with a part of the next one
+        for size in [1, 60] {
+            let transport = reqs
+                .chunks(size)
+                .fold(MockTransport::default(), |transport, chunk| {
+                    transport.receive(chunk)
+                });
+            let written = transport.written();
+            Connection::new(transport, ConnectionOptions::default())
+                .serve(&router)
+                .await;
+
+            let res = written.text();
+            let bodies = res
+                .split("HTTP/1.1 ")
+                .skip(1)
+                .map(|r| r.split("\r\n\r\n").nth(1).unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(bodies, ["first", "second"], "{}", size);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slow_request() {
+        /// Log output shared with the subscriber.
+        #[derive(Clone, Default)]
+        struct Captured(Arc<std::sync::Mutex<Vec<u8>>>);
+
+        impl io::Write for Captured {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut router = Router::default();
+        router.add_route(Route::get(
+            "/slow",
+            |_: Request| async {
+                tokio::time::sleep(Duration::from_millis(30)).await;
+                Response::from(HttpCode::Ok)
+            },
+            ComparePath::Exact,
+        ));
+        let router = SharedRouter::from(router);
+        let options = ConnectionOptions {
+            slow_request_threshold: Some(Duration::from_millis(20)),
+            ..Default::default()
+        };
+
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        // The connection is served on this thread, so the subscriber sees its events
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let (mut client, server) = tokio::io::duplex(MAX_BUFFER_SIZE);
+        let conn = Connection::new(server, options).serve(&router);
+        let exchange = async {
+            for path in ["/slow?q=1", "/fast"] {
+                let req = format!(
+                    "GET {} HTTP/1.1\r\nHost: localhost\r\nUser-Agent: test\r\n\r\n",
+                    path
+                );
+                client.write_all(req.as_bytes()).await.unwrap();
+            }
+            client.shutdown().await.unwrap();
+            let mut res = String::new();
+            client.read_to_string(&mut res).await.unwrap();
+        };
+        tokio::join!(conn, exchange);
+
+        let logs = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let warnings = logs
+            .lines()
+            .filter(|l| l.contains("WARN"))
+            .collect::<Vec<_>>();
+        assert_eq!(warnings.len(), 1);
+        let warning = warnings[0];
+        assert!(warning.contains(r#"Slow request "GET /slow?q=1 HTTP/1.1" took"#));
+        for field in [
+            "parse=",
+            "handler=",
+            "write=",
+            "user_agent=test",
+            "status=200",
+        ] {
+            assert!(warning.contains(field), "missing {} in {}", field, warning);
+        }
+    }
+}
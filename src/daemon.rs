@@ -0,0 +1,165 @@
+//! Running in the background for init scripts: `--daemon` detaches the process from
+//! its terminal, `--pid-file` records its pid for `kill $(cat server.pid)`.
+//!
+//! The working directory is kept, so that relative paths of the settings, read again
+//! on reload, keep pointing at the same files.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use http_server_starter_rust::restart::LISTEN_FDS_ENV;
+use http_server_starter_rust::ServerHandle;
+
+/// Whether the process takes over the listeners of a previous one after a restart,
+/// which already detached and wrote the pid file.
+pub fn is_successor() -> bool {
+    std::env::var_os(LISTEN_FDS_ENV).is_some()
+}
+
+/// Forks into the background, the current process exiting once the child is
+/// detached. stdin reads from `/dev/null`, stdout and stderr write to `log`, or to
+/// `/dev/null` when unset.
+///
+/// Must run before any thread is started, as only the calling thread survives.
+pub fn daemonize(log: Option<&Path>) -> io::Result<()> {
+    // Opened first so that errors still reach the terminal
+    let null = File::open("/dev/null")?;
+    let out = match log {
+        Some(path) => OpenOptions::new().create(true).append(true).open(path)?,
+        None => OpenOptions::new().write(true).open("/dev/null")?,
+    };
+
+    fork()?;
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // Not a session leader anymore, so no terminal can be acquired again
+    fork()?;
+
+    for (file, fd) in [(&null, 0), (&out, 1), (&out, 2)] {
+        if unsafe { libc::dup2(file.as_raw_fd(), fd) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Forks, the parent exiting right away.
+fn fork() -> io::Result<()> {
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(()),
+        _ => std::process::exit(0),
+    }
+}
+
+/// File holding the pid of the server, removed once it exits.
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+    pid: u32,
+}
+
+impl PidFile {
+    /// Writes the pid of the current process to `path`, failing when the pid
+    /// already there is that of a running process, unless `take_over` is set.
+    pub fn create(path: &Path, take_over: bool) -> io::Result<Self> {
+        if !take_over {
+            if let Some(pid) = read_pid(path)? {
+                if unsafe { libc::kill(pid as libc::pid_t, 0) } == 0 {
+                    let message = format!("already running as process {}", pid);
+                    return Err(io::Error::new(ErrorKind::AlreadyExists, message));
+                }
+            }
+        }
+
+        let pid = std::process::id();
+        std::fs::write(path, format!("{}\n", pid))?;
+        Ok(PidFile {
+            path: path.to_path_buf(),
+            pid,
+        })
+    }
+
+    /// Writes the pid again, once the process forked.
+    pub fn refresh(&mut self) -> io::Result<()> {
+        self.pid = std::process::id();
+        std::fs::write(&self.path, format!("{}\n", self.pid))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // Left alone once a restarted process wrote its own pid
+        if read_pid(&self.path).ok().flatten() == Some(self.pid) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Pid written in the file at `path`, `None` when there is no such file.
+fn read_pid(path: &Path) -> io::Result<Option<u32>> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => contents.trim().parse().map(Some).map_err(|_| {
+            let message = format!("invalid pid file {}", path.display());
+            io::Error::new(ErrorKind::InvalidData, message)
+        }),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Shuts the server down gracefully on `SIGTERM` and `SIGINT` rather than exiting
+/// right away, so that the pid file gets removed.
+pub fn on_terminate(server: ServerHandle) -> io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let (mut terminate, mut interrupt) = {
+        let _guard = runtime.enter();
+        (
+            signal(SignalKind::terminate())?,
+            signal(SignalKind::interrupt())?,
+        )
+    };
+    std::thread::Builder::new()
+        .name(String::from("terminate"))
+        .spawn(move || {
+            runtime.block_on(async move {
+                tokio::select! {
+                    _ = terminate.recv() => {}
+                    _ = interrupt.recv() => {}
+                }
+                tracing::info!("Shutting down");
+                server.shutdown();
+            })
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pid_file() {
+        let path = std::env::temp_dir().join(format!("server-{}.pid", std::process::id()));
+        let pid_file = PidFile::create(&path, false).unwrap();
+        assert_eq!(read_pid(&path).unwrap(), Some(std::process::id()));
+
+        // The pid written is that of a running process, this one
+        let err = PidFile::create(&path, false).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+
+        drop(pid_file);
+        assert!(!path.exists());
+
+        std::fs::write(&path, "garbage").unwrap();
+        assert!(PidFile::create(&path, false).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,230 @@
+//! `/_debug` report for live troubleshooting: the route table, the open connections,
+//! the server configuration and its uptime, as JSON.
+
+use std::fmt::Write;
+use std::io;
+use std::net::TcpListener as StdTcpListener;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tokio::net::TcpListener;
+use tokio::runtime::Builder;
+use tracing::{info_span, warn, Instrument};
+
+use super::connection::{Connection, ConnectionInfo, ConnectionOptions};
+use super::middleware::{Middleware, Middlewares, Next};
+use super::router::{BoxFuture, ComparePath, SharedRouter};
+use super::{Method, Metrics, Request, Response, Shutdown};
+
+pub const DEBUG_PATH: &str = "/_debug";
+
+/// What the report is made of.
+pub(crate) struct DebugReport {
+    pub(crate) router: SharedRouter,
+    pub(crate) metrics: Arc<Metrics>,
+    /// Server settings, as names and JSON values.
+    pub(crate) config: Vec<(&'static str, String)>,
+    pub(crate) started: Instant,
+}
+
+impl DebugReport {
+    fn render(&self) -> String {
+        let routes = self.router.load().routes().into_iter().map(|route| {
+            let methods = route.methods.iter().map(|m| json_string(m.as_str()));
+            let compare = match route.compare_path {
+                ComparePath::Exact => "exact",
+                ComparePath::Prefix => "prefix",
+            };
+            json_object([
+                ("methods", json_array(methods)),
+                ("path", json_string(&route.path)),
+                ("match", json_string(compare)),
+            ])
+        });
+        let connections = json_object([
+            ("open", self.metrics.open_connections().to_string()),
+            ("in_flight", self.metrics.in_flight().to_string()),
+        ]);
+
+        json_object([
+            (
+                "uptime_secs",
+                self.started.elapsed().as_secs_f64().to_string(),
+            ),
+            ("connections", connections),
+            ("routes", json_array(routes)),
+            ("config", json_object(self.config.iter().cloned())),
+        ])
+    }
+}
+
+/// Middleware answering GET requests on `/_debug` with the report.
+#[derive(Clone)]
+pub(crate) struct DebugEndpoint(Arc<DebugReport>);
+
+impl DebugEndpoint {
+    pub(crate) fn new(report: DebugReport) -> Self {
+        DebugEndpoint(Arc::new(report))
+    }
+}
+
+impl Middleware for DebugEndpoint {
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<Response> {
+        if *req.method() != Method::Get || req.path() != DEBUG_PATH {
+            return next.run(req);
+        }
+
+        let mut res = Response::from(self.0.render());
+        res.header("Content-Type", "application/json");
+        res.header("Cache-Control", "no-store");
+        Box::pin(async move { res })
+    }
+}
+
+/// Serves the report alone on `listener`, from a thread of its own so that it keeps
+/// answering whatever the server runtime is busy with.
+pub(crate) fn spawn_listener(
+    listener: StdTcpListener,
+    endpoint: DebugEndpoint,
+    shutdown: Shutdown,
+) -> io::Result<()> {
+    listener.set_nonblocking(true)?;
+    let runtime = Builder::new_current_thread().enable_all().build()?;
+    thread::Builder::new()
+        .name(String::from("http-debug"))
+        .spawn(move || runtime.block_on(serve(listener, endpoint, shutdown)))?;
+    Ok(())
+}
+
+async fn serve(listener: StdTcpListener, endpoint: DebugEndpoint, shutdown: Shutdown) {
+    let listener = match TcpListener::from_std(listener) {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!("Failed to listen for debug requests: {}", e);
+            return;
+        }
+    };
+    let router = SharedRouter::default();
+    let middlewares = Middlewares::from([Arc::new(endpoint) as Arc<dyn Middleware>]);
+
+    loop {
+        let (stream, peer_addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Failed to accept debug connection: {}", e);
+                    continue;
+                }
+            },
+            _ = shutdown.wait() => break,
+        };
+        let info = ConnectionInfo {
+            peer_addr: Some(peer_addr),
+            local_addr: stream.local_addr().ok(),
+            tls: false,
+        };
+        let connection = Connection::new(stream, ConnectionOptions::default())
+            .with_shutdown(shutdown.clone())
+            .with_info(info)
+            .with_middlewares(middlewares.clone());
+        let router = router.clone();
+        tokio::spawn(
+            async move { connection.serve(&router).await }.instrument(info_span!("debug")),
+        );
+    }
+}
+
+/// JSON string literal of `s`.
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Milliseconds of `duration` as a JSON number, `null` when unset.
+pub(crate) fn json_millis(duration: Option<Duration>) -> String {
+    duration.map_or_else(|| String::from("null"), |d| d.as_millis().to_string())
+}
+
+fn json_array<I>(values: I) -> String
+where
+    I: IntoIterator<Item = String>,
+{
+    let values = values.into_iter().collect::<Vec<_>>();
+    format!("[{}]", values.join(","))
+}
+
+fn json_object<'a, I>(fields: I) -> String
+where
+    I: IntoIterator<Item = (&'a str, String)>,
+{
+    let fields = fields
+        .into_iter()
+        .map(|(name, value)| format!("{}:{}", json_string(name), value))
+        .collect::<Vec<_>>();
+    format!("{{{}}}", fields.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{middleware, HttpCode, Route, Router};
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string("/echo/{msg}"), r#""/echo/{msg}""#);
+        assert_eq!(json_string("a\"b\\c\n\u{1}"), r#""a\"b\\c\n\u0001""#);
+    }
+
+    #[tokio::test]
+    async fn test_debug_endpoint() {
+        let mut router = Router::default();
+        router.add_route(Route::get(
+            "/echo/{msg}",
+            |_: Request| Response::from(HttpCode::Ok),
+            ComparePath::Exact,
+        ));
+        router.add_route(Route::new(
+            Method::Put,
+            "/files",
+            |_: Request| Response::from(HttpCode::Ok),
+            ComparePath::Prefix,
+        ));
+        let endpoint = DebugEndpoint::new(DebugReport {
+            router: SharedRouter::from(router),
+            metrics: Arc::default(),
+            config: vec![("keep_alive", String::from("true"))],
+            started: Instant::now(),
+        });
+        let send = |req: &'static str| {
+            middleware::send(endpoint.clone(), req, |_| async {
+                Response::from(HttpCode::NotFound)
+            })
+        };
+        let res = send("GET /_debug HTTP/1.1\r\n\r\n").await;
+        assert_eq!(res.header_value("Content-Type"), Some("application/json"));
+        let body = String::from_utf8(res.content().to_vec()).unwrap();
+        assert!(body.starts_with(r#"{"uptime_secs":"#), "{}", body);
+        assert!(body.ends_with(
+            r#","connections":{"open":0,"in_flight":0},"routes":[{"methods":["GET"],"path":"/echo/{msg}","match":"exact"},{"methods":["PUT"],"path":"/files","match":"prefix"}],"config":{"keep_alive":true}}"#
+        ));
+
+        let res = send("POST /_debug HTTP/1.1\r\n\r\n").await;
+        assert_eq!(res.code(), HttpCode::NotFound);
+    }
+}
@@ -0,0 +1,148 @@
+//! 103 Early Hints: interim responses letting browsers preload the assets of a page
+//! while the server is still producing it.
+
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use super::middleware::{Middleware, Next};
+use super::router::BoxFuture;
+use super::{Method, Request, Response};
+
+pub(crate) type Hints = Vec<(String, String)>;
+
+/// Sends 103 responses on the connection of a request, ahead of its final response.
+/// Found in the extensions of HTTP/1.1 requests, see [`Request::early_hints`]; HTTP/1.0
+/// clients do not expect interim responses.
+#[derive(Debug, Clone)]
+pub struct EarlyHints(UnboundedSender<Hints>);
+
+impl EarlyHints {
+    pub(crate) fn channel() -> (Self, UnboundedReceiver<Hints>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (EarlyHints(tx), rx)
+    }
+
+    /// Sends a 103 carrying `headers`. Hints sent once the final response was written
+    /// are dropped.
+    pub fn send(&self, headers: Hints) {
+        let _ = self.0.send(headers);
+    }
+
+    /// Sends a 103 with a `Link` header per link, e.g. `</style.css>; rel=preload; as=style`.
+    pub fn link<I, S>(&self, links: I)
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let headers = links
+            .into_iter()
+            .map(|link| (String::from("Link"), link.into()))
+            .collect::<Vec<_>>();
+        if !headers.is_empty() {
+            self.send(headers);
+        }
+    }
+}
+
+/// Middleware sending the links of a page as early hints before its handler runs,
+/// for GET requests on the pages it knows.
+#[derive(Debug, Clone, Default)]
+pub struct Preload {
+    pages: Vec<(String, Vec<String>)>,
+}
+
+impl Preload {
+    /// Hints `links` to the requests of the page at `path`.
+    pub fn with_page<P, I, S>(mut self, path: P, links: I) -> Self
+    where
+        P: Into<String>,
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let links = links.into_iter().map(Into::into).collect();
+        self.pages.push((path.into(), links));
+        self
+    }
+}
+
+impl Middleware for Preload {
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<Response> {
+        if *req.method() == Method::Get {
+            let links = self
+                .pages
+                .iter()
+                .find_map(|(path, links)| (path == req.path()).then_some(links));
+            if let (Some(links), Some(hints)) = (links, req.early_hints()) {
+                hints.link(links.iter().cloned());
+            }
+        }
+        next.run(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::connection::{Connection, ConnectionOptions};
+    use crate::middleware::Middlewares;
+    use crate::{ComparePath, Route, Router, SharedRouter};
+
+    async fn send(req: &str) -> String {
+        let mut router = Router::default();
+        router.add_route(Route::get(
+            "/",
+            |req: Request| async move {
+                // Still producing the page while the client receives the hints
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                if let Some(hints) = req.early_hints() {
+                    hints.link(["</logo.png>; rel=preload; as=image"]);
+                }
+                Response::from("<html>")
+            },
+            ComparePath::Exact,
+        ));
+        let router = SharedRouter::from(router);
+        let preload = Preload::default().with_page(
+            "/",
+            [
+                "</style.css>; rel=preload; as=style",
+                "</app.js>; rel=preload; as=script",
+            ],
+        );
+        let middlewares = Middlewares::from([Arc::new(preload) as Arc<dyn Middleware>]);
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        tokio::spawn(async move {
+            Connection::new(server, ConnectionOptions::default())
+                .with_middlewares(middlewares)
+                .serve(&router)
+                .await
+        });
+        client.write_all(req.as_bytes()).await.unwrap();
+        let mut res = String::new();
+        client.read_to_string(&mut res).await.unwrap();
+        res
+    }
+
+    #[tokio::test]
+    async fn test_early_hints() {
+        let res = send("GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await;
+        assert!(res.starts_with(
+            "HTTP/1.1 103 Early Hints\r\n\
+             Link: </style.css>; rel=preload; as=style\r\n\
+             Link: </app.js>; rel=preload; as=script\r\n\r\n\
+             HTTP/1.1 103 Early Hints\r\n\
+             Link: </logo.png>; rel=preload; as=image\r\n\r\n\
+             HTTP/1.1 200 OK\r\n"
+        ));
+        assert!(res.ends_with("\r\n\r\n<html>"));
+
+        let res = send("GET / HTTP/1.0\r\n\r\n").await;
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(!res.contains("103"));
+    }
+}
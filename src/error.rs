@@ -0,0 +1,124 @@
+use std::fmt;
+use std::io;
+use std::num::ParseIntError;
+use std::str::Utf8Error;
+use std::string::FromUtf8Error;
+
+use tracing::error;
+
+use super::{HttpCode, Response};
+
+/// Error returned by fallible handlers, turned into a response by the router's
+/// error handler.
+#[derive(Debug)]
+pub enum AppError {
+    NotFound,
+    Forbidden,
+    /// No representation matches the `Accept` header of the request.
+    NotAcceptable,
+    BadRequest(String),
+    UnsupportedMediaType(String),
+    PayloadTooLarge,
+    Io(io::Error),
+    Internal(String),
+}
+
+impl AppError {
+    pub fn code(&self) -> HttpCode {
+        match self {
+            AppError::NotFound => HttpCode::NotFound,
+            AppError::Forbidden => HttpCode::Forbidden,
+            AppError::NotAcceptable => HttpCode::NotAcceptable,
+            AppError::BadRequest(_) => HttpCode::BadRequest,
+            AppError::UnsupportedMediaType(_) => HttpCode::UnsupportedMediaType,
+            AppError::PayloadTooLarge => HttpCode::PayloadTooLarge,
+            AppError::Io(e) => match e.kind() {
+                io::ErrorKind::NotFound => HttpCode::NotFound,
+                io::ErrorKind::PermissionDenied => HttpCode::Forbidden,
+                _ => HttpCode::InternalServerError,
+            },
+            AppError::Internal(_) => HttpCode::InternalServerError,
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AppError::NotFound => write!(f, "Not found"),
+            AppError::Forbidden => write!(f, "Forbidden"),
+            AppError::NotAcceptable => write!(f, "Not acceptable"),
+            AppError::BadRequest(msg) => write!(f, "Bad request: {}", msg),
+            AppError::UnsupportedMediaType(ty) => write!(f, "Unsupported media type: {}", ty),
+            AppError::PayloadTooLarge => write!(f, "Payload too large"),
+            AppError::Io(e) => write!(f, "I/O error: {}", e),
+            AppError::Internal(msg) => write!(f, "Internal error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+/// Default error mapping: the status code of the error, with the error message as
+/// body for client errors. Server errors are logged rather than exposed.
+impl From<AppError> for Response {
+    fn from(err: AppError) -> Self {
+        let code = err.code();
+        if code.as_u16() >= 500 {
+            error!("Handler error: {}", err);
+            return Response::from(code);
+        }
+
+        let mut response = Response::from(err.to_string());
+        response.set_code(code);
+        response.header("Content-Type", "text/plain");
+        response
+    }
+}
+
+impl From<io::Error> for AppError {
+    fn from(err: io::Error) -> Self {
+        AppError::Io(err)
+    }
+}
+
+impl From<ParseIntError> for AppError {
+    fn from(err: ParseIntError) -> Self {
+        AppError::BadRequest(err.to_string())
+    }
+}
+
+impl From<Utf8Error> for AppError {
+    fn from(err: Utf8Error) -> Self {
+        AppError::BadRequest(err.to_string())
+    }
+}
+
+impl From<FromUtf8Error> for AppError {
+    fn from(err: FromUtf8Error) -> Self {
+        AppError::BadRequest(err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_mapping() {
+        let err = AppError::from(io::Error::from(io::ErrorKind::NotFound));
+        assert!(Response::from(err)
+            .into_bytes()
+            .starts_with(b"HTTP/1.1 404 Not Found\r\n"));
+
+        let err = AppError::from("abc".parse::<u32>().unwrap_err());
+        let res = String::from_utf8(Response::from(err).into_bytes()).unwrap();
+        assert!(res.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(res.ends_with("\r\n\r\nBad request: invalid digit found in string"));
+
+        let err = AppError::Internal(String::from("database is down"));
+        let res = Response::from(err).into_bytes();
+        assert!(res.starts_with(b"HTTP/1.1 500 Internal Server Error\r\n"));
+        assert!(res.ends_with(b"\r\n\r\n"));
+    }
+}
@@ -0,0 +1,97 @@
+//! Strong ETags computed from response bodies, answering conditional GET requests
+//! with 304 Not Modified.
+
+use base64::engine::general_purpose::STANDARD_NO_PAD as BASE64;
+use base64::Engine;
+use sha1::{Digest, Sha1};
+
+use super::middleware::{Middleware, Next};
+use super::router::BoxFuture;
+use super::{HttpCode, Method, Request, Response};
+
+/// Middleware adding an `ETag` header to successful GET responses lacking one, and
+/// answering 304 with an empty body when it matches the request `If-None-Match`.
+///
+/// It hashes the final body, so it belongs outside compression for each encoding to
+/// get its own tag.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ETag;
+
+/// Strong entity tag of `body`, quotes included.
+pub fn etag(body: &[u8]) -> String {
+    format!("\"{}\"", BASE64.encode(Sha1::digest(body)))
+}
+
+/// Whether an `If-None-Match` header matches `etag`, using the weak comparison.
+pub fn none_match(header: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let etag = opaque(etag);
+    header
+        .split(',')
+        .any(|tag| tag.trim() == "*" || opaque(tag) == etag)
+}
+
+impl Middleware for ETag {
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<Response> {
+        if *req.method() != Method::Get {
+            return next.run(req);
+        }
+
+        let if_none_match = req.header("If-None-Match").map(str::to_string);
+        Box::pin(async move {
+            let mut res = next.run(req).await;
+            if res.code() != HttpCode::Ok {
+                return res;
+            }
+
+            let etag = match res.header_value("ETag") {
+                Some(etag) => etag.to_string(),
+                // Hashing would need the whole body in memory
+                None if res.is_stream() => return res,
+                None => {
+                    let etag = etag(res.content());
+                    res.header("ETag", etag.clone());
+                    etag
+                }
+            };
+            if if_none_match.is_some_and(|header| none_match(&header, &etag)) {
+                res.set_code(HttpCode::NotModified);
+                res.content_mut().clear();
+                res.remove_header("Content-Length");
+            }
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware;
+
+    async fn send(req: &str) -> Response {
+        middleware::send(ETag, req, |_| async { Response::from("hello") }).await
+    }
+
+    #[tokio::test]
+    async fn test_etag() {
+        let res = send("GET / HTTP/1.1\r\n\r\n").await;
+        let tag = res.header_value("ETag").unwrap().to_string();
+        assert_eq!(tag, etag(b"hello"));
+        assert!(res.into_bytes().ends_with(b"\r\n\r\nhello"));
+
+        let res = send(&format!(
+            "GET / HTTP/1.1\r\nIf-None-Match: \"x\", W/{}\r\n\r\n",
+            tag
+        ))
+        .await;
+        assert_eq!(res.header_value("ETag"), Some(tag.as_str()));
+        let bytes = res.into_bytes();
+        assert!(bytes.starts_with(b"HTTP/1.1 304 Not Modified\r\n"));
+        assert!(bytes.ends_with(b"\r\n\r\n"));
+        assert!(!String::from_utf8(bytes).unwrap().contains("Content-Length"));
+
+        let res = send("GET / HTTP/1.1\r\nIf-None-Match: \"x\"\r\n\r\n").await;
+        assert!(res.into_bytes().starts_with(b"HTTP/1.1 200 "));
+    }
+}
@@ -0,0 +1,404 @@
+use std::future::{self, Future};
+use std::marker::PhantomData;
+use std::str::FromStr;
+
+use bytes::Bytes;
+#[cfg(feature = "serde")]
+use serde::{de::DeserializeOwned, Serialize};
+#[cfg(feature = "serde")]
+use tracing::error;
+
+use super::router::{BoxFuture, HandlerOutput, HandlerResult, Ready};
+use super::{AppError, ByteStr, Handler, Request};
+#[cfg(feature = "serde")]
+use super::{HttpCode, IntoResponse, Response};
+
+/// Value extracted from the request before calling a handler.
+///
+/// Extractors run in the order of the handler arguments; those consuming part of
+/// the request, such as [`Body`], should come last.
+pub trait FromRequest: Sized {
+    fn from_request(req: &mut Request) -> Result<Self, AppError>;
+}
+
+/// The single parameter captured by the matched route, parsed as `T`.
+#[derive(Debug)]
+pub struct Path<T>(pub T);
+
+/// Request headers, looked up ignoring the case of their name.
+#[derive(Debug, Default)]
+pub struct Headers(pub Vec<(ByteStr, ByteStr)>);
+
+/// Value of type `T` attached to the request extensions by a middleware.
+#[derive(Debug)]
+pub struct Extension<T>(pub T);
+
+/// Raw request body, sharing the buffer it was received in.
+#[derive(Debug, Default)]
+pub struct Body(pub Bytes);
+
+/// JSON request body deserialized into `T`, or JSON response serialized from `T`.
+#[cfg(feature = "serde")]
+#[derive(Debug)]
+pub struct Json<T>(pub T);
+
+/// Query string deserialized into `T`.
+#[cfg(feature = "serde")]
+#[derive(Debug)]
+pub struct Query<T>(pub T);
+
+/// `application/x-www-form-urlencoded` body deserialized into `T`.
+#[cfg(feature = "serde")]
+#[derive(Debug)]
+pub struct Form<T>(pub T);
+
+/// Marker for handlers taking the extractors `A`, `M` telling sync and async handlers
+/// apart.
+pub struct Extract<A, M>(PhantomData<(A, M)>);
+
+impl<T> FromRequest for Path<T>
+where
+    T: FromStr,
+{
+    fn from_request(req: &mut Request) -> Result<Self, AppError> {
+        let [(name, value)] = req.params() else {
+            return Err(AppError::Internal(format!(
+                "Path expects a single route parameter, got {}",
+                req.params().len()
+            )));
+        };
+
+        value
+            .parse()
+            .map(Path)
+            .map_err(|_| AppError::BadRequest(format!("Invalid path parameter {}", name)))
+    }
+}
+
+impl Headers {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+impl FromRequest for Headers {
+    fn from_request(req: &mut Request) -> Result<Self, AppError> {
+        Ok(Headers(req.headers().to_vec()))
+    }
+}
+
+impl<T> FromRequest for Extension<T>
+where
+    T: Clone + Send + Sync + 'static,
+{
+    fn from_request(req: &mut Request) -> Result<Self, AppError> {
+        req.extensions_mut().remove().map(Extension).ok_or_else(|| {
+            AppError::Internal(format!(
+                "Missing request extension {}",
+                std::any::type_name::<T>()
+            ))
+        })
+    }
+}
+
+impl FromRequest for Body {
+    fn from_request(req: &mut Request) -> Result<Self, AppError> {
+        Ok(Body(req.take_body()))
+    }
+}
+
+/// Text body, for requests without a content type or with a `text/*` one.
+impl FromRequest for String {
+    fn from_request(req: &mut Request) -> Result<Self, AppError> {
+        if let Some(ty) = req.header("Content-Type") {
+            if !ty.trim_start().to_ascii_lowercase().starts_with("text/") {
+                return Err(AppError::UnsupportedMediaType(ty.to_string()));
+            }
+        }
+
+        Ok(String::from_utf8(Vec::from(req.take_body()))?)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<T> FromRequest for Json<T>
+where
+    T: DeserializeOwned,
+{
+    fn from_request(req: &mut Request) -> Result<Self, AppError> {
+        let ty = req.header("Content-Type").unwrap_or_default();
+        let mime = ty.split(';').next().unwrap_or_default().trim();
+        if !mime.eq_ignore_ascii_case("application/json") && !mime.ends_with("+json") {
+            return Err(AppError::UnsupportedMediaType(ty.to_string()));
+        }
+
+        serde_json::from_slice(req.body())
+            .map(Json)
+            .map_err(|e| AppError::BadRequest(format!("Invalid JSON body: {}", e)))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<T> IntoResponse for Json<T>
+where
+    T: Serialize,
+{
+    fn into_response(self) -> Response {
+        match serde_json::to_vec(&self.0) {
+            Ok(body) => {
+                let mut response = Response::from(body);
+                response.header("Content-Type", "application/json");
+                response
+            }
+            Err(e) => {
+                error!("Failed to serialize JSON response: {}", e);
+                Response::from(HttpCode::InternalServerError)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<T> FromRequest for Query<T>
+where
+    T: DeserializeOwned,
+{
+    fn from_request(req: &mut Request) -> Result<Self, AppError> {
+        serde_urlencoded::from_str(req.query().unwrap_or_default())
+            .map(Query)
+            .map_err(|e| AppError::BadRequest(format!("Invalid query string: {}", e)))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<T> FromRequest for Form<T>
+where
+    T: DeserializeOwned,
+{
+    fn from_request(req: &mut Request) -> Result<Self, AppError> {
+        if !req.is_form() {
+            let ty = req.header("Content-Type").unwrap_or_default();
+            return Err(AppError::UnsupportedMediaType(ty.to_string()));
+        }
+
+        serde_urlencoded::from_bytes(req.body())
+            .map(Form)
+            .map_err(|e| AppError::BadRequest(format!("Invalid form body: {}", e)))
+    }
+}
+
+macro_rules! impl_handler {
+    ($($ty:ident),+) => {
+        #[allow(non_snake_case)]
+        impl<F, R, $($ty,)+> Handler<Extract<($($ty,)+), Ready<R>>> for F
+        where
+            F: Fn($($ty),+) -> R + Send + Sync + 'static,
+            R: HandlerOutput,
+            $($ty: FromRequest,)+
+        {
+            fn call(&self, mut req: Request) -> BoxFuture<HandlerResult> {
+                $(
+                    let $ty = match $ty::from_request(&mut req) {
+                        Ok(value) => value,
+                        Err(err) => return Box::pin(future::ready(Err(err))),
+                    };
+                )+
+                Box::pin(future::ready(self($($ty),+).into_result()))
+            }
+        }
+
+        #[allow(non_snake_case)]
+        impl<F, Fut, $($ty,)+> Handler<Extract<($($ty,)+), Fut>> for F
+        where
+            F: Fn($($ty),+) -> Fut + Send + Sync + 'static,
+            Fut: Future + Send + 'static,
+            Fut::Output: HandlerOutput,
+            $($ty: FromRequest,)+
+        {
+            fn call(&self, mut req: Request) -> BoxFuture<HandlerResult> {
+                $(
+                    let $ty = match $ty::from_request(&mut req) {
+                        Ok(value) => value,
+                        Err(err) => return Box::pin(future::ready(Err(err))),
+                    };
+                )+
+                let fut = self($($ty),+);
+                Box::pin(async move { fut.await.into_result() })
+            }
+        }
+    };
+}
+
+impl_handler!(T1);
+impl_handler!(T1, T2);
+impl_handler!(T1, T2, T3);
+impl_handler!(T1, T2, T3, T4);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ComparePath, RequestBuffer, Response, Route, Router};
+
+    fn upload(Path(name): Path<String>, headers: Headers, Body(body): Body) -> Response {
+        let agent = headers.get("user-agent").unwrap_or_default();
+        Response::from(format!("{} {} {}", name, agent, body.len()))
+    }
+
+    async fn double(Path(n): Path<u32>) -> Response {
+        Response::from((n * 2).to_string())
+    }
+
+    fn text(body: String) -> Response {
+        Response::from(body)
+    }
+
+    #[cfg(feature = "serde")]
+    #[derive(serde::Deserialize, serde::Serialize)]
+    struct User {
+        name: String,
+        age: u8,
+    }
+
+    #[cfg(feature = "serde")]
+    fn birthday(Json(mut user): Json<User>) -> Json<User> {
+        user.age += 1;
+        Json(user)
+    }
+
+    #[cfg(feature = "serde")]
+    #[derive(serde::Deserialize)]
+    struct Page {
+        page: u32,
+        per_page: Option<u32>,
+    }
+
+    #[cfg(feature = "serde")]
+    fn list(Query(q): Query<Page>, Form(user): Form<User>) -> Response {
+        Response::from(format!(
+            "{} {:?} {} {}",
+            q.page, q.per_page, user.name, user.age
+        ))
+    }
+
+    fn whoami(Extension(user): Extension<String>) -> Response {
+        Response::from(user)
+    }
+
+    fn request(req: &str) -> Request {
+        Request::parse(&mut RequestBuffer::from(req.bytes())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_extractors() {
+        let mut router = Router::default();
+        router.add_route(Route::post("/files/{name}", upload, ComparePath::Exact));
+        router.add_route(Route::get("/double/{n}", double, ComparePath::Exact));
+        router.add_route(Route::post("/text", text, ComparePath::Exact));
+
+        let req =
+            "POST /files/notes HTTP/1.1\r\nUser-Agent: curl\r\nContent-Length: 5\r\n\r\nhello";
+        let res = router.route(request(req)).await.into_bytes();
+        assert!(res.ends_with(b"\r\n\r\nnotes curl 5"));
+
+        let res = router
+            .route(request("POST /files/a/b HTTP/1.1\r\n\r\n"))
+            .await;
+        assert!(res.into_bytes().starts_with(b"HTTP/1.1 404 "));
+
+        let res = router
+            .route(request("GET /double/21 HTTP/1.1\r\n\r\n"))
+            .await;
+        assert!(res.into_bytes().ends_with(b"\r\n\r\n42"));
+        let res = router
+            .route(request("GET /double/abc HTTP/1.1\r\n\r\n"))
+            .await;
+        assert!(res
+            .into_bytes()
+            .starts_with(b"HTTP/1.1 400 Bad Request\r\n"));
+
+        let req = "POST /text HTTP/1.1\r\nContent-Type: text/plain\r\nContent-Length: 2\r\n\r\nhi";
+        let res = router.route(request(req)).await.into_bytes();
+        assert!(res.ends_with(b"\r\n\r\nhi"));
+        let req = "POST /text HTTP/1.1\r\nContent-Type: image/png\r\nContent-Length: 2\r\n\r\nhi";
+        let res = router.route(request(req)).await.into_bytes();
+        assert!(res.starts_with(b"HTTP/1.1 415 Unsupported Media Type\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_extension() {
+        let mut router = Router::default();
+        router.add_route(Route::get("/whoami", whoami, ComparePath::Exact));
+
+        let mut req = request("GET /whoami HTTP/1.1\r\n\r\n");
+        let res = router.route(req.clone()).await.into_bytes();
+        assert!(res.starts_with(b"HTTP/1.1 500 "));
+
+        req.extensions_mut().insert(String::from("ada"));
+        let res = router.route(req).await.into_bytes();
+        assert!(res.ends_with(b"\r\n\r\nada"));
+    }
+
+    #[cfg(feature = "serde")]
+    #[tokio::test]
+    async fn test_json() {
+        let mut router = Router::default();
+        router.add_route(Route::post("/birthday", birthday, ComparePath::Exact));
+
+        let body = r#"{"name":"Ada","age":36}"#;
+        let req = format!(
+            "POST /birthday HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let res = String::from_utf8(router.route(request(&req)).await.into_bytes()).unwrap();
+        assert!(res.contains("Content-Type: application/json\r\n"));
+        assert!(res.ends_with(r#"{"name":"Ada","age":37}"#));
+
+        let req = "POST /birthday HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: 14\r\n\r\n{\"name\":\"Ada\"}";
+        let res = String::from_utf8(router.route(request(req)).await.into_bytes()).unwrap();
+        assert!(res.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(res.contains("Invalid JSON body: missing field `age`"));
+
+        let req = format!(
+            "POST /birthday HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let res = router.route(request(&req)).await.into_bytes();
+        assert!(res.starts_with(b"HTTP/1.1 415 "));
+    }
+
+    #[cfg(feature = "serde")]
+    #[tokio::test]
+    async fn test_query_and_form() {
+        let mut router = Router::default();
+        router.add_route(Route::post("/users", list, ComparePath::Exact));
+
+        let form = "POST /users?page=2 HTTP/1.1\r\n\
+                    Content-Type: application/x-www-form-urlencoded\r\nContent-Length: 15\r\n\r\n\
+                    name=Ada&age=36";
+        let res = router.route(request(form)).await.into_bytes();
+        assert!(res.ends_with(b"\r\n\r\n2 None Ada 36"));
+
+        let res = router
+            .route(request(&form.replace("page=2", "page=two")))
+            .await;
+        let res = String::from_utf8(res.into_bytes()).unwrap();
+        assert!(res.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(res.contains("Invalid query string"));
+
+        let res = router
+            .route(request(&form.replace("age=36", "age=xy")))
+            .await;
+        let res = String::from_utf8(res.into_bytes()).unwrap();
+        assert!(res.contains("Invalid form body"));
+
+        let res = router
+            .route(request(&form.replace("x-www-form-urlencoded", "json")))
+            .await;
+        assert!(res.into_bytes().starts_with(b"HTTP/1.1 415 "));
+    }
+}
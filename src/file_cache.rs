@@ -0,0 +1,208 @@
+//! In-memory copies of small static files, checked against the file modification
+//! time and length on every use.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::Metadata;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+const DEFAULT_MAX_FILE_SIZE: u64 = 64 * 1024;
+
+/// Contents of recently served files, evicting the least recently used ones once
+/// over its size bound. Only files up to the maximum file size, and with one of the
+/// configured extensions when set, are kept. Cloning it shares the entries.
+#[derive(Clone)]
+pub struct FileCache {
+    inner: Arc<Mutex<Entries>>,
+    max_bytes: usize,
+    max_file_size: u64,
+    extensions: Option<Arc<[String]>>,
+}
+
+#[derive(Default)]
+struct Entries {
+    files: HashMap<PathBuf, Entry>,
+    bytes: usize,
+    /// Counter giving the recency of entries.
+    clock: u64,
+}
+
+struct Entry {
+    content: Arc<[u8]>,
+    modified: Option<SystemTime>,
+    last_used: u64,
+}
+
+impl FileCache {
+    pub fn new(max_bytes: usize) -> Self {
+        FileCache {
+            inner: Arc::default(),
+            max_bytes,
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            extensions: None,
+        }
+    }
+
+    /// Size of the largest file kept, 64 KiB by default.
+    pub fn with_max_file_size(mut self, max_file_size: u64) -> Self {
+        self.max_file_size = max_file_size;
+        self
+    }
+
+    /// Restricts the cache to files with one of `extensions`, compared without case.
+    pub fn with_extensions<I, S>(mut self, extensions: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.extensions = Some(extensions.into_iter().map(Into::into).collect());
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Total size of the cached contents.
+    pub fn bytes(&self) -> usize {
+        self.inner.lock().unwrap().bytes
+    }
+
+    fn is_cacheable(&self, path: &Path, metadata: &Metadata) -> bool {
+        let extension_allowed = match &self.extensions {
+            Some(extensions) => path.extension().is_some_and(|ext| {
+                extensions
+                    .iter()
+                    .any(|allowed| ext.eq_ignore_ascii_case(allowed.as_str()))
+            }),
+            None => true,
+        };
+        metadata.is_file()
+            && metadata.len() <= self.max_file_size
+            && metadata.len() as usize <= self.max_bytes
+            && extension_allowed
+    }
+
+    /// Content of the file at `path`, whose current metadata is `metadata`, read from
+    /// the disk when missing or stale. Files outside the policy give `None`.
+    pub async fn get(&self, path: &Path, metadata: &Metadata) -> io::Result<Option<Arc<[u8]>>> {
+        if !self.is_cacheable(path, metadata) {
+            return Ok(None);
+        }
+
+        let modified = metadata.modified().ok();
+        {
+            let mut entries = self.inner.lock().unwrap();
+            entries.clock += 1;
+            let clock = entries.clock;
+            if let Some(entry) = entries.files.get_mut(path) {
+                if entry.modified == modified && entry.content.len() as u64 == metadata.len() {
+                    entry.last_used = clock;
+                    return Ok(Some(entry.content.clone()));
+                }
+            }
+        }
+
+        let content: Arc<[u8]> = tokio::fs::read(path).await?.into();
+        // The file changed while being read, the next request stores it
+        if content.len() as u64 != metadata.len() {
+            return Ok(None);
+        }
+        self.insert(path, content.clone(), modified);
+        Ok(Some(content))
+    }
+
+    fn insert(&self, path: &Path, content: Arc<[u8]>, modified: Option<SystemTime>) {
+        let mut entries = self.inner.lock().unwrap();
+        entries.clock += 1;
+        let entry = Entry {
+            content,
+            modified,
+            last_used: entries.clock,
+        };
+        entries.bytes += entry.content.len();
+        if let Some(old) = entries.files.insert(path.to_path_buf(), entry) {
+            entries.bytes -= old.content.len();
+        }
+
+        while entries.bytes > self.max_bytes {
+            let Some(oldest) = entries
+                .files
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(path, _)| path.clone())
+            else {
+                break;
+            };
+            let removed = entries.files.remove(&oldest).unwrap();
+            entries.bytes -= removed.content.len();
+        }
+    }
+}
+
+impl fmt::Debug for FileCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileCache")
+            .field("len", &self.len())
+            .field("bytes", &self.bytes())
+            .field("max_bytes", &self.max_bytes)
+            .field("max_file_size", &self.max_file_size)
+            .field("extensions", &self.extensions)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    async fn get(cache: &FileCache, path: &Path) -> Option<Arc<[u8]>> {
+        let metadata = tokio::fs::metadata(path).await.unwrap();
+        cache.get(path, &metadata).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_file_cache() {
+        let dir = std::env::temp_dir().join(format!("file-cache-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (css, js, big) = (dir.join("a.css"), dir.join("b.js"), dir.join("c.css"));
+        std::fs::write(&css, "aaaa").unwrap();
+        std::fs::write(&js, "bbbb").unwrap();
+        std::fs::write(&big, "c".repeat(100)).unwrap();
+
+        let cache = FileCache::new(6)
+            .with_max_file_size(10)
+            .with_extensions(["CSS"]);
+        assert_eq!(get(&cache, &css).await.as_deref(), Some(&b"aaaa"[..]));
+        assert!(get(&cache, &js).await.is_none());
+        assert!(get(&cache, &big).await.is_none());
+        assert_eq!((cache.len(), cache.bytes()), (1, 4));
+
+        // Same length and modification time: the stale copy is still served
+        let modified = std::fs::metadata(&css).unwrap().modified().unwrap();
+        std::fs::write(&css, "AAAA").unwrap();
+        let file = std::fs::File::options().write(true).open(&css).unwrap();
+        file.set_modified(modified).unwrap();
+        assert_eq!(get(&cache, &css).await.as_deref(), Some(&b"aaaa"[..]));
+        file.set_modified(modified + Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(get(&cache, &css).await.as_deref(), Some(&b"AAAA"[..]));
+
+        // Over the total bound, the least recently used file goes
+        let other = dir.join("d.css");
+        std::fs::write(&other, "ddd").unwrap();
+        get(&cache, &other).await.unwrap();
+        assert_eq!((cache.len(), cache.bytes()), (1, 3));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
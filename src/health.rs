@@ -0,0 +1,186 @@
+//! Liveness and readiness endpoints, `/healthz` and `/readyz`, for the load balancers
+//! and orchestrators deciding whether to restart the server or send it traffic.
+
+use std::fmt::{self, Write};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use super::middleware::{Middleware, Next};
+use super::router::{BoxFuture, MatchedPath};
+use super::{HttpCode, Method, Request, Response, Shutdown};
+
+pub const HEALTHZ_PATH: &str = "/healthz";
+pub const READYZ_PATH: &str = "/readyz";
+
+type Check = Arc<dyn Fn() -> BoxFuture<Result<(), String>> + Send + Sync>;
+
+/// Middleware answering GET and HEAD requests on `/healthz` and `/readyz` with the
+/// outcome of the registered checks, one line per check. Both answer 503 when a
+/// check fails; `/readyz` also does while the server is starting or shutting down.
+///
+/// Added with [`Server::with_health`](crate::Server::with_health), the server is
+/// ready once it accepts connections. Used on its own, it is ready from the start.
+#[derive(Clone)]
+pub struct Health {
+    checks: Vec<(String, Check)>,
+    started: Arc<AtomicBool>,
+    shutdown: Shutdown,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Health {
+            checks: Vec::new(),
+            started: Arc::new(AtomicBool::new(true)),
+            shutdown: Shutdown::default(),
+        }
+    }
+}
+
+impl Health {
+    /// Runs `check` on every probe, reporting it under `name`. Failed checks return
+    /// the reason, e.g. `Err("files directory is read-only".into())`.
+    pub fn with_check<N, F, Fut>(mut self, name: N, check: F) -> Self
+    where
+        N: Into<String>,
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        let check: Check = Arc::new(move || Box::pin(check()));
+        self.checks.push((name.into(), check));
+        self
+    }
+
+    /// Ties readiness to the lifecycle of a server.
+    pub(crate) fn attach(mut self, started: Arc<AtomicBool>, shutdown: Shutdown) -> Self {
+        self.started = started;
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Reason for the server not to receive traffic, if any.
+    fn not_ready(&self) -> Option<&'static str> {
+        if !self.started.load(Ordering::SeqCst) {
+            Some("starting")
+        } else if self.shutdown.is_triggered() {
+            Some("shutting down")
+        } else {
+            None
+        }
+    }
+
+    async fn probe(self, path: &'static str) -> Response {
+        let mut report = String::new();
+        let mut healthy = true;
+        if path == READYZ_PATH {
+            if let Some(reason) = self.not_ready() {
+                healthy = false;
+                let _ = writeln!(report, "[-]server {}", reason);
+            }
+        }
+        for (name, check) in &self.checks {
+            match check().await {
+                Ok(()) => {
+                    let _ = writeln!(report, "[+]{} ok", name);
+                }
+                Err(reason) => {
+                    healthy = false;
+                    let _ = writeln!(report, "[-]{} failed: {}", name, reason);
+                }
+            }
+        }
+        let outcome = if healthy { "passed" } else { "failed" };
+        let _ = writeln!(report, "{} check {}", &path[1..], outcome);
+
+        let mut res = Response::from(report);
+        if !healthy {
+            res.set_code(HttpCode::ServiceUnavailable);
+        }
+        res.header("Content-Type", "text/plain; charset=utf-8");
+        res.header("Cache-Control", "no-store");
+        res.extensions_mut().insert(MatchedPath(path.to_string()));
+        res
+    }
+}
+
+impl Middleware for Health {
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<Response> {
+        let path = match req.path() {
+            HEALTHZ_PATH => HEALTHZ_PATH,
+            READYZ_PATH => READYZ_PATH,
+            _ => return next.run(req),
+        };
+        if !matches!(req.method(), Method::Get | Method::Head) {
+            return next.run(req);
+        }
+        Box::pin(self.clone().probe(path))
+    }
+}
+
+impl fmt::Debug for Health {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let checks = self.checks.iter().map(|(name, _)| name).collect::<Vec<_>>();
+        f.debug_struct("Health").field("checks", &checks).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware;
+
+    async fn probe(health: &Health, path: &str) -> (u16, String) {
+        let req = format!("GET {} HTTP/1.1\r\n\r\n", path);
+        let res = middleware::send(health.clone(), &req, |_| async {
+            Response::from(HttpCode::NotFound)
+        })
+        .await;
+        let body = String::from_utf8(res.content().to_vec()).unwrap();
+        (res.code().as_u16(), body)
+    }
+
+    #[tokio::test]
+    async fn test_health() {
+        let writable = Arc::new(AtomicBool::new(true));
+        let check = writable.clone();
+        let started = Arc::new(AtomicBool::new(false));
+        let shutdown = Shutdown::default();
+        let health = Health::default()
+            .with_check("files", move || {
+                let writable = check.load(Ordering::SeqCst);
+                async move { writable.then_some(()).ok_or_else(|| "read-only".into()) }
+            })
+            .attach(started.clone(), shutdown.clone());
+
+        // Alive but not ready while starting
+        assert_eq!(
+            probe(&health, "/healthz").await,
+            (200, String::from("[+]files ok\nhealthz check passed\n"))
+        );
+        assert_eq!(
+            probe(&health, "/readyz").await,
+            (
+                503,
+                String::from("[-]server starting\n[+]files ok\nreadyz check failed\n")
+            )
+        );
+        started.store(true, Ordering::SeqCst);
+        assert_eq!(probe(&health, "/readyz").await.0, 200);
+
+        writable.store(false, Ordering::SeqCst);
+        assert_eq!(
+            probe(&health, "/healthz").await,
+            (
+                503,
+                String::from("[-]files failed: read-only\nhealthz check failed\n")
+            )
+        );
+        writable.store(true, Ordering::SeqCst);
+
+        shutdown.trigger();
+        assert_eq!(probe(&health, "/healthz").await.0, 200);
+        assert_eq!(probe(&health, "/readyz").await.0, 503);
+        assert_eq!(probe(&health, "/other").await.0, 404);
+    }
+}
@@ -0,0 +1,152 @@
+//! Callbacks run along the life of HTTP/1 connections, for accounting or auditing
+//! without touching the accept loop.
+//!
+//! Hooks are awaited by the connection they report on: a slow `on_request` delays the
+//! handler, a slow `on_response` the next request. Work that may take long is better
+//! spawned from the callback.
+
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::router::BoxFuture;
+use super::{ConnectionInfo, Method};
+
+type Hook<E> = Arc<dyn Fn(E) -> BoxFuture<()> + Send + Sync>;
+
+/// Request read from a connection, about to be handed to the middlewares.
+#[derive(Debug, Clone)]
+pub struct RequestEvent {
+    pub connection: ConnectionInfo,
+    pub method: Method,
+    pub path: String,
+    /// From the first byte received to the complete request.
+    pub parse: Duration,
+}
+
+/// Response written to a connection.
+#[derive(Debug, Clone)]
+pub struct ResponseEvent {
+    pub connection: ConnectionInfo,
+    pub method: Method,
+    pub path: String,
+    /// Pattern of the route which answered, if any.
+    pub route: Option<String>,
+    pub status: u16,
+    pub parse: Duration,
+    /// Time taken by the middlewares and the handler.
+    pub handler: Duration,
+    pub write: Duration,
+    /// Whether the whole response reached the client.
+    pub written: bool,
+}
+
+/// Connection closed, by either side.
+#[derive(Debug, Clone)]
+pub struct DisconnectEvent {
+    pub connection: ConnectionInfo,
+    /// Number of requests answered on the connection.
+    pub requests: usize,
+    /// Time the connection stayed open.
+    pub duration: Duration,
+}
+
+/// Lifecycle callbacks, registered on a [`Server`](crate::Server) with
+/// [`Server::with_hooks`](crate::Server::with_hooks). Several callbacks of the same
+/// kind run in the order they were added.
+#[derive(Clone, Default)]
+pub struct Hooks {
+    connect: Vec<Hook<ConnectionInfo>>,
+    request: Vec<Hook<RequestEvent>>,
+    response: Vec<Hook<ResponseEvent>>,
+    disconnect: Vec<Hook<DisconnectEvent>>,
+}
+
+impl Hooks {
+    /// Runs `hook` once a connection is accepted, before its first request is read.
+    pub fn on_connect<F, Fut>(mut self, hook: F) -> Self
+    where
+        F: Fn(ConnectionInfo) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.connect
+            .push(Arc::new(move |event| Box::pin(hook(event))));
+        self
+    }
+
+    /// Runs `hook` for every request read, before it is handled.
+    pub fn on_request<F, Fut>(mut self, hook: F) -> Self
+    where
+        F: Fn(RequestEvent) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.request
+            .push(Arc::new(move |event| Box::pin(hook(event))));
+        self
+    }
+
+    /// Runs `hook` for every response written, requests rejected before reaching a
+    /// handler excepted.
+    pub fn on_response<F, Fut>(mut self, hook: F) -> Self
+    where
+        F: Fn(ResponseEvent) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.response
+            .push(Arc::new(move |event| Box::pin(hook(event))));
+        self
+    }
+
+    /// Runs `hook` once a connection is closed, or handed over after an upgrade.
+    pub fn on_disconnect<F, Fut>(mut self, hook: F) -> Self
+    where
+        F: Fn(DisconnectEvent) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.disconnect
+            .push(Arc::new(move |event| Box::pin(hook(event))));
+        self
+    }
+
+    pub(crate) fn has_request(&self) -> bool {
+        !self.request.is_empty()
+    }
+
+    pub(crate) fn has_response(&self) -> bool {
+        !self.response.is_empty()
+    }
+
+    pub(crate) async fn connect(&self, info: ConnectionInfo) {
+        run(&self.connect, info).await
+    }
+
+    pub(crate) async fn request(&self, event: RequestEvent) {
+        run(&self.request, event).await
+    }
+
+    pub(crate) async fn response(&self, event: ResponseEvent) {
+        run(&self.response, event).await
+    }
+
+    pub(crate) async fn disconnect(&self, event: DisconnectEvent) {
+        run(&self.disconnect, event).await
+    }
+}
+
+async fn run<E: Clone>(hooks: &[Hook<E>], event: E) {
+    for hook in hooks {
+        hook(event.clone()).await;
+    }
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Hooks")
+            .field("on_connect", &self.connect.len())
+            .field("on_request", &self.request.len())
+            .field("on_response", &self.response.len())
+            .field("on_disconnect", &self.disconnect.len())
+            .finish()
+    }
+}
@@ -1,17 +1,48 @@
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum HttpCode {
     Ok = 200,
     NotFound = 404,
     Created = 201,
+    NoContent = 204,
+    PartialContent = 206,
+    MovedPermanently = 301,
+    NotModified = 304,
+    PermanentRedirect = 308,
     InternalServerError = 500,
+    RequestTimeout = 408,
+    TooManyRequests = 429,
+    BadRequest = 400,
+    Forbidden = 403,
+    NotAcceptable = 406,
+    UnsupportedMediaType = 415,
+    PreconditionFailed = 412,
+    PayloadTooLarge = 413,
+    RequestHeaderFieldsTooLarge = 431,
+    RangeNotSatisfiable = 416,
+    Unauthorized = 401,
+    ServiceUnavailable = 503,
+    BadGateway = 502,
+    GatewayTimeout = 504,
+    SwitchingProtocols = 101,
+    EarlyHints = 103,
+    UpgradeRequired = 426,
+    NotImplemented = 501,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Method {
     Get,
+    Head,
     Post,
     Put,
     Delete,
+    Connect,
+    Options,
+    Trace,
+    Patch,
+    /// Any other method, such as the WebDAV `PROPFIND`. Servers answer those no route
+    /// handles with a 501.
+    Extension(String),
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -28,35 +59,101 @@ impl std::fmt::Display for HttpCode {
             Ok => write!(f, "200 OK"),
             NotFound => write!(f, "404 Not Found"),
             Created => write!(f, "201 Created"),
+            NoContent => write!(f, "204 No Content"),
+            PartialContent => write!(f, "206 Partial Content"),
+            MovedPermanently => write!(f, "301 Moved Permanently"),
+            NotModified => write!(f, "304 Not Modified"),
+            PermanentRedirect => write!(f, "308 Permanent Redirect"),
             InternalServerError => write!(f, "500 Internal Server Error"),
+            RequestTimeout => write!(f, "408 Request Timeout"),
+            TooManyRequests => write!(f, "429 Too Many Requests"),
+            BadRequest => write!(f, "400 Bad Request"),
+            Forbidden => write!(f, "403 Forbidden"),
+            NotAcceptable => write!(f, "406 Not Acceptable"),
+            UnsupportedMediaType => write!(f, "415 Unsupported Media Type"),
+            PreconditionFailed => write!(f, "412 Precondition Failed"),
+            PayloadTooLarge => write!(f, "413 Payload Too Large"),
+            RequestHeaderFieldsTooLarge => write!(f, "431 Request Header Fields Too Large"),
+            RangeNotSatisfiable => write!(f, "416 Range Not Satisfiable"),
+            Unauthorized => write!(f, "401 Unauthorized"),
+            ServiceUnavailable => write!(f, "503 Service Unavailable"),
+            BadGateway => write!(f, "502 Bad Gateway"),
+            GatewayTimeout => write!(f, "504 Gateway Timeout"),
+            SwitchingProtocols => write!(f, "101 Switching Protocols"),
+            EarlyHints => write!(f, "103 Early Hints"),
+            UpgradeRequired => write!(f, "426 Upgrade Required"),
+            NotImplemented => write!(f, "501 Not Implemented"),
         }
     }
 }
 
-impl<S> From<S> for Method
-where
-    S: AsRef<str>,
-{
-    fn from(value: S) -> Self {
-        match value.as_ref() {
-            "GET" => Method::Get,
-            "POST" => Method::Post,
-            "PUT" => Method::Put,
-            "DELETE" => Method::Delete,
-            _ => panic!("Invalid method"),
+impl HttpCode {
+    pub fn as_u16(self) -> u16 {
+        self as u16
+    }
+}
+
+impl Method {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Connect => "CONNECT",
+            Method::Options => "OPTIONS",
+            Method::Trace => "TRACE",
+            Method::Patch => "PATCH",
+            Method::Extension(method) => method,
         }
     }
 }
 
-impl<S> From<S> for HttpVersion
-where
-    S: AsRef<str>,
-{
-    fn from(value: S) -> Self {
-        match value.as_ref() {
-            "HTTP/1.0" => HttpVersion::V1_0,
-            "HTTP/1.1" => HttpVersion::V1_1,
-            _ => panic!("Invalid HTTP version"),
+impl HttpVersion {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HttpVersion::V1_0 => "HTTP/1.0",
+            HttpVersion::V1_1 => "HTTP/1.1",
+        }
+    }
+}
+
+/// Whether `s` is a token, the grammar of methods and header names.
+pub(crate) fn is_token(s: &str) -> bool {
+    let is_tchar = |b: u8| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b);
+    !s.is_empty() && s.bytes().all(is_tchar)
+}
+
+impl std::str::FromStr for Method {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Methods are case-sensitive tokens
+        match s {
+            "GET" => Ok(Method::Get),
+            "HEAD" => Ok(Method::Head),
+            "POST" => Ok(Method::Post),
+            "PUT" => Ok(Method::Put),
+            "DELETE" => Ok(Method::Delete),
+            "CONNECT" => Ok(Method::Connect),
+            "OPTIONS" => Ok(Method::Options),
+            "TRACE" => Ok(Method::Trace),
+            "PATCH" => Ok(Method::Patch),
+            _ if is_token(s) => Ok(Method::Extension(s.to_string())),
+            _ => Err(format!("invalid method {:?}", s)),
+        }
+    }
+}
+
+impl std::str::FromStr for HttpVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "HTTP/1.0" => Ok(HttpVersion::V1_0),
+            "HTTP/1.1" => Ok(HttpVersion::V1_1),
+            _ => Err(format!("invalid HTTP version {:?}", s)),
         }
     }
 }
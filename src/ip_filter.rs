@@ -0,0 +1,159 @@
+//! Filtering of requests by client address.
+
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use super::error::AppError;
+use super::middleware::{Middleware, Next};
+use super::router::BoxFuture;
+use super::{Request, Response};
+
+/// Range of addresses, such as `10.0.0.0/8` or `2001:db8::/32`. A bare address is a
+/// range of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid CIDR range: {}", s);
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|_| invalid())?
+            .to_canonical();
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(invalid());
+        }
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+/// Middleware answering 403 to blocked clients.
+///
+/// The most specific range matching the client address decides, a deny winning over
+/// an allow of the same length. Addresses matching no range are only let through
+/// when the allow list is empty.
+#[derive(Debug, Clone, Default)]
+pub struct IpFilter {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl IpFilter {
+    pub fn with_allow(mut self, cidr: Cidr) -> Self {
+        self.allow.push(cidr);
+        self
+    }
+
+    pub fn with_deny(mut self, cidr: Cidr) -> Self {
+        self.deny.push(cidr);
+        self
+    }
+
+    pub fn is_allowed(&self, addr: Option<IpAddr>) -> bool {
+        let longest = |list: &[Cidr]| {
+            let addr = addr?;
+            list.iter()
+                .filter(|cidr| cidr.contains(addr))
+                .map(|cidr| cidr.prefix)
+                .max()
+        };
+
+        match (longest(&self.allow), longest(&self.deny)) {
+            (Some(allow), Some(deny)) => allow > deny,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => self.allow.is_empty(),
+        }
+    }
+}
+
+impl Middleware for IpFilter {
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<Response> {
+        if self.is_allowed(req.peer_addr().map(|addr| addr.ip())) {
+            next.run(req)
+        } else {
+            Box::pin(async { Response::from(AppError::Forbidden) })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(addr: &str) -> Option<IpAddr> {
+        Some(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn test_cidr() {
+        let net: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains("10.1.2.3".parse().unwrap()));
+        assert!(net.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!net.contains("10.2.0.1".parse().unwrap()));
+        assert!("0.0.0.0/0"
+            .parse::<Cidr>()
+            .unwrap()
+            .contains("1.2.3.4".parse().unwrap()));
+
+        let net: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(net.contains("2001:db8::1".parse().unwrap()));
+        assert!(!net.contains("2001:db9::1".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_ip_filter() {
+        let filter = IpFilter::default()
+            .with_allow("10.0.0.0/8".parse().unwrap())
+            .with_deny("10.1.0.0/16".parse().unwrap())
+            .with_allow("10.1.0.7".parse().unwrap());
+        assert!(filter.is_allowed(ip("10.2.0.1")));
+        assert!(!filter.is_allowed(ip("10.1.0.1")));
+        assert!(filter.is_allowed(ip("10.1.0.7")));
+        assert!(!filter.is_allowed(ip("192.168.0.1")));
+        assert!(!filter.is_allowed(None));
+
+        let filter = IpFilter::default().with_deny("192.168.0.0/16".parse().unwrap());
+        assert!(!filter.is_allowed(ip("192.168.0.1")));
+        assert!(filter.is_allowed(ip("10.0.0.1")));
+        assert!(filter.is_allowed(None));
+    }
+}
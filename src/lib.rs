@@ -0,0 +1,142 @@
+//! HTTP/1.1 server: connections, routing, middlewares and the handlers' building
+//! blocks. The binary serves the CodeCrafters routes on top of it.
+//!
+//! Optional parts are behind cargo features. The default ones are those the binary
+//! is built with, `cli` and `compression`; without them only the core is left.
+//!
+//! - `cli`: the binary, along with `auth`, `etag` and `logging`
+//! - `logging`: the `tracing` subscriber of `logging::init`
+//! - `auth`: `BasicAuth` and `Htpasswd`
+//! - `etag`: the `ETag` middleware
+//! - `session`: cookie based sessions
+//! - `compression`: gzip and deflate `Compression` and `Decompression`, with
+//!   `brotli` and `zstd` adding those encodings
+//! - `websocket`: the WebSocket handshake and framing
+//! - `metrics`: the Prometheus `MetricsEndpoint`
+//! - `serde`: the `Json`, `Query` and `Form` extractors
+//! - `jwt`: Bearer JWT authentication with `JwtAuth`
+//! - `http3`: HTTP/3 over QUIC, with TLS
+//! - `tower`: interop with tower services
+//! - `io-uring`: io_uring connection I/O on Linux
+
+pub use access_log::{AccessLog, LogFormat, Rotation};
+#[cfg(feature = "auth")]
+pub use auth::{BasicAuth, Htpasswd};
+#[cfg(feature = "jwt")]
+pub use auth::JwtAuth;
+pub use body_limit::BodyLimit;
+pub use byte_str::ByteStr;
+pub use cache::Cache;
+pub use chaos::{Chaos, ChaosOptions};
+#[doc(hidden)]
+pub use chunked::decode_chunked;
+#[cfg(feature = "compression")]
+pub use compression::{Compression, Decompression};
+pub use connection::{Connection, ConnectionInfo, ConnectionOptions, IdleAction};
+pub use early_hints::{EarlyHints, Preload};
+pub use error::AppError;
+#[cfg(feature = "etag")]
+pub use etag::ETag;
+pub use extensions::Extensions;
+pub use extract::{FromRequest, Headers};
+pub use file_cache::FileCache;
+pub use health::Health;
+pub use hooks::Hooks;
+pub use http::{HttpCode, HttpVersion, Method};
+#[cfg(feature = "http3")]
+pub use http3::Http3;
+pub use ip_filter::IpFilter;
+#[cfg(feature = "logging")]
+pub use logging::LogFilter;
+pub use logging::TraceFormat;
+pub use method_override::MethodOverride;
+pub use metrics::Metrics;
+pub use middleware::SharedMiddleware;
+pub use multipart::Multipart;
+pub use normalize_path::NormalizePath;
+#[cfg(feature = "metrics")]
+pub use prometheus::MetricsEndpoint;
+pub use rate_limit::{Quota, RateLimit};
+pub use request::{Request, RequestBuffer, RequestBuilder};
+pub use request_id::SetRequestId;
+pub use response::{IntoResponse, Response};
+pub use router::{ComparePath, Handler, MatchedPath, Route, RouteInfo, Router, SharedRouter};
+pub use security_headers::SecurityHeaders;
+pub use server::{RuntimeFlavor, Server, ServerHandle};
+pub use shutdown::Shutdown;
+pub use state::{State, StateMap};
+pub use static_files::StaticFiles;
+pub use test_client::{TestClient, TestRequest};
+pub use test_server::{test_server, TestServer};
+pub use timeout::Timeout;
+pub use trace_context::{SetTraceContext, TraceContext};
+pub use transport::{MockTransport, Transport};
+pub use tunnel::Tunnel;
+
+pub mod access_log;
+#[cfg(feature = "auth")]
+pub mod auth;
+pub mod body_limit;
+pub mod byte_str;
+pub mod cache;
+pub mod chaos;
+mod chunked;
+#[cfg(feature = "compression")]
+pub mod compression;
+#[cfg(test)]
+mod conformance;
+pub mod connection;
+mod date;
+mod debug;
+pub mod early_hints;
+pub mod error;
+#[cfg(feature = "etag")]
+pub mod etag;
+pub mod extensions;
+pub mod extract;
+pub mod file_cache;
+pub mod health;
+pub mod hooks;
+pub mod http;
+#[cfg(feature = "http3")]
+pub mod http3;
+pub mod ip_filter;
+pub mod limit;
+pub mod logging;
+pub mod method_override;
+pub mod metrics;
+pub mod middleware;
+mod mime;
+pub mod multipart;
+pub mod negotiate;
+pub mod normalize_path;
+#[cfg(feature = "metrics")]
+pub mod prometheus;
+pub mod rate_limit;
+pub mod request;
+pub mod request_id;
+pub mod response;
+#[cfg(unix)]
+pub mod restart;
+pub mod router;
+pub mod security_headers;
+pub mod server;
+#[cfg(feature = "tower")]
+pub mod service;
+#[cfg(feature = "session")]
+pub mod session;
+pub mod shutdown;
+pub mod sse;
+pub mod state;
+pub mod static_files;
+pub mod test_client;
+pub mod test_server;
+pub mod timeout;
+pub mod trace_context;
+pub mod transport;
+pub mod tunnel;
+pub mod upgrade;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
+#[cfg(feature = "websocket")]
+pub mod websocket;
@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+/// Caps the number of connections simultaneously open from a single client address.
+#[derive(Debug)]
+pub struct IpLimiter {
+    max: usize,
+    open: Mutex<HashMap<IpAddr, usize>>,
+}
+
+/// Counts a connection against its address until dropped.
+pub struct IpGuard {
+    limiter: Arc<IpLimiter>,
+    ip: IpAddr,
+}
+
+/// How connections over the per address cap are turned away.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LimitAction {
+    /// Answer the first request with a 429 and close.
+    #[default]
+    TooManyRequests,
+    /// Reset the connection without reading from it.
+    Reset,
+}
+
+impl IpLimiter {
+    pub fn new(max: usize) -> Self {
+        IpLimiter {
+            max,
+            open: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Registers a connection from `ip`, or `None` if it already reached the cap.
+    pub fn acquire(self: &Arc<Self>, ip: IpAddr) -> Option<IpGuard> {
+        let mut open = self.open.lock().unwrap();
+        let count = open.entry(ip).or_default();
+        if *count >= self.max {
+            return None;
+        }
+
+        *count += 1;
+        Some(IpGuard {
+            limiter: self.clone(),
+            ip,
+        })
+    }
+
+    pub fn open_connections(&self, ip: IpAddr) -> usize {
+        self.open
+            .lock()
+            .unwrap()
+            .get(&ip)
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+impl Drop for IpGuard {
+    fn drop(&mut self) {
+        let mut open = self.limiter.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ip_limiter() {
+        let limiter = Arc::new(IpLimiter::new(2));
+        let ip = IpAddr::from([127, 0, 0, 1]);
+
+        let first = limiter.acquire(ip).unwrap();
+        let _second = limiter.acquire(ip).unwrap();
+        assert!(limiter.acquire(ip).is_none());
+        assert!(limiter.acquire(IpAddr::from([127, 0, 0, 2])).is_some());
+
+        drop(first);
+        assert_eq!(limiter.open_connections(ip), 1);
+        assert!(limiter.acquire(ip).is_some());
+    }
+}
@@ -0,0 +1,358 @@
+//! `--selftest`: load on a running server rather than serving, reporting throughput
+//! and latency percentiles, e.g. to compare two builds.
+//!
+//! Every connection uploads its own file first when the mix reads files, so that
+//! `files` requests find something to serve. Those uploads are not measured.
+
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::{Buf, BytesMut};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Kinds of requests sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// `GET /echo/...`
+    Echo,
+    /// `GET /files/...` of the file uploaded by the connection.
+    Files,
+    /// `POST /files/...` of a body of the upload size.
+    Upload,
+}
+
+const KINDS: [(&str, Kind); 3] = [
+    ("echo", Kind::Echo),
+    ("files", Kind::Files),
+    ("upload", Kind::Upload),
+];
+
+/// Share of each kind of request, e.g. `echo=8,files=1,upload=1`. Only echo requests
+/// by default, which need no files directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mix(Vec<(Kind, u32)>);
+
+impl Default for Mix {
+    fn default() -> Self {
+        Mix(vec![(Kind::Echo, 1)])
+    }
+}
+
+impl Mix {
+    /// Kind of the `n`th request, the kinds being interleaved in their proportions.
+    fn pick(&self, n: usize) -> Kind {
+        let total = self
+            .0
+            .iter()
+            .map(|(_, weight)| *weight as usize)
+            .sum::<usize>();
+        let mut n = n % total;
+        for (kind, weight) in &self.0 {
+            match n.checked_sub(*weight as usize) {
+                Some(rest) => n = rest,
+                None => return *kind,
+            }
+        }
+        unreachable!("n is below the total weight")
+    }
+
+    fn has(&self, kind: Kind) -> bool {
+        self.0.iter().any(|(k, _)| *k == kind)
+    }
+}
+
+impl FromStr for Mix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut mix = Vec::new();
+        for part in s.split(',') {
+            let (name, weight) = part.split_once('=').unwrap_or((part, "1"));
+            let kind = KINDS
+                .iter()
+                .find(|(known, _)| *known == name.trim())
+                .map(|(_, kind)| *kind)
+                .ok_or_else(|| {
+                    format!(
+                        "Unknown request kind {:?}, expected echo, files or upload",
+                        name
+                    )
+                })?;
+            let weight = weight
+                .trim()
+                .parse::<u32>()
+                .map_err(|_| format!("Invalid weight {:?}", weight))?;
+            if weight > 0 {
+                mix.push((kind, weight));
+            }
+        }
+        match mix.is_empty() {
+            true => Err(String::from("No request to send")),
+            false => Ok(Mix(mix)),
+        }
+    }
+}
+
+/// What to send, and where.
+#[derive(Debug, Clone)]
+pub struct LoadTest {
+    pub addr: SocketAddr,
+    /// Connections sending requests at the same time.
+    pub concurrency: usize,
+    /// Requests sent in all.
+    pub requests: usize,
+    pub mix: Mix,
+    /// Size of the bodies uploaded, in bytes.
+    pub upload_size: usize,
+}
+
+/// Outcome of a single request.
+struct Sample {
+    kind: Kind,
+    /// `None` when the exchange failed.
+    status: Option<u16>,
+    latency: Duration,
+}
+
+impl LoadTest {
+    /// Sends the requests, prints the report and returns whether every one of them
+    /// was answered with a 2xx.
+    pub fn run(self) -> io::Result<bool> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+        let test = Arc::new(self);
+        let started = Instant::now();
+        let samples = runtime.block_on(async {
+            let next = Arc::new(AtomicUsize::new(0));
+            let workers = (0..test.concurrency)
+                .map(|id| tokio::spawn(test.clone().worker(id, next.clone())))
+                .collect::<Vec<_>>();
+            let mut samples = Vec::with_capacity(test.requests);
+            for worker in workers {
+                samples.extend(worker.await.map_err(io::Error::other)??);
+            }
+            Ok::<_, io::Error>(samples)
+        })?;
+        Ok(report(&samples, started.elapsed()))
+    }
+
+    /// Sends requests over a connection of its own until they are all sent, opening a
+    /// new one whenever it is closed.
+    async fn worker(self: Arc<Self>, id: usize, next: Arc<AtomicUsize>) -> io::Result<Vec<Sample>> {
+        let upload = format!("/files/selftest-{}-{}.bin", std::process::id(), id);
+        let body = vec![b'x'; self.upload_size];
+        let mut client = None;
+        let mut samples = Vec::new();
+
+        if self.mix.has(Kind::Files) {
+            let req = post(&upload, &body);
+            let status = Client::connect(self.addr).await?.send(&req).await?;
+            if !(200..300).contains(&status) {
+                let message = format!("upload of {} answered with a {}", upload, status);
+                return Err(io::Error::other(message));
+            }
+        }
+
+        loop {
+            let n = next.fetch_add(1, Ordering::Relaxed);
+            if n >= self.requests {
+                return Ok(samples);
+            }
+            let kind = self.mix.pick(n);
+            let req = match kind {
+                Kind::Echo => get(&format!("/echo/{}", n)),
+                Kind::Files => get(&upload),
+                Kind::Upload => post(&upload, &body),
+            };
+
+            let started = Instant::now();
+            let status = match client.take() {
+                Some(client) => Ok(client),
+                None => Client::connect(self.addr).await,
+            };
+            let status = match status {
+                Ok(mut connected) => {
+                    let status = connected.send(&req).await;
+                    if status.is_ok() && !connected.closed {
+                        client = Some(connected);
+                    }
+                    status.ok()
+                }
+                Err(_) => None,
+            };
+            samples.push(Sample {
+                kind,
+                status,
+                latency: started.elapsed(),
+            });
+        }
+    }
+}
+
+fn get(path: &str) -> Vec<u8> {
+    format!("GET {} HTTP/1.1\r\nHost: selftest\r\n\r\n", path).into_bytes()
+}
+
+fn post(path: &str, body: &[u8]) -> Vec<u8> {
+    let mut req = format!(
+        "POST {} HTTP/1.1\r\nHost: selftest\r\nContent-Length: {}\r\n\r\n",
+        path,
+        body.len()
+    )
+    .into_bytes();
+    req.extend_from_slice(body);
+    req
+}
+
+/// Keep-alive connection to the server under test.
+struct Client {
+    stream: TcpStream,
+    buf: BytesMut,
+    /// Whether the server asked for the connection to be closed.
+    closed: bool,
+}
+
+impl Client {
+    async fn connect(addr: SocketAddr) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        Ok(Client {
+            stream,
+            buf: BytesMut::with_capacity(4096),
+            closed: false,
+        })
+    }
+
+    /// Sends `req`, returning the status it was answered with once the whole
+    /// response is received.
+    async fn send(&mut self, req: &[u8]) -> io::Result<u16> {
+        self.stream.write_all(req).await?;
+
+        let head_len = loop {
+            if let Some(i) = self.buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                break i + 4;
+            }
+            self.fill().await?;
+        };
+        let head = String::from_utf8_lossy(&self.buf[..head_len]).into_owned();
+        let invalid = || io::Error::new(ErrorKind::InvalidData, "invalid response head");
+        let status = head
+            .split(' ')
+            .nth(1)
+            .and_then(|status| status.parse::<u16>().ok())
+            .ok_or_else(invalid)?;
+        let header = |name: &str| {
+            head.lines().skip(1).find_map(|line| {
+                let (key, value) = line.split_once(':')?;
+                key.eq_ignore_ascii_case(name).then(|| value.trim())
+            })
+        };
+        // The server never streams the responses to these requests
+        let len = match header("Content-Length") {
+            Some(len) => len.parse::<usize>().map_err(|_| invalid())?,
+            None => return Err(invalid()),
+        };
+        self.closed = header("Connection").is_some_and(|v| v.eq_ignore_ascii_case("close"));
+
+        while self.buf.len() < head_len + len {
+            self.fill().await?;
+        }
+        self.buf.advance(head_len + len);
+        Ok(status)
+    }
+
+    async fn fill(&mut self) -> io::Result<()> {
+        match self.stream.read_buf(&mut self.buf).await? {
+            0 => Err(ErrorKind::UnexpectedEof.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Prints throughput, statuses and latency percentiles, returning whether every
+/// request was answered with a 2xx.
+fn report(samples: &[Sample], elapsed: Duration) -> bool {
+    let secs = elapsed.as_secs_f64();
+    println!(
+        "Requests   {} in {:.2}s, {:.1} per second",
+        samples.len(),
+        secs,
+        samples.len() as f64 / secs
+    );
+    let kinds = KINDS
+        .iter()
+        .map(|(name, kind)| (name, samples.iter().filter(|s| s.kind == *kind).count()))
+        .filter(|(_, count)| *count > 0)
+        .map(|(name, count)| format!("{} {}", name, count))
+        .collect::<Vec<_>>();
+    println!("Kinds      {}", kinds.join(", "));
+
+    let mut classes = [0; 6];
+    let mut failed = 0;
+    for sample in samples {
+        match sample.status {
+            Some(status) => classes[(status as usize / 100).min(5)] += 1,
+            None => failed += 1,
+        }
+    }
+    let statuses = classes
+        .iter()
+        .enumerate()
+        .filter(|(_, count)| **count > 0)
+        .map(|(class, count)| format!("{}xx {}", class, count))
+        .chain((failed > 0).then(|| format!("failed {}", failed)))
+        .collect::<Vec<_>>();
+    println!("Statuses   {}", statuses.join(", "));
+
+    let mut latencies = samples.iter().map(|s| s.latency).collect::<Vec<_>>();
+    latencies.sort();
+    if let Some(max) = latencies.last() {
+        let percentile = |p: usize| latencies[(latencies.len() * p / 100).min(latencies.len() - 1)];
+        println!(
+            "Latency    p50 {:.2?}, p90 {:.2?}, p99 {:.2?}, max {:.2?}",
+            percentile(50),
+            percentile(90),
+            percentile(99),
+            max
+        );
+    }
+
+    failed == 0
+        && classes
+            .iter()
+            .enumerate()
+            .all(|(class, count)| class == 2 || *count == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mix() {
+        let mix = "echo=2,upload,files=0".parse::<Mix>().unwrap();
+        assert!(!mix.has(Kind::Files));
+        let kinds = (0..6).map(|n| mix.pick(n)).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                Kind::Echo,
+                Kind::Echo,
+                Kind::Upload,
+                Kind::Echo,
+                Kind::Echo,
+                Kind::Upload
+            ]
+        );
+
+        for invalid in ["", "echo=x", "ping=1", "echo=0"] {
+            assert!(invalid.parse::<Mix>().is_err(), "{:?}", invalid);
+        }
+    }
+}
@@ -0,0 +1,175 @@
+//! Diagnostics output: the `tracing` subscriber printing the events of the server
+//! along with the `connection` and `request` spans they happened in.
+
+#[cfg(feature = "logging")]
+use std::fmt;
+use std::str::FromStr;
+#[cfg(feature = "logging")]
+use std::sync::Arc;
+
+#[cfg(feature = "logging")]
+use tracing_subscriber::{reload, EnvFilter};
+
+/// Verbosity used when `RUST_LOG` is not set.
+#[cfg(feature = "logging")]
+const DEFAULT_FILTER: &str = "info";
+
+/// Target of the events about parsing requests.
+pub const PARSER_TARGET: &str = "http_server_starter_rust::parser";
+
+/// Short names usable in directives for the targets of parts of the server, e.g.
+/// `info,parser=trace`.
+#[cfg(feature = "logging")]
+const TARGETS: &[(&str, &str)] = &[
+    ("router", "http_server_starter_rust::router"),
+    ("parser", PARSER_TARGET),
+    ("files", "http_server_starter_rust::static_files"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TraceFormat {
+    /// One line per event, its spans and fields inline.
+    #[default]
+    Compact,
+    /// Multi-line events, easier to read while developing.
+    Pretty,
+    /// One JSON object per event, for log collectors.
+    Json,
+}
+
+impl FromStr for TraceFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "compact" => Ok(TraceFormat::Compact),
+            "pretty" => Ok(TraceFormat::Pretty),
+            "json" => Ok(TraceFormat::Json),
+            _ => Err(format!("Invalid log format: {}", s)),
+        }
+    }
+}
+
+/// Verbosity of the subscriber installed by [`init`], which can be changed while the
+/// server is running.
+#[cfg(feature = "logging")]
+#[derive(Clone)]
+pub struct LogFilter(Arc<dyn Fn(EnvFilter) -> Result<(), String> + Send + Sync>);
+
+#[cfg(feature = "logging")]
+impl LogFilter {
+    /// Applies `directives`, or the `RUST_LOG` ones when `None`.
+    pub fn set(&self, directives: Option<&str>) -> Result<(), String> {
+        (self.0)(env_filter(directives)?)
+    }
+
+    fn new<S: 'static>(handle: reload::Handle<EnvFilter, S>) -> Self {
+        LogFilter(Arc::new(move |filter| {
+            handle.reload(filter).map_err(|e| e.to_string())
+        }))
+    }
+}
+
+#[cfg(feature = "logging")]
+impl fmt::Debug for LogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LogFilter").finish_non_exhaustive()
+    }
+}
+
+/// Installs the global subscriber, writing to stderr so stdout is left to the access
+/// log. Verbosity follows the `directives` when given, the `RUST_LOG` ones otherwise,
+/// e.g. `http_server_starter_rust=debug` or `warn,router=debug,files=debug`.
+/// Colors are only used when `ansi` is set, e.g. not when stderr goes to a file.
+#[cfg(feature = "logging")]
+pub fn init(
+    format: TraceFormat,
+    directives: Option<&str>,
+    ansi: bool,
+) -> Result<LogFilter, String> {
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(env_filter(directives)?)
+        .with_ansi(ansi)
+        .with_writer(std::io::stderr);
+    let result = match format {
+        TraceFormat::Compact => {
+            let builder = builder.compact().with_filter_reloading();
+            let filter = LogFilter::new(builder.reload_handle());
+            builder.try_init().map(|()| filter)
+        }
+        TraceFormat::Pretty => {
+            let builder = builder.pretty().with_filter_reloading();
+            let filter = LogFilter::new(builder.reload_handle());
+            builder.try_init().map(|()| filter)
+        }
+        TraceFormat::Json => {
+            let builder = builder.json().with_filter_reloading();
+            let filter = LogFilter::new(builder.reload_handle());
+            builder.try_init().map(|()| filter)
+        }
+    };
+    result.map_err(|e| e.to_string())
+}
+
+#[cfg(feature = "logging")]
+fn env_filter(directives: Option<&str>) -> Result<EnvFilter, String> {
+    match directives {
+        Some(directives) => EnvFilter::try_new(expand(directives)).map_err(|e| e.to_string()),
+        None => Ok(std::env::var(EnvFilter::DEFAULT_ENV)
+            .ok()
+            .and_then(|directives| EnvFilter::try_new(expand(&directives)).ok())
+            .unwrap_or_else(|| DEFAULT_FILTER.into())),
+    }
+}
+
+/// `directives` with the short target names replaced by the full ones.
+#[cfg(feature = "logging")]
+fn expand(directives: &str) -> String {
+    directives
+        .split(',')
+        .map(|directive| {
+            let (target, level) = match directive.split_once('=') {
+                Some((target, level)) => (target, Some(level)),
+                None => (directive, None),
+            };
+            let full = TARGETS
+                .iter()
+                .find(|(name, _)| *name == target.trim())
+                .map(|(_, full)| *full);
+            match (full, level) {
+                (Some(full), Some(level)) => format!("{}={}", full, level),
+                (Some(full), None) => full.to_string(),
+                (None, _) => directive.to_string(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trace_format() {
+        assert_eq!("compact".parse(), Ok(TraceFormat::Compact));
+        assert_eq!("pretty".parse(), Ok(TraceFormat::Pretty));
+        assert_eq!("json".parse(), Ok(TraceFormat::Json));
+        assert!("JSON".parse::<TraceFormat>().is_err());
+    }
+
+    #[cfg(feature = "logging")]
+    #[test]
+    fn test_expand() {
+        assert_eq!(
+            expand("info,parser=trace,router"),
+            "info,http_server_starter_rust::parser=trace,http_server_starter_rust::router"
+        );
+        assert_eq!(expand("files[path]=debug"), "files[path]=debug");
+        assert_eq!(
+            expand("http_server_starter_rust::router=debug"),
+            "http_server_starter_rust::router=debug"
+        );
+        assert!(env_filter(Some("warn,files=debug")).is_ok());
+    }
+}
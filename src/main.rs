@@ -1,56 +1,258 @@
-use std::io::{Read, Write};
-use std::path::Path;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::time::Duration;
 
-use http::{HttpCode, HttpVersion, Method};
-use request::{Request, RequestBuffer};
-use response::Response;
-use router::{ComparePath, Route, Router};
+use clap::error::ErrorKind;
+use http_server_macros::{get, routes};
+use http_server_starter_rust::{
+    logging, AccessLog, AppError, BasicAuth, BodyLimit, Cache, ComparePath, ETag, FileCache,
+    FromRequest, Headers, Health, Htpasswd, HttpCode, MethodOverride, Multipart, NormalizePath,
+    Request, Response, Rotation, Route, Router, SecurityHeaders, Server, SetRequestId,
+    SetTraceContext, State, StaticFiles, Tunnel,
+};
 
-mod http;
-mod request;
-mod response;
-mod router;
+use cli::Cli;
+use reload::Limits;
+#[cfg(unix)]
+use reload::Reloader;
 
-const MAX_BUFFER_SIZE: usize = 2048;
+mod cli;
+mod config;
+#[cfg(unix)]
+mod daemon;
+mod load_test;
+mod reload;
 
-#[tokio::main]
-async fn main() {
-    // You can use print statements as follows for debugging, they'll be visible when running tests.
-    println!("Logs from your program will appear here!");
+fn main() {
+    let cli = config::parse();
+    if let Some(load_test) = cli.load_test() {
+        match load_test.run() {
+            Ok(passed) => std::process::exit(if passed { 0 } else { 1 }),
+            Err(e) => Cli::command().error(ErrorKind::Io, e).exit(),
+        }
+    }
+    // Colors would end up in the log file
+    let ansi = !cli.detached();
+    let log_filter = match logging::init(cli.log_format, cli.log_level.as_deref(), ansi) {
+        Ok(log_filter) => log_filter,
+        Err(e) => {
+            let message = format!("invalid value for '--log-level': {}", e);
+            Cli::command()
+                .error(ErrorKind::InvalidValue, message)
+                .exit()
+        }
+    };
+
+    // The files directory is checked once at startup and shared through the server state
+    let files = cli.directory.as_ref().map(|dir| {
+        let files = StaticFiles::new(dir)
+            .with_listing(cli.listing)
+            .with_symlinks(cli.symlinks.unwrap_or_default());
+        match cli.file_cache {
+            Some(size) => files.with_cache(FileCache::new(size)),
+            None => files,
+        }
+    });
+    let router = router(&cli, files.as_ref())
+        .unwrap_or_else(|e| Cli::command().error(ErrorKind::Io, e).exit());
+    // Before any thread is started, which forking would lose
+    #[cfg(unix)]
+    let pid_file = detach(&cli).unwrap_or_else(|e| Cli::command().error(ErrorKind::Io, e).exit());
+    let mut health = Health::default();
+    // Uploads fail once the files directory turns read-only, e.g. after a remount
+    if let Some(root) = files.as_ref().map(|files| files.root().to_path_buf()) {
+        health = health.with_check("files", move || files_writable(root.clone()));
+    }
+
+    let mut rotation = Rotation::default();
+    if let Some(bytes) = cli.access_log_max_size {
+        rotation = rotation.with_max_size(bytes);
+    }
+    if let Some(secs) = cli.access_log_max_age {
+        rotation = rotation.with_max_age(Duration::from_secs(secs));
+    }
+    let access_log = match &cli.access_log {
+        Some(path) => match AccessLog::rotating(path, cli.access_log_format, rotation) {
+            Ok(access_log) => access_log,
+            Err(e) => {
+                // Exiting skips the destructor removing it
+                #[cfg(unix)]
+                drop(pid_file);
+                let message = format!("Invalid access log {}: {}", path.display(), e);
+                Cli::command().error(ErrorKind::Io, message).exit()
+            }
+        },
+        None => AccessLog::stdout(cli.access_log_format),
+    };
+    let limits = Limits::default();
+    limits.apply(None, &cli);
+
+    let mut server = Server::new(router)
+        .with_middleware(SetRequestId)
+        .with_middleware(SetTraceContext)
+        .with_request_metrics()
+        .with_middleware(access_log)
+        .with_middleware(SecurityHeaders::default())
+        .with_middleware(NormalizePath::default());
+    if let Some(files) = files.clone() {
+        server = server.with_state(files);
+    }
+    if cli.method_override {
+        server = server.with_middleware(MethodOverride);
+    }
+    server = server.with_middleware(limits.ip_filter.clone());
+    if cli.health {
+        server = server.with_health(health);
+    }
+    #[cfg(feature = "metrics")]
+    if let Some(path) = &cli.metrics {
+        server = server.with_metrics_endpoint(path);
+    }
+    if let Some(addr) = cli.debug_addr {
+        server = server.with_debug_addr(addr);
+    } else if cli.debug {
+        server = server.with_debug();
+    }
+    if let Some(ms) = cli.slow_request_ms {
+        server = server.with_slow_request_threshold(Duration::from_millis(ms));
+    }
+    server = server.with_middleware(limits.timeout.clone());
+    if let Some(size) = cli.max_body_size {
+        server = server.with_max_body_size(size);
+    }
+    if let Some(secs) = cli.header_timeout {
+        server = server.with_header_timeout(Duration::from_secs(secs));
+    }
+    server = server.with_middleware(limits.rate_limit.clone());
+    #[cfg(feature = "http3")]
+    let http3 = match (cli.http3, &cli.tls_cert, &cli.tls_key) {
+        (Some(addr), Some(cert), Some(key)) => {
+            Some(http_server_starter_rust::Http3::new(addr, cert, key))
+        }
+        _ => None,
+    };
+    #[cfg(feature = "http3")]
+    if let Some(http3) = http3.clone() {
+        server = server.with_http3(http3);
+    }
+
+    server = server.with_middleware(ETag);
+    #[cfg(feature = "compression")]
+    {
+        use http_server_starter_rust::{Compression, Decompression};
+
+        if !cli.no_compression {
+            let compression = Compression::default();
+            server = server.with_middleware(match cli.compression_min_size {
+                Some(size) => compression.with_min_size(size),
+                None => compression,
+            });
+        }
+        server = server.with_middleware(Decompression::default());
+    }
+    let server = server.with_nodelay(true).with_runtime(cli.runtime());
 
-    let listener = TcpListener::bind("127.0.0.1:4221").await.unwrap();
+    let addr = cli.addr();
+    #[cfg(unix)]
+    {
+        let reloader = Reloader::new(cli, server.handle(), files, limits, log_filter);
+        #[cfg(feature = "http3")]
+        let reloader = match http3 {
+            Some(http3) => reloader.with_http3(http3),
+            None => reloader,
+        };
+        if let Err(e) = reloader.on_hangup() {
+            tracing::warn!("Failed to listen for SIGHUP, reloads are disabled: {}", e);
+        }
+        if pid_file.is_some() {
+            if let Err(e) = daemon::on_terminate(server.handle()) {
+                tracing::warn!("Failed to listen for SIGTERM: {}", e);
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = (files, log_filter);
+    let served = server.start(addr);
+    #[cfg(unix)]
+    drop(pid_file);
+    if let Err(e) = served {
+        let message = format!("Failed to serve on {}: {}", addr, e);
+        Cli::command().error(ErrorKind::Io, message).exit()
+    }
+}
+
+/// Runs in the background and writes the pid file as `cli` asks. A process taking
+/// over after a restart does neither again.
+#[cfg(unix)]
+fn detach(cli: &Cli) -> Result<Option<daemon::PidFile>, String> {
+    let successor = daemon::is_successor();
+    // Written first so that a server already running is reported on the terminal
+    let mut pid_file = match &cli.pid_file {
+        Some(path) => Some(
+            daemon::PidFile::create(path, successor)
+                .map_err(|e| format!("Invalid pid file {}: {}", path.display(), e))?,
+        ),
+        None => None,
+    };
+    if cli.daemon && !successor {
+        daemon::daemonize(cli.log_file.as_deref())
+            .map_err(|e| format!("Failed to run in the background: {}", e))?;
+        if let Some(pid_file) = &mut pid_file {
+            pid_file.refresh().map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(pid_file)
+}
+
+/// Routes served for the settings of `cli`, `files` being the files directory.
+fn router(cli: &Cli, files: Option<&StaticFiles>) -> Result<Router, String> {
     let mut router = Router::default();
+    router.add_routes(routes![echo_handler, ok_handler, user_agent_handler]);
 
-    router.add_route(Route::get("/echo", echo_handler, ComparePath::Prefix));
-    router.add_route(Route::get("/", ok_handler, ComparePath::Exact));
-    router.add_route(Route::get(
-        "/user-agent",
-        user_agent_handler,
-        ComparePath::Exact,
-    ));
-    router.add_route(Route::get("/files", get_file_handler, ComparePath::Prefix));
-    router.add_route(Route::post(
-        "/files",
-        post_file_handler,
-        ComparePath::Prefix,
-    ));
-
-    while let Ok((mut stream, _)) = listener.accept().await {
-        let router = router.clone();
-        tokio::spawn(async move {
-            let req = read_stream(&mut stream).await;
-            let res = router.route(req);
-            write_stream(&mut stream, &res.into_bytes()).await;
-        });
+    if let Some(files) = files {
+        let mut routes = file_routes(files);
+        if let Some(path) = &cli.htpasswd {
+            let users = Htpasswd::load(path)
+                .map_err(|e| format!("Invalid htpasswd file {}: {}", path.display(), e))?;
+            let auth = BasicAuth::new("files", users);
+            routes = routes.map(|route| route.with_middleware(auth.clone()));
+        }
+        if let Some(max) = cli.max_upload {
+            let limit = BodyLimit::new(max);
+            routes = routes.map(|route| route.with_middleware(limit));
+        }
+        // Uploads drop the cached copy of the file, so both routes share the cache
+        if let Some(secs) = cli.cache_ttl {
+            let cache = Cache::new(Duration::from_secs(secs));
+            routes = routes.map(|route| route.with_middleware(cache.clone()));
+        }
+        router.add_routes(routes);
     }
+    for mount in &cli.mounts {
+        router.add_route(StaticFiles::new(&mount.dir).mount(&mount.prefix));
+    }
+    // Forward proxy for HTTPS traffic
+    if cli.proxy {
+        router.add_route(Tunnel::default().route());
+    }
+    Ok(router)
+}
+
+async fn files_writable(root: PathBuf) -> Result<(), String> {
+    let metadata = tokio::fs::metadata(&root)
+        .await
+        .map_err(|e| e.to_string())?;
+    if metadata.permissions().readonly() {
+        return Err(String::from("files directory is read-only"));
+    }
+    Ok(())
 }
 
+#[get("/")]
 fn ok_handler(_req: Request) -> Response {
     Response::from(HttpCode::Ok)
 }
 
+#[get("/echo", prefix)]
 fn echo_handler(req: Request) -> Response {
     let response_content = req.path().strip_prefix("/echo/").unwrap_or_default();
 
@@ -62,13 +264,12 @@ fn echo_handler(req: Request) -> Response {
     response
 }
 
-fn user_agent_handler(req: Request) -> Response {
-    let default_user_agent = "No User-Agent".to_string();
-    let user_agent = req
-        .headers()
+#[get("/user-agent")]
+fn user_agent_handler(headers: Headers) -> Response {
+    let user_agent = headers
         .get("User-Agent")
-        .unwrap_or(&default_user_agent)
-        .clone();
+        .unwrap_or("No User-Agent")
+        .to_string();
 
     let mut response = Response::from(HttpCode::Ok);
     response.header("Content-Type", "text/plain");
@@ -78,70 +279,125 @@ fn user_agent_handler(req: Request) -> Response {
     response
 }
 
-fn get_file_handler(req: Request) -> Response {
-    let dir = std::env::args().nth(2).unwrap();
-    let path = req.path().strip_prefix("/files/").unwrap_or_default();
-    let file_path = Path::new(&dir);
-    let file_path = file_path.join(path);
-
-    if file_path.metadata().is_err() {
-        Response::from(HttpCode::NotFound)
-    } else {
-        let mut file = std::fs::File::open(&file_path).unwrap();
-        let mut content = Vec::new();
-        file.read_to_end(&mut content).unwrap();
-
-        // Respond with application/octet-stream
-        let mut response = Response::from(HttpCode::Ok);
-        response.header("Content-Type", "application/octet-stream");
-        response.header(
-            "Content-Length",
-            file_path.metadata().unwrap().len().to_string(),
-        );
-        *response.content_mut() = content;
-        response
-    }
+/// Routes reading, uploading, replacing and deleting the files under `/files`. The
+/// write handlers find the [`StaticFiles`] in the server state.
+fn file_routes(files: &StaticFiles) -> [Route; 4] {
+    [
+        files.mount("/files"),
+        Route::post("/files", post_file_handler, ComparePath::Prefix),
+        Route::put("/files/", put_file_handler, ComparePath::Prefix),
+        Route::delete("/files/", delete_file_handler, ComparePath::Prefix),
+    ]
 }
 
-fn post_file_handler(req: Request) -> Response {
-    let dir = std::env::args().nth(2).unwrap();
+/// Files registered in the server state along with the path of the requested file.
+fn requested_file(req: &Request) -> Result<(State<StaticFiles>, &str), AppError> {
+    let files = req
+        .state::<StaticFiles>()
+        .ok_or_else(|| AppError::Internal("no files directory in the server state".into()))?;
     let path = req.path().strip_prefix("/files/").unwrap_or_default();
-    let file_path = Path::new(&dir);
-    let file_path = file_path.join(path);
-
-    let mut file = std::fs::File::create(file_path).unwrap();
+    Ok((files, path))
+}
 
-    if file.write_all(req.body()).is_err() {
-        Response::from(HttpCode::InternalServerError)
-    } else {
-        Response::from(HttpCode::Created)
+async fn post_file_handler(req: Request) -> Result<Response, AppError> {
+    if req.header("Content-Type").is_some_and(|ty| {
+        ty.trim_start()
+            .to_ascii_lowercase()
+            .starts_with("multipart/")
+    }) {
+        return upload_files_handler(req).await;
     }
+
+    let (files, path) = requested_file(&req)?;
+    tokio::fs::write(files.resolve_new(path).await?, req.body()).await?;
+
+    Ok(Response::from(HttpCode::Created))
 }
 
-async fn read_stream(stream: &mut TcpStream) -> Request {
-    let mut buf = [0; MAX_BUFFER_SIZE];
+/// Stores every file part of a `multipart/form-data` body in the requested directory
+/// and answers with a JSON summary of the stored files.
+async fn upload_files_handler(mut req: Request) -> Result<Response, AppError> {
+    let Multipart(parts) = Multipart::from_request(&mut req)?;
+    let (files, dir) = requested_file(&req)?;
+    let dir = dir.trim_end_matches('/');
+    if !tokio::fs::metadata(files.resolve(dir).await?)
+        .await?
+        .is_dir()
+    {
+        return Err(AppError::BadRequest("Uploads target a directory".into()));
+    }
 
-    match stream.read(&mut buf).await {
-        Ok(_) => Request::parse::<std::array::IntoIter<u8, 2048>>(&mut RequestBuffer::from(
-            buf.into_iter(),
-        )),
-        Err(e) => {
-            panic!("Failed to receive data: {}", e);
-        }
+    let mut stored = Vec::new();
+    for part in &parts {
+        let Some(name) = part.safe_filename() else {
+            continue;
+        };
+        let path = files.resolve_new(&format!("{}/{}", dir, name)).await?;
+        tokio::fs::write(path, &part.body).await?;
+        stored.push(format!(
+            "{{\"field\":{},\"filename\":{},\"size\":{}}}",
+            json_string(part.name.as_deref().unwrap_or_default()),
+            json_string(&name),
+            part.body.len()
+        ));
     }
+    if stored.is_empty() {
+        return Err(AppError::BadRequest("No file in the multipart body".into()));
+    }
+
+    let mut res = Response::from(format!("{{\"files\":[{}]}}", stored.join(",")));
+    res.set_code(HttpCode::Created);
+    res.header("Content-Type", "application/json");
+    Ok(res)
 }
 
-async fn write_stream(stream: &mut TcpStream, data: &[u8]) {
-    match stream.write(data).await {
-        Ok(_) => {}
-        Err(e) => {
-            println!("Failed to send data: {}", e);
+/// JSON string literal of `s`.
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
         }
     }
+    out.push('"');
+    out
+}
+
+/// Creates or replaces a file, answering 201 or 204 respectively.
+async fn put_file_handler(req: Request) -> Result<Response, AppError> {
+    let (files, path) = requested_file(&req)?;
+    let path = files.resolve_new(path).await?;
+    let existed = match tokio::fs::metadata(&path).await {
+        Ok(metadata) if metadata.is_dir() => return Err(AppError::Forbidden),
+        Ok(_) => true,
+        Err(_) => false,
+    };
+    tokio::fs::write(path, req.body()).await?;
+
+    Ok(Response::from(match existed {
+        true => HttpCode::NoContent,
+        false => HttpCode::Created,
+    }))
+}
+
+async fn delete_file_handler(req: Request) -> Result<Response, AppError> {
+    let (files, path) = requested_file(&req)?;
+    let path = files.resolve(path).await?;
+    if tokio::fs::metadata(&path).await?.is_dir() {
+        return Err(AppError::Forbidden);
+    }
+    tokio::fs::remove_file(path).await?;
+
+    Ok(Response::from(HttpCode::NoContent))
 }
 
 #[cfg(test)]
 mod tests {
+    use http_server_starter_rust::{RequestBuffer, StateMap};
+
     use super::*;
 
     fn assert_send<T: Send>() {}
@@ -152,4 +408,77 @@ mod tests {
         assert_send::<Router>();
         assert_sync::<Router>();
     }
+
+    #[tokio::test]
+    async fn test_route_attributes() {
+        let mut router = Router::default();
+        router.add_routes(routes![echo_handler, ok_handler]);
+
+        let req = Request::parse(&mut RequestBuffer::from(
+            "GET /echo/hello HTTP/1.1\r\n\r\n".bytes(),
+        ))
+        .unwrap();
+        let res = router.route(req).await.into_bytes();
+        assert!(res.ends_with(b"\r\n\r\nhello"));
+
+        let req =
+            Request::parse(&mut RequestBuffer::from("POST / HTTP/1.1\r\n\r\n".bytes())).unwrap();
+        let res = router.route(req).await.into_bytes();
+        assert!(res.starts_with(b"HTTP/1.1 404 "));
+    }
+
+    #[tokio::test]
+    async fn test_file_routes() {
+        let dir = std::env::temp_dir().join(format!("file-routes-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        let files = StaticFiles::new(&dir);
+        let mut router = Router::default();
+        router.add_routes(file_routes(&files));
+        let mut states = StateMap::default();
+        states.insert(files);
+        let send = |req: &str| {
+            let mut req = Request::parse(&mut RequestBuffer::from(req.bytes())).unwrap();
+            req.set_states(states.clone());
+            router.route(req)
+        };
+
+        let put = "PUT /files/a.txt HTTP/1.1\r\nContent-Length: 3\r\n\r\none";
+        let res = send(put).await.into_bytes();
+        assert!(res.starts_with(b"HTTP/1.1 201 "));
+        let put = "PUT /files/a.txt HTTP/1.1\r\nContent-Length: 3\r\n\r\ntwo";
+        let res = send(put).await.into_bytes();
+        assert!(res.starts_with(b"HTTP/1.1 204 "));
+        assert_eq!(std::fs::read(dir.join("a.txt")).unwrap(), b"two");
+        let res = send("PUT /files/sub HTTP/1.1\r\n\r\n").await.into_bytes();
+        assert!(res.starts_with(b"HTTP/1.1 403 "));
+
+        let res = send("DELETE /files/a.txt HTTP/1.1\r\n\r\n")
+            .await
+            .into_bytes();
+        assert!(res.starts_with(b"HTTP/1.1 204 "));
+        assert!(!dir.join("a.txt").exists());
+        let res = send("DELETE /files/a.txt HTTP/1.1\r\n\r\n")
+            .await
+            .into_bytes();
+        assert!(res.starts_with(b"HTTP/1.1 404 "));
+        let res = send("DELETE /files/../x HTTP/1.1\r\n\r\n")
+            .await
+            .into_bytes();
+        assert!(res.starts_with(b"HTTP/1.1 403 "));
+
+        let body = "--b\r\nContent-Disposition: form-data; name=doc; filename=\"../x \\\"1\\\".txt\"\r\n\r\n\
+                    abc\r\n--b\r\nContent-Disposition: form-data; name=note\r\n\r\nhi\r\n--b--";
+        let upload = format!(
+            "POST /files/sub HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=b\r\n\
+             Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let res = String::from_utf8(send(&upload).await.into_bytes()).unwrap();
+        assert!(res.starts_with("HTTP/1.1 201 "));
+        assert!(res.ends_with(r#"{"files":[{"field":"doc","filename":"x__1_.txt","size":3}]}"#));
+        assert_eq!(std::fs::read(dir.join("sub/x__1_.txt")).unwrap(), b"abc");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }
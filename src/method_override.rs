@@ -0,0 +1,95 @@
+//! Tunnelling of PUT, PATCH and DELETE requests through POST, for HTML forms and clients
+//! restricted to GET and POST.
+
+use super::error::AppError;
+use super::middleware::{Middleware, Next};
+use super::router::BoxFuture;
+use super::{Method, Request, Response};
+
+const HEADER: &str = "X-HTTP-Method-Override";
+const FORM_FIELD: &str = "_method";
+
+/// Middleware rewriting the method of POST requests carrying an
+/// `X-HTTP-Method-Override` header or a `_method` form field, the header winning
+/// when both are present. Only PUT, PATCH and DELETE may be requested, anything else is
+/// answered with a 400.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MethodOverride;
+
+impl Middleware for MethodOverride {
+    fn handle(&self, mut req: Request, next: Next) -> BoxFuture<Response> {
+        if *req.method() != Method::Post {
+            return next.run(req);
+        }
+
+        let method = req.remove_header(HEADER).map(String::from).or_else(|| {
+            req.form_pairs()
+                .into_iter()
+                .find_map(|(key, value)| (key == FORM_FIELD).then_some(value))
+        });
+        let Some(method) = method else {
+            return next.run(req);
+        };
+
+        match overridden(&method) {
+            Some(method) => req.set_method(method),
+            None => {
+                let err = AppError::BadRequest(format!("Invalid method override: {}", method));
+                return Box::pin(async { Response::from(err) });
+            }
+        }
+        next.run(req)
+    }
+
+    /// Only the header is known before the body is received.
+    fn rewrite_head(&self, req: &mut Request) {
+        if *req.method() != Method::Post {
+            return;
+        }
+        if let Some(method) = req.header(HEADER).and_then(overridden) {
+            req.set_method(method);
+        }
+    }
+}
+
+fn overridden(method: &str) -> Option<Method> {
+    match method.to_ascii_uppercase().as_str() {
+        "PUT" => Some(Method::Put),
+        "PATCH" => Some(Method::Patch),
+        "DELETE" => Some(Method::Delete),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware;
+
+    async fn send(req: &str) -> Response {
+        middleware::send(MethodOverride, req, |req: Request| async move {
+            Response::from(req.method().as_str())
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_method_override() {
+        let res = send("POST / HTTP/1.1\r\nX-HTTP-Method-Override: put\r\n\r\n").await;
+        assert_eq!(res.content(), b"PUT");
+        let res = send("POST / HTTP/1.1\r\nX-HTTP-Method-Override: PATCH\r\n\r\n").await;
+        assert_eq!(res.content(), b"PATCH");
+
+        let form = "POST / HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\n\
+                    Content-Length: 14\r\n\r\n_method=DELETE";
+        assert_eq!(send(form).await.content(), b"DELETE");
+
+        let res = send("GET / HTTP/1.1\r\nX-HTTP-Method-Override: DELETE\r\n\r\n").await;
+        assert_eq!(res.content(), b"GET");
+        let res = send("POST / HTTP/1.1\r\n\r\n").await;
+        assert_eq!(res.content(), b"POST");
+
+        let res = send("POST / HTTP/1.1\r\nX-HTTP-Method-Override: GET\r\n\r\n").await;
+        assert!(res.into_bytes().starts_with(b"HTTP/1.1 400 "));
+    }
+}
@@ -0,0 +1,392 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use super::middleware::{Middleware, Next};
+use super::router::{BoxFuture, MatchedPath};
+use super::{Method, Request, Response};
+
+/// Upper bounds of the latency histogram buckets, in seconds. Slower requests fall in
+/// an implicit last bucket.
+pub const LATENCY_BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0,
+];
+
+/// Number of labels past which requests are recorded under [`OVERFLOW_LABEL`], so
+/// unbounded labels cannot exhaust the memory.
+const MAX_LABELS: usize = 1000;
+pub const OVERFLOW_LABEL: &str = "other";
+/// Route label of requests no route matched.
+pub const UNMATCHED_LABEL: &str = "unmatched";
+
+type LabelExtractor = Arc<dyn Fn(&Request) -> String + Send + Sync>;
+
+/// Counters shared by every connection of a server.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    header_timeouts: AtomicU64,
+    idle_timeouts: AtomicU64,
+    rejected_connections: AtomicU64,
+    parse_errors: AtomicU64,
+    client_disconnects: AtomicU64,
+    /// Responses written by the connections by status class, from 1xx to 5xx.
+    responses: [AtomicU64; 5],
+    in_flight: AtomicU64,
+    open_connections: AtomicU64,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    routes: RwLock<HashMap<String, Arc<RouteMetrics>>>,
+}
+
+/// Requests recorded under one label.
+#[derive(Debug, Default)]
+pub struct RouteMetrics {
+    requests: AtomicU64,
+    /// Responses by status class, from 1xx to 5xx.
+    statuses: [AtomicU64; 5],
+    latency: Histogram,
+}
+
+/// Distribution of durations over [`LATENCY_BUCKETS`].
+#[derive(Debug)]
+pub struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Metrics {
+    pub fn record_header_timeout(&self) {
+        self.header_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_idle_timeout(&self) {
+        self.idle_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_rejected_connection(&self) {
+        self.rejected_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_parse_error(&self) {
+        self.parse_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_client_disconnect(&self) {
+        self.client_disconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_response(&self, status: u16) {
+        if let Some(class) = self
+            .responses
+            .get(usize::from(status / 100).wrapping_sub(1))
+        {
+            class.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_received(&self, bytes: usize) {
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a connection as open until the returned guard is dropped.
+    pub(crate) fn open_connection(&self) -> Gauge<'_> {
+        Gauge::increment(&self.open_connections)
+    }
+
+    /// Requests whose head was not received before the header timeout.
+    pub fn header_timeouts(&self) -> u64 {
+        self.header_timeouts.load(Ordering::Relaxed)
+    }
+
+    /// Keep-alive connections closed after staying idle past the keep-alive timeout.
+    pub fn idle_timeouts(&self) -> u64 {
+        self.idle_timeouts.load(Ordering::Relaxed)
+    }
+
+    /// Connections turned away for exceeding the per address cap.
+    pub fn rejected_connections(&self) -> u64 {
+        self.rejected_connections.load(Ordering::Relaxed)
+    }
+
+    /// Requests rejected as malformed, or for a head or body over the size limits,
+    /// before reaching a handler.
+    pub fn parse_errors(&self) -> u64 {
+        self.parse_errors.load(Ordering::Relaxed)
+    }
+
+    /// Connections the client closed or reset in the middle of a request or response.
+    pub fn client_disconnects(&self) -> u64 {
+        self.client_disconnects.load(Ordering::Relaxed)
+    }
+
+    /// Responses of a status class written by HTTP/1 connections, `5` counting the
+    /// 5xx ones. Unlike route metrics, this includes the requests rejected before
+    /// being routed and interim responses.
+    pub fn responses(&self, class: u16) -> u64 {
+        usize::from(class)
+            .checked_sub(1)
+            .and_then(|i| self.responses.get(i))
+            .map_or(0, |count| count.load(Ordering::Relaxed))
+    }
+
+    /// Requests currently being handled.
+    pub fn in_flight(&self) -> u64 {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// HTTP/1 connections currently open, upgraded ones included.
+    pub fn open_connections(&self) -> u64 {
+        self.open_connections.load(Ordering::Relaxed)
+    }
+
+    /// Bytes read from the clients of HTTP/1 connections, before upgrades.
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received.load(Ordering::Relaxed)
+    }
+
+    /// Bytes written to the clients of HTTP/1 connections, before upgrades.
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    /// Metrics of the requests recorded under `label`, created on first use.
+    pub fn route(&self, label: &str) -> Arc<RouteMetrics> {
+        if let Some(route) = self.routes.read().unwrap().get(label) {
+            return route.clone();
+        }
+
+        let mut routes = self.routes.write().unwrap();
+        let label = if routes.len() < MAX_LABELS || routes.contains_key(label) {
+            label
+        } else {
+            OVERFLOW_LABEL
+        };
+        routes.entry(label.to_string()).or_default().clone()
+    }
+
+    /// Every label with its metrics, sorted by label.
+    pub fn routes(&self) -> Vec<(String, Arc<RouteMetrics>)> {
+        let mut routes = self
+            .routes
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(label, route)| (label.clone(), route.clone()))
+            .collect::<Vec<_>>();
+        routes.sort_by(|a, b| a.0.cmp(&b.0));
+        routes
+    }
+}
+
+impl RouteMetrics {
+    pub fn record(&self, status: u16, latency: Duration) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if let Some(class) = self.statuses.get(usize::from(status / 100).wrapping_sub(1)) {
+            class.fetch_add(1, Ordering::Relaxed);
+        }
+        self.latency.observe(latency);
+    }
+
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    /// Responses of a status class, `2` counting the 2xx ones.
+    pub fn status_class(&self, class: u16) -> u64 {
+        usize::from(class)
+            .checked_sub(1)
+            .and_then(|i| self.statuses.get(i))
+            .map_or(0, |count| count.load(Ordering::Relaxed))
+    }
+
+    pub fn latency(&self) -> &Histogram {
+        &self.latency
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            buckets: Default::default(),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&bound| secs <= bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().map(|b| b.load(Ordering::Relaxed)).sum()
+    }
+
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum_micros.load(Ordering::Relaxed))
+    }
+
+    /// Cumulative count of observations at or below each bucket bound, the last
+    /// bound being infinite.
+    pub fn buckets(&self) -> Vec<(f64, u64)> {
+        let bounds = LATENCY_BUCKETS.iter().copied().chain([f64::INFINITY]);
+        bounds
+            .zip(&self.buckets)
+            .scan(0, |total, (bound, count)| {
+                *total += count.load(Ordering::Relaxed);
+                Some((bound, *total))
+            })
+            .collect()
+    }
+}
+
+/// Increments a gauge for as long as it lives, decrementing it even if the future
+/// holding it is dropped before completing.
+pub(crate) struct Gauge<'a>(&'a AtomicU64);
+
+impl<'a> Gauge<'a> {
+    fn increment(gauge: &'a AtomicU64) -> Self {
+        gauge.fetch_add(1, Ordering::Relaxed);
+        Gauge(gauge)
+    }
+}
+
+impl Drop for Gauge<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Middleware recording requests in a [`Metrics`] registry, labelled by method and
+/// matched route pattern unless told otherwise, e.g. `GET /echo/{msg}`. It measures
+/// the time taken by the middlewares added after it, so it should come early.
+#[derive(Clone)]
+pub struct RequestMetrics {
+    metrics: Arc<Metrics>,
+    label: Option<LabelExtractor>,
+}
+
+impl RequestMetrics {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        RequestMetrics {
+            metrics,
+            label: None,
+        }
+    }
+
+    /// Records requests under the label returned by `label`, which should only take
+    /// a handful of values.
+    pub fn with_label<F>(mut self, label: F) -> Self
+    where
+        F: Fn(&Request) -> String + Send + Sync + 'static,
+    {
+        self.label = Some(Arc::new(label));
+        self
+    }
+}
+
+/// Label of a request answered with `res`, from the route the router matched.
+fn route_label(method: &Method, res: &Response) -> String {
+    let route = res
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or(UNMATCHED_LABEL, |MatchedPath(path)| path);
+    format!("{} {}", method.as_str(), route)
+}
+
+impl Middleware for RequestMetrics {
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<Response> {
+        let label = self.label.as_ref().map(|label| label(&req));
+        let method = req.method().clone();
+        let metrics = self.metrics.clone();
+
+        Box::pin(async move {
+            let _in_flight = Gauge::increment(&metrics.in_flight);
+            let start = Instant::now();
+
+            let res = next.run(req).await;
+            let label = label.unwrap_or_else(|| route_label(&method, &res));
+            metrics
+                .route(&label)
+                .record(res.code().as_u16(), start.elapsed());
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{middleware, ComparePath, HttpCode, Route, Router};
+
+    #[test]
+    fn test_histogram() {
+        let histogram = Histogram::default();
+        histogram.observe(Duration::from_micros(500));
+        histogram.observe(Duration::from_millis(30));
+        histogram.observe(Duration::from_secs(20));
+
+        assert_eq!(histogram.count(), 3);
+        assert_eq!(histogram.sum(), Duration::from_micros(20_030_500));
+        let buckets = histogram.buckets();
+        assert_eq!(buckets[0], (0.001, 1));
+        assert_eq!(buckets[4], (0.025, 1));
+        assert_eq!(buckets[5], (0.05, 2));
+        assert_eq!(buckets[11], (10.0, 2));
+        assert_eq!(buckets[12], (f64::INFINITY, 3));
+    }
+
+    #[tokio::test]
+    async fn test_request_metrics() {
+        let metrics = Arc::new(Metrics::default());
+        let in_flight = metrics.clone();
+        let mut router = Router::default();
+        router.add_route(Route::get(
+            "/echo/{msg}",
+            move |req: Request| {
+                assert_eq!(in_flight.in_flight(), 1);
+                match req.param("msg") {
+                    Some("missing") => Response::from(HttpCode::NotFound),
+                    _ => Response::from(HttpCode::Ok),
+                }
+            },
+            ComparePath::Exact,
+        ));
+        let router = Arc::new(router);
+        let request_metrics = RequestMetrics::new(metrics.clone());
+
+        for path in ["/echo/a", "/echo/missing", "/echo/b", "/nowhere"] {
+            let req = format!("GET {} HTTP/1.1\r\n\r\n", path);
+            let router = router.clone();
+            middleware::send(request_metrics.clone(), &req, move |req| {
+                let router = router.clone();
+                async move { router.route(req).await }
+            })
+            .await;
+        }
+
+        assert_eq!(metrics.in_flight(), 0);
+        let routes = metrics.routes();
+        assert_eq!(routes.len(), 2);
+        let (label, route) = &routes[0];
+        assert_eq!(label, "GET /echo/{msg}");
+        assert_eq!(route.requests(), 3);
+        assert_eq!(route.status_class(2), 2);
+        assert_eq!(route.status_class(4), 1);
+        assert_eq!(route.latency().count(), 3);
+        assert_eq!(routes[1].0, "GET unmatched");
+    }
+}
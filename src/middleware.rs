@@ -0,0 +1,251 @@
+use std::future::Future;
+use std::sync::{Arc, RwLock};
+
+use super::router::BoxFuture;
+use super::{Request, Response};
+
+/// Middlewares applied in order, the first one being the outermost.
+pub type Middlewares = Arc<[Arc<dyn Middleware>]>;
+
+type Endpoint = Arc<dyn Fn(Request) -> BoxFuture<Response> + Send + Sync>;
+
+/// Code running around request handling. A middleware may change the request
+/// before passing it to `next`, change the response it gets back, or answer on its
+/// own without calling `next` at all.
+///
+/// Async functions and closures taking the request and [`Next`] are middlewares.
+pub trait Middleware: Send + Sync + 'static {
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<Response>;
+
+    /// Largest request body accepted, letting connections turn down bigger ones
+    /// before reading them.
+    fn body_limit(&self) -> Option<usize> {
+        None
+    }
+
+    /// Changes the middleware makes to request heads on their way to the router,
+    /// applied by connections to find the route of requests whose body is still to
+    /// be received.
+    fn rewrite_head(&self, _req: &mut Request) {}
+}
+
+impl<F, Fut> Middleware for F
+where
+    F: Fn(Request, Next) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Response> + Send + 'static,
+{
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<Response> {
+        Box::pin(self(req, next))
+    }
+}
+
+/// Remainder of the chain: the following middlewares, then the endpoint.
+pub struct Next {
+    middlewares: Middlewares,
+    index: usize,
+    endpoint: Endpoint,
+}
+
+impl Next {
+    pub fn new<E>(middlewares: Middlewares, endpoint: E) -> Self
+    where
+        E: Fn(Request) -> BoxFuture<Response> + Send + Sync + 'static,
+    {
+        Next {
+            middlewares,
+            index: 0,
+            endpoint: Arc::new(endpoint),
+        }
+    }
+
+    pub fn run(self, req: Request) -> BoxFuture<Response> {
+        match self.middlewares.get(self.index) {
+            Some(middleware) => {
+                let middleware = middleware.clone();
+                let next = Next {
+                    index: self.index + 1,
+                    ..self
+                };
+                middleware.handle(req, next)
+            }
+            None => (self.endpoint)(req),
+        }
+    }
+}
+
+/// Middleware which can be replaced while the server is running, e.g. to apply new
+/// limits on a configuration reload. Requests already in the chain keep the middleware
+/// they started with. Until one is stored, requests go straight through.
+#[derive(Clone, Default)]
+pub struct SharedMiddleware {
+    inner: Arc<RwLock<Option<Arc<dyn Middleware>>>>,
+}
+
+impl SharedMiddleware {
+    pub fn store<M: Middleware>(&self, middleware: M) {
+        *self.inner.write().unwrap() = Some(Arc::new(middleware));
+    }
+
+    /// Lets requests go straight through again.
+    pub fn clear(&self) {
+        *self.inner.write().unwrap() = None;
+    }
+
+    fn load(&self) -> Option<Arc<dyn Middleware>> {
+        self.inner.read().unwrap().clone()
+    }
+}
+
+impl Middleware for SharedMiddleware {
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<Response> {
+        match self.load() {
+            Some(middleware) => middleware.handle(req, next),
+            None => next.run(req),
+        }
+    }
+
+    fn body_limit(&self) -> Option<usize> {
+        self.load()?.body_limit()
+    }
+
+    fn rewrite_head(&self, req: &mut Request) {
+        if let Some(middleware) = self.load() {
+            middleware.rewrite_head(req);
+        }
+    }
+}
+
+/// Smallest body limit of `middlewares`.
+pub fn body_limit(middlewares: &Middlewares) -> Option<usize> {
+    middlewares.iter().filter_map(|m| m.body_limit()).min()
+}
+
+/// Appends `middleware` to `middlewares`, making it the innermost one.
+pub fn push<M>(middlewares: &Middlewares, middleware: M) -> Middlewares
+where
+    M: Middleware,
+{
+    let mut middlewares = middlewares.to_vec();
+    middlewares.push(Arc::new(middleware));
+    middlewares.into()
+}
+
+/// Response of `middleware` to the raw request `req`, `handler` standing for the route.
+#[cfg(test)]
+pub(crate) async fn send<M, H, Fut>(middleware: M, req: &str, handler: H) -> Response
+where
+    M: Middleware,
+    H: Fn(Request) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Response> + Send + 'static,
+{
+    let req = Request::parse(&mut crate::RequestBuffer::from(req.bytes())).unwrap();
+    send_request(middleware, req, handler).await
+}
+
+/// Response of `middleware` to `req`, for requests the raw ones of [`send`] cannot
+/// describe.
+#[cfg(test)]
+pub(crate) async fn send_request<M, H, Fut>(middleware: M, req: Request, handler: H) -> Response
+where
+    M: Middleware,
+    H: Fn(Request) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Response> + Send + 'static,
+{
+    let middlewares = Middlewares::from([Arc::new(middleware) as Arc<dyn Middleware>]);
+    Next::new(middlewares, move |req| Box::pin(handler(req)))
+        .run(req)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ComparePath, HttpCode, RequestBuffer, Route, Router};
+
+    async fn outer(req: Request, next: Next) -> Response {
+        let mut res = next.run(req).await;
+        res.content_mut().extend_from_slice(b" outer");
+        res
+    }
+
+    async fn inner(req: Request, next: Next) -> Response {
+        let mut res = next.run(req).await;
+        res.content_mut().extend_from_slice(b" inner");
+        res
+    }
+
+    async fn deny(req: Request, next: Next) -> Response {
+        if req.header("Authorization").is_none() {
+            return Response::from(HttpCode::Forbidden);
+        }
+        next.run(req).await
+    }
+
+    fn get(req: &str) -> Request {
+        Request::parse(&mut RequestBuffer::from(req.bytes())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_middleware_chain() {
+        let mut router = Router::default();
+        router.add_route(
+            Route::get(
+                "/",
+                |_: Request| Response::from("handler"),
+                ComparePath::Exact,
+            )
+            .with_middleware(inner)
+            .with_middleware(deny),
+        );
+        let router = Arc::new(router);
+
+        let middlewares = push(&Middlewares::from([]), outer);
+        let run = |req| {
+            let router = router.clone();
+            Next::new(middlewares.clone(), move |req| {
+                let router = router.clone();
+                Box::pin(async move { router.route(req).await })
+            })
+            .run(req)
+        };
+
+        let res = run(get("GET / HTTP/1.1\r\nAuthorization: yes\r\n\r\n")).await;
+        assert!(res.into_bytes().ends_with(b"\r\n\r\nhandler inner outer"));
+
+        let res = run(get("GET / HTTP/1.1\r\n\r\n")).await.into_bytes();
+        assert!(res.starts_with(b"HTTP/1.1 403 Forbidden\r\n"));
+        assert!(res.ends_with(b"\r\n\r\n inner outer"));
+    }
+
+    #[tokio::test]
+    async fn test_shared_middleware() {
+        let shared = SharedMiddleware::default();
+        let middlewares = push(&Middlewares::from([]), shared.clone());
+        let run = |req| {
+            Next::new(middlewares.clone(), |_| {
+                Box::pin(async { Response::from(HttpCode::Ok) })
+            })
+            .run(req)
+        };
+
+        assert_eq!(
+            run(get("GET / HTTP/1.1\r\n\r\n")).await.code(),
+            HttpCode::Ok
+        );
+        assert_eq!(body_limit(&middlewares), None);
+
+        shared.store(deny);
+        assert_eq!(
+            run(get("GET / HTTP/1.1\r\n\r\n")).await.code(),
+            HttpCode::Forbidden
+        );
+        shared.store(crate::BodyLimit::new(10));
+        assert_eq!(body_limit(&middlewares), Some(10));
+
+        shared.clear();
+        assert_eq!(
+            run(get("GET / HTTP/1.1\r\n\r\n")).await.code(),
+            HttpCode::Ok
+        );
+    }
+}
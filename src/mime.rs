@@ -0,0 +1,65 @@
+//! Media types of files, guessed from their extension.
+
+use std::path::Path;
+
+/// Type of files with an unknown extension.
+pub const OCTET_STREAM: &str = "application/octet-stream";
+
+/// Media type of the file at `path`, text types being declared as UTF-8.
+pub fn from_path(path: &Path) -> &'static str {
+    let Some(ext) = path.extension().and_then(|ext| ext.to_str()) else {
+        return OCTET_STREAM;
+    };
+
+    match ext.to_ascii_lowercase().as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "txt" | "log" => "text/plain; charset=utf-8",
+        "md" => "text/markdown; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "xml" => "application/xml",
+        "json" | "map" => "application/json",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "tar" => "application/x-tar",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "svg" => "image/svg+xml",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        "wav" => "audio/wav",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        _ => OCTET_STREAM,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_path() {
+        assert_eq!(
+            from_path(Path::new("a/index.HTML")),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(from_path(Path::new("style.css")), "text/css; charset=utf-8");
+        assert_eq!(from_path(Path::new("logo.png")), "image/png");
+        assert_eq!(from_path(Path::new("data.json")), "application/json");
+        assert_eq!(from_path(Path::new("archive.tar.gz")), "application/gzip");
+        assert_eq!(from_path(Path::new("README")), OCTET_STREAM);
+        assert_eq!(from_path(Path::new("a.unknown")), OCTET_STREAM);
+    }
+}
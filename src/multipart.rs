@@ -0,0 +1,247 @@
+//! Parsing of `multipart/form-data` request bodies.
+
+use super::extract::FromRequest;
+use super::{AppError, Request};
+
+/// One part of a multipart body.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Part {
+    pub headers: Vec<(String, String)>,
+    /// Form field name from the `Content-Disposition` header.
+    pub name: Option<String>,
+    /// File name given by the client, as sent and thus untrusted.
+    pub filename: Option<String>,
+    pub body: Vec<u8>,
+}
+
+impl Part {
+    /// Looks up a header value, ignoring the case of the header name.
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.header("Content-Type")
+    }
+
+    /// File name safe to create in a directory: the last component of the client
+    /// one, restricted to ASCII letters, digits, `.`, `-` and `_`, and without
+    /// leading dots.
+    pub fn safe_filename(&self) -> Option<String> {
+        let name = self.filename.as_deref()?.rsplit(['/', '\\']).next()?;
+        let name: String = name
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' => c,
+                _ => '_',
+            })
+            .collect();
+        let name = name.trim_start_matches('.');
+        (!name.is_empty()).then(|| name.to_string())
+    }
+}
+
+/// Parts of a `multipart/form-data` body, extracted from requests of that type.
+#[derive(Debug, Default)]
+pub struct Multipart(pub Vec<Part>);
+
+impl FromRequest for Multipart {
+    fn from_request(req: &mut Request) -> Result<Self, AppError> {
+        let ty = req.header("Content-Type").unwrap_or_default();
+        let mut params = ty.split(';');
+        let mime = params.next().unwrap_or_default().trim();
+        if !mime.eq_ignore_ascii_case("multipart/form-data") {
+            return Err(AppError::UnsupportedMediaType(ty.to_string()));
+        }
+
+        let boundary = params
+            .filter_map(|param| param.split_once('='))
+            .find(|(key, _)| key.trim().eq_ignore_ascii_case("boundary"))
+            .map(|(_, value)| value.trim().trim_matches('"').to_string())
+            .filter(|boundary| !boundary.is_empty())
+            .ok_or_else(|| AppError::BadRequest("Missing multipart boundary".into()))?;
+
+        parse(req.body(), &boundary)
+            .map(Multipart)
+            .map_err(|e| AppError::BadRequest(format!("Invalid multipart body: {}", e)))
+    }
+}
+
+/// Splits `body` into its parts delimited by `boundary`, ignoring the preamble and
+/// epilogue.
+pub fn parse(body: &[u8], boundary: &str) -> Result<Vec<Part>, String> {
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let close = [b"\r\n".as_slice(), &delimiter].concat();
+
+    let start = find(body, &delimiter).ok_or("missing opening boundary")?;
+    let mut rest = &body[start + delimiter.len()..];
+    let mut parts = Vec::new();
+    loop {
+        if rest.starts_with(b"--") {
+            return Ok(parts);
+        }
+        // Transport padding may follow the boundary
+        let line_end = find(rest, b"\r\n").ok_or("unterminated boundary line")?;
+        if rest[..line_end].iter().any(|b| !matches!(b, b' ' | b'\t')) {
+            return Err("unexpected data after boundary".into());
+        }
+        rest = &rest[line_end + 2..];
+
+        let (headers, body_start) = match rest.starts_with(b"\r\n") {
+            true => (&rest[..0], 2),
+            false => {
+                let end = find(rest, b"\r\n\r\n").ok_or("unterminated part headers")?;
+                (&rest[..end], end + 4)
+            }
+        };
+        let mut part = Part::default();
+        for line in std::str::from_utf8(headers)
+            .map_err(|_| "part headers are not UTF-8")?
+            .split("\r\n")
+        {
+            let (key, value) = line.split_once(':').ok_or("malformed part header")?;
+            part.headers
+                .push((key.trim().to_string(), value.trim().to_string()));
+        }
+        if let Some(disposition) = part.header("Content-Disposition") {
+            let params = disposition_params(disposition);
+            let param = |name: &str| {
+                params
+                    .iter()
+                    .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                    .map(|(_, value)| value.clone())
+            };
+            part.name = param("name");
+            part.filename = param("filename");
+        }
+
+        rest = &rest[body_start..];
+        let end = find(rest, &close).ok_or("missing closing boundary")?;
+        part.body = rest[..end].to_vec();
+        parts.push(part);
+        rest = &rest[end + close.len()..];
+    }
+}
+
+/// Parameters of a `Content-Disposition` header, with quoted values unescaped.
+fn disposition_params(header: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut chars = header.chars().peekable();
+    // Skips the disposition type
+    for c in chars.by_ref() {
+        if c == ';' {
+            break;
+        }
+    }
+
+    loop {
+        let key: String = chars
+            .by_ref()
+            .take_while(|&c| c != '=')
+            .collect::<String>()
+            .trim()
+            .to_string();
+        if key.is_empty() {
+            return params;
+        }
+
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let mut value = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => value.extend(chars.next()),
+                    c => value.push(c),
+                }
+            }
+            for c in chars.by_ref() {
+                if c == ';' {
+                    break;
+                }
+            }
+        } else {
+            value = chars.by_ref().take_while(|&c| c != ';').collect();
+        }
+        params.push((key, value.trim().to_string()));
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let body = "preamble\r\n--xyz\r\n\
+                    Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+                    hello\r\n--xyz  \r\n\
+                    Content-Disposition: form-data; name=files; filename=\"a \\\"b\\\".txt\"\r\n\
+                    Content-Type: text/plain\r\n\r\n\
+                    line 1\r\n--xy\r\nline 2\r\n--xyz--\r\nepilogue";
+        let parts = parse(body.as_bytes(), "xyz").unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].name.as_deref(), Some("title"));
+        assert_eq!(parts[0].filename, None);
+        assert_eq!(parts[0].body, b"hello");
+        assert_eq!(parts[1].name.as_deref(), Some("files"));
+        assert_eq!(parts[1].filename.as_deref(), Some("a \"b\".txt"));
+        assert_eq!(parts[1].content_type(), Some("text/plain"));
+        assert_eq!(parts[1].body, b"line 1\r\n--xy\r\nline 2");
+
+        assert!(parse(b"--xyz\r\n\r\nunterminated", "xyz").is_err());
+        assert!(parse(b"no boundary", "xyz").is_err());
+        assert_eq!(parse(b"--xyz--", "xyz").unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn test_safe_filename() {
+        let part = |name: &str| Part {
+            filename: Some(name.to_string()),
+            ..Part::default()
+        };
+        assert_eq!(part("a.txt").safe_filename().as_deref(), Some("a.txt"));
+        assert_eq!(
+            part("../../etc/passwd").safe_filename().as_deref(),
+            Some("passwd")
+        );
+        assert_eq!(
+            part("C:\\dir\\my file.txt").safe_filename().as_deref(),
+            Some("my_file.txt")
+        );
+        assert_eq!(part("..").safe_filename(), None);
+        assert_eq!(part("").safe_filename(), None);
+        assert_eq!(Part::default().safe_filename(), None);
+    }
+
+    #[test]
+    fn test_extract() {
+        let body = "--b\r\nContent-Disposition: form-data; name=x\r\n\r\n1\r\n--b--";
+        let req = format!(
+            "POST / HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=\"b\"\r\n\
+             Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let mut req = Request::parse(&mut crate::RequestBuffer::from(req.bytes())).unwrap();
+        let Multipart(parts) = Multipart::from_request(&mut req).unwrap();
+        assert_eq!(parts[0].name.as_deref(), Some("x"));
+        assert_eq!(parts[0].body, b"1");
+
+        let req = "POST / HTTP/1.1\r\nContent-Type: text/plain\r\n\r\n";
+        let mut req = Request::parse(&mut crate::RequestBuffer::from(req.bytes())).unwrap();
+        assert!(matches!(
+            Multipart::from_request(&mut req),
+            Err(AppError::UnsupportedMediaType(_))
+        ));
+    }
+}
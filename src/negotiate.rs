@@ -0,0 +1,214 @@
+//! Content negotiation, picking the representation of a response from the `Accept`,
+//! `Accept-Encoding` and `Accept-Language` headers of the request.
+
+/// Splits an `Accept` header into media ranges and their quality values, dropping
+/// the other media type parameters.
+pub fn parse_accept(header: &str) -> Vec<(&str, f32)> {
+    parse_ranges(header)
+        .into_iter()
+        .filter(|(range, _)| range.contains('/'))
+        .collect()
+}
+
+/// Splits an `Accept-Language` header into language ranges and their quality values.
+pub fn parse_accept_language(header: &str) -> Vec<(&str, f32)> {
+    parse_ranges(header)
+}
+
+/// Splits an `Accept-Encoding` header into encodings and their quality values.
+pub fn parse_accept_encoding(header: &str) -> Vec<(&str, f32)> {
+    parse_ranges(header)
+}
+
+fn parse_ranges(header: &str) -> Vec<(&str, f32)> {
+    header
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let range = parts.next()?.trim();
+            if range.is_empty() {
+                return None;
+            }
+            let q = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .and_then(|q| q.trim().parse().ok())
+                .unwrap_or(1.0);
+            Some((range, q))
+        })
+        .collect()
+}
+
+/// Quality value given to `media_type` by the most specific of the `accepted` media
+/// ranges matching it, 0 when none does.
+fn quality(accepted: &[(&str, f32)], media_type: &str) -> f32 {
+    let (ty, _) = media_type.split_once('/').unwrap_or((media_type, ""));
+    let specificity = |range: &str| {
+        if range.eq_ignore_ascii_case(media_type) {
+            Some(2)
+        } else if range
+            .strip_suffix("/*")
+            .is_some_and(|range_ty| range_ty.eq_ignore_ascii_case(ty))
+        {
+            Some(1)
+        } else {
+            (range == "*/*").then_some(0)
+        }
+    };
+
+    accepted
+        .iter()
+        .filter_map(|&(range, q)| Some((specificity(range)?, q)))
+        .max_by_key(|&(specificity, _)| specificity)
+        .map_or(0.0, |(_, q)| q)
+}
+
+/// Picks the media type of `available` the `Accept` header prefers, the order of
+/// `available` breaking ties. Requests without the header accept anything.
+pub fn best<'a>(accept: Option<&str>, available: &[&'a str]) -> Option<&'a str> {
+    let Some(accept) = accept else {
+        return available.first().copied();
+    };
+    let accepted = parse_accept(accept);
+
+    let mut best: Option<(&str, f32)> = None;
+    for &media_type in available {
+        let q = quality(&accepted, media_type);
+        if q > 0.0 && best.map_or(true, |(_, best_q)| q > best_q) {
+            best = Some((media_type, q));
+        }
+    }
+    best.map(|(media_type, _)| media_type)
+}
+
+/// Quality value given to the language `tag` by the closest of the `accepted`
+/// language ranges: the same tag, a range it belongs to (`en` for `en-US`), then a
+/// more specific range falling back to it (`en-US` for `en`), then `*`.
+fn language_quality(accepted: &[(&str, f32)], tag: &str) -> f32 {
+    let is_prefix = |prefix: &str, tag: &str| {
+        tag.get(..prefix.len())
+            .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+            && tag.as_bytes().get(prefix.len()) == Some(&b'-')
+    };
+    let closeness = |range: &str| {
+        if range.eq_ignore_ascii_case(tag) {
+            Some(3)
+        } else if is_prefix(range, tag) {
+            Some(2)
+        } else if is_prefix(tag, range) {
+            Some(1)
+        } else {
+            (range == "*").then_some(0)
+        }
+    };
+
+    accepted
+        .iter()
+        .filter_map(|&(range, q)| Some((closeness(range)?, q)))
+        .max_by_key(|&(closeness, _)| closeness)
+        .map_or(0.0, |(_, q)| q)
+}
+
+/// Picks the language of `supported` the `Accept-Language` header prefers, the
+/// order of `supported` breaking ties. Requests without the header get the first one.
+pub fn best_language<'a>(accept: Option<&str>, supported: &[&'a str]) -> Option<&'a str> {
+    let Some(accept) = accept else {
+        return supported.first().copied();
+    };
+    let accepted = parse_accept_language(accept);
+
+    let mut best: Option<(&str, f32)> = None;
+    for &tag in supported {
+        let q = language_quality(&accepted, tag);
+        if q > 0.0 && best.map_or(true, |(_, best_q)| q > best_q) {
+            best = Some((tag, q));
+        }
+    }
+    best.map(|(tag, _)| tag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AppError, ComparePath, Request, RequestBuffer, Response, Route, Router};
+
+    #[test]
+    fn test_best() {
+        let available = ["application/json", "text/html"];
+        assert_eq!(best(None, &available), Some("application/json"));
+        assert_eq!(best(Some("text/html"), &available), Some("text/html"));
+        assert_eq!(
+            best(Some("text/html;q=0.5, application/json;q=0.8"), &available),
+            Some("application/json")
+        );
+        assert_eq!(
+            best(Some("text/*;q=0.9, */*;q=0.1"), &available),
+            Some("text/html")
+        );
+        assert_eq!(best(Some("*/*"), &available), Some("application/json"));
+        // The most specific range wins over wildcards
+        assert_eq!(
+            best(Some("*/*, application/json;q=0"), &available),
+            Some("text/html")
+        );
+        assert_eq!(best(Some("image/png"), &available), None);
+        assert_eq!(
+            best(Some("TEXT/HTML;level=1"), &available),
+            Some("text/html")
+        );
+    }
+
+    #[test]
+    fn test_best_language() {
+        let supported = ["en", "fr", "de-CH"];
+        assert_eq!(best_language(None, &supported), Some("en"));
+        assert_eq!(best_language(Some("fr"), &supported), Some("fr"));
+        assert_eq!(
+            best_language(Some("fr-CH, fr;q=0.9, en;q=0.8"), &supported),
+            Some("fr")
+        );
+        assert_eq!(best_language(Some("en-US"), &supported), Some("en"));
+        assert_eq!(best_language(Some("de"), &supported), Some("de-CH"));
+        assert_eq!(
+            best_language(Some("es, *;q=0.1, en;q=0"), &supported),
+            Some("fr")
+        );
+        assert_eq!(best_language(Some("es"), &supported), None);
+        assert_eq!(best_language(Some("english"), &supported), None);
+    }
+
+    #[tokio::test]
+    async fn test_negotiate() {
+        let mut router = Router::default();
+        router.add_route(Route::get(
+            "/data",
+            |req: Request| -> Result<Response, AppError> {
+                let ty = req.negotiate(&["application/json", "text/html"])?;
+                let mut res = match ty {
+                    "text/html" => Response::from("<p>1</p>"),
+                    _ => Response::from("[1]"),
+                };
+                res.header("Content-Type", ty);
+                if let Some(lang) = req.preferred_language(&["en", "fr"]) {
+                    res.header("Content-Language", lang);
+                }
+                Ok(res)
+            },
+            ComparePath::Exact,
+        ));
+
+        let get = |accept: &str| {
+            let req = format!("GET /data HTTP/1.1\r\nAccept: {}\r\n\r\n", accept);
+            let req = Request::parse(&mut RequestBuffer::from(req.bytes())).unwrap();
+            router.route(req)
+        };
+        let res = get("text/html").await;
+        assert_eq!(res.content(), b"<p>1</p>");
+        assert_eq!(res.header_value("Vary"), Some("Accept, Accept-Language"));
+        assert_eq!(res.header_value("Content-Language"), Some("en"));
+        let res = get("application/*").await;
+        assert_eq!(res.header_value("Content-Type"), Some("application/json"));
+        let res = get("image/png").await;
+        assert_eq!(res.code().as_u16(), 406);
+        assert_eq!(res.header_value("Vary"), Some("Accept"));
+    }
+}
@@ -0,0 +1,141 @@
+//! Normalization of request paths before routing.
+
+use super::middleware::{Middleware, Next};
+use super::router::BoxFuture;
+use super::{HttpCode, Method, Request, Response};
+
+/// What to do with a slash ending the path.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TrailingSlash {
+    /// Leave the path as it is.
+    #[default]
+    Keep,
+    /// Redirect `/a/` to `/a`.
+    Trim,
+    /// Redirect `/a` to `/a/`, leaving paths whose last segment has an extension alone.
+    Append,
+}
+
+/// Middleware rewriting messy paths so routes match them predictably. Repeated
+/// slashes are always merged and the path may be lowercased, both silently, while
+/// trailing slash changes are redirected for clients to use the canonical URL.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NormalizePath {
+    trailing_slash: TrailingSlash,
+    lowercase: bool,
+}
+
+impl NormalizePath {
+    pub fn with_trailing_slash(mut self, trailing_slash: TrailingSlash) -> Self {
+        self.trailing_slash = trailing_slash;
+        self
+    }
+
+    pub fn with_lowercase(mut self, lowercase: bool) -> Self {
+        self.lowercase = lowercase;
+        self
+    }
+
+    /// Path with its repeated slashes merged, and lowercased if configured.
+    fn rewrite(&self, path: &str) -> String {
+        let mut rewritten = String::with_capacity(path.len());
+        for c in path.chars() {
+            if !(c == '/' && rewritten.ends_with('/')) {
+                rewritten.push(c);
+            }
+        }
+        if self.lowercase {
+            rewritten.make_ascii_lowercase();
+        }
+        rewritten
+    }
+
+    /// Canonical path the client should be redirected to, if any.
+    fn redirect(&self, path: &str) -> Option<String> {
+        match self.trailing_slash {
+            TrailingSlash::Keep => None,
+            TrailingSlash::Trim if path.len() > 1 && path.ends_with('/') => {
+                Some(path.trim_end_matches('/').to_string())
+            }
+            TrailingSlash::Append
+                if !path.ends_with('/') && !path.rsplit('/').next()?.contains('.') =>
+            {
+                Some(format!("{}/", path))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Middleware for NormalizePath {
+    fn handle(&self, mut req: Request, next: Next) -> BoxFuture<Response> {
+        // CONNECT targets are authorities, not paths
+        if *req.method() == Method::Connect {
+            return next.run(req);
+        }
+        let path = self.rewrite(req.path());
+
+        let Some(location) = self.redirect(&path) else {
+            req.set_path(path);
+            return next.run(req);
+        };
+
+        // Browsers turn a POST redirected with a 301 into a GET
+        let code = match req.method() {
+            Method::Get => HttpCode::MovedPermanently,
+            _ => HttpCode::PermanentRedirect,
+        };
+        let location = match req.query() {
+            Some(query) => format!("{}?{}", location, query),
+            None => location,
+        };
+        let mut res = Response::from(code);
+        res.header("Location", location);
+        Box::pin(async { res })
+    }
+
+    fn rewrite_head(&self, req: &mut Request) {
+        if *req.method() == Method::Connect {
+            return;
+        }
+        req.set_path(self.rewrite(req.path()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware;
+
+    async fn send(normalize: NormalizePath, req: &str) -> Response {
+        middleware::send(normalize, req, |req: Request| async move {
+            Response::from(req.path().to_string())
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_normalize_path() {
+        let normalize = NormalizePath::default();
+        let res = send(normalize, "GET //a///b/ HTTP/1.1\r\n\r\n").await;
+        assert_eq!(res.content(), b"/a/b/");
+
+        let normalize = normalize
+            .with_trailing_slash(TrailingSlash::Trim)
+            .with_lowercase(true);
+        let res = send(normalize, "GET /A//b HTTP/1.1\r\n\r\n").await;
+        assert_eq!(res.content(), b"/a/b");
+        let res = send(normalize, "GET /A//b/?x=1 HTTP/1.1\r\n\r\n").await;
+        assert_eq!(res.header_value("Location"), Some("/a/b?x=1"));
+        assert!(res.into_bytes().starts_with(b"HTTP/1.1 301 "));
+        let res = send(normalize, "GET / HTTP/1.1\r\n\r\n").await;
+        assert_eq!(res.content(), b"/");
+
+        let normalize = normalize.with_trailing_slash(TrailingSlash::Append);
+        let res = send(normalize, "POST /a HTTP/1.1\r\n\r\n").await;
+        assert_eq!(res.header_value("Location"), Some("/a/"));
+        assert!(res.into_bytes().starts_with(b"HTTP/1.1 308 "));
+        let res = send(normalize, "GET /files/a.txt HTTP/1.1\r\n\r\n").await;
+        assert_eq!(res.content(), b"/files/a.txt");
+    }
+}
@@ -0,0 +1,259 @@
+//! Prometheus text exposition of a [`Metrics`] registry, served by a middleware so
+//! the endpoint survives router replacements.
+
+use std::fmt::Write;
+use std::sync::Arc;
+
+use super::metrics::Histogram;
+use super::middleware::{Middleware, Next};
+use super::router::{BoxFuture, MatchedPath};
+use super::{Method, Metrics, Request, Response};
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Middleware answering GET and HEAD requests on its path with the metrics of the
+/// registry, in the Prometheus text format. Other requests go through.
+#[derive(Debug, Clone)]
+pub struct MetricsEndpoint {
+    path: String,
+    metrics: Arc<Metrics>,
+}
+
+impl MetricsEndpoint {
+    pub fn new<P: Into<String>>(path: P, metrics: Arc<Metrics>) -> Self {
+        MetricsEndpoint {
+            path: path.into(),
+            metrics,
+        }
+    }
+}
+
+impl Middleware for MetricsEndpoint {
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<Response> {
+        let scrape = matches!(req.method(), Method::Get | Method::Head) && req.path() == self.path;
+        if !scrape {
+            return next.run(req);
+        }
+
+        let mut res = Response::from(encode(&self.metrics));
+        res.header("Content-Type", CONTENT_TYPE);
+        res.header("Cache-Control", "no-store");
+        // Scrapes are recorded under their own label rather than as unmatched requests
+        res.extensions_mut().insert(MatchedPath(self.path.clone()));
+        Box::pin(async move { res })
+    }
+}
+
+/// Renders `metrics` in the Prometheus text format.
+pub fn encode(metrics: &Metrics) -> String {
+    let mut out = String::new();
+    let routes = metrics.routes();
+
+    family(
+        &mut out,
+        "http_requests_total",
+        "counter",
+        "Requests answered.",
+    );
+    for (label, route) in &routes {
+        for class in 1..=5 {
+            let count = route.status_class(class);
+            if count > 0 {
+                let labels = format!("route=\"{}\",status=\"{}xx\"", escape(label), class);
+                sample(&mut out, "http_requests_total", &labels, count);
+            }
+        }
+    }
+
+    family(
+        &mut out,
+        "http_request_duration_seconds",
+        "histogram",
+        "Time taken to answer requests.",
+    );
+    for (label, route) in &routes {
+        histogram(&mut out, &escape(label), route.latency());
+    }
+
+    family(
+        &mut out,
+        "http_responses_total",
+        "counter",
+        "Responses written by connections, rejected requests included.",
+    );
+    for class in 1..=5 {
+        let labels = format!("status=\"{}xx\"", class);
+        sample(
+            &mut out,
+            "http_responses_total",
+            &labels,
+            metrics.responses(class),
+        );
+    }
+
+    let gauges = [
+        (
+            "http_requests_in_flight",
+            "Requests currently being handled.",
+            metrics.in_flight(),
+        ),
+        (
+            "http_open_connections",
+            "HTTP/1 connections currently open.",
+            metrics.open_connections(),
+        ),
+    ];
+    for (name, help, value) in gauges {
+        family(&mut out, name, "gauge", help);
+        sample(&mut out, name, "", value);
+    }
+
+    let counters = [
+        (
+            "http_received_bytes_total",
+            "Bytes read from clients.",
+            metrics.bytes_received(),
+        ),
+        (
+            "http_sent_bytes_total",
+            "Bytes written to clients.",
+            metrics.bytes_sent(),
+        ),
+        (
+            "http_parse_errors_total",
+            "Requests rejected before reaching a handler.",
+            metrics.parse_errors(),
+        ),
+        (
+            "http_client_disconnects_total",
+            "Connections closed by clients in the middle of an exchange.",
+            metrics.client_disconnects(),
+        ),
+        (
+            "http_header_timeouts_total",
+            "Requests whose head was not received in time.",
+            metrics.header_timeouts(),
+        ),
+        (
+            "http_idle_timeouts_total",
+            "Keep-alive connections closed for staying idle.",
+            metrics.idle_timeouts(),
+        ),
+        (
+            "http_rejected_connections_total",
+            "Connections turned away for exceeding the per address cap.",
+            metrics.rejected_connections(),
+        ),
+    ];
+    for (name, help, value) in counters {
+        family(&mut out, name, "counter", help);
+        sample(&mut out, name, "", value);
+    }
+    out
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn sample(out: &mut String, name: &str, labels: &str, value: impl std::fmt::Display) {
+    if labels.is_empty() {
+        let _ = writeln!(out, "{} {}", name, value);
+    } else {
+        let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+    }
+}
+
+fn histogram(out: &mut String, route: &str, histogram: &Histogram) {
+    const NAME: &str = "http_request_duration_seconds";
+    for (bound, count) in histogram.buckets() {
+        let le = if bound.is_infinite() {
+            String::from("+Inf")
+        } else {
+            bound.to_string()
+        };
+        let labels = format!("route=\"{}\",le=\"{}\"", route, le);
+        sample(out, &format!("{}_bucket", NAME), &labels, count);
+    }
+    let labels = format!("route=\"{}\"", route);
+    let sum = histogram.sum().as_secs_f64();
+    sample(out, &format!("{}_sum", NAME), &labels, sum);
+    sample(out, &format!("{}_count", NAME), &labels, histogram.count());
+}
+
+/// Escapes a label value, in which backslashes, quotes and line feeds are special.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{middleware, HttpCode};
+
+    #[test]
+    fn test_encode() {
+        let metrics = Metrics::default();
+        let route = metrics.route("GET /echo/{msg}");
+        route.record(200, Duration::from_millis(3));
+        route.record(404, Duration::from_millis(30));
+        metrics
+            .route("say \"hi\"")
+            .record(200, Duration::from_secs(20));
+        metrics.record_received(120);
+        metrics.record_sent(80);
+        metrics.record_response(503);
+        metrics.record_parse_error();
+
+        let out = encode(&metrics);
+        for line in [
+            "# TYPE http_requests_total counter",
+            "http_requests_total{route=\"GET /echo/{msg}\",status=\"2xx\"} 1",
+            "http_requests_total{route=\"GET /echo/{msg}\",status=\"4xx\"} 1",
+            "http_requests_total{route=\"say \\\"hi\\\"\",status=\"2xx\"} 1",
+            "# TYPE http_request_duration_seconds histogram",
+            "http_request_duration_seconds_bucket{route=\"GET /echo/{msg}\",le=\"0.005\"} 1",
+            "http_request_duration_seconds_bucket{route=\"GET /echo/{msg}\",le=\"+Inf\"} 2",
+            "http_request_duration_seconds_sum{route=\"GET /echo/{msg}\"} 0.033",
+            "http_request_duration_seconds_count{route=\"GET /echo/{msg}\"} 2",
+            "http_requests_in_flight 0",
+            "http_open_connections 0",
+            "http_received_bytes_total 120",
+            "http_sent_bytes_total 80",
+            "http_responses_total{status=\"2xx\"} 0",
+            "http_responses_total{status=\"5xx\"} 1",
+            "http_parse_errors_total 1",
+            "http_client_disconnects_total 0",
+        ] {
+            assert!(out.lines().any(|l| l == line), "missing {}", line);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_metrics_endpoint() {
+        let metrics = Arc::new(Metrics::default());
+        metrics.record_sent(42);
+        let endpoint = MetricsEndpoint::new("/metrics", metrics);
+        let send = |req: &'static str| {
+            middleware::send(endpoint.clone(), req, |_| async {
+                Response::from(HttpCode::NotFound)
+            })
+        };
+        let res = send("GET /metrics HTTP/1.1\r\n\r\n").await;
+        assert_eq!(res.code().as_u16(), 200);
+        assert_eq!(res.header_value("Content-Type"), Some(CONTENT_TYPE));
+        let body = String::from_utf8(res.content().to_vec()).unwrap();
+        assert!(body.contains("http_sent_bytes_total 42\n"));
+
+        let res = send("POST /metrics HTTP/1.1\r\n\r\n").await;
+        assert_eq!(res.code().as_u16(), 404);
+        let res = send("GET /metrics/other HTTP/1.1\r\n\r\n").await;
+        assert_eq!(res.code().as_u16(), 404);
+    }
+}
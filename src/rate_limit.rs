@@ -0,0 +1,233 @@
+//! Token-bucket rate limiting of requests, keyed by client address by default.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::middleware::{Middleware, Next};
+use super::router::BoxFuture;
+use super::{HttpCode, Request, Response};
+
+/// Number of buckets the memory store holds at most.
+const MAX_BUCKETS: usize = 10_000;
+
+/// Bucket size and refill rate.
+#[derive(Debug, Clone, Copy)]
+pub struct Quota {
+    /// Requests allowed in a burst.
+    pub burst: u32,
+    /// Time needed to regain one request.
+    pub period: Duration,
+}
+
+impl Quota {
+    pub fn per_second(requests: u32) -> Self {
+        Quota {
+            burst: requests,
+            period: Duration::from_secs(1) / requests.max(1),
+        }
+    }
+
+    pub fn per_minute(requests: u32) -> Self {
+        Quota {
+            burst: requests,
+            period: Duration::from_secs(60) / requests.max(1),
+        }
+    }
+
+    pub fn with_burst(mut self, burst: u32) -> Self {
+        self.burst = burst;
+        self
+    }
+}
+
+/// Storage of the buckets, so limits can be shared between servers.
+pub trait RateLimitStore: Send + Sync + 'static {
+    /// Takes a token from the bucket of `key`, or returns how long to wait for one.
+    fn acquire(&self, key: &str, quota: Quota) -> Result<(), Duration>;
+}
+
+/// Buckets kept in the server memory.
+///
+/// Once full, the store forgets the buckets that refilled, then the least recently
+/// used ones until half of it is free, so clients rotating keys cannot grow it and
+/// the cleanup cost is spread over the following requests.
+#[derive(Default)]
+pub struct MemoryStore {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, quota: Quota, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed / quota.period.as_secs_f64()).min(f64::from(quota.burst));
+        self.updated = now;
+    }
+
+    fn is_full(&self, quota: Quota, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens + elapsed / quota.period.as_secs_f64() >= f64::from(quota.burst)
+    }
+}
+
+/// Frees at least half of `buckets`, full ones first then the least recently used.
+fn evict(buckets: &mut HashMap<String, Bucket>, quota: Quota, now: Instant) {
+    // Full buckets are the same as missing ones
+    buckets.retain(|_, bucket| !bucket.is_full(quota, now));
+
+    let keep = MAX_BUCKETS / 2;
+    if buckets.len() > keep {
+        let mut updated = buckets.values().map(|b| b.updated).collect::<Vec<_>>();
+        let index = updated.len() - keep;
+        let (_, &mut oldest_kept, _) = updated.select_nth_unstable(index);
+        buckets.retain(|_, bucket| bucket.updated > oldest_kept);
+    }
+}
+
+impl RateLimitStore for MemoryStore {
+    fn acquire(&self, key: &str, quota: Quota) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(key) {
+            evict(&mut buckets, quota, now);
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: f64::from(quota.burst),
+            updated: now,
+        });
+        bucket.refill(quota, now);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(quota.period.mul_f64(1.0 - bucket.tokens))
+        }
+    }
+}
+
+type KeyExtractor = Arc<dyn Fn(&Request) -> Option<String> + Send + Sync>;
+
+/// Middleware answering 429 with a `Retry-After` header once a client exhausted its
+/// quota. Requests without a key are not limited.
+#[derive(Clone)]
+pub struct RateLimit {
+    quota: Quota,
+    store: Arc<dyn RateLimitStore>,
+    key: KeyExtractor,
+}
+
+impl RateLimit {
+    pub fn new(quota: Quota) -> Self {
+        RateLimit {
+            quota,
+            store: Arc::new(MemoryStore::default()),
+            key: Arc::new(|req| req.peer_addr().map(|addr| addr.ip().to_string())),
+        }
+    }
+
+    pub fn with_store<S>(mut self, store: S) -> Self
+    where
+        S: RateLimitStore,
+    {
+        self.store = Arc::new(store);
+        self
+    }
+
+    /// Limits requests by the key returned by `key`, e.g. an API token header.
+    pub fn with_key<F>(mut self, key: F) -> Self
+    where
+        F: Fn(&Request) -> Option<String> + Send + Sync + 'static,
+    {
+        self.key = Arc::new(key);
+        self
+    }
+}
+
+impl Middleware for RateLimit {
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<Response> {
+        let Some(key) = (self.key)(&req) else {
+            return next.run(req);
+        };
+
+        match self.store.acquire(&key, self.quota) {
+            Ok(()) => next.run(req),
+            Err(retry_after) => {
+                // Retry-After only has a one second resolution
+                let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                let mut res = Response::from(HttpCode::TooManyRequests);
+                res.header("Retry-After", secs.max(1).to_string());
+                Box::pin(async move { res })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware;
+
+    async fn send(limit: &RateLimit, key: &str) -> Response {
+        let req = format!("GET / HTTP/1.1\r\nX-Api-Key: {}\r\n\r\n", key);
+        middleware::send(limit.clone(), &req, |_| async {
+            Response::from(HttpCode::Ok)
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let limit = RateLimit::new(Quota::per_minute(2))
+            .with_key(|req| req.header("X-Api-Key").map(str::to_string));
+
+        for _ in 0..2 {
+            let res = send(&limit, "a").await;
+            assert!(res.into_bytes().starts_with(b"HTTP/1.1 200 "));
+        }
+        let res = send(&limit, "a").await;
+        assert_eq!(res.header_value("Retry-After"), Some("30"));
+        assert!(res.into_bytes().starts_with(b"HTTP/1.1 429 "));
+
+        let res = send(&limit, "b").await;
+        assert!(res.into_bytes().starts_with(b"HTTP/1.1 200 "));
+    }
+
+    #[test]
+    fn test_refill() {
+        let store = MemoryStore::default();
+        let quota = Quota::per_second(1000).with_burst(1);
+
+        assert!(store.acquire("a", quota).is_ok());
+        assert!(store.acquire("a", quota).is_err());
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(store.acquire("a", quota).is_ok());
+    }
+
+    #[test]
+    fn test_eviction() {
+        let store = MemoryStore::default();
+        let quota = Quota::per_minute(1);
+        assert!(store.acquire("first", quota).is_ok());
+        for i in 1..MAX_BUCKETS {
+            assert!(store.acquire(&i.to_string(), quota).is_ok());
+        }
+        assert_eq!(store.buckets.lock().unwrap().len(), MAX_BUCKETS);
+
+        // No bucket refilled, the oldest ones go
+        assert!(store.acquire("new", quota).is_ok());
+        let buckets = store.buckets.lock().unwrap();
+        assert!(buckets.len() <= MAX_BUCKETS / 2 + 1);
+        assert!(buckets.contains_key("new"));
+        assert!(buckets.contains_key(&(MAX_BUCKETS - 1).to_string()));
+        assert!(!buckets.contains_key("first"));
+    }
+}
@@ -0,0 +1,168 @@
+//! Reloading the settings on `SIGHUP`, without dropping the open connections.
+//!
+//! The log level, the limits and the routes (file access, mounts and proxy) follow the new
+//! settings: requests already in flight complete with the previous ones. The TLS
+//! certificates are read again as well, for those renewed in place. The other
+//! settings, such as the address or the files directory, only apply after a restart.
+
+use std::time::Duration;
+
+use http_server_starter_rust::{
+    IpFilter, LogFilter, Quota, RateLimit, ServerHandle, SharedMiddleware, StaticFiles, Timeout,
+};
+
+#[cfg(feature = "http3")]
+use http_server_starter_rust::Http3;
+
+use super::cli::Cli;
+
+/// Limits the server is built with, swapped on reload.
+#[derive(Clone, Default)]
+pub struct Limits {
+    pub ip_filter: SharedMiddleware,
+    pub rate_limit: SharedMiddleware,
+    pub timeout: SharedMiddleware,
+}
+
+impl Limits {
+    /// Applies the limits of `cli`, leaving alone those unchanged since `old` so that
+    /// the rate limit keeps counting.
+    pub fn apply(&self, old: Option<&Cli>, cli: &Cli) {
+        let changed = |same: fn(&Cli, &Cli) -> bool| old.map_or(true, |old| !same(old, cli));
+
+        if changed(|a, b| a.allow == b.allow && a.deny == b.deny) {
+            if cli.allow.is_empty() && cli.deny.is_empty() {
+                self.ip_filter.clear();
+            } else {
+                let filter = cli
+                    .allow
+                    .iter()
+                    .copied()
+                    .fold(IpFilter::default(), IpFilter::with_allow);
+                self.ip_filter
+                    .store(cli.deny.iter().copied().fold(filter, IpFilter::with_deny));
+            }
+        }
+        if changed(|a, b| a.rate_limit == b.rate_limit) {
+            match cli.rate_limit {
+                Some(rate) => self
+                    .rate_limit
+                    .store(RateLimit::new(Quota::per_second(rate))),
+                None => self.rate_limit.clear(),
+            }
+        }
+        if changed(|a, b| a.request_timeout == b.request_timeout) {
+            match cli.request_timeout {
+                Some(secs) => self.timeout.store(Timeout::new(Duration::from_secs(secs))),
+                None => self.timeout.clear(),
+            }
+        }
+    }
+}
+
+/// Applies the settings read again to a running server.
+pub struct Reloader {
+    cli: Cli,
+    server: ServerHandle,
+    files: Option<StaticFiles>,
+    limits: Limits,
+    log_filter: LogFilter,
+    #[cfg(feature = "http3")]
+    http3: Option<Http3>,
+}
+
+impl Reloader {
+    pub fn new(
+        cli: Cli,
+        server: ServerHandle,
+        files: Option<StaticFiles>,
+        limits: Limits,
+        log_filter: LogFilter,
+    ) -> Self {
+        Reloader {
+            cli,
+            server,
+            files,
+            limits,
+            log_filter,
+            #[cfg(feature = "http3")]
+            http3: None,
+        }
+    }
+
+    /// Reloads the certificates of `http3` as well.
+    #[cfg(feature = "http3")]
+    pub fn with_http3(mut self, http3: Http3) -> Self {
+        self.http3 = Some(http3);
+        self
+    }
+
+    /// Reads the settings again and applies them. Nothing changes when they are
+    /// invalid.
+    pub fn reload(&mut self) -> Result<(), String> {
+        let cli = super::config::reparse()?;
+        let router = super::router(&cli, self.files.as_ref())?;
+        self.log_filter
+            .set(cli.log_level.as_deref())
+            .map_err(|e| format!("invalid value for '--log-level': {}", e))?;
+        self.server.set_router(router);
+        self.limits.apply(Some(&self.cli), &cli);
+        // Certificates don't depend on the other settings, which apply either way
+        #[cfg(feature = "http3")]
+        if let Some(Err(e)) = self.http3.as_ref().map(Http3::reload_certificates) {
+            tracing::error!("Failed to reload TLS certificates: {}", e);
+        }
+
+        if restart_needed(&self.cli, &cli) {
+            tracing::warn!("Some changed settings only apply after a restart");
+        }
+        self.cli = cli;
+        Ok(())
+    }
+
+    /// Reloads on every `SIGHUP`, from a thread of its own.
+    #[cfg(unix)]
+    pub fn on_hangup(mut self) -> std::io::Result<()> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        // Registered right away, so that a signal sent early is not fatal
+        let mut hangup = {
+            let _guard = runtime.enter();
+            signal(SignalKind::hangup())?
+        };
+        std::thread::Builder::new()
+            .name(String::from("config-reload"))
+            .spawn(move || {
+                runtime.block_on(async move {
+                    while hangup.recv().await.is_some() {
+                        match self.reload() {
+                            Ok(()) => tracing::info!("Configuration reloaded"),
+                            Err(e) => tracing::error!("Failed to reload configuration: {}", e),
+                        }
+                    }
+                })
+            })?;
+        Ok(())
+    }
+}
+
+/// Whether settings which are not reloaded differ between `old` and `new`.
+fn restart_needed(old: &Cli, new: &Cli) -> bool {
+    let fixed = |cli: &Cli| Cli {
+        cache_ttl: None,
+        htpasswd: None,
+        max_upload: None,
+        mounts: Vec::new(),
+        log_level: None,
+        request_timeout: None,
+        rate_limit: None,
+        allow: Vec::new(),
+        deny: Vec::new(),
+        proxy: false,
+        ..cli.clone()
+    };
+    fixed(old) != fixed(new)
+}
@@ -1,98 +1,529 @@
-#![allow(dead_code)]
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
 
-use std::collections::HashMap;
-use std::iter::Peekable;
+use bytes::Bytes;
 
-use super::{HttpVersion, Method};
+use super::byte_str::ByteStr;
+use super::early_hints::EarlyHints;
+use super::http::is_token;
+use super::negotiate;
+use super::router::MatchedPath;
+#[cfg(feature = "session")]
+use super::session::Session;
+use super::{AppError, ConnectionInfo, Extensions, HttpVersion, Method, Response, State, StateMap};
 
 #[derive(Debug, Clone)]
 pub struct Request {
     method: Method,
-    path: String,
+    /// Path and query, like the headers and the body, point into the buffer the
+    /// request was received in.
+    path: ByteStr,
+    query: Option<ByteStr>,
     version: HttpVersion,
-    headers: HashMap<String, String>,
-    body: Vec<u8>,
+    /// Headers in the order they were received. Requests have few of them, for which
+    /// comparing names one after the other beats hashing them.
+    headers: Vec<(ByteStr, ByteStr)>,
+    body: Bytes,
+    connection: ConnectionInfo,
+    states: StateMap,
+    params: Vec<(String, String)>,
+    extensions: Extensions,
+    vary: VaryOn,
+}
+
+/// Request headers a response depends on, recorded while handling the request.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct VaryOn(Arc<Mutex<Vec<&'static str>>>);
+
+impl VaryOn {
+    /// Lists the recorded headers in the `Vary` header of `res`.
+    pub(crate) fn apply(&self, res: &mut Response) {
+        for name in self.0.lock().unwrap().iter() {
+            res.add_vary(name);
+        }
+    }
 }
 
 impl Request {
-    pub fn method(&self) -> Method {
-        self.method
+    pub fn method(&self) -> &Method {
+        &self.method
+    }
+
+    pub fn set_method(&mut self, method: Method) {
+        self.method = method;
     }
 
     pub fn path(&self) -> &str {
         &self.path
     }
 
-    pub fn headers(&self) -> &HashMap<String, String> {
+    pub fn set_path<P>(&mut self, path: P)
+    where
+        P: Into<ByteStr>,
+    {
+        self.path = path.into();
+    }
+
+    /// Raw query string, without the leading `?`.
+    pub fn query(&self) -> Option<&str> {
+        self.query.as_deref()
+    }
+
+    /// Decoded query string parameters, in order.
+    pub fn query_pairs(&self) -> Vec<(String, String)> {
+        parse_urlencoded(self.query().unwrap_or_default().as_bytes())
+    }
+
+    /// Decoded fields of an `application/x-www-form-urlencoded` body, empty for any
+    /// other content type.
+    pub fn form_pairs(&self) -> Vec<(String, String)> {
+        if self.is_form() {
+            parse_urlencoded(&self.body)
+        } else {
+            Vec::new()
+        }
+    }
+
+    pub fn is_form(&self) -> bool {
+        self.header("Content-Type").is_some_and(|ty| {
+            ty.split(';')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .eq_ignore_ascii_case("application/x-www-form-urlencoded")
+        })
+    }
+
+    pub fn version(&self) -> HttpVersion {
+        self.version
+    }
+
+    /// Every header in the order they were received.
+    pub fn headers(&self) -> &[(ByteStr, ByteStr)] {
         &self.headers
     }
 
+    /// Looks up a header value, ignoring the case of the header name.
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Value of the cookie `name`, from the `Cookie` header.
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.header("Cookie")?
+            .split(';')
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find_map(|(key, value)| (key == name).then_some(value))
+    }
+
+    /// Sets a header, replacing any value set under a differently cased name.
+    pub fn set_header<K, V>(&mut self, key: K, value: V)
+    where
+        K: Into<ByteStr>,
+        V: Into<ByteStr>,
+    {
+        let key = key.into();
+        self.remove_header(&key);
+        self.headers.push((key, value.into()));
+    }
+
+    pub fn remove_header(&mut self, key: &str) -> Option<ByteStr> {
+        let i = self
+            .headers
+            .iter()
+            .position(|(k, _)| k.eq_ignore_ascii_case(key))?;
+        Some(self.headers.remove(i).1)
+    }
+
     pub fn body(&self) -> &[u8] {
         &self.body
     }
 
-    pub fn parse<I>(req_buf: &mut RequestBuffer<I>) -> Request
+    pub fn set_body<B>(&mut self, body: B)
+    where
+        B: Into<Bytes>,
+    {
+        self.body = body.into();
+    }
+
+    /// Takes the body out of the request, leaving it empty.
+    pub fn take_body(&mut self) -> Bytes {
+        std::mem::take(&mut self.body)
+    }
+
+    /// Address of the client, unknown for requests not received over TCP.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.connection.peer_addr
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.connection.local_addr
+    }
+
+    /// Whether the request was received over TLS.
+    pub fn is_secure(&self) -> bool {
+        self.connection.tls
+    }
+
+    pub fn connection_info(&self) -> ConnectionInfo {
+        self.connection
+    }
+
+    pub fn set_connection_info(&mut self, info: ConnectionInfo) {
+        self.connection = info;
+    }
+
+    /// Application state of type `T` registered on the server, if any.
+    pub fn state<T>(&self) -> Option<State<T>>
+    where
+        T: Send + Sync + 'static,
+    {
+        self.states.get()
+    }
+
+    pub fn set_states(&mut self, states: StateMap) {
+        self.states = states;
+    }
+
+    /// Parameters captured by the `{name}` segments of the matched route, in order.
+    pub fn params(&self) -> &[(String, String)] {
+        &self.params
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Pattern of the route the request matched, e.g. `/echo/{msg}`, once routed.
+    pub fn matched_path(&self) -> Option<&str> {
+        self.extensions()
+            .get::<MatchedPath>()
+            .map(|MatchedPath(path)| path.as_str())
+    }
+
+    pub fn set_params(&mut self, params: Vec<(String, String)>) {
+        self.params = params;
+    }
+
+    /// Sender of 103 Early Hints ahead of the response, on connections supporting them.
+    pub fn early_hints(&self) -> Option<EarlyHints> {
+        self.extensions.get::<EarlyHints>().cloned()
+    }
+
+    /// Session of the request, when the sessions middleware runs.
+    #[cfg(feature = "session")]
+    pub fn session(&self) -> Option<Session> {
+        self.extensions.get::<Session>().cloned()
+    }
+
+    /// Values attached to this request by middleware.
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+
+    /// Records that the response depends on the request header `name`. The router
+    /// lists the recorded headers in the `Vary` header of the handler response, for
+    /// caches to tell its variants apart.
+    pub fn vary_on(&self, name: &'static str) {
+        let mut names = self.vary.0.lock().unwrap();
+        if !names.iter().any(|n| n.eq_ignore_ascii_case(name)) {
+            names.push(name);
+        }
+    }
+
+    /// Headers recorded by [`Request::vary_on`], shared with the clones of the request.
+    pub(crate) fn varied_on(&self) -> VaryOn {
+        self.vary.clone()
+    }
+
+    /// Media type of `available` the `Accept` header prefers, the order of `available`
+    /// breaking ties. The response then varies on `Accept`.
+    pub fn negotiate<'a>(&self, available: &[&'a str]) -> Result<&'a str, AppError> {
+        self.vary_on("Accept");
+        negotiate::best(self.header("Accept"), available).ok_or(AppError::NotAcceptable)
+    }
+
+    /// Language of `supported` the `Accept-Language` header prefers, the order of
+    /// `supported` breaking ties, or `None` when the client accepts none of them.
+    /// Handlers should set the `Content-Language` of their response to it, which then
+    /// varies on `Accept-Language`.
+    pub fn preferred_language<'a>(&self, supported: &[&'a str]) -> Option<&'a str> {
+        self.vary_on("Accept-Language");
+        negotiate::best_language(self.header("Accept-Language"), supported)
+    }
+
+    /// Number of body bytes announced by the `Content-Length` header, 0 when absent.
+    pub fn content_length(&self) -> usize {
+        self.header("Content-Length")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or_default()
+    }
+
+    /// Whether the connection should stay open after this request has been answered.
+    pub fn keep_alive(&self) -> bool {
+        let connection = self.header("Connection");
+        match self.version {
+            HttpVersion::V1_0 => connection.is_some_and(|v| v.eq_ignore_ascii_case("keep-alive")),
+            HttpVersion::V1_1 => !connection.is_some_and(|v| v.eq_ignore_ascii_case("close")),
+        }
+    }
+
+    /// Parses a request, failing on malformed request lines, headers or
+    /// `Content-Length` values, as well as on repeated `Host` headers and conflicting
+    /// `Content-Length` ones.
+    pub fn parse<I>(req_buf: &mut RequestBuffer<I>) -> Result<Request, String>
     where
         I: Iterator<Item = u8>,
     {
-        let (method, path, version) = Self::parse_start_line(req_buf);
-        let headers = Self::parse_headers(req_buf);
-        let body = Self::parse_body(req_buf);
+        let head = req_buf.read_head();
+        let mut req = Self::parse_head(Bytes::from(head))?;
+        req.body = Bytes::from(Self::parse_body(req_buf, req.content_length()));
+        Ok(req)
+    }
 
+    /// Parses a request head, from the request line to the empty line ending it, the
+    /// body being left empty. The path and headers are slices of `head`, as received
+    /// by the connection, rather than copies of it.
+    pub fn parse_head(head: Bytes) -> Result<Request, String> {
+        let head = ByteStr::from_utf8(head).map_err(|_| "request head is not UTF-8")?;
+        let mut lines = Lines(&head);
+        let (method, path, version) = Self::parse_start_line(lines.next().unwrap_or_default())?;
+        let headers = Self::parse_headers(&head, lines)?;
+
+        let req = Request::new(method, head.slice_ref(path), version, headers);
+        if let Some(len) = req.header("Content-Length") {
+            // Signs are accepted by `parse` but not by the grammar
+            if !len.bytes().all(|b| b.is_ascii_digit()) || len.parse::<usize>().is_err() {
+                return Err(format!("invalid Content-Length {:?}", len));
+            }
+        }
+        Ok(req)
+    }
+
+    /// Starts building a request, e.g. for unit tests of handlers. It is a GET of `/`
+    /// until told otherwise.
+    pub fn builder() -> RequestBuilder {
+        RequestBuilder(Request::new(
+            Method::Get,
+            ByteStr::from_static("/"),
+            HttpVersion::V1_1,
+            Vec::new(),
+        ))
+    }
+
+    /// Request without a body, `path` including the query if any.
+    fn new(
+        method: Method,
+        path: ByteStr,
+        version: HttpVersion,
+        headers: Vec<(ByteStr, ByteStr)>,
+    ) -> Request {
+        let (path, query) = split_query(path);
         Request {
             method,
             path,
+            query,
             version,
             headers,
-            body,
+            body: Bytes::new(),
+            connection: ConnectionInfo::default(),
+            states: StateMap::default(),
+            params: Vec::new(),
+            extensions: Extensions::default(),
+            vary: VaryOn::default(),
         }
     }
 
-    fn parse_start_line<I>(req_buf: &mut RequestBuffer<I>) -> (Method, String, HttpVersion)
+    fn parse_start_line(line: &str) -> Result<(Method, &str, HttpVersion), String> {
+        let mut parts = line.split(' ');
+        let (Some(method), Some(path), Some(version), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(format!("malformed request line {:?}", line));
+        };
+        // Stray CR or LF included, which other parsers may see as line endings
+        if path.is_empty() || path.bytes().any(|b| b.is_ascii_control()) {
+            return Err(format!("invalid request target {:?}", path));
+        }
+
+        Ok((method.parse()?, path, version.parse()?))
+    }
+
+    /// Headers of the `lines` of `head`, sharing its buffer.
+    fn parse_headers<'a>(
+        head: &ByteStr,
+        lines: impl Iterator<Item = &'a str>,
+    ) -> Result<Vec<(ByteStr, ByteStr)>, String> {
+        let mut headers = Vec::<(ByteStr, ByteStr)>::new();
+        for line in lines.take_while(|line| !line.is_empty()) {
+            if line.starts_with([' ', '\t']) {
+                return Err(format!("obsolete line folding {:?}", line));
+            }
+            // Values may contain colons themselves, as in dates
+            let (key, value) = line
+                .split_once(':')
+                .filter(|(key, _)| is_token(key))
+                .ok_or_else(|| format!("malformed header line {:?}", line))?;
+            let value = value.trim_matches([' ', '\t']);
+            if value.bytes().any(|b| matches!(b, b'\r' | b'\n' | b'\0')) {
+                return Err(format!("invalid value of header {}: {:?}", key, value));
+            }
+
+            // Repeating these would leave the length or the target of the request to
+            // guess, which intermediaries may do differently
+            let previous = headers
+                .iter_mut()
+                .find(|(k, _)| k.eq_ignore_ascii_case(key));
+            match previous {
+                Some((_, v)) if key.eq_ignore_ascii_case("Content-Length") && *v != value => {
+                    return Err(format!(
+                        "conflicting Content-Length {:?} and {:?}",
+                        v, value
+                    ));
+                }
+                Some(_) if key.eq_ignore_ascii_case("Host") => {
+                    return Err(String::from("repeated Host header"));
+                }
+                // The last value wins, as it would setting the header
+                Some((_, v)) => *v = head.slice_ref(value),
+                None => headers.push((head.slice_ref(key), head.slice_ref(value))),
+            }
+        }
+        Ok(headers)
+    }
+
+    fn parse_body<I>(req_buf: &mut RequestBuffer<I>, len: usize) -> Vec<u8>
     where
         I: Iterator<Item = u8>,
     {
-        let mut buf = Vec::new();
-        req_buf.read_next_line(&mut buf);
+        // The length comes from the client, the buffer only grows with what was received
+        let mut body = Vec::new();
+        req_buf.read_exact(&mut body, len);
+        body
+    }
+}
+
+/// Splits the query off `path`, both sharing its buffer.
+fn split_query(path: ByteStr) -> (ByteStr, Option<ByteStr>) {
+    match path.split_once('?') {
+        Some((before, query)) => (path.slice_ref(before), Some(path.slice_ref(query))),
+        None => (path, None),
+    }
+}
 
-        let parts = buf.split(|&c| c == b' ').collect::<Vec<_>>();
-        assert_eq!(parts.len(), 3);
+/// Request built piece by piece, see [`Request::builder`].
+#[derive(Debug)]
+pub struct RequestBuilder(Request);
 
-        let method = Method::from(std::str::from_utf8(parts[0]).unwrap());
-        let path = unsafe { String::from_utf8_unchecked(parts[1].to_vec()) };
-        let version = HttpVersion::from(std::str::from_utf8(parts[2]).unwrap());
+impl RequestBuilder {
+    pub fn method(mut self, method: Method) -> Self {
+        self.0.method = method;
+        self
+    }
 
-        (method, path, version)
+    /// Sets the path, along with the query following a `?` if any.
+    pub fn path<P: Into<ByteStr>>(mut self, path: P) -> Self {
+        (self.0.path, self.0.query) = split_query(path.into());
+        self
     }
 
-    fn parse_headers<I>(req_buf: &mut RequestBuffer<I>) -> HashMap<String, String>
+    pub fn version(mut self, version: HttpVersion) -> Self {
+        self.0.version = version;
+        self
+    }
+
+    /// Sets a header, replacing any value set under a differently cased name.
+    pub fn header<K, V>(mut self, key: K, value: V) -> Self
     where
-        I: Iterator<Item = u8>,
+        K: Into<ByteStr>,
+        V: Into<ByteStr>,
     {
-        let mut headers = HashMap::new();
-        let mut buf = Vec::new();
-        while req_buf.read_next_line(&mut buf) > 0 && buf.len() > 2 {
-            let parts = buf.split(|&b| b == b':').collect::<Vec<_>>();
-            assert!(parts.len() >= 2);
-
-            let key = parts[0];
-            let value = parts[1..].concat();
-
-            let key = unsafe { std::str::from_utf8_unchecked(key).trim().to_string() };
-            let value = unsafe { std::str::from_utf8_unchecked(&value).trim().to_string() };
-            headers.insert(key, value);
-            buf.clear();
+        self.0.set_header(key, value);
+        self
+    }
+
+    /// Sets the body, along with its `Content-Length`.
+    pub fn body<B: Into<Bytes>>(mut self, body: B) -> Self {
+        self.0.body = body.into();
+        let len = self.0.body.len().to_string();
+        self.0.set_header("Content-Length", len);
+        self
+    }
+
+    pub fn peer_addr(mut self, addr: SocketAddr) -> Self {
+        self.0.connection.peer_addr = Some(addr);
+        self
+    }
+
+    pub fn build(self) -> Request {
+        self.0
+    }
+}
+
+/// Splits `key=value` pairs separated by `&`, decoding `+` and percent escapes.
+fn parse_urlencoded(input: &[u8]) -> Vec<(String, String)> {
+    input
+        .split(|&b| b == b'&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut parts = pair.splitn(2, |&b| b == b'=');
+            let key = decode_component(parts.next().unwrap_or_default());
+            let value = decode_component(parts.next().unwrap_or_default());
+            (key, value)
+        })
+        .collect()
+}
+
+fn decode_component(input: &[u8]) -> String {
+    let mut decoded = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        match input[i] {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let hex = input
+                    .get(i + 1..i + 3)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match hex {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
         }
-        headers
+        i += 1;
     }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
 
-    fn parse_body<I>(req_buf: &mut RequestBuffer<I>) -> Vec<u8>
-    where
-        I: Iterator<Item = u8>,
-    {
-        let mut body = Vec::new();
-        req_buf.read_to_end(&mut body);
-        body
+/// Lines of a request head, split on CRLF alone: a bare LF or CR is kept in the line
+/// for the parser to refuse.
+struct Lines<'a>(&'a str);
+
+impl<'a> Iterator for Lines<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        if self.0.is_empty() {
+            return None;
+        }
+        let (line, rest) = self.0.split_once("\r\n").unwrap_or((self.0, ""));
+        self.0 = rest;
+        Some(line)
     }
 }
 
@@ -100,38 +531,27 @@ pub struct RequestBuffer<I>
 where
     I: Iterator<Item = u8>,
 {
-    iter: Peekable<I>,
+    iter: I,
 }
 
 impl<I> RequestBuffer<I>
 where
     I: Iterator<Item = u8>,
 {
-    fn read_next_line(&mut self, buf: &mut Vec<u8>) -> usize {
-        let mut i = 0;
-        let mut last_byte = 0;
-        while let Some(&byte) = self.iter.peek() {
-            if byte == b'\n' && last_byte == b'\r' {
-                buf.pop();
-                // Consume the \n
-                self.iter.next();
+    /// Reads up to the empty line ending the head, included, or to the end of the input.
+    fn read_head(&mut self) -> Vec<u8> {
+        let mut head = Vec::with_capacity(self.iter.size_hint().0.min(1024));
+        for byte in self.iter.by_ref() {
+            head.push(byte);
+            if byte == b'\n' && head.ends_with(b"\r\n\r\n") {
                 break;
             }
-            buf.push(byte);
-            self.iter.next();
-            last_byte = byte;
-            i += 1;
         }
-        i
+        head
     }
 
-    fn read_to_end(&mut self, buf: &mut Vec<u8>) {
-        for byte in self.iter.by_ref() {
-            if byte == 0 {
-                break;
-            }
-            buf.push(byte);
-        }
+    fn read_exact(&mut self, buf: &mut Vec<u8>, len: usize) {
+        buf.extend(self.iter.by_ref().take(len));
     }
 }
 
@@ -140,9 +560,7 @@ where
     I: Iterator<Item = u8>,
 {
     fn from(iter: I) -> Self {
-        RequestBuffer {
-            iter: iter.peekable(),
-        }
+        RequestBuffer { iter }
     }
 }
 
@@ -152,39 +570,152 @@ mod tests {
 
     #[test]
     fn test_parse_start_line() {
-        let mut buf = RequestBuffer::from("GET / HTTP/1.1\r\n".bytes());
-        let (method, path, version) = Request::parse_start_line(&mut buf);
+        let (method, path, version) = Request::parse_start_line("GET / HTTP/1.1").unwrap();
         assert_eq!(method, Method::Get);
         assert_eq!(path, "/");
         assert_eq!(version, HttpVersion::V1_1);
+
+        for (line, expected) in [
+            ("PATCH", Method::Patch),
+            ("OPTIONS", Method::Options),
+            ("PROPFIND", Method::Extension(String::from("PROPFIND"))),
+            // Methods are case-sensitive
+            ("get", Method::Extension(String::from("get"))),
+        ] {
+            let line = format!("{} / HTTP/1.1", line);
+            let (method, _, _) = Request::parse_start_line(&line).unwrap();
+            assert_eq!(method, expected);
+        }
     }
 
     #[test]
     fn test_parse_headers() {
-        let mut buf = RequestBuffer::from("Host: localhost\r\nContent-Length: 10\r\n\r\n".bytes());
-        let headers = Request::parse_headers(&mut buf);
-        assert_eq!(headers.get("Host").unwrap(), "localhost");
-        assert_eq!(headers.get("Content-Length").unwrap(), "10");
+        let head = ByteStr::from("Host: localhost:4221\r\nContent-Length: 10\r\n\r\nbody");
+        let headers = Request::parse_headers(&head, Lines(&head)).unwrap();
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers[0], ("Host".into(), "localhost:4221".into()));
+        assert_eq!(headers[1], ("Content-Length".into(), "10".into()));
+
+        // In the order received, repeated ones keeping their last value
+        let head = ByteStr::from("Accept: text/html\r\nX-Id: 1\r\naccept: */*\r\n\r\n");
+        let headers = Request::parse_headers(&head, Lines(&head)).unwrap();
+        assert_eq!(headers[0], ("Accept".into(), "*/*".into()));
+        assert_eq!(headers[1], ("X-Id".into(), "1".into()));
+        assert_eq!(headers.len(), 2);
     }
 
     #[test]
     fn test_parse_body() {
-        let mut buf = RequestBuffer::from("Hello, World!".bytes());
-        let body = Request::parse_body(&mut buf);
+        let mut buf = RequestBuffer::from("Hello, World!GET / HTTP/1.1".bytes());
+        let body = Request::parse_body(&mut buf, 13);
         assert_eq!(body, "Hello, World!".as_bytes());
     }
 
     #[test]
     fn test_parse() {
         let mut buf = RequestBuffer::from(
-            "GET / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 10\r\n\r\nHello, World!".bytes(),
+            "GET / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 13\r\n\r\nHello, World!".bytes(),
         );
-        let req = Request::parse(&mut buf);
-        assert_eq!(req.method(), Method::Get);
+        let req = Request::parse(&mut buf).unwrap();
+        assert_eq!(*req.method(), Method::Get);
         assert_eq!(req.path(), "/");
-        assert_eq!(req.headers().get("Host").unwrap(), "localhost");
-        assert_eq!(req.headers().get("Content-Length").unwrap(), "10");
+        assert_eq!(req.header("Host"), Some("localhost"));
+        assert_eq!(req.header("content-length"), Some("13"));
         assert_eq!(req.body(), "Hello, World!".as_bytes());
         assert_eq!(req.version, HttpVersion::V1_1);
     }
+
+    #[test]
+    fn test_parse_head_in_place() {
+        let head = Bytes::from_static(b"GET /a?b HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let req = Request::parse_head(head.clone()).unwrap();
+        // Slices of the head rather than copies of it
+        let range = head.as_ptr_range();
+        for text in [
+            req.path(),
+            req.query().unwrap(),
+            &req.headers()[0].0,
+            &req.headers()[0].1,
+        ] {
+            assert!(range.contains(&text.as_ptr()), "{:?} was copied", text);
+        }
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for req in [
+            "GET / HTTP/1.1 extra\r\n\r\n",
+            "GE(T / HTTP/1.1\r\n\r\n",
+            "GET / HTTP/2\r\n\r\n",
+            "GET / HTTP/1.1\r\nHost localhost\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: 1 2\r\n\r\n",
+        ] {
+            assert!(Request::parse(&mut RequestBuffer::from(req.bytes())).is_err());
+        }
+        let mut buf = RequestBuffer::from(b"GET /\xff HTTP/1.1\r\n\r\n".iter().copied());
+        assert!(Request::parse(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_query_and_form() {
+        let mut buf = RequestBuffer::from(
+            "POST /search?q=rust+http&tag=a%26b&empty HTTP/1.1\r\n\
+             Content-Type: application/x-www-form-urlencoded\r\nContent-Length: 21\r\n\r\n\
+             name=J%C3%A9r%C3%B4me"
+                .bytes(),
+        );
+        let req = Request::parse(&mut buf).unwrap();
+        assert_eq!(req.path(), "/search");
+        assert_eq!(req.query(), Some("q=rust+http&tag=a%26b&empty"));
+        assert_eq!(
+            req.query_pairs(),
+            vec![
+                ("q".to_string(), "rust http".to_string()),
+                ("tag".to_string(), "a&b".to_string()),
+                ("empty".to_string(), String::new()),
+            ]
+        );
+        assert_eq!(
+            req.form_pairs(),
+            vec![("name".to_string(), "Jérôme".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_builder() {
+        let req = Request::builder()
+            .method(Method::Post)
+            .path("/search?q=rust")
+            .header("content-type", "text/plain")
+            .header("Content-Type", "application/json")
+            .body("{}")
+            .build();
+        assert_eq!(*req.method(), Method::Post);
+        assert_eq!(req.path(), "/search");
+        assert_eq!(req.query(), Some("q=rust"));
+        assert_eq!(req.header("Content-Type"), Some("application/json"));
+        assert_eq!(req.headers().len(), 2);
+        assert_eq!(req.content_length(), 2);
+        assert_eq!(req.body(), b"{}");
+
+        let req = Request::builder().build();
+        assert_eq!((req.method(), req.path()), (&Method::Get, "/"));
+        assert!(req.keep_alive());
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        let mut buf = RequestBuffer::from("GET / HTTP/1.1\r\n\r\n".bytes());
+        assert!(Request::parse(&mut buf).unwrap().keep_alive());
+
+        let mut buf = RequestBuffer::from("GET / HTTP/1.1\r\nConnection: close\r\n\r\n".bytes());
+        assert!(!Request::parse(&mut buf).unwrap().keep_alive());
+
+        let mut buf = RequestBuffer::from("GET / HTTP/1.0\r\n\r\n".bytes());
+        assert!(!Request::parse(&mut buf).unwrap().keep_alive());
+
+        let mut buf =
+            RequestBuffer::from("GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n".bytes());
+        assert!(Request::parse(&mut buf).unwrap().keep_alive());
+    }
 }
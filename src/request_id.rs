@@ -0,0 +1,120 @@
+//! Request identifiers, taken from the `X-Request-Id` header of the request or
+//! generated, and sent back on the response.
+//!
+//! While a request is handled its identifier is also available through
+//! [`current`], and recorded on its `request` span so the events logged on its
+//! behalf can be correlated.
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+
+use tracing::Span;
+
+use super::middleware::{Middleware, Next};
+use super::router::BoxFuture;
+use super::{Request, Response};
+
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Longest identifier accepted from a client.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+tokio::task_local! {
+    static CURRENT: RequestId;
+}
+
+/// Identifier of a request, stored in its extensions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    /// Unique 128-bit identifier in hexadecimal. It is hard enough to guess for
+    /// correlating logs, but does not come from a cryptographic generator and must
+    /// not be used as a secret.
+    pub fn generate() -> Self {
+        RequestId(format!("{:016x}{:016x}", random_u64(), random_u64()))
+    }
+
+    /// Identifiers sent by clients are kept when short and printable, so they cannot
+    /// break log lines or response headers.
+    fn from_client(id: &str) -> Option<Self> {
+        let valid = !id.is_empty()
+            && id.len() <= MAX_REQUEST_ID_LEN
+            && id.bytes().all(|b| b.is_ascii_graphic());
+        valid.then(|| RequestId(id.to_string()))
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Random 64-bit value, unpredictable enough for identifiers but not for secrets.
+pub(crate) fn random_u64() -> u64 {
+    // Every RandomState is seeded differently, which is all the uniqueness needed here
+    RandomState::new().build_hasher().finish()
+}
+
+/// Identifier of the request being handled by the current task, if any.
+pub fn current() -> Option<RequestId> {
+    CURRENT.try_with(RequestId::clone).ok()
+}
+
+/// Middleware assigning the request identifier. It should come first so that the
+/// other middlewares run with the identifier set.
+#[derive(Clone, Copy, Default)]
+pub struct SetRequestId;
+
+impl Middleware for SetRequestId {
+    fn handle(&self, mut req: Request, next: Next) -> BoxFuture<Response> {
+        let id = req
+            .header(REQUEST_ID_HEADER)
+            .and_then(RequestId::from_client)
+            .unwrap_or_else(RequestId::generate);
+        req.set_header(REQUEST_ID_HEADER, id.0.clone());
+        req.extensions_mut().insert(id.clone());
+        Span::current().record("request_id", id.0.as_str());
+
+        Box::pin(CURRENT.scope(id.clone(), async move {
+            let mut res = next.run(req).await;
+            res.header(REQUEST_ID_HEADER, id.0);
+            res
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{middleware, HttpCode};
+
+    async fn send(req: &str) -> (Response, Option<RequestId>) {
+        let res = middleware::send(SetRequestId, req, |req: Request| {
+            let seen = req.extensions().get::<RequestId>().cloned();
+            async move {
+                assert_eq!(current(), seen);
+                Response::from(HttpCode::Ok)
+            }
+        })
+        .await;
+        (res, current())
+    }
+
+    #[tokio::test]
+    async fn test_request_id() {
+        let (res, outside) = send("GET / HTTP/1.1\r\nX-Request-Id: abc-123\r\n\r\n").await;
+        assert_eq!(res.header_value("X-Request-Id"), Some("abc-123"));
+        assert_eq!(outside, None);
+
+        let (res, _) = send("GET / HTTP/1.1\r\nX-Request-Id: has space\r\n\r\n").await;
+        let id = res.header_value("X-Request-Id").unwrap();
+        assert_eq!(id.len(), 32);
+        assert!(id.bytes().all(|b| b.is_ascii_hexdigit()));
+
+        let (other, _) = send("GET / HTTP/1.1\r\n\r\n").await;
+        assert_ne!(other.header_value("X-Request-Id"), Some(id));
+    }
+}
@@ -1,37 +1,357 @@
-use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 
-use super::HttpCode;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::upgrade::{OnUpgrade, UpgradeFn, Upgraded};
+use super::{Extensions, HttpCode, Method};
+
+/// Size of the chunks a streamed body is read and written in.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+pub(crate) type BodyReader = Pin<Box<dyn AsyncRead + Send>>;
 
 #[derive(Clone)]
 pub struct Response {
     code: HttpCode,
     content: Vec<u8>,
-    headers: HashMap<String, String>,
+    stream: Option<BodyStream>,
+    upgrade: Option<OnUpgrade>,
+    /// Headers in the order they are written, a name appearing once per value.
+    headers: Vec<(String, String)>,
+    extensions: Extensions,
+}
+
+/// Body written to the socket as it is read instead of being held in memory. Without
+/// a known length it is sent with the chunked transfer coding.
+///
+/// Clones share the reader, which only the first one written consumes.
+#[derive(Clone)]
+pub struct BodyStream {
+    reader: Arc<Mutex<Option<BodyReader>>>,
+    len: Option<u64>,
+}
+
+impl BodyStream {
+    pub fn new<R>(reader: R, len: Option<u64>) -> Self
+    where
+        R: AsyncRead + Send + 'static,
+    {
+        BodyStream {
+            reader: Arc::new(Mutex::new(Some(Box::pin(reader)))),
+            len,
+        }
+    }
+
+    fn len(&self) -> Option<u64> {
+        self.len
+    }
+
+    fn take(&self) -> Option<BodyReader> {
+        self.reader.lock().unwrap().take()
+    }
+}
+
+impl fmt::Debug for BodyStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BodyStream")
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+/// Types handlers can answer with.
+pub trait IntoResponse {
+    fn into_response(self) -> Response;
 }
 
 impl Response {
+    pub fn code(&self) -> HttpCode {
+        self.code
+    }
+
+    pub fn set_code(&mut self, code: HttpCode) {
+        self.code = code;
+    }
+
+    pub fn content(&self) -> &[u8] {
+        &self.content
+    }
+
     pub fn content_mut(&mut self) -> &mut Vec<u8> {
         &mut self.content
     }
 
+    /// Status code as a number, e.g. `404`.
+    pub fn status(&self) -> u16 {
+        self.code.as_u16()
+    }
+
+    /// Body as text, invalid UTF-8 sequences replaced. Empty for streams.
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.content).into_owned()
+    }
+
+    /// Body deserialized from JSON. Fails for streams.
+    #[cfg(feature = "serde")]
+    pub fn json<T>(&self) -> serde_json::Result<T>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        serde_json::from_slice(&self.content)
+    }
+
+    /// Response streaming its body from `reader`, which yields `len` bytes when known.
+    pub fn stream<R>(reader: R, len: Option<u64>) -> Self
+    where
+        R: AsyncRead + Send + 'static,
+    {
+        let mut res = Response::from(HttpCode::Ok);
+        res.stream = Some(BodyStream::new(reader, len));
+        res
+    }
+
+    /// Whether the body is streamed, in which case [`Response::content`] is empty.
+    pub fn is_stream(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// Length of the body, unknown for streams without one.
+    pub fn body_len(&self) -> Option<u64> {
+        match &self.stream {
+            Some(stream) => stream.len(),
+            None => Some(self.content.len() as u64),
+        }
+    }
+
+    /// Sets a header, replacing any value set under a differently cased name.
     pub fn header<K, V>(&mut self, key: K, value: V)
     where
         K: Into<String>,
         V: Into<String>,
     {
-        self.headers.insert(key.into(), value.into());
+        let key = key.into();
+        self.remove_header(&key);
+        self.headers.push((key, value.into()));
+    }
+
+    /// Adds a value to a header without replacing the ones already set, for headers
+    /// like `Set-Cookie` that cannot be combined into a single line.
+    pub fn append_header<K, V>(&mut self, key: K, value: V)
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.headers.push((key.into(), value.into()));
+    }
+
+    /// Lists `header` in the `Vary` header, unless already there or the response varies
+    /// on everything with `Vary: *`.
+    pub fn add_vary(&mut self, header: &str) {
+        let vary = match self.header_value("Vary") {
+            Some(vary)
+                if vary.split(',').any(|v| {
+                    let v = v.trim();
+                    v == "*" || v.eq_ignore_ascii_case(header)
+                }) =>
+            {
+                return
+            }
+            Some(vary) => format!("{}, {}", vary, header),
+            None => header.to_string(),
+        };
+        self.header("Vary", vary);
+    }
+
+    /// Every header in the order they are written, repeated headers once per value.
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    /// Looks up a header value, ignoring the case of the header name. Only the first
+    /// value of repeated headers is given.
+    pub fn header_value(&self, key: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Removes every value of a header, returning the first one.
+    pub fn remove_header(&mut self, key: &str) -> Option<String> {
+        let mut removed = None;
+        self.headers.retain_mut(|(k, v)| {
+            if !k.eq_ignore_ascii_case(key) {
+                return true;
+            }
+            if removed.is_none() {
+                removed = Some(std::mem::take(v));
+            }
+            false
+        });
+        removed
+    }
+
+    /// Values attached to this response by the router or middleware, never sent.
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
     }
 
-    pub fn into_bytes(mut self) -> Vec<u8> {
-        let mut buf = format!("HTTP/1.1 {}\r\n", self.code).into_bytes();
-        for (key, value) in self.headers {
-            let mut header = format!("{}: {}\r\n", key, value).into_bytes();
-            buf.append(&mut header);
+    /// Has the connection run `callback` once this response, which must be a 101 or a
+    /// 2xx to a CONNECT request, is written. The connection is closed when the callback
+    /// returns. Transports that cannot be handed over answer with a 501 instead.
+    pub fn on_upgrade<F, Fut>(&mut self, callback: F)
+    where
+        F: FnOnce(Upgraded) -> Fut + Send + 'static,
+        Fut: std::future::Future<Output = ()> + Send + 'static,
+    {
+        self.upgrade = Some(OnUpgrade::new(callback));
+    }
+
+    /// Upgrade callback of a 101 response, or of a 2xx one to a CONNECT request.
+    /// Responses handing the connection over are then sent without a body length.
+    pub(crate) fn take_upgrade(&mut self, method: &Method) -> Option<UpgradeFn> {
+        let tunnel = *method == Method::Connect && (200..300).contains(&self.code.as_u16());
+        if self.code != HttpCode::SwitchingProtocols && !tunnel {
+            self.upgrade = None;
         }
-        buf.append(&mut b"\r\n".to_vec());
-        buf.append(&mut self.content);
+        self.upgrade.as_ref()?.take()
+    }
+
+    /// Reader of a streamed body, for transports framing the body on their own. `None`
+    /// when the body is not streamed or the reader was already taken.
+    #[cfg(feature = "http3")]
+    pub(crate) fn take_body_reader(&mut self) -> Option<BodyReader> {
+        self.stream.as_ref()?.take()
+    }
+
+    /// Serialized response, with only the head of streamed ones.
+    pub fn into_bytes(self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.into_parts(&mut buf);
         buf
     }
+
+    /// Writes the whole response, reading a streamed body chunk by chunk.
+    pub async fn write_to<W>(self, out: &mut W) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        self.write_buffered(out, &mut Vec::new()).await
+    }
+
+    /// Same as [`Response::write_to`], serializing through `buf` so that connections
+    /// reuse its allocation from one response to the next.
+    pub(crate) async fn write_buffered<W>(self, out: &mut W, buf: &mut Vec<u8>) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        buf.clear();
+        let stream = self.into_parts(buf);
+        out.write_all(buf).await?;
+        let Some(stream) = stream else {
+            return Ok(());
+        };
+        let Some(mut reader) = stream.take() else {
+            return Err(io::Error::other("response body stream already consumed"));
+        };
+
+        let mut written = 0;
+        loop {
+            buf.clear();
+            buf.reserve(CHUNK_SIZE);
+            let read = reader.read_buf(buf).await?;
+            if read == 0 {
+                break;
+            }
+            match stream.len {
+                Some(_) => out.write_all(buf).await?,
+                // Streams of unknown length are often live, e.g. events
+                None => {
+                    out.write_all(format!("{:x}\r\n", read).as_bytes()).await?;
+                    out.write_all(buf).await?;
+                    out.write_all(b"\r\n").await?;
+                    out.flush().await?;
+                }
+            }
+            written += read as u64;
+        }
+
+        match stream.len {
+            // The client would wait for the missing bytes
+            Some(len) if written != len => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "response body stream ended after {} of {} bytes",
+                    written, len
+                ),
+            )),
+            Some(_) => Ok(()),
+            None => out.write_all(b"0\r\n\r\n").await,
+        }
+    }
+
+    /// Drops the body, keeping the headers framing it, for answers to HEAD requests.
+    pub(crate) fn strip_body(&mut self) {
+        self.frame();
+        self.content.clear();
+        self.stream = None;
+    }
+
+    /// Sets the headers telling where the body ends.
+    fn frame(&mut self) {
+        // Persistent connections rely on the length to find where the next response starts,
+        // 1xx, 204 and 304 responses never have a body, nor those turning the connection
+        // into a tunnel
+        match self.stream.as_ref().map(BodyStream::len) {
+            _ if self.upgrade.is_some()
+                || self.code.as_u16() < 200
+                || matches!(self.code, HttpCode::NoContent | HttpCode::NotModified) => {}
+            Some(None) => {
+                self.remove_header("Content-Length");
+                self.header("Transfer-Encoding", "chunked");
+            }
+            _ if self.header_value("Content-Length").is_some()
+                || self.header_value("Transfer-Encoding").is_some() => {}
+            Some(Some(len)) => self.header("Content-Length", len.to_string()),
+            None => self.header("Content-Length", self.content.len().to_string()),
+        }
+    }
+
+    /// Appends the head to `buf`, followed by the body unless it is streamed.
+    fn into_parts(mut self, buf: &mut Vec<u8>) -> Option<BodyStream> {
+        self.frame();
+
+        // Writing to a Vec cannot fail
+        let _ = write!(buf, "HTTP/1.1 {}\r\n", self.code);
+        for (key, value) in &self.headers {
+            buf.extend_from_slice(key.as_bytes());
+            buf.extend_from_slice(b": ");
+            buf.extend_from_slice(value.as_bytes());
+            buf.extend_from_slice(b"\r\n");
+        }
+        buf.extend_from_slice(b"\r\n");
+        buf.extend_from_slice(&self.content);
+        self.stream
+    }
+}
+
+impl IntoResponse for Response {
+    fn into_response(self) -> Response {
+        self
+    }
+}
+
+impl IntoResponse for HttpCode {
+    fn into_response(self) -> Response {
+        Response::from(self)
+    }
 }
 
 impl From<HttpCode> for Response {
@@ -39,7 +359,10 @@ impl From<HttpCode> for Response {
         Response {
             code,
             content: Vec::new(),
-            headers: HashMap::new(),
+            stream: None,
+            upgrade: None,
+            headers: Vec::new(),
+            extensions: Extensions::default(),
         }
     }
 }
@@ -52,7 +375,169 @@ where
         Response {
             code: HttpCode::Ok,
             content: value.into(),
-            headers: HashMap::new(),
+            stream: None,
+            upgrade: None,
+            headers: Vec::new(),
+            extensions: Extensions::default(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_vary() {
+        let mut res = Response::from(HttpCode::Ok);
+        res.add_vary("Accept");
+        res.add_vary("Accept-Encoding");
+        res.add_vary("accept");
+        assert_eq!(res.header_value("Vary"), Some("Accept, Accept-Encoding"));
+
+        res.header("Vary", "*");
+        res.add_vary("Origin");
+        assert_eq!(res.header_value("Vary"), Some("*"));
+    }
+
+    #[test]
+    fn test_assertions() {
+        let res = Response::from(HttpCode::NotFound);
+        assert_eq!(res.status(), 404);
+
+        let res = Response::from("{\"name\":\"Ferris\"}");
+        assert_eq!(res.text(), "{\"name\":\"Ferris\"}");
+        #[cfg(feature = "serde")]
+        {
+            let body = res.json::<serde_json::Value>().unwrap();
+            assert_eq!(body["name"], "Ferris");
+            assert!(Response::from("nope").json::<serde_json::Value>().is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream() {
+        let mut out = Vec::new();
+        let res = Response::stream(&b"hello world"[..], Some(11));
+        res.write_to(&mut out).await.unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("Content-Length: 11\r\n"));
+        assert!(out.ends_with("\r\n\r\nhello world"));
+
+        let mut out = Vec::new();
+        let res = Response::stream(&b"hello world"[..], None);
+        res.clone().write_to(&mut out).await.unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(!out.contains("Content-Length"));
+        assert!(out.ends_with("\r\n\r\nb\r\nhello world\r\n0\r\n\r\n"));
+        // The clone consumed the shared reader
+        assert!(res.write_to(&mut Vec::new()).await.is_err());
+
+        let res = Response::stream(&b"short"[..], Some(10));
+        assert!(res.write_to(&mut Vec::new()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_write_buffered() {
+        let mut buf = b"left over".to_vec();
+        let streamed = || Response::stream(&b"hello world"[..], None);
+        let mut out = Vec::new();
+        streamed().write_to(&mut out).await.unwrap();
+        let mut reused = Vec::new();
+        streamed()
+            .write_buffered(&mut reused, &mut buf)
+            .await
+            .unwrap();
+        assert_eq!(reused, out);
+
+        let capacity = buf.capacity();
+        let res = Response::from("hello");
+        let mut reused = Vec::new();
+        res.clone()
+            .write_buffered(&mut reused, &mut buf)
+            .await
+            .unwrap();
+        assert_eq!(reused, res.into_bytes());
+        assert_eq!(buf.capacity(), capacity);
+    }
+
+    /// Response as sent, line endings spelled out so that a lost `\r` shows.
+    fn wire(bytes: Vec<u8>) -> String {
+        String::from_utf8(bytes)
+            .unwrap()
+            .replace("\r\n", "\\r\\n\n")
+    }
+
+    #[test]
+    fn test_wire_format() {
+        let mut res = Response::from("hello");
+        res.header("Content-Type", "text/plain");
+        insta::assert_snapshot!(wire(res.into_bytes()), @r"
+        HTTP/1.1 200 OK\r\n
+        Content-Type: text/plain\r\n
+        Content-Length: 5\r\n
+        \r\n
+        hello
+        ");
+
+        insta::assert_snapshot!(wire(Response::from(HttpCode::NotFound).into_bytes()), @r"
+        HTTP/1.1 404 Not Found\r\n
+        Content-Length: 0\r\n
+        \r\n
+        ");
+
+        // Never a body, nor a length
+        let mut res = Response::from(HttpCode::NoContent);
+        res.header("X-Request-Id", "42");
+        insta::assert_snapshot!(wire(res.into_bytes()), @r"
+        HTTP/1.1 204 No Content\r\n
+        X-Request-Id: 42\r\n
+        \r\n
+        ");
+        let mut res = Response::from(HttpCode::NotModified);
+        res.header("ETag", "\"v1\"");
+        insta::assert_snapshot!(wire(res.into_bytes()), @r#"
+        HTTP/1.1 304 Not Modified\r\n
+        ETag: "v1"\r\n
+        \r\n
+        "#);
+    }
+
+    #[tokio::test]
+    async fn test_wire_format_chunked() {
+        let mut res = Response::stream(&b"hello world"[..], None);
+        res.header("Content-Type", "text/event-stream");
+        res.header("Content-Length", "11");
+        let mut out = Vec::new();
+        res.write_to(&mut out).await.unwrap();
+        insta::assert_snapshot!(wire(out), @r"
+        HTTP/1.1 200 OK\r\n
+        Content-Type: text/event-stream\r\n
+        Transfer-Encoding: chunked\r\n
+        \r\n
+        b\r\n
+        hello world\r\n
+        0\r\n
+        \r\n
+        ");
+    }
+
+    #[test]
+    fn test_wire_format_cookies() {
+        let mut res = Response::from(HttpCode::MovedPermanently);
+        res.header("Location", "/");
+        res.append_header("Set-Cookie", "session=abc; Path=/; HttpOnly");
+        res.append_header("Set-Cookie", "theme=dark; Path=/");
+        res.header("Cache-Control", "no-store");
+        insta::assert_snapshot!(wire(res.into_bytes()), @r"
+        HTTP/1.1 301 Moved Permanently\r\n
+        Location: /\r\n
+        Set-Cookie: session=abc; Path=/; HttpOnly\r\n
+        Set-Cookie: theme=dark; Path=/\r\n
+        Cache-Control: no-store\r\n
+        Content-Length: 0\r\n
+        \r\n
+        ");
+    }
+}
@@ -0,0 +1,64 @@
+//! Zero-downtime restarts: on `SIGUSR2` the listening sockets are handed over to a
+//! freshly executed copy of the server, while the current process drains its
+//! connections and exits.
+
+use std::env;
+use std::io;
+use std::net::TcpListener;
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+use itertools::Itertools;
+
+/// Comma separated list of the listening socket descriptors inherited from the
+/// previous process.
+pub const LISTEN_FDS_ENV: &str = "HTTP_SERVER_LISTEN_FDS";
+
+/// Takes over the listeners passed by the previous process, if any.
+pub fn inherited_listeners() -> io::Result<Option<Vec<TcpListener>>> {
+    let Ok(fds) = env::var(LISTEN_FDS_ENV) else {
+        return Ok(None);
+    };
+    env::remove_var(LISTEN_FDS_ENV);
+
+    fds.split(',')
+        .map(|fd| {
+            let fd = fd.parse::<RawFd>().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid {} entry: {}", LISTEN_FDS_ENV, fd),
+                )
+            })?;
+            set_cloexec(fd, true)?;
+
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true)?;
+            Ok(listener)
+        })
+        .collect::<io::Result<Vec<_>>>()
+        .map(Some)
+}
+
+/// Re-executes the current binary with the same arguments, letting it inherit `fds`.
+/// Returns the pid of the new process.
+pub fn spawn_successor(fds: &[RawFd]) -> io::Result<u32> {
+    let inherited = fds.to_vec();
+
+    let mut cmd = Command::new(env::current_exe()?);
+    cmd.args(env::args_os().skip(1))
+        .env(LISTEN_FDS_ENV, fds.iter().join(","));
+    unsafe {
+        cmd.pre_exec(move || inherited.iter().try_for_each(|&fd| set_cloexec(fd, false)));
+    }
+
+    cmd.spawn().map(|child| child.id())
+}
+
+fn set_cloexec(fd: RawFd, cloexec: bool) -> io::Result<()> {
+    let flags = if cloexec { libc::FD_CLOEXEC } else { 0 };
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, flags) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -1,10 +1,110 @@
-use super::{HttpCode, Method, Request, Response};
+use std::any::Any;
+use std::future::{self, Future};
+use std::marker::PhantomData;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::thread;
 
-type Handler = fn(Request) -> Response;
+use tracing::{debug, error, Span};
+
+use super::middleware::{self, Middleware, Middlewares, Next};
+#[cfg(feature = "tower")]
+use super::service::{self, HandlerService};
+use super::{AppError, HttpCode, IntoResponse, Method, Request, Response};
+
+pub type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+pub type HandlerResult = Result<Response, AppError>;
+
+pub(crate) type BoxedHandler = Arc<dyn Fn(Request) -> BoxFuture<HandlerResult> + Send + Sync>;
+
+type ErrorHandler = Arc<dyn Fn(AppError) -> Response + Send + Sync>;
+
+/// Function or closure answering a request, either synchronously or asynchronously
+/// by returning a future. `T` only tells both implementations apart.
+///
+/// Handlers answer with anything implementing [`IntoResponse`], or with a `Result`
+/// whose error is turned into a response by the router's error handler.
+///
+/// Instead of the whole [`Request`], handlers may take up to four
+/// [`FromRequest`](crate::extract::FromRequest) extractors. A failing extraction is answered through the error handler without
+/// calling the handler.
+///
+/// Closures may capture configuration, which is shared by every request they handle.
+pub trait Handler<T>: Send + Sync + 'static {
+    fn call(&self, req: Request) -> BoxFuture<HandlerResult>;
+}
+
+/// Marker for handlers answering synchronously with `R`.
+pub struct Ready<R>(PhantomData<R>);
+
+impl<F, R> Handler<Ready<R>> for F
+where
+    F: Fn(Request) -> R + Send + Sync + 'static,
+    R: HandlerOutput,
+{
+    fn call(&self, req: Request) -> BoxFuture<HandlerResult> {
+        Box::pin(future::ready(self(req).into_result()))
+    }
+}
+
+impl<F, Fut> Handler<Fut> for F
+where
+    F: Fn(Request) -> Fut + Send + Sync + 'static,
+    Fut: Future + Send + 'static,
+    Fut::Output: HandlerOutput,
+{
+    fn call(&self, req: Request) -> BoxFuture<HandlerResult> {
+        let fut = self(req);
+        Box::pin(async move { fut.await.into_result() })
+    }
+}
+
+/// Value returned by a handler.
+pub trait HandlerOutput {
+    fn into_result(self) -> HandlerResult;
+}
+
+impl<R> HandlerOutput for R
+where
+    R: IntoResponse,
+{
+    fn into_result(self) -> HandlerResult {
+        Ok(self.into_response())
+    }
+}
+
+impl<R, E> HandlerOutput for Result<R, E>
+where
+    R: IntoResponse,
+    E: Into<AppError>,
+{
+    fn into_result(self) -> HandlerResult {
+        self.map(IntoResponse::into_response).map_err(Into::into)
+    }
+}
+
+/// Path pattern of the route a request matched, as given to the route. It is in the
+/// extensions of both the request and the response, so outer middlewares can group
+/// requests by route rather than by path, and recorded as the `route` field of the
+/// request span.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MatchedPath(pub String);
+
+/// Description of a route, as listed by [`Router::routes`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteInfo {
+    pub methods: Vec<Method>,
+    pub path: String,
+    pub compare_path: ComparePath,
+}
 
 #[derive(Default, Clone)]
 pub struct Router {
     routes: Vec<Route>,
+    error_handler: Option<ErrorHandler>,
 }
 
 impl Router {
@@ -12,61 +112,516 @@ impl Router {
         self.routes.push(route);
     }
 
-    pub fn route(&self, req: Request) -> Response {
-        let mut response = Response::from(HttpCode::NotFound);
+    /// Adds every route, typically collected with `routes![]`.
+    pub fn add_routes<I>(&mut self, routes: I)
+    where
+        I: IntoIterator<Item = Route>,
+    {
+        self.routes.extend(routes);
+    }
+
+    /// Wraps the handlers of the routes added so far in a tower layer.
+    #[cfg(feature = "tower")]
+    pub fn layer<L>(mut self, layer: L) -> Self
+    where
+        L: tower::Layer<HandlerService>,
+        L::Service: tower::Service<Request, Response = Response> + Clone + Send + Sync + 'static,
+        <L::Service as tower::Service<Request>>::Error: Into<tower::BoxError>,
+        <L::Service as tower::Service<Request>>::Future: Send,
+    {
+        self.routes = self
+            .routes
+            .into_iter()
+            .map(|route| route.layer(&layer))
+            .collect();
+        self
+    }
+
+    /// Replaces the mapping of handler errors to responses, which defaults to the
+    /// status code of the error.
+    pub fn set_error_handler<F>(&mut self, error_handler: F)
+    where
+        F: Fn(AppError) -> Response + Send + Sync + 'static,
+    {
+        self.error_handler = Some(Arc::new(error_handler));
+    }
+
+    /// Routes in the order they are matched.
+    pub fn routes(&self) -> Vec<RouteInfo> {
+        self.routes
+            .iter()
+            .map(|route| RouteInfo {
+                methods: route.methods.clone(),
+                path: route.path.clone(),
+                compare_path: route.compare_path,
+            })
+            .collect()
+    }
+
+    /// Body limit of the route matching `req`, as set by its middlewares.
+    pub fn body_limit(&self, req: &Request) -> Option<usize> {
+        let (route, _) = self.find(req)?;
+        middleware::body_limit(&route.middlewares)
+    }
 
-        if let Some(route) = self.routes.iter().find(|route| route.matches(&req)) {
-            response = (route.handler)(req);
+    /// Route matching `req` along with the parameters it captures. HEAD requests no
+    /// route accepts go to the GET route for their path, as RFC 9110 section 9.3.2 has
+    /// them answered the same way.
+    fn find(&self, req: &Request) -> Option<(&Route, Vec<(String, String)>)> {
+        let find = |method: &Method| {
+            self.routes
+                .iter()
+                .find_map(|route| route.matches(method, req).map(|params| (route, params)))
+        };
+        match req.method() {
+            Method::Head => find(&Method::Head).or_else(|| find(&Method::Get)),
+            method => find(method),
         }
+    }
+
+    pub async fn route(&self, mut req: Request) -> Response {
+        let Some((route, params)) = self.find(&req) else {
+            // Methods the server knows nothing about, rather than known ones no route
+            // accepts on this path
+            let unknown = matches!(req.method(), Method::Extension(_))
+                && !self.routes.iter().any(|r| r.methods.contains(req.method()));
+            debug!("No route for {} {}", req.method().as_str(), req.path());
+            return match unknown {
+                true => Response::from(HttpCode::NotImplemented),
+                false => Response::from(HttpCode::NotFound),
+            };
+        };
+        debug!(
+            route = route.path.as_str(),
+            params = params.len(),
+            "Matched route"
+        );
+        req.set_params(params);
+        Span::current().record("route", route.path.as_str());
+        let matched = MatchedPath(route.path.clone());
+        req.extensions_mut().insert(matched.clone());
+
+        let mut res = if route.middlewares.is_empty() {
+            respond(&route.handler, self.error_handler.as_ref(), req).await
+        } else {
+            let handler = route.handler.clone();
+            let error_handler = self.error_handler.clone();
+            let endpoint = move |req| -> BoxFuture<Response> {
+                let handler = handler.clone();
+                let error_handler = error_handler.clone();
+                Box::pin(async move { respond(&handler, error_handler.as_ref(), req).await })
+            };
+            Next::new(route.middlewares.clone(), endpoint)
+                .run(req)
+                .await
+        };
+        res.extensions_mut().insert(matched);
+        res
+    }
+}
+
+/// Calls the handler, mapping its error to a response, which varies on the request
+/// headers the handler recorded.
+async fn respond(
+    handler: &BoxedHandler,
+    error_handler: Option<&ErrorHandler>,
+    req: Request,
+) -> Response {
+    let vary = req.varied_on();
+    let mut res = match Route::call(handler, req).await {
+        Ok(response) => response,
+        Err(err) => match error_handler {
+            Some(error_handler) => error_handler(err),
+            None => Response::from(err),
+        },
+    };
+    vary.apply(&mut res);
+    res
+}
+
+/// Router shared by every connection, which can be atomically replaced while the
+/// server is running. Requests already being handled keep the router they started with.
+#[derive(Clone, Default)]
+pub struct SharedRouter {
+    inner: Arc<RwLock<Arc<Router>>>,
+}
+
+impl SharedRouter {
+    pub fn load(&self) -> Arc<Router> {
+        self.inner.read().unwrap().clone()
+    }
+
+    pub fn store(&self, router: Router) {
+        *self.inner.write().unwrap() = Arc::new(router);
+    }
+}
 
-        response
+impl From<Router> for SharedRouter {
+    fn from(router: Router) -> Self {
+        SharedRouter {
+            inner: Arc::new(RwLock::new(Arc::new(router))),
+        }
     }
 }
 
 #[derive(Clone)]
 pub struct Route {
     path: String,
-    handler: Box<Handler>,
+    handler: BoxedHandler,
     compare_path: ComparePath,
     methods: Vec<Method>,
+    middlewares: Middlewares,
 }
 
 impl Route {
-    fn matches(&self, req: &Request) -> bool {
-        let path_bool = match self.compare_path {
-            ComparePath::Exact => self.path == req.path(),
-            ComparePath::Prefix => req.path().starts_with(&self.path),
+    /// Runs the handler, turning a panic into a 500 so the connection still gets a reply.
+    async fn call(handler: &BoxedHandler, req: Request) -> HandlerResult {
+        let method = req.method().clone();
+        let path = req.path().to_string();
+
+        let response = match panic::catch_unwind(AssertUnwindSafe(|| handler(req))) {
+            Ok(fut) => CatchUnwind(fut).await,
+            Err(e) => Err(e),
         };
-        path_bool && self.methods.contains(&req.method())
+
+        match response {
+            Ok(result) => result,
+            Err(e) => {
+                error!(
+                    "Handler for {} {} panicked: {}",
+                    method.as_str(),
+                    path,
+                    panic_message(&*e)
+                );
+                Ok(Response::from(HttpCode::InternalServerError))
+            }
+        }
     }
 
-    pub fn get<S>(path: S, handler: Handler, compare_path: ComparePath) -> Self
+    /// Returns the parameters captured from the request path when the route matches,
+    /// `method` standing for the method of the request.
+    fn matches(&self, method: &Method, req: &Request) -> Option<Vec<(String, String)>> {
+        if !self.methods.contains(method) {
+            return None;
+        }
+        if !self.path.contains('{') {
+            let path_bool = match self.compare_path {
+                ComparePath::Exact => self.path == req.path(),
+                ComparePath::Prefix => req.path().starts_with(&self.path),
+            };
+            return path_bool.then(Vec::new);
+        }
+
+        // Patterns are matched segment by segment, `{name}` capturing a whole segment
+        let mut segments = req.path().split('/');
+        let mut params = Vec::new();
+        for pattern in self.path.split('/') {
+            let segment = segments.next()?;
+            match pattern.strip_prefix('{').and_then(|p| p.strip_suffix('}')) {
+                Some(name) if !segment.is_empty() => {
+                    params.push((name.to_string(), segment.to_string()))
+                }
+                None if pattern == segment => {}
+                _ => return None,
+            }
+        }
+
+        match self.compare_path {
+            ComparePath::Exact if segments.next().is_some() => None,
+            _ => Some(params),
+        }
+    }
+
+    /// Wraps the handler in a tower layer.
+    #[cfg(feature = "tower")]
+    pub fn layer<L>(mut self, layer: L) -> Self
+    where
+        L: tower::Layer<HandlerService>,
+        L::Service: tower::Service<Request, Response = Response> + Clone + Send + Sync + 'static,
+        <L::Service as tower::Service<Request>>::Error: Into<tower::BoxError>,
+        <L::Service as tower::Service<Request>>::Future: Send,
+    {
+        self.handler = service::into_handler(layer.layer(HandlerService(self.handler)));
+        self
+    }
+
+    pub fn new<S, H, T>(method: Method, path: S, handler: H, compare_path: ComparePath) -> Self
     where
         S: Into<String>,
+        H: Handler<T>,
     {
         Route {
             path: path.into(),
-            handler: Box::new(handler),
+            handler: Arc::new(move |req| handler.call(req)),
             compare_path,
-            methods: vec![Method::Get],
+            methods: vec![method],
+            middlewares: Middlewares::from([]),
         }
     }
 
-    pub fn post<S>(path: S, handler: Handler, compare_path: ComparePath) -> Self
+    /// Runs `middleware` around this route's handler, inside the middlewares added
+    /// before it and the server ones.
+    pub fn with_middleware<M>(mut self, middleware: M) -> Self
+    where
+        M: Middleware,
+    {
+        self.middlewares = middleware::push(&self.middlewares, middleware);
+        self
+    }
+
+    pub fn get<S, H, T>(path: S, handler: H, compare_path: ComparePath) -> Self
     where
         S: Into<String>,
+        H: Handler<T>,
     {
-        Route {
-            path: path.into(),
-            handler: Box::new(handler),
-            compare_path,
-            methods: vec![Method::Post],
-        }
+        Route::new(Method::Get, path, handler, compare_path)
+    }
+
+    pub fn post<S, H, T>(path: S, handler: H, compare_path: ComparePath) -> Self
+    where
+        S: Into<String>,
+        H: Handler<T>,
+    {
+        Route::new(Method::Post, path, handler, compare_path)
+    }
+
+    pub fn put<S, H, T>(path: S, handler: H, compare_path: ComparePath) -> Self
+    where
+        S: Into<String>,
+        H: Handler<T>,
+    {
+        Route::new(Method::Put, path, handler, compare_path)
+    }
+
+    pub fn delete<S, H, T>(path: S, handler: H, compare_path: ComparePath) -> Self
+    where
+        S: Into<String>,
+        H: Handler<T>,
+    {
+        Route::new(Method::Delete, path, handler, compare_path)
+    }
+
+    pub fn patch<S, H, T>(path: S, handler: H, compare_path: ComparePath) -> Self
+    where
+        S: Into<String>,
+        H: Handler<T>,
+    {
+        Route::new(Method::Patch, path, handler, compare_path)
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ComparePath {
     Exact,
     Prefix,
 }
+
+/// Resolves to the output of the wrapped future, or to the payload of the panic it
+/// raised while being polled.
+struct CatchUnwind<F>(F);
+
+impl<F> Future for CatchUnwind<F>
+where
+    F: Future + Unpin,
+{
+    type Output = thread::Result<F::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let fut = &mut self.0;
+        match panic::catch_unwind(AssertUnwindSafe(|| Pin::new(fut).poll(cx))) {
+            Ok(Poll::Pending) => Poll::Pending,
+            Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg
+    } else {
+        "unknown panic payload"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RequestBuffer;
+
+    fn panic_handler(_req: Request) -> Response {
+        panic!("handler failure")
+    }
+
+    async fn async_panic_handler(_req: Request) -> Response {
+        tokio::task::yield_now().await;
+        panic!("handler failure")
+    }
+
+    async fn async_handler(req: Request) -> Response {
+        tokio::task::yield_now().await;
+        Response::from(req.path().to_string())
+    }
+
+    fn parse_handler(req: Request) -> Result<Response, AppError> {
+        let n = req.path().trim_start_matches('/').parse::<u32>()?;
+        Ok(Response::from((n * 2).to_string()))
+    }
+
+    fn get(path: &str) -> Request {
+        let req = format!("GET {} HTTP/1.1\r\n\r\n", path);
+        Request::parse(&mut RequestBuffer::from(req.bytes())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_panicking_handler() {
+        let mut router = Router::default();
+        router.add_route(Route::get("/panic", panic_handler, ComparePath::Exact));
+        router.add_route(Route::get(
+            "/async-panic",
+            async_panic_handler,
+            ComparePath::Exact,
+        ));
+
+        for path in ["/panic", "/async-panic"] {
+            let res = router.route(get(path)).await.into_bytes();
+            assert!(res.starts_with(b"HTTP/1.1 500 Internal Server Error\r\n"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_async_handler() {
+        let mut router = Router::default();
+        router.add_route(Route::get("/async", async_handler, ComparePath::Exact));
+
+        let res = router.route(get("/async")).await.into_bytes();
+        assert!(res.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(res.ends_with(b"\r\n\r\n/async"));
+    }
+
+    #[tokio::test]
+    async fn test_closure_handler() {
+        let greeting = String::from("Hello");
+
+        let mut router = Router::default();
+        router.add_route(Route::get(
+            "/greet",
+            move |req: Request| Response::from(format!("{} from {}", greeting, req.path())),
+            ComparePath::Exact,
+        ));
+
+        let res = router.route(get("/greet")).await.into_bytes();
+        assert!(res.ends_with(b"\r\n\r\nHello from /greet"));
+    }
+
+    #[tokio::test]
+    async fn test_fallible_handler() {
+        let mut router = Router::default();
+        router.add_route(Route::get("/", parse_handler, ComparePath::Prefix));
+
+        let res = router.route(get("/21")).await.into_bytes();
+        assert!(res.ends_with(b"\r\n\r\n42"));
+        let res = router.route(get("/abc")).await.into_bytes();
+        assert!(res.starts_with(b"HTTP/1.1 400 Bad Request\r\n"));
+
+        router.set_error_handler(|err| Response::from(format!("oops: {}", err)));
+        let res = router.route(get("/abc")).await.into_bytes();
+        assert!(res.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(res.ends_with(b"oops: Bad request: invalid digit found in string"));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_method() {
+        let mut router = Router::default();
+        router.add_route(Route::get("/", async_handler, ComparePath::Prefix));
+        router.add_route(Route::new(
+            Method::Extension(String::from("PROPFIND")),
+            "/dav",
+            |_: Request| Response::from(HttpCode::Ok),
+            ComparePath::Prefix,
+        ));
+
+        let request = |line: &str| {
+            let req = format!("{} HTTP/1.1\r\n\r\n", line);
+            Request::parse(&mut RequestBuffer::from(req.bytes())).unwrap()
+        };
+        let res = router.route(request("PROPFIND /dav/a")).await;
+        assert_eq!(res.code(), HttpCode::Ok);
+        let res = router.route(request("PROPFIND /missing")).await;
+        assert_eq!(res.code(), HttpCode::NotFound);
+        let res = router.route(request("BREW /pot")).await;
+        assert_eq!(res.code(), HttpCode::NotImplemented);
+        let res = router.route(request("PATCH /a")).await;
+        assert_eq!(res.code(), HttpCode::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_head_falls_back_to_get() {
+        let mut router = Router::default();
+        router.add_route(Route::get("/", async_handler, ComparePath::Prefix));
+        router.add_route(Route::new(
+            Method::Head,
+            "/head",
+            |_: Request| Response::from("head"),
+            ComparePath::Exact,
+        ));
+
+        let head = |path: &str| {
+            let req = format!("HEAD {} HTTP/1.1\r\n\r\n", path);
+            Request::parse(&mut RequestBuffer::from(req.bytes())).unwrap()
+        };
+        let res = router.route(head("/a")).await;
+        assert_eq!(res.code(), HttpCode::Ok);
+        assert_eq!(res.content(), b"/a");
+        // Routes for HEAD itself come first, whatever their order
+        assert_eq!(router.route(head("/head")).await.content(), b"head");
+        // Only GET routes stand in for them
+        let mut router = Router::default();
+        router.add_route(Route::post("/post", async_handler, ComparePath::Exact));
+        assert_eq!(router.route(head("/post")).await.code(), HttpCode::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_matched_path() {
+        let mut router = Router::default();
+        router.add_route(Route::get(
+            "/echo/{msg}",
+            |req: Request| Response::from(req.matched_path().unwrap_or_default().to_string()),
+            ComparePath::Exact,
+        ));
+
+        let req = Request::parse(&mut RequestBuffer::from(
+            "GET /echo/abc HTTP/1.1\r\n\r\n".bytes(),
+        ))
+        .unwrap();
+        let res = router.route(req).await;
+        assert_eq!(res.content(), b"/echo/{msg}");
+        assert_eq!(
+            res.extensions().get::<MatchedPath>(),
+            Some(&MatchedPath(String::from("/echo/{msg}")))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shared_router_swap() {
+        let shared = SharedRouter::from(Router::default());
+        let before = shared.load();
+        // Connections share the routes rather than a copy of them
+        assert!(Arc::ptr_eq(&shared.clone().load(), &before));
+
+        let mut router = Router::default();
+        router.add_route(Route::get(
+            "/",
+            |_: Request| Response::from(HttpCode::Ok),
+            ComparePath::Exact,
+        ));
+        shared.store(router);
+
+        let res = before.route(get("/")).await.into_bytes();
+        assert!(res.starts_with(b"HTTP/1.1 404"));
+        let res = shared.load().route(get("/")).await.into_bytes();
+        assert!(res.starts_with(b"HTTP/1.1 200"));
+    }
+}
@@ -0,0 +1,128 @@
+//! Hardening headers added to every response.
+
+use super::middleware::{Middleware, Next};
+use super::router::BoxFuture;
+use super::{Request, Response};
+
+const HSTS: &str = "Strict-Transport-Security";
+
+/// Middleware adding security headers to responses, without replacing the ones set
+/// by handlers. `Strict-Transport-Security` is only sent over TLS, browsers ignore
+/// it otherwise.
+#[derive(Debug, Clone)]
+pub struct SecurityHeaders {
+    headers: Vec<(&'static str, String)>,
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        SecurityHeaders {
+            headers: vec![
+                (HSTS, "max-age=31536000; includeSubDomains".to_string()),
+                ("X-Content-Type-Options", "nosniff".to_string()),
+                ("X-Frame-Options", "DENY".to_string()),
+                (
+                    "Referrer-Policy",
+                    "strict-origin-when-cross-origin".to_string(),
+                ),
+                ("Content-Security-Policy", "default-src 'self'".to_string()),
+            ],
+        }
+    }
+}
+
+impl SecurityHeaders {
+    /// Sets the value of a header, adding it if it is not one of the defaults.
+    pub fn with_header<V>(mut self, name: &'static str, value: V) -> Self
+    where
+        V: Into<String>,
+    {
+        self = self.without(name);
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    /// Stops sending a header.
+    pub fn without(mut self, name: &str) -> Self {
+        self.headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        self
+    }
+
+    pub fn with_hsts<V: Into<String>>(self, value: V) -> Self {
+        self.with_header(HSTS, value)
+    }
+
+    pub fn with_frame_options<V: Into<String>>(self, value: V) -> Self {
+        self.with_header("X-Frame-Options", value)
+    }
+
+    pub fn with_referrer_policy<V: Into<String>>(self, value: V) -> Self {
+        self.with_header("Referrer-Policy", value)
+    }
+
+    pub fn with_content_security_policy<V: Into<String>>(self, value: V) -> Self {
+        self.with_header("Content-Security-Policy", value)
+    }
+}
+
+impl Middleware for SecurityHeaders {
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<Response> {
+        let secure = req.is_secure();
+        let headers = self.headers.clone();
+
+        Box::pin(async move {
+            let mut res = next.run(req).await;
+            for (name, value) in headers {
+                if (secure || name != HSTS) && res.header_value(name).is_none() {
+                    res.header(name, value);
+                }
+            }
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{middleware, ConnectionInfo, RequestBuffer};
+
+    async fn send(headers: SecurityHeaders, tls: bool) -> Response {
+        let mut req =
+            Request::parse(&mut RequestBuffer::from("GET / HTTP/1.1\r\n\r\n".bytes())).unwrap();
+        req.set_connection_info(ConnectionInfo {
+            tls,
+            ..req.connection_info()
+        });
+        middleware::send_request(headers, req, |_| async {
+            let mut res = Response::from("hello");
+            res.header("X-Frame-Options", "SAMEORIGIN");
+            res
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_security_headers() {
+        let res = send(SecurityHeaders::default(), false).await;
+        assert_eq!(res.header_value("X-Content-Type-Options"), Some("nosniff"));
+        assert_eq!(res.header_value("X-Frame-Options"), Some("SAMEORIGIN"));
+        assert_eq!(
+            res.header_value("Content-Security-Policy"),
+            Some("default-src 'self'")
+        );
+        assert_eq!(res.header_value(HSTS), None);
+
+        let headers = SecurityHeaders::default()
+            .with_hsts("max-age=60")
+            .with_content_security_policy("default-src 'none'")
+            .without("referrer-policy");
+        let res = send(headers, true).await;
+        assert_eq!(res.header_value(HSTS), Some("max-age=60"));
+        assert_eq!(
+            res.header_value("Content-Security-Policy"),
+            Some("default-src 'none'")
+        );
+        assert_eq!(res.header_value("Referrer-Policy"), None);
+    }
+}
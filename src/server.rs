@@ -0,0 +1,790 @@
+use std::io;
+use std::net::{SocketAddr, TcpListener as StdTcpListener};
+#[cfg(unix)]
+use std::os::fd::{AsRawFd, RawFd};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use socket2::{Domain, SockRef, Socket, Type};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Builder;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::JoinSet;
+use tracing::{error, info, warn};
+
+use super::debug::{self, json_millis, json_string, DebugEndpoint, DebugReport};
+#[cfg(feature = "http3")]
+use super::http3::{self, AltSvc, Http3, Http3Context};
+use super::limit::{IpGuard, IpLimiter, LimitAction};
+use super::metrics::RequestMetrics;
+use super::middleware::{self, Middleware, Middlewares};
+#[cfg(feature = "metrics")]
+use super::prometheus::MetricsEndpoint;
+#[cfg(unix)]
+use super::restart;
+use super::shutdown::ConnectionGuard;
+use super::transport::Transport;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use super::uring::UringStream;
+use super::{
+    Connection, ConnectionInfo, ConnectionOptions, Health, Hooks, HttpCode, IdleAction, Metrics,
+    Response, Router, SharedRouter, Shutdown, StateMap,
+};
+
+const DEFAULT_BACKLOG: u32 = 1024;
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const DEFAULT_MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+const REJECT_READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub struct Server {
+    router: SharedRouter,
+    socket: SocketOptions,
+    connection: ConnectionOptions,
+    acceptors: usize,
+    accept_backoff: Option<Duration>,
+    ip_limiter: Option<Arc<IpLimiter>>,
+    limit_action: LimitAction,
+    runtime: RuntimeFlavor,
+    shutdown: Shutdown,
+    metrics: Arc<Metrics>,
+    /// Set once the server accepts connections, making it ready.
+    started: Arc<AtomicBool>,
+    states: StateMap,
+    middlewares: Middlewares,
+    hooks: Hooks,
+    debug: Option<DebugOn>,
+    #[cfg(feature = "http3")]
+    http3: Option<Http3>,
+}
+
+/// Where the `/_debug` report is served.
+#[derive(Debug, Clone, Copy)]
+enum DebugOn {
+    Main,
+    Addr(SocketAddr),
+}
+
+/// Resources held by a connection for as long as it is open.
+struct ConnectionSlot {
+    _drain: ConnectionGuard,
+    _ip: Option<IpGuard>,
+}
+
+/// Controls a server from outside, including while it is running.
+#[derive(Clone)]
+pub struct ServerHandle {
+    router: SharedRouter,
+    shutdown: Shutdown,
+    metrics: Arc<Metrics>,
+}
+
+/// Shape of the tokio runtime(s) driving the server when started with [`Server::start`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuntimeFlavor {
+    CurrentThread,
+    /// Work-stealing runtime, with as many workers as cores unless specified.
+    MultiThread {
+        workers: Option<usize>,
+    },
+    /// One single-threaded runtime per core, each accepting on its own `SO_REUSEPORT`
+    /// listener. Connections never migrate between threads.
+    ThreadPerCore,
+    /// Single thread performing connection I/O through io_uring.
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    IoUring,
+}
+
+/// Options applied to the listening socket and to every accepted connection.
+#[derive(Debug, Clone, Copy)]
+pub struct SocketOptions {
+    nodelay: bool,
+    keepalive: bool,
+    recv_buffer_size: Option<usize>,
+    send_buffer_size: Option<usize>,
+    backlog: u32,
+}
+
+impl Server {
+    pub fn new(router: Router) -> Self {
+        Server {
+            router: router.into(),
+            socket: SocketOptions::default(),
+            connection: ConnectionOptions::default(),
+            acceptors: 1,
+            accept_backoff: Some(DEFAULT_MAX_ACCEPT_BACKOFF),
+            ip_limiter: None,
+            limit_action: LimitAction::default(),
+            runtime: RuntimeFlavor::MultiThread { workers: None },
+            shutdown: Shutdown::default(),
+            metrics: Arc::default(),
+            started: Arc::default(),
+            states: StateMap::default(),
+            middlewares: Middlewares::from([]),
+            hooks: Hooks::default(),
+            debug: None,
+            #[cfg(feature = "http3")]
+            http3: None,
+        }
+    }
+
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle {
+            router: self.router.clone(),
+            shutdown: self.shutdown.clone(),
+            metrics: self.metrics.clone(),
+        }
+    }
+
+    /// Replaces the routes served by this server, see [`ServerHandle::set_router`].
+    pub fn set_router(&self, router: Router) {
+        self.router.store(router);
+    }
+
+    /// Shares `state` with every handler, which retrieve it through [`Request::state`].
+    /// Registering a state of the same type twice replaces the previous one.
+    pub fn with_state<T>(mut self, state: T) -> Self
+    where
+        T: Send + Sync + 'static,
+    {
+        self.states.insert(state);
+        self
+    }
+
+    /// Runs `middleware` around every request, after the middlewares added before it
+    /// and before the route ones.
+    pub fn with_middleware<M>(mut self, middleware: M) -> Self
+    where
+        M: Middleware,
+    {
+        self.middlewares = middleware::push(&self.middlewares, middleware);
+        self
+    }
+
+    /// Records every request in the server metrics, see [`RequestMetrics`]. Called
+    /// first, the latency covers the other middlewares too.
+    pub fn with_request_metrics(self) -> Self {
+        let metrics = RequestMetrics::new(self.metrics.clone());
+        self.with_middleware(metrics)
+    }
+
+    /// Runs the lifecycle callbacks of `hooks` on every HTTP/1 connection, see
+    /// [`Hooks`]. Replaces the hooks set before.
+    pub fn with_hooks(mut self, hooks: Hooks) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    /// Serves `/healthz` and `/readyz`, see [`Health`]. The server reports itself
+    /// ready from the moment it accepts connections until it starts shutting down.
+    pub fn with_health(self, health: Health) -> Self {
+        let health = health.attach(self.started.clone(), self.shutdown.clone());
+        self.with_middleware(health)
+    }
+
+    /// Serves a JSON report of the routes, open connections, configuration and uptime
+    /// at `/_debug`. It tells a lot about the server, so it should not be reachable
+    /// from the outside: see [`Server::with_debug_addr`] to serve it on its own port.
+    pub fn with_debug(mut self) -> Self {
+        self.debug = Some(DebugOn::Main);
+        self
+    }
+
+    /// Serves the `/_debug` report on `addr` rather than with the other routes.
+    pub fn with_debug_addr(mut self, addr: SocketAddr) -> Self {
+        self.debug = Some(DebugOn::Addr(addr));
+        self
+    }
+
+    /// Serves the server metrics at `path` in the Prometheus text format, see
+    /// [`MetricsEndpoint`].
+    #[cfg(feature = "metrics")]
+    pub fn with_metrics_endpoint<P: Into<String>>(self, path: P) -> Self {
+        let endpoint = MetricsEndpoint::new(path, self.metrics.clone());
+        self.with_middleware(endpoint)
+    }
+
+    /// Serves HTTP/3 on the `http3` endpoint as well, advertised to HTTP/1.1 clients
+    /// through `Alt-Svc`. Only the current-thread and multi-thread runtimes serve it.
+    #[cfg(feature = "http3")]
+    pub fn with_http3(mut self, http3: Http3) -> Self {
+        self = self.with_middleware(AltSvc(http3.alt_svc()));
+        self.http3 = Some(http3);
+        self
+    }
+
+    /// Disables Nagle's algorithm on accepted sockets.
+    pub fn with_nodelay(mut self, nodelay: bool) -> Self {
+        self.socket.nodelay = nodelay;
+        self
+    }
+
+    /// Enables `SO_KEEPALIVE` probes on accepted sockets.
+    pub fn with_keepalive(mut self, keepalive: bool) -> Self {
+        self.socket.keepalive = keepalive;
+        self
+    }
+
+    pub fn with_recv_buffer_size(mut self, size: usize) -> Self {
+        self.socket.recv_buffer_size = Some(size);
+        self
+    }
+
+    pub fn with_send_buffer_size(mut self, size: usize) -> Self {
+        self.socket.send_buffer_size = Some(size);
+        self
+    }
+
+    /// Maximum number of pending connections queued by the kernel.
+    pub fn with_backlog(mut self, backlog: u32) -> Self {
+        self.socket.backlog = backlog;
+        self
+    }
+
+    /// Number of acceptor tasks. When greater than one, every acceptor binds its own
+    /// `SO_REUSEPORT` listener so that the kernel balances new connections between them.
+    pub fn with_acceptors(mut self, acceptors: usize) -> Self {
+        self.acceptors = acceptors.max(1);
+        self
+    }
+
+    /// Longest pause of the accept loop when the process runs out of resources such as
+    /// file descriptors, doubling from 10ms on consecutive failures. `None` retries
+    /// immediately.
+    pub fn with_accept_backoff(mut self, max: Option<Duration>) -> Self {
+        self.accept_backoff = max;
+        self
+    }
+
+    /// Caps the connections simultaneously open from one client address, turning
+    /// away the excess ones as specified by `action`.
+    pub fn with_max_connections_per_ip(mut self, max: usize, action: LimitAction) -> Self {
+        self.ip_limiter = Some(Arc::new(IpLimiter::new(max)));
+        self.limit_action = action;
+        self
+    }
+
+    /// Deadline for receiving a complete request head, after which slow clients get
+    /// a 408 and are disconnected. Defaults to 10 seconds.
+    pub fn with_header_timeout(mut self, timeout: Duration) -> Self {
+        self.connection.header_timeout = Some(timeout);
+        self
+    }
+
+    /// Size of the largest request head accepted, 16 KiB by default. Clients sending
+    /// larger ones get a 431 and are disconnected.
+    pub fn with_max_head_size(mut self, size: usize) -> Self {
+        self.connection.max_head_size = size;
+        self
+    }
+
+    /// Size of the largest request body accepted on any route, 64 MiB by default.
+    /// Route body limits only apply below it.
+    pub fn with_max_body_size(mut self, size: usize) -> Self {
+        self.connection.max_body_size = size;
+        self
+    }
+
+    /// Allows connections to be reused for several requests, enabled by default.
+    pub fn with_keep_alive(mut self, keep_alive: bool) -> Self {
+        self.connection.keep_alive = keep_alive;
+        self
+    }
+
+    /// Closes connections left idle for longer than `timeout` between two requests.
+    pub fn with_keep_alive_timeout(mut self, timeout: Duration) -> Self {
+        self.connection.keep_alive_timeout = Some(timeout);
+        self
+    }
+
+    /// Answers connections idle past the keep-alive timeout with a 408 rather than
+    /// closing them silently.
+    pub fn with_idle_action(mut self, action: IdleAction) -> Self {
+        self.connection.idle_action = action;
+        self
+    }
+
+    /// Logs a warning with the parse, handler and write times of the requests taking
+    /// longer than `threshold` to answer.
+    pub fn with_slow_request_threshold(mut self, threshold: Duration) -> Self {
+        self.connection.slow_request_threshold = Some(threshold);
+        self
+    }
+
+    pub fn with_max_requests_per_connection(mut self, max: usize) -> Self {
+        self.connection.max_requests = Some(max.max(1));
+        self
+    }
+
+    pub fn with_runtime(mut self, runtime: RuntimeFlavor) -> Self {
+        self.runtime = runtime;
+        self
+    }
+
+    /// Builds the configured runtime and serves on it, blocking the current thread.
+    pub fn start(self, addr: SocketAddr) -> io::Result<()> {
+        match self.runtime {
+            RuntimeFlavor::CurrentThread => Builder::new_current_thread()
+                .enable_all()
+                .build()?
+                .block_on(self.run(addr)),
+            RuntimeFlavor::MultiThread { workers } => {
+                let mut builder = Builder::new_multi_thread();
+                if let Some(workers) = workers {
+                    builder.worker_threads(workers);
+                }
+                builder.enable_all().build()?.block_on(self.run(addr))
+            }
+            RuntimeFlavor::ThreadPerCore => self.start_per_core(addr),
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            RuntimeFlavor::IoUring => self.start_uring(addr),
+        }
+    }
+
+    fn start_per_core(self, addr: SocketAddr) -> io::Result<()> {
+        let cores = thread::available_parallelism().map_or(1, |n| n.get());
+        let listeners = self.listeners(addr, cores)?;
+        #[cfg(unix)]
+        let fds = raw_fds(&listeners);
+        let server = Arc::new(self.install_debug()?);
+
+        let threads = listeners
+            .into_iter()
+            .enumerate()
+            .map(|(core, listener)| {
+                let server = server.clone();
+                thread::Builder::new()
+                    .name(format!("http-core-{}", core))
+                    .spawn(move || -> io::Result<()> {
+                        let rt = Builder::new_current_thread().enable_all().build()?;
+                        rt.block_on(async {
+                            let listener = TcpListener::from_std(listener)?;
+                            server
+                                .clone()
+                                .accept(listener, Self::spawn_connection)
+                                .await;
+                            server.shutdown.drained().await;
+                            Ok(())
+                        })
+                    })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        // The signal loop only returns after a hand-over, a shutdown must not wait on it
+        #[cfg(unix)]
+        Builder::new_current_thread()
+            .enable_all()
+            .build()?
+            .block_on(async {
+                tokio::select! {
+                    _ = server.clone().upgrade_on_signal(fds) => {}
+                    _ = server.shutdown.wait() => {}
+                }
+            });
+
+        for thread in threads {
+            thread
+                .join()
+                .map_err(|_| io::Error::other("core thread panicked"))??;
+        }
+        Ok(())
+    }
+
+    /// Serves on the current runtime until the server is shut down and every
+    /// connection has been drained.
+    pub async fn run(self, addr: SocketAddr) -> io::Result<()> {
+        let listeners = self.listeners(addr, self.acceptors)?;
+        self.serve(listeners).await
+    }
+
+    /// Serves on `listener`, bound by the caller, as [`Server::run`] does. The socket
+    /// options of the server are left out, as are inherited listeners.
+    pub async fn run_with_listener(self, listener: StdTcpListener) -> io::Result<()> {
+        listener.set_nonblocking(true)?;
+        self.serve(vec![listener]).await
+    }
+
+    async fn serve(self, listeners: Vec<StdTcpListener>) -> io::Result<()> {
+        let server = Arc::new(self.install_debug()?);
+
+        #[cfg(unix)]
+        tokio::spawn(server.clone().upgrade_on_signal(raw_fds(&listeners)));
+
+        #[cfg(feature = "http3")]
+        if let Some(http3) = &server.http3 {
+            let context = Http3Context {
+                router: server.router.clone(),
+                middlewares: server.middlewares.clone(),
+                states: server.states.clone(),
+                shutdown: server.shutdown.clone(),
+                max_body_size: server.connection.max_body_size,
+            };
+            tokio::spawn(http3::serve(http3.bind()?, Arc::new(context)));
+        }
+
+        let mut acceptors = JoinSet::new();
+        for listener in listeners {
+            let listener = TcpListener::from_std(listener)?;
+            acceptors.spawn(server.clone().accept(listener, Self::spawn_connection));
+        }
+        while acceptors.join_next().await.is_some() {}
+
+        server.shutdown.drained().await;
+        Ok(())
+    }
+
+    /// Sets up the `/_debug` report, as a middleware of the main listeners or on its
+    /// own address, once the configuration it shows is final.
+    fn install_debug(self) -> io::Result<Self> {
+        let Some(debug) = self.debug else {
+            return Ok(self);
+        };
+        let endpoint = DebugEndpoint::new(DebugReport {
+            router: self.router.clone(),
+            metrics: self.metrics.clone(),
+            config: self.config_snapshot(),
+            started: Instant::now(),
+        });
+        match debug {
+            DebugOn::Main => Ok(self.with_middleware(endpoint)),
+            DebugOn::Addr(addr) => {
+                let listener = StdTcpListener::bind(addr)?;
+                debug::spawn_listener(listener, endpoint, self.shutdown.clone())?;
+                Ok(self)
+            }
+        }
+    }
+
+    /// Settings shown by the `/_debug` report, as JSON values.
+    fn config_snapshot(&self) -> Vec<(&'static str, String)> {
+        let (runtime, workers) = match self.runtime {
+            RuntimeFlavor::CurrentThread => ("current-thread", None),
+            RuntimeFlavor::MultiThread { workers } => ("multi-thread", workers),
+            RuntimeFlavor::ThreadPerCore => ("thread-per-core", None),
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            RuntimeFlavor::IoUring => ("io-uring", None),
+        };
+        let number = |n: Option<usize>| n.map_or_else(|| String::from("null"), |n| n.to_string());
+        let options = &self.connection;
+        let config = vec![
+            ("runtime", json_string(runtime)),
+            ("workers", number(workers)),
+            ("acceptors", self.acceptors.to_string()),
+            ("backlog", self.socket.backlog.to_string()),
+            ("nodelay", self.socket.nodelay.to_string()),
+            ("header_timeout_ms", json_millis(options.header_timeout)),
+            ("max_head_size", options.max_head_size.to_string()),
+            ("max_body_size", options.max_body_size.to_string()),
+            ("keep_alive", options.keep_alive.to_string()),
+            (
+                "keep_alive_timeout_ms",
+                json_millis(options.keep_alive_timeout),
+            ),
+            ("max_requests", number(options.max_requests)),
+            (
+                "slow_request_threshold_ms",
+                json_millis(options.slow_request_threshold),
+            ),
+            ("middlewares", self.middlewares.len().to_string()),
+        ];
+        #[cfg(feature = "http3")]
+        let config = [config, vec![("http3", self.http3.is_some().to_string())]].concat();
+        config
+    }
+
+    /// Listeners inherited from a previous process, or `count` freshly bound ones.
+    fn listeners(&self, addr: SocketAddr, count: usize) -> io::Result<Vec<StdTcpListener>> {
+        #[cfg(unix)]
+        if let Some(listeners) = restart::inherited_listeners()? {
+            return Ok(listeners);
+        }
+
+        (0..count)
+            .map(|_| self.socket.bind(addr, count > 1))
+            .collect()
+    }
+
+    /// Accepts connections until shutdown, handing each of them to `spawn`.
+    async fn accept<F>(self: Arc<Self>, listener: TcpListener, spawn: F)
+    where
+        F: Fn(Arc<Self>, TcpStream, ConnectionInfo, ConnectionSlot),
+    {
+        let mut backoff = MIN_ACCEPT_BACKOFF;
+        self.started.store(true, Ordering::SeqCst);
+
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = self.shutdown.wait() => break,
+            };
+
+            let (stream, peer_addr) = match accepted {
+                Ok(accepted) => {
+                    backoff = MIN_ACCEPT_BACKOFF;
+                    accepted
+                }
+                Err(e) => {
+                    warn!("Failed to accept connection: {}", e);
+                    if let Some(max) = self.accept_backoff.filter(|_| is_resource_exhaustion(&e)) {
+                        tokio::select! {
+                            _ = tokio::time::sleep(backoff) => {}
+                            _ = self.shutdown.wait() => break,
+                        }
+                        backoff = (backoff * 2).min(max);
+                    }
+                    continue;
+                }
+            };
+
+            if let Err(e) = self.socket.apply(&stream) {
+                warn!("Failed to set socket options: {}", e);
+            }
+
+            let ip = match &self.ip_limiter {
+                Some(limiter) => match limiter.acquire(peer_addr.ip()) {
+                    Some(guard) => Some(guard),
+                    None => {
+                        self.reject(stream);
+                        continue;
+                    }
+                },
+                None => None,
+            };
+
+            let info = ConnectionInfo {
+                peer_addr: Some(peer_addr),
+                local_addr: stream.local_addr().ok(),
+                tls: false,
+            };
+            let slot = ConnectionSlot {
+                _drain: self.shutdown.track(),
+                _ip: ip,
+            };
+            spawn(self.clone(), stream, info, slot);
+        }
+    }
+
+    /// Turns away a connection over the per address cap.
+    fn reject(&self, mut stream: TcpStream) {
+        self.metrics.record_rejected_connection();
+
+        match self.limit_action {
+            LimitAction::Reset => {
+                // A zero linger makes closing the socket send a RST
+                let _ = SockRef::from(&stream).set_linger(Some(Duration::ZERO));
+            }
+            LimitAction::TooManyRequests => {
+                tokio::spawn(async move {
+                    // Consume the request first, closing with unread data would reset
+                    // the connection before the client reads the response
+                    let mut buf = [0; 1024];
+                    let _ = tokio::time::timeout(REJECT_READ_TIMEOUT, stream.read(&mut buf)).await;
+
+                    let mut res = Response::from(HttpCode::TooManyRequests);
+                    res.header("Connection", "close");
+                    let _ = stream.write_all(&res.into_bytes()).await;
+                    let _ = stream.shutdown().await;
+                });
+            }
+        }
+    }
+
+    fn spawn_connection(
+        self: Arc<Self>,
+        stream: TcpStream,
+        info: ConnectionInfo,
+        slot: ConnectionSlot,
+    ) {
+        let connection = self.connection(stream, info).with_upgrades();
+        tokio::spawn(self.serve_connection(connection, slot));
+    }
+
+    fn connection<S>(&self, stream: S, info: ConnectionInfo) -> Connection<S>
+    where
+        S: Transport,
+    {
+        Connection::new(stream, self.connection)
+            .with_shutdown(self.shutdown.clone())
+            .with_info(info)
+            .with_metrics(self.metrics.clone())
+            .with_states(self.states.clone())
+            .with_middlewares(self.middlewares.clone())
+            .with_hooks(self.hooks.clone())
+    }
+
+    async fn serve_connection<S>(self: Arc<Self>, connection: Connection<S>, slot: ConnectionSlot)
+    where
+        S: Transport,
+    {
+        connection.serve(&self.router).await;
+        drop(slot);
+    }
+
+    /// Runs a single io_uring driven thread. Connections are still accepted through
+    /// epoll so that listener options and hand-over keep working, but all their reads
+    /// and writes are submitted to the ring.
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    fn start_uring(self, addr: SocketAddr) -> io::Result<()> {
+        let listeners = self.listeners(addr, self.acceptors)?;
+        let server = Arc::new(self.install_debug()?);
+
+        tokio_uring::start(async move {
+            tokio::spawn(server.clone().upgrade_on_signal(raw_fds(&listeners)));
+
+            let mut acceptors = JoinSet::new();
+            for listener in listeners {
+                let listener = TcpListener::from_std(listener)?;
+                acceptors.spawn_local(
+                    server
+                        .clone()
+                        .accept(listener, Self::spawn_uring_connection),
+                );
+            }
+            while acceptors.join_next().await.is_some() {}
+
+            server.shutdown.drained().await;
+            Ok(())
+        })
+    }
+
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    fn spawn_uring_connection(
+        self: Arc<Self>,
+        stream: TcpStream,
+        info: ConnectionInfo,
+        slot: ConnectionSlot,
+    ) {
+        tokio_uring::spawn(async move {
+            match UringStream::from_tokio(stream) {
+                Ok(stream) => {
+                    let connection = self.connection(stream, info);
+                    self.serve_connection(connection, slot).await
+                }
+                Err(e) => error!("Failed to register connection: {}", e),
+            }
+        });
+    }
+
+    /// Hands the listeners over to a new process on `SIGUSR2`, then stops accepting
+    /// so that this process exits once its connections are drained.
+    #[cfg(unix)]
+    async fn upgrade_on_signal(self: Arc<Self>, fds: Vec<RawFd>) {
+        let mut signal = match signal(SignalKind::user_defined2()) {
+            Ok(signal) => signal,
+            Err(e) => {
+                error!("Failed to listen for SIGUSR2: {}", e);
+                return;
+            }
+        };
+
+        while signal.recv().await.is_some() {
+            match restart::spawn_successor(&fds) {
+                Ok(pid) => {
+                    info!("Started process {}, draining connections", pid);
+                    self.shutdown.trigger();
+                    return;
+                }
+                Err(e) => error!("Failed to start new process: {}", e),
+            }
+        }
+    }
+}
+
+impl ServerHandle {
+    /// Atomically swaps the routes, new requests being routed with `router` while
+    /// those in flight complete with the previous one.
+    pub fn set_router(&self, router: Router) {
+        self.router.store(router);
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Stops accepting connections and closes the idle ones, letting the server
+    /// return once the remaining connections are drained.
+    pub fn shutdown(&self) {
+        self.shutdown.trigger();
+    }
+}
+
+impl FromStr for RuntimeFlavor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "current-thread" => Ok(RuntimeFlavor::CurrentThread),
+            "multi-thread" => Ok(RuntimeFlavor::MultiThread { workers: None }),
+            "thread-per-core" => Ok(RuntimeFlavor::ThreadPerCore),
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            "io-uring" => Ok(RuntimeFlavor::IoUring),
+            _ => Err(format!("Invalid runtime flavor: {}", s)),
+        }
+    }
+}
+
+impl SocketOptions {
+    fn bind(&self, addr: SocketAddr, reuseport: bool) -> io::Result<StdTcpListener> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+        socket.set_reuse_address(true)?;
+        if reuseport {
+            #[cfg(unix)]
+            socket.set_reuse_port(true)?;
+            #[cfg(not(unix))]
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "SO_REUSEPORT is not supported on this platform",
+            ));
+        }
+        socket.bind(&addr.into())?;
+        socket.listen(self.backlog as i32)?;
+        socket.set_nonblocking(true)?;
+        Ok(socket.into())
+    }
+
+    fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+
+        let socket = SockRef::from(stream);
+        socket.set_keepalive(self.keepalive)?;
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        SocketOptions {
+            nodelay: false,
+            keepalive: false,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+            backlog: DEFAULT_BACKLOG,
+        }
+    }
+}
+
+/// Errors that won't go away until some connections are closed.
+fn is_resource_exhaustion(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    return matches!(
+        e.raw_os_error(),
+        Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM)
+    );
+    #[cfg(not(unix))]
+    return e.kind() == io::ErrorKind::OutOfMemory;
+}
+
+#[cfg(unix)]
+fn raw_fds(listeners: &[StdTcpListener]) -> Vec<RawFd> {
+    listeners.iter().map(|l| l.as_raw_fd()).collect()
+}
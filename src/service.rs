@@ -0,0 +1,127 @@
+//! Interoperability with the tower ecosystem: routers are tower services, and tower
+//! layers can wrap route handlers.
+
+use std::convert::Infallible;
+use std::future;
+use std::task::{Context, Poll};
+
+use tower::{BoxError, Service};
+
+use super::router::{BoxFuture, BoxedHandler, HandlerResult};
+use super::{AppError, Request, Response, Router};
+
+/// Route handler seen as a tower service, which layers wrap.
+#[derive(Clone)]
+pub struct HandlerService(pub(crate) BoxedHandler);
+
+impl Service<Request> for HandlerService {
+    type Response = Response;
+    type Error = AppError;
+    type Future = BoxFuture<HandlerResult>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        (self.0)(req)
+    }
+}
+
+impl Service<Request> for Router {
+    type Response = Response;
+    type Error = Infallible;
+    type Future = BoxFuture<Result<Response, Infallible>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let router = self.clone();
+        Box::pin(async move { Ok(router.route(req).await) })
+    }
+}
+
+/// Turns a layered service back into a route handler.
+pub(crate) fn into_handler<S>(service: S) -> BoxedHandler
+where
+    S: Service<Request, Response = Response> + Clone + Send + Sync + 'static,
+    S::Error: Into<BoxError>,
+    S::Future: Send,
+{
+    std::sync::Arc::new(move |req| {
+        let mut service = service.clone();
+        Box::pin(async move {
+            future::poll_fn(|cx| service.poll_ready(cx))
+                .await
+                .map_err(|e| AppError::from(e.into()))?;
+            service
+                .call(req)
+                .await
+                .map_err(|e| AppError::from(e.into()))
+        })
+    })
+}
+
+/// Errors raised by layers become internal errors, unless they carry an [`AppError`].
+impl From<BoxError> for AppError {
+    fn from(err: BoxError) -> Self {
+        match err.downcast::<AppError>() {
+            Ok(err) => *err,
+            Err(err) => AppError::Internal(err.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tower::util::MapResponseLayer;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{ComparePath, HttpCode, RequestBuffer, Route};
+
+    fn get(path: &str) -> Request {
+        let req = format!("GET {} HTTP/1.1\r\n\r\n", path);
+        Request::parse(&mut RequestBuffer::from(req.bytes())).unwrap()
+    }
+
+    fn tag(mut res: Response) -> Response {
+        res.header("X-Layer", "tower");
+        res
+    }
+
+    #[tokio::test]
+    async fn test_tower_interop() {
+        let mut router = Router::default();
+        router.add_route(
+            Route::get(
+                "/",
+                |_: Request| Response::from(HttpCode::Ok),
+                ComparePath::Exact,
+            )
+            .layer(MapResponseLayer::new(tag)),
+        );
+        router.add_route(Route::get(
+            "/plain",
+            |_: Request| Response::from(HttpCode::Ok),
+            ComparePath::Exact,
+        ));
+
+        let res = router.clone().oneshot(get("/")).await.unwrap().into_bytes();
+        assert!(String::from_utf8(res)
+            .unwrap()
+            .contains("X-Layer: tower\r\n"));
+        let res = router.clone().oneshot(get("/plain")).await.unwrap();
+        assert!(!String::from_utf8(res.into_bytes())
+            .unwrap()
+            .contains("X-Layer"));
+
+        let router = router.layer(MapResponseLayer::new(tag));
+        let res = router.oneshot(get("/plain")).await.unwrap().into_bytes();
+        assert!(String::from_utf8(res)
+            .unwrap()
+            .contains("X-Layer: tower\r\n"));
+    }
+}
@@ -0,0 +1,465 @@
+//! Cookie based sessions. The cookie only carries the signed session identifier,
+//! the data stays on the server in a [`SessionStore`].
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use super::middleware::{Middleware, Next};
+use super::router::BoxFuture;
+use super::{AppError, Request, Response};
+
+/// Number of sessions the memory store holds at most.
+const MAX_SESSIONS: usize = 10_000;
+
+/// Lifetime of sessions whose cookie has no `Max-Age`.
+const DEFAULT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub type SessionData = HashMap<String, String>;
+
+/// Storage of the session data, so sessions can outlive the server or be shared
+/// between servers.
+pub trait SessionStore: Send + Sync + 'static {
+    fn load(&self, id: &str) -> Option<SessionData>;
+    fn save(&self, id: &str, data: SessionData, ttl: Duration);
+    fn remove(&self, id: &str);
+}
+
+/// Sessions kept in the server memory.
+#[derive(Default)]
+pub struct MemoryStore {
+    sessions: Mutex<HashMap<String, (SessionData, Instant)>>,
+}
+
+/// Frees at least half of `sessions`, expired ones first then those closest to
+/// expiring.
+fn evict(sessions: &mut HashMap<String, (SessionData, Instant)>, now: Instant) {
+    sessions.retain(|_, (_, expires)| *expires > now);
+
+    let keep = MAX_SESSIONS / 2;
+    if sessions.len() > keep {
+        let mut expiries = sessions.values().map(|(_, e)| *e).collect::<Vec<_>>();
+        let index = expiries.len() - keep;
+        let (_, &mut first_kept, _) = expiries.select_nth_unstable(index);
+        sessions.retain(|_, (_, expires)| *expires > first_kept);
+    }
+}
+
+impl SessionStore for MemoryStore {
+    fn load(&self, id: &str) -> Option<SessionData> {
+        let sessions = self.sessions.lock().unwrap();
+        let (data, expires) = sessions.get(id)?;
+        (*expires > Instant::now()).then(|| data.clone())
+    }
+
+    fn save(&self, id: &str, data: SessionData, ttl: Duration) {
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.len() >= MAX_SESSIONS && !sessions.contains_key(id) {
+            evict(&mut sessions, now);
+        }
+        sessions.insert(id.to_string(), (data, now + ttl));
+    }
+
+    fn remove(&self, id: &str) {
+        self.sessions.lock().unwrap().remove(id);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Unchanged,
+    Changed,
+    Destroyed,
+}
+
+#[derive(Debug)]
+struct SessionState {
+    /// Identifier of the session, only drawn when a new or renewed session is saved.
+    id: Option<String>,
+    /// Identifier the session had when loaded, before being renewed.
+    loaded_id: Option<String>,
+    data: SessionData,
+    status: Status,
+}
+
+/// Session of the request, found in its extensions and through
+/// [`Request::session`]. Changes are saved once the response is produced.
+#[derive(Clone)]
+pub struct Session {
+    inner: Arc<Mutex<SessionState>>,
+}
+
+impl Session {
+    fn new(loaded_id: Option<String>, data: SessionData) -> Self {
+        Session {
+            inner: Arc::new(Mutex::new(SessionState {
+                id: loaded_id.clone(),
+                loaded_id,
+                data,
+                status: Status::Unchanged,
+            })),
+        }
+    }
+
+    /// Identifier of the session, `None` for a new or renewed one until it is saved
+    /// with the response.
+    pub fn id(&self) -> Option<String> {
+        self.inner.lock().unwrap().id.clone()
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.inner.lock().unwrap().data.get(key).cloned()
+    }
+
+    pub fn set<K, V>(&self, key: K, value: V)
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        let mut state = self.inner.lock().unwrap();
+        state.data.insert(key.into(), value.into());
+        state.status = Status::Changed;
+    }
+
+    pub fn remove(&self, key: &str) -> Option<String> {
+        let mut state = self.inner.lock().unwrap();
+        let value = state.data.remove(key)?;
+        state.status = Status::Changed;
+        Some(value)
+    }
+
+    /// Gives the session a new identifier, e.g. on login so an identifier planted by
+    /// an attacker before it is worthless.
+    pub fn renew(&self) {
+        let mut state = self.inner.lock().unwrap();
+        state.id = None;
+        state.status = Status::Changed;
+    }
+
+    /// Deletes the session from the store and expires its cookie.
+    pub fn destroy(&self) {
+        let mut state = self.inner.lock().unwrap();
+        state.data.clear();
+        state.status = Status::Destroyed;
+    }
+}
+
+impl fmt::Debug for Session {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The data may hold secrets, only the identifier is shown
+        f.debug_struct("Session").field("id", &self.id()).finish()
+    }
+}
+
+/// Random 128-bit identifier from the operating system generator. The cookie holding
+/// it is signed, but the identifier alone still grants access to the session, so it
+/// must not be guessable.
+fn generate_id() -> Result<String, getrandom::Error> {
+    let mut bytes = [0; 16];
+    getrandom::getrandom(&mut bytes)?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    #[default]
+    Lax,
+    None,
+}
+
+impl SameSite {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        }
+    }
+}
+
+/// Middleware loading the session named by the request cookie, or starting an
+/// empty one, and saving it after the handler ran. Cookies are only sent for
+/// sessions that changed.
+#[derive(Clone)]
+pub struct Sessions {
+    key: Arc<[u8]>,
+    store: Arc<dyn SessionStore>,
+    cookie_name: String,
+    path: String,
+    domain: Option<String>,
+    secure: bool,
+    http_only: bool,
+    same_site: SameSite,
+    max_age: Option<Duration>,
+}
+
+impl Sessions {
+    /// Sessions whose identifiers are signed with `key`, which should be at least 32
+    /// random bytes.
+    pub fn new<K>(key: K) -> Self
+    where
+        K: Into<Vec<u8>>,
+    {
+        Sessions {
+            key: key.into().into(),
+            store: Arc::new(MemoryStore::default()),
+            cookie_name: "session".to_string(),
+            path: "/".to_string(),
+            domain: None,
+            secure: false,
+            http_only: true,
+            same_site: SameSite::default(),
+            max_age: None,
+        }
+    }
+
+    pub fn with_store<S>(mut self, store: S) -> Self
+    where
+        S: SessionStore,
+    {
+        self.store = Arc::new(store);
+        self
+    }
+
+    pub fn with_cookie_name<N: Into<String>>(mut self, name: N) -> Self {
+        self.cookie_name = name.into();
+        self
+    }
+
+    pub fn with_path<P: Into<String>>(mut self, path: P) -> Self {
+        self.path = path.into();
+        self
+    }
+
+    pub fn with_domain<D: Into<String>>(mut self, domain: D) -> Self {
+        self.domain = Some(domain.into());
+        self
+    }
+
+    pub fn with_secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    pub fn with_http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+
+    pub fn with_same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = same_site;
+        self
+    }
+
+    /// Lifetime of the cookie and of the stored session. Without it the cookie lasts
+    /// until the browser is closed and the session a day.
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    fn mac(&self) -> Hmac<Sha256> {
+        Hmac::new_from_slice(&self.key).expect("HMAC accepts keys of any size")
+    }
+
+    /// Cookie value carrying `id` and its signature.
+    fn sign(&self, id: &str) -> String {
+        let mut mac = self.mac();
+        mac.update(id.as_bytes());
+        format!("{}.{}", id, BASE64.encode(mac.finalize().into_bytes()))
+    }
+
+    /// Identifier of a cookie value, if its signature is valid.
+    fn verify<'a>(&self, value: &'a str) -> Option<&'a str> {
+        let (id, signature) = value.rsplit_once('.')?;
+        let signature = BASE64.decode(signature).ok()?;
+        let mut mac = self.mac();
+        mac.update(id.as_bytes());
+        mac.verify_slice(&signature).ok()?;
+        Some(id)
+    }
+
+    fn cookie(&self, value: &str, max_age: Option<Duration>) -> String {
+        let mut cookie = format!("{}={}; Path={}", self.cookie_name, value, self.path);
+        if let Some(domain) = &self.domain {
+            cookie.push_str(&format!("; Domain={}", domain));
+        }
+        if let Some(max_age) = max_age {
+            cookie.push_str(&format!("; Max-Age={}", max_age.as_secs()));
+        }
+        if self.secure {
+            cookie.push_str("; Secure");
+        }
+        if self.http_only {
+            cookie.push_str("; HttpOnly");
+        }
+        cookie.push_str(&format!("; SameSite={}", self.same_site.as_str()));
+        cookie
+    }
+}
+
+impl Middleware for Sessions {
+    fn handle(&self, mut req: Request, next: Next) -> BoxFuture<Response> {
+        let loaded = req
+            .cookie(&self.cookie_name)
+            .and_then(|value| self.verify(value))
+            .and_then(|id| Some((id.to_string(), self.store.load(id)?)));
+        let session = match loaded {
+            Some((id, data)) => Session::new(Some(id), data),
+            None => Session::new(None, SessionData::new()),
+        };
+        req.extensions_mut().insert(session.clone());
+
+        let sessions = self.clone();
+        Box::pin(async move {
+            let mut res = next.run(req).await;
+            let mut state = session.inner.lock().unwrap();
+
+            match state.status {
+                Status::Unchanged => {}
+                Status::Changed => {
+                    let id = match state.id.clone().map_or_else(generate_id, Ok) {
+                        Ok(id) => id,
+                        // The handler relies on the session being kept, e.g. on login
+                        Err(e) => {
+                            let msg = format!("Failed to generate a session identifier: {}", e);
+                            return Response::from(AppError::Internal(msg));
+                        }
+                    };
+                    if let Some(renewed) = state.loaded_id.as_ref().filter(|&old| *old != id) {
+                        sessions.store.remove(renewed);
+                    }
+                    let ttl = sessions.max_age.unwrap_or(DEFAULT_TTL);
+                    sessions.store.save(&id, state.data.clone(), ttl);
+                    let cookie = sessions.cookie(&sessions.sign(&id), sessions.max_age);
+                    res.append_header("Set-Cookie", cookie);
+                    state.id = Some(id);
+                }
+                Status::Destroyed => {
+                    if let Some(id) = &state.loaded_id {
+                        sessions.store.remove(id);
+                        res.append_header("Set-Cookie", sessions.cookie("", Some(Duration::ZERO)));
+                    }
+                }
+            }
+            drop(state);
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{middleware, HttpCode};
+
+    /// Counts visits, or logs out on `/logout`. `/theme` sets a cookie of its own.
+    async fn send(sessions: &Sessions, cookie: Option<&str>, path: &str) -> Response {
+        let cookie = cookie.map_or_else(String::new, |c| format!("Cookie: theme=dark; {}\r\n", c));
+        let req = format!("GET {} HTTP/1.1\r\n{}\r\n", path, cookie);
+        middleware::send(sessions.clone(), &req, |req: Request| {
+            let session = req.session().unwrap();
+            if req.path() == "/logout" {
+                session.destroy();
+            } else {
+                let visits = session.get("visits").map_or(0, |v| v.parse().unwrap());
+                session.set("visits", (visits + 1).to_string());
+            }
+            let mut res = Response::from(HttpCode::Ok);
+            if req.path() == "/theme" {
+                res.header("Set-Cookie", "theme=light; Path=/");
+            }
+            async { res }
+        })
+        .await
+    }
+
+    fn cookie(res: &Response) -> String {
+        let set_cookie = res.header_value("Set-Cookie").unwrap();
+        set_cookie.split(';').next().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_sessions() {
+        let sessions = Sessions::new("secret").with_max_age(Duration::from_secs(60));
+
+        let res = send(&sessions, None, "/").await;
+        assert!(res
+            .header_value("Set-Cookie")
+            .unwrap()
+            .ends_with("; Path=/; Max-Age=60; HttpOnly; SameSite=Lax"));
+        let session = cookie(&res);
+        let id = session.strip_prefix("session=").unwrap();
+        let id = sessions.verify(id).unwrap();
+        assert_eq!(sessions.store.load(id).unwrap()["visits"], "1");
+
+        send(&sessions, Some(&session), "/").await;
+        assert_eq!(sessions.store.load(id).unwrap()["visits"], "2");
+
+        // A tampered identifier starts a new session
+        let forged = format!("session=x{}", &session["session=".len() + 1..]);
+        let res = send(&sessions, Some(&forged), "/").await;
+        assert_ne!(cookie(&res), session);
+        assert_eq!(sessions.store.load(id).unwrap()["visits"], "2");
+
+        // The handler cookie is kept along with the session one
+        let res = send(&sessions, Some(&session), "/theme").await;
+        let out = String::from_utf8(res.into_bytes()).unwrap();
+        assert!(out.contains("\r\nSet-Cookie: theme=light; Path=/\r\n"));
+        assert!(out.contains(&format!("\r\nSet-Cookie: {};", session)));
+
+        let res = send(&sessions, Some(&session), "/logout").await;
+        assert!(res
+            .header_value("Set-Cookie")
+            .unwrap()
+            .contains("Max-Age=0"));
+        assert!(sessions.store.load(id).is_none());
+    }
+
+    #[test]
+    fn test_renew() {
+        // Sessions only draw an identifier once saved
+        assert_eq!(Session::new(None, SessionData::new()).id(), None);
+
+        let store = MemoryStore::default();
+        store.save("a", SessionData::new(), Duration::ZERO);
+        assert!(store.load("a").is_none());
+
+        let session = Session::new(Some("a".to_string()), SessionData::new());
+        session.renew();
+        assert_eq!(session.id(), None);
+        assert_eq!(generate_id().unwrap().len(), 32);
+        assert_ne!(generate_id().unwrap(), generate_id().unwrap());
+    }
+
+    #[test]
+    fn test_store_bound() {
+        let store = MemoryStore::default();
+        let ttl = Duration::from_secs(60);
+        store.save("first", SessionData::new(), ttl);
+        for i in 1..MAX_SESSIONS {
+            store.save(&i.to_string(), SessionData::new(), ttl);
+        }
+        assert_eq!(store.sessions.lock().unwrap().len(), MAX_SESSIONS);
+
+        // Saving a known session leaves the others be
+        store.save("first", SessionData::new(), Duration::from_secs(1));
+        assert_eq!(store.sessions.lock().unwrap().len(), MAX_SESSIONS);
+
+        // None expired, those closest to expiring go
+        store.save("new", SessionData::new(), ttl);
+        let sessions = store.sessions.lock().unwrap();
+        assert!(sessions.len() <= MAX_SESSIONS / 2 + 1);
+        assert!(sessions.contains_key("new"));
+        assert!(sessions.contains_key(&(MAX_SESSIONS - 1).to_string()));
+        assert!(!sessions.contains_key("first"));
+    }
+}
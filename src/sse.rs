@@ -0,0 +1,228 @@
+//! Server-Sent Events: responses streaming `text/event-stream` frames as events are
+//! sent on a channel.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::{Buf, BytesMut};
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::sync::mpsc;
+use tokio::time::{Interval, MissedTickBehavior};
+
+use super::{IntoResponse, Response};
+
+/// One event of the stream.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Event {
+    data: String,
+    event: Option<String>,
+    id: Option<String>,
+    retry: Option<Duration>,
+}
+
+impl Event {
+    /// Event carrying `data`, sent as one `data:` line per line.
+    pub fn data<S>(data: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Event {
+            data: data.into(),
+            ..Event::default()
+        }
+    }
+
+    /// Event type, dispatched to the matching listeners of the client.
+    pub fn with_event<S>(mut self, event: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.event = Some(event.into());
+        self
+    }
+
+    /// Id sent back by the client in `Last-Event-ID` when reconnecting.
+    pub fn with_id<S>(mut self, id: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.id = Some(id.into());
+        self
+    }
+
+    /// Delay the client waits before reconnecting.
+    pub fn with_retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    fn encode(&self, out: &mut BytesMut) {
+        // Line breaks would end the field early
+        let line = |out: &mut BytesMut, field: &str, value: &str| {
+            for value in value.split(['\n', '\r']) {
+                out.extend_from_slice(field.as_bytes());
+                out.extend_from_slice(b": ");
+                out.extend_from_slice(value.as_bytes());
+                out.extend_from_slice(b"\n");
+            }
+        };
+
+        if let Some(event) = &self.event {
+            line(out, "event", event.lines().next().unwrap_or_default());
+        }
+        if let Some(id) = &self.id {
+            line(out, "id", id.lines().next().unwrap_or_default());
+        }
+        if let Some(retry) = self.retry {
+            line(out, "retry", &retry.as_millis().to_string());
+        }
+        line(out, "data", &self.data.replace("\r\n", "\n"));
+        out.extend_from_slice(b"\n");
+    }
+}
+
+/// Response body formatting the events received on a channel, ending when every
+/// sender is dropped.
+///
+/// The connection stays open for as long as the stream does, and the request
+/// timeouts do not apply to it. Once the client goes away the stream is dropped,
+/// which senders notice as their `send` failing or through `Sender::closed`.
+#[derive(Debug)]
+pub struct SseStream {
+    events: mpsc::Receiver<Event>,
+    keep_alive: Option<Interval>,
+    pending: BytesMut,
+}
+
+impl SseStream {
+    pub fn new(events: mpsc::Receiver<Event>) -> Self {
+        SseStream {
+            events,
+            keep_alive: None,
+            pending: BytesMut::new(),
+        }
+    }
+
+    /// Stream along with the sender of its events.
+    pub fn channel(capacity: usize) -> (mpsc::Sender<Event>, Self) {
+        let (tx, rx) = mpsc::channel(capacity);
+        (tx, SseStream::new(rx))
+    }
+
+    /// Sends a comment after `interval` without events, keeping proxies from closing
+    /// the idle connection and detecting clients gone away.
+    pub fn with_keep_alive(mut self, interval: Duration) -> Self {
+        let mut keep_alive =
+            tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+        keep_alive.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        self.keep_alive = Some(keep_alive);
+        self
+    }
+}
+
+impl AsyncRead for SseStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if !this.pending.is_empty() {
+                let n = this.pending.len().min(buf.remaining());
+                buf.put_slice(&this.pending[..n]);
+                this.pending.advance(n);
+                return Poll::Ready(Ok(()));
+            }
+
+            match this.events.poll_recv(cx) {
+                Poll::Ready(Some(event)) => {
+                    event.encode(&mut this.pending);
+                    if let Some(keep_alive) = &mut this.keep_alive {
+                        keep_alive.reset();
+                    }
+                }
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => {
+                    let tick = this.keep_alive.as_mut().map(|k| k.poll_tick(cx));
+                    if !matches!(tick, Some(Poll::Ready(_))) {
+                        return Poll::Pending;
+                    }
+                    this.pending.extend_from_slice(b":\n\n");
+                }
+            }
+        }
+    }
+}
+
+impl IntoResponse for SseStream {
+    fn into_response(self) -> Response {
+        let mut res = Response::stream(self, None);
+        res.header("Content-Type", "text/event-stream");
+        res.header("Cache-Control", "no-cache");
+        // Proxies would otherwise hold events back
+        res.header("X-Accel-Buffering", "no");
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, DuplexStream};
+
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let mut out = BytesMut::new();
+        Event::data("a\nb")
+            .with_event("update")
+            .with_id("7")
+            .with_retry(Duration::from_secs(3))
+            .encode(&mut out);
+        assert_eq!(
+            out,
+            "event: update\nid: 7\nretry: 3000\ndata: a\ndata: b\n\n".as_bytes()
+        );
+    }
+
+    async fn read_until(client: &mut DuplexStream, received: &mut String, end: &str) {
+        let mut buf = [0; 1024];
+        while !received.ends_with(end) {
+            let n = client.read(&mut buf).await.unwrap();
+            *received += &String::from_utf8_lossy(&buf[..n]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream() {
+        let (tx, stream) = SseStream::channel(4);
+        let stream = stream.with_keep_alive(Duration::from_millis(50));
+        let res = stream.into_response();
+        assert_eq!(res.header_value("Content-Type"), Some("text/event-stream"));
+
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let writer = tokio::spawn(async move { res.write_to(&mut server).await });
+
+        tx.send(Event::data("one")).await.unwrap();
+        let mut received = String::new();
+        read_until(&mut client, &mut received, "\r\n\r\nb\r\ndata: one\n\n\r\n").await;
+        assert!(received.contains("Transfer-Encoding: chunked\r\n"));
+        // Idle streams send comments
+        read_until(
+            &mut client,
+            &mut received,
+            "data: one\n\n\r\n3\r\n:\n\n\r\n",
+        )
+        .await;
+
+        // The client going away ends the stream and its senders notice
+        drop(client);
+        tokio::time::timeout(Duration::from_secs(5), tx.closed())
+            .await
+            .unwrap();
+        assert!(writer.await.unwrap().is_err());
+    }
+}
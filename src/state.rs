@@ -0,0 +1,75 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::Arc;
+
+/// Application state registered once on the server and shared by every request.
+#[derive(Debug)]
+pub struct State<T>(Arc<T>);
+
+/// States registered on a server, keyed by type.
+#[derive(Clone, Default)]
+pub struct StateMap {
+    states: Arc<HashMap<TypeId, Arc<dyn Any + Send + Sync>>>,
+}
+
+impl StateMap {
+    /// Registers `state`, replacing any previous state of the same type.
+    pub fn insert<T>(&mut self, state: T)
+    where
+        T: Send + Sync + 'static,
+    {
+        Arc::make_mut(&mut self.states).insert(TypeId::of::<T>(), Arc::new(state));
+    }
+
+    pub fn get<T>(&self) -> Option<State<T>>
+    where
+        T: Send + Sync + 'static,
+    {
+        let state = self.states.get(&TypeId::of::<T>())?.clone();
+        state.downcast().ok().map(State)
+    }
+}
+
+impl fmt::Debug for StateMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StateMap")
+            .field("len", &self.states.len())
+            .finish()
+    }
+}
+
+impl<T> Clone for State<T> {
+    fn clone(&self) -> Self {
+        State(self.0.clone())
+    }
+}
+
+impl<T> Deref for State<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct Counter(u32);
+
+    #[test]
+    fn test_state_map() {
+        let mut states = StateMap::default();
+        states.insert(Counter(1));
+        states.insert(String::from("files"));
+        states.insert(Counter(2));
+
+        assert_eq!(*states.get::<Counter>().unwrap(), Counter(2));
+        assert_eq!(*states.get::<String>().unwrap(), "files");
+        assert!(states.get::<u32>().is_none());
+    }
+}
@@ -0,0 +1,826 @@
+//! Serving of the files under a root directory.
+
+use std::fs::Metadata;
+use std::io::SeekFrom;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tracing::debug;
+
+use super::date;
+use super::file_cache::FileCache;
+use super::mime;
+use super::negotiate;
+use super::{AppError, ComparePath, HttpCode, Request, Response, Route};
+
+/// Content codings of the precompressed variants looked up next to the files, with
+/// their extensions, from the most to the least preferred.
+const PRECOMPRESSED: [(&str, &str); 2] = [("br", "br"), ("gzip", "gz")];
+
+const DEFAULT_STREAM_THRESHOLD: u64 = 1024 * 1024;
+
+/// Whether symbolic links under the served directory are followed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SymlinkPolicy {
+    /// Paths going through a symbolic link are answered with a 403.
+    Never,
+    /// Links are followed as long as their target is under the root.
+    #[default]
+    WithinRoot,
+    /// Links are followed wherever they lead.
+    Always,
+}
+
+impl FromStr for SymlinkPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(SymlinkPolicy::Never),
+            "within-root" => Ok(SymlinkPolicy::WithinRoot),
+            "always" => Ok(SymlinkPolicy::Always),
+            _ => Err(format!("Invalid symlink policy: {}", s)),
+        }
+    }
+}
+
+/// Files of a directory, served by GET routes mounted under a path prefix with a
+/// media type guessed from their extension. A file missing or unreadable for lack of
+/// permissions is answered with a 404 or a 403.
+///
+/// A file with a `.br` or `.gz` sibling is served from that precompressed variant
+/// to clients accepting its encoding.
+///
+/// Directories are answered with their index file when present, or with an HTML
+/// listing of their entries when enabled, and with a 404 otherwise.
+///
+/// Bodies larger than the stream threshold are streamed to the socket in chunks
+/// rather than read into memory, which also leaves them out of the response cache,
+/// the ETag and the compression middlewares.
+///
+/// An optional [`FileCache`] keeps small files in memory.
+///
+/// The same service may be mounted at several prefixes.
+#[derive(Debug, Clone)]
+pub struct StaticFiles {
+    root: Arc<PathBuf>,
+    index: Option<Arc<str>>,
+    listing: bool,
+    stream_threshold: u64,
+    cache: Option<FileCache>,
+    symlinks: SymlinkPolicy,
+}
+
+impl StaticFiles {
+    pub fn new<P>(root: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        StaticFiles {
+            root: Arc::new(root.into()),
+            index: Some("index.html".into()),
+            listing: false,
+            stream_threshold: DEFAULT_STREAM_THRESHOLD,
+            cache: None,
+            symlinks: SymlinkPolicy::default(),
+        }
+    }
+
+    /// Name of the file served for directories, `index.html` by default.
+    pub fn with_index(mut self, index: Option<&str>) -> Self {
+        self.index = index.map(Into::into);
+        self
+    }
+
+    pub fn with_listing(mut self, listing: bool) -> Self {
+        self.listing = listing;
+        self
+    }
+
+    /// Size above which bodies are streamed, 1 MiB by default.
+    pub fn with_stream_threshold(mut self, stream_threshold: u64) -> Self {
+        self.stream_threshold = stream_threshold;
+        self
+    }
+
+    pub fn with_cache(mut self, cache: FileCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    pub fn with_symlinks(mut self, symlinks: SymlinkPolicy) -> Self {
+        self.symlinks = symlinks;
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Route serving `prefix/<file>` from `root/<file>`.
+    pub fn mount(&self, prefix: &str) -> Route {
+        let files = self.clone();
+        let prefix = prefix.trim_end_matches('/').to_string();
+        Route::get(
+            format!("{}/", prefix),
+            move |req: Request| {
+                let files = files.clone();
+                let prefix = prefix.clone();
+                async move { files.serve(&prefix, req).await }
+            },
+            ComparePath::Prefix,
+        )
+    }
+
+    /// Existing path of `relative` under the root. Paths escaping the root through
+    /// `..`, or through a symbolic link the [`SymlinkPolicy`] forbids, are answered
+    /// with a 403.
+    pub async fn resolve(&self, relative: &str) -> Result<PathBuf, AppError> {
+        self.check(self.join(relative)?).await
+    }
+
+    /// Path of `relative` under the root for a file that may not exist yet, whose
+    /// parent directory must.
+    pub async fn resolve_new(&self, relative: &str) -> Result<PathBuf, AppError> {
+        let path = self.join(relative)?;
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+            return Err(AppError::Forbidden);
+        };
+        let path = self.check(parent.to_path_buf()).await?.join(name);
+
+        // Writing through a link writes its target
+        match tokio::fs::symlink_metadata(&path).await {
+            Ok(metadata) if metadata.is_symlink() => match self.symlinks {
+                SymlinkPolicy::Never => Err(AppError::Forbidden),
+                SymlinkPolicy::WithinRoot => self.check(path).await,
+                SymlinkPolicy::Always => Ok(path),
+            },
+            _ => Ok(path),
+        }
+    }
+
+    fn join(&self, relative: &str) -> Result<PathBuf, AppError> {
+        let relative = Path::new(relative.trim_start_matches('/'));
+        if !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(AppError::Forbidden);
+        }
+        Ok(self.root.join(relative))
+    }
+
+    /// Canonical form of `path`, an existing path under the root, enforcing the
+    /// symlink policy.
+    async fn check(&self, path: PathBuf) -> Result<PathBuf, AppError> {
+        match self.symlinks {
+            SymlinkPolicy::Never => {
+                let relative = path.strip_prefix(self.root.as_path()).unwrap_or(&path);
+                let mut current = self.root.to_path_buf();
+                for component in relative.components() {
+                    current.push(component);
+                    if tokio::fs::symlink_metadata(&current).await?.is_symlink() {
+                        return Err(AppError::Forbidden);
+                    }
+                }
+                self.check_contained(tokio::fs::canonicalize(path).await?)
+                    .await
+            }
+            SymlinkPolicy::WithinRoot => {
+                self.check_contained(tokio::fs::canonicalize(path).await?)
+                    .await
+            }
+            SymlinkPolicy::Always => Ok(tokio::fs::canonicalize(path).await?),
+        }
+    }
+
+    async fn check_contained(&self, path: PathBuf) -> Result<PathBuf, AppError> {
+        let root = tokio::fs::canonicalize(self.root.as_path()).await?;
+        if path.starts_with(root) {
+            Ok(path)
+        } else {
+            Err(AppError::Forbidden)
+        }
+    }
+
+    async fn serve(&self, prefix: &str, req: Request) -> Result<Response, AppError> {
+        let relative = &req.path()[prefix.len()..];
+        let path = self.resolve(relative).await.inspect_err(|e| {
+            debug!("Failed to resolve {}: {}", relative, e);
+        })?;
+        if !tokio::fs::metadata(&path).await?.is_dir() {
+            return self.serve_file(&req, &path).await;
+        }
+
+        let index = match &self.index {
+            Some(index) => self
+                .resolve(&format!("{}/{}", relative, index))
+                .await
+                .ok()
+                .filter(|index| index.is_file()),
+            None => None,
+        };
+        if index.is_none() && !self.listing {
+            return Err(AppError::NotFound);
+        }
+
+        // Relative links in the page resolve against the directory only with the slash
+        if !req.path().ends_with('/') {
+            let location = match req.query() {
+                Some(query) => format!("{}/?{}", req.path(), query),
+                None => format!("{}/", req.path()),
+            };
+            let mut res = Response::from(HttpCode::MovedPermanently);
+            res.header("Location", location);
+            return Ok(res);
+        }
+
+        match index {
+            Some(index) => self.serve_file(&req, &index).await,
+            None => listing(req.path(), &path, relative.trim_matches('/').is_empty()).await,
+        }
+    }
+
+    /// Variant of `path` compressed ahead of time with the encoding `req` prefers,
+    /// and whether `path` has any such variant.
+    async fn precompressed(
+        &self,
+        req: &Request,
+        path: &Path,
+    ) -> (Option<(PathBuf, &'static str)>, bool) {
+        let accepted = req
+            .header("Accept-Encoding")
+            .map(negotiate::parse_accept_encoding)
+            .unwrap_or_default();
+        let quality = |encoding: &str| {
+            let explicit = accepted
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(encoding));
+            let wildcard = accepted.iter().find(|(name, _)| *name == "*");
+            explicit.or(wildcard).map_or(0.0, |&(_, q)| q)
+        };
+
+        let mut best: Option<(PathBuf, &'static str, f32)> = None;
+        let mut any = false;
+        for (encoding, extension) in PRECOMPRESSED {
+            let mut variant = path.as_os_str().to_owned();
+            variant.push(".");
+            variant.push(extension);
+            let Ok(variant) = self.check(variant.into()).await else {
+                continue;
+            };
+            if !variant.is_file() {
+                continue;
+            }
+            any = true;
+
+            let q = quality(encoding);
+            if q > 0.0 && best.as_ref().map_or(true, |(_, _, best_q)| q > *best_q) {
+                best = Some((variant, encoding, q));
+            }
+        }
+        (best.map(|(variant, encoding, _)| (variant, encoding)), any)
+    }
+
+    async fn serve_file(&self, req: &Request, path: &Path) -> Result<Response, AppError> {
+        let (variant, has_variants) = self.precompressed(req, path).await;
+        let (file_path, encoding) = match &variant {
+            Some((variant, encoding)) => (variant.as_path(), Some(*encoding)),
+            None => (path, None),
+        };
+        let metadata = tokio::fs::metadata(file_path).await?;
+        debug!(
+            file = %file_path.display(),
+            encoding,
+            size = metadata.len(),
+            "Serving file"
+        );
+
+        // HTTP dates have a one second resolution
+        let modified = metadata.modified().ok().map(|modified| {
+            let secs = modified
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            UNIX_EPOCH + Duration::from_secs(secs)
+        });
+        if let Some(code) = modified.and_then(|modified| precondition(req, modified)) {
+            let mut res = Response::from(code);
+            if code == HttpCode::NotModified {
+                res.header("Last-Modified", date::http_date(modified.unwrap()));
+                if has_variants {
+                    res.add_vary("Accept-Encoding");
+                }
+            }
+            return Ok(res);
+        }
+
+        let len = metadata.len();
+        let range = req
+            .header("Range")
+            .and_then(|range| parse_range(range, len));
+        let mut res = match range {
+            None => self.body(file_path, &metadata, 0, len).await?,
+            Some(Ok((start, end))) => {
+                let mut res = self
+                    .body(file_path, &metadata, start, end - start + 1)
+                    .await?;
+                res.set_code(HttpCode::PartialContent);
+                res.header("Content-Range", format!("bytes {}-{}/{}", start, end, len));
+                res
+            }
+            Some(Err(())) => {
+                let mut res = Response::from(HttpCode::RangeNotSatisfiable);
+                res.header("Content-Range", format!("bytes */{}", len));
+                return Ok(res);
+            }
+        };
+        if let Some(modified) = modified {
+            res.header("Last-Modified", date::http_date(modified));
+        }
+        res.header("Accept-Ranges", "bytes");
+        res.header("Content-Type", mime::from_path(path));
+        if let Some(encoding) = encoding {
+            res.header("Content-Encoding", encoding);
+        }
+        if has_variants {
+            res.add_vary("Accept-Encoding");
+        }
+        Ok(res)
+    }
+
+    /// Response with the `len` bytes of the file at `path` starting at `start`.
+    async fn body(
+        &self,
+        path: &Path,
+        metadata: &Metadata,
+        start: u64,
+        len: u64,
+    ) -> Result<Response, AppError> {
+        if let Some(cache) = &self.cache {
+            if let Some(content) = cache.get(path, metadata).await? {
+                return Ok(Response::from(
+                    &content[start as usize..(start + len) as usize],
+                ));
+            }
+        }
+
+        let mut file = File::open(path).await?;
+        if start > 0 {
+            file.seek(SeekFrom::Start(start)).await?;
+        }
+        if len > self.stream_threshold {
+            return Ok(Response::stream(file.take(len), Some(len)));
+        }
+        let mut content = Vec::with_capacity(len as usize);
+        file.take(len).read_to_end(&mut content).await?;
+        Ok(Response::from(content))
+    }
+}
+
+/// HTML page listing the entries of `dir`, directories first.
+async fn listing(path: &str, dir: &Path, is_root: bool) -> Result<Response, AppError> {
+    let mut entries = Vec::new();
+    let mut read_dir = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        let metadata = entry.metadata().await?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        entries.push((!metadata.is_dir(), name, metadata));
+    }
+    entries.sort_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+
+    let title = html_escape(path);
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {0}</title></head>\n\
+         <body>\n<h1>Index of {0}</h1>\n<table>\n\
+         <tr><th>Name</th><th>Size</th><th>Last modified</th></tr>\n",
+        title
+    );
+    if !is_root {
+        html.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for (is_file, name, metadata) in entries {
+        let name = html_escape(&name) + if is_file { "" } else { "/" };
+        let size = if is_file {
+            metadata.len().to_string()
+        } else {
+            "-".to_string()
+        };
+        let modified = metadata.modified().map(date::http_date).unwrap_or_default();
+        html.push_str(&format!(
+            "<tr><td><a href=\"{0}\">{0}</a></td><td>{1}</td><td>{2}</td></tr>\n",
+            name, size, modified
+        ));
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+
+    let mut res = Response::from(html);
+    res.header("Content-Type", "text/html; charset=utf-8");
+    Ok(res)
+}
+
+fn html_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Status answering a conditional request on a file last modified at `modified`:
+/// 412 when it changed since `If-Unmodified-Since`, 304 when it did not since
+/// `If-Modified-Since`. The latter is ignored in favor of `If-None-Match`.
+fn precondition(req: &Request, modified: SystemTime) -> Option<HttpCode> {
+    let since = |name| req.header(name).and_then(date::parse_http_date);
+
+    if req.header("If-Match").is_none() {
+        if let Some(since) = since("If-Unmodified-Since") {
+            if modified > since {
+                return Some(HttpCode::PreconditionFailed);
+            }
+        }
+    }
+    if req.header("If-None-Match").is_none() {
+        if let Some(since) = since("If-Modified-Since") {
+            if modified <= since {
+                return Some(HttpCode::NotModified);
+            }
+        }
+    }
+    None
+}
+
+/// First and last byte of the single range requested by a `Range` header, or an
+/// error if it lies past the end of the file. Malformed and multiple ranges are
+/// ignored, the whole file being served instead.
+fn parse_range(header: &str, len: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", "") => return None,
+        // Suffix range, the last `n` bytes
+        ("", n) => {
+            let n = n.parse::<u64>().ok()?;
+            if n == 0 {
+                return Some(Err(()));
+            }
+            (len.saturating_sub(n), len.checked_sub(1)?)
+        }
+        (start, "") => (start.parse().ok()?, len.saturating_sub(1)),
+        (start, end) => {
+            let (start, end) = (start.parse().ok()?, end.parse::<u64>().ok()?);
+            if end < start {
+                return None;
+            }
+            (start, end.min(len.saturating_sub(1)))
+        }
+    };
+
+    if start >= len {
+        Some(Err(()))
+    } else {
+        Some(Ok((start, end)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{RequestBuffer, Router};
+
+    /// Temporary directory removed once dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    async fn get(router: &Router, path: &str) -> Vec<u8> {
+        get_with(router, path, "").await
+    }
+
+    async fn get_with(router: &Router, path: &str, headers: &str) -> Vec<u8> {
+        let req = format!("GET {} HTTP/1.1\r\n{}\r\n", path, headers);
+        let req = Request::parse(&mut RequestBuffer::from(req.bytes())).unwrap();
+        let mut out = Vec::new();
+        router.route(req).await.write_to(&mut out).await.unwrap();
+        out
+    }
+
+    #[tokio::test]
+    async fn test_static_files() {
+        let dir = TempDir::new("static-files");
+        std::fs::create_dir(dir.0.join("sub")).unwrap();
+        std::fs::write(dir.0.join("sub/a.txt"), "hello").unwrap();
+
+        let files = StaticFiles::new(&dir.0);
+        let mut router = Router::default();
+        router.add_routes([files.mount("/files"), files.mount("/static/")]);
+
+        for path in ["/files/sub/a.txt", "/static/sub/a.txt"] {
+            let res = get(&router, path).await;
+            assert!(res.starts_with(b"HTTP/1.1 200 OK\r\n"));
+            assert!(res.ends_with(b"\r\n\r\nhello"));
+        }
+        assert!(get(&router, "/files/missing")
+            .await
+            .starts_with(b"HTTP/1.1 404 "));
+        assert!(get(&router, "/files/sub")
+            .await
+            .starts_with(b"HTTP/1.1 404 "));
+        assert!(get(&router, "/filesystem")
+            .await
+            .starts_with(b"HTTP/1.1 404 "));
+    }
+
+    #[tokio::test]
+    async fn test_path_traversal() {
+        let dir = TempDir::new("static-files-traversal");
+        std::fs::create_dir(dir.0.join("root")).unwrap();
+        std::fs::write(dir.0.join("secret"), "secret").unwrap();
+        std::fs::write(dir.0.join("root/a.txt"), "hello").unwrap();
+
+        let files = StaticFiles::new(dir.0.join("root"));
+        let mut router = Router::default();
+        router.add_route(files.mount("/files"));
+
+        let res = get(&router, "/files/../secret").await;
+        assert!(res.starts_with(b"HTTP/1.1 403 "));
+        let res = get(&router, "/files/./a.txt").await;
+        assert!(res.ends_with(b"\r\n\r\nhello"));
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(dir.0.join("secret"), dir.0.join("root/link")).unwrap();
+            let res = get(&router, "/files/link").await;
+            assert!(res.starts_with(b"HTTP/1.1 403 "));
+        }
+
+        assert!(files.resolve_new("../new").await.is_err());
+        let new = files.resolve_new("new").await.unwrap();
+        assert_eq!(new, dir.0.join("root").canonicalize().unwrap().join("new"));
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-4", 10), Some(Ok((0, 4))));
+        assert_eq!(parse_range("bytes=5-", 10), Some(Ok((5, 9))));
+        assert_eq!(parse_range("bytes=-3", 10), Some(Ok((7, 9))));
+        assert_eq!(parse_range("bytes=-30", 10), Some(Ok((0, 9))));
+        assert_eq!(parse_range("bytes=8-100", 10), Some(Ok((8, 9))));
+        assert_eq!(parse_range("bytes=10-", 10), Some(Err(())));
+        assert_eq!(parse_range("bytes=-0", 10), Some(Err(())));
+        assert_eq!(parse_range("bytes=-1", 0), None);
+        assert_eq!(parse_range("bytes=4-2", 10), None);
+        assert_eq!(parse_range("bytes=0-1,4-5", 10), None);
+        assert_eq!(parse_range("items=0-1", 10), None);
+    }
+
+    #[tokio::test]
+    async fn test_range_requests() {
+        let dir = TempDir::new("static-files-range");
+        std::fs::write(dir.0.join("a.txt"), "0123456789").unwrap();
+        let mut router = Router::default();
+        router.add_route(StaticFiles::new(&dir.0).mount("/files"));
+
+        let res = get(&router, "/files/a.txt").await;
+        assert!(String::from_utf8(res)
+            .unwrap()
+            .contains("Accept-Ranges: bytes\r\n"));
+
+        let res = get_with(&router, "/files/a.txt", "Range: bytes=2-4\r\n").await;
+        let res = String::from_utf8(res).unwrap();
+        assert!(res.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        assert!(res.contains("Content-Range: bytes 2-4/10\r\n"));
+        assert!(res.ends_with("\r\n\r\n234"));
+
+        let res = get_with(&router, "/files/a.txt", "Range: bytes=20-\r\n").await;
+        let res = String::from_utf8(res).unwrap();
+        assert!(res.starts_with("HTTP/1.1 416 Range Not Satisfiable\r\n"));
+        assert!(res.contains("Content-Range: bytes */10\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_conditional_requests() {
+        let dir = TempDir::new("static-files-conditional");
+        std::fs::write(dir.0.join("a.txt"), "hello").unwrap();
+        let mut router = Router::default();
+        router.add_route(StaticFiles::new(&dir.0).mount("/files"));
+
+        let res = String::from_utf8(get(&router, "/files/a.txt").await).unwrap();
+        let modified = res
+            .lines()
+            .find_map(|line| line.strip_prefix("Last-Modified: "))
+            .unwrap()
+            .to_string();
+        let later =
+            date::http_date(date::parse_http_date(&modified).unwrap() + Duration::from_secs(60));
+        let earlier = date::http_date(UNIX_EPOCH);
+
+        let since = |name, date| format!("{}: {}\r\n", name, date);
+        let res = get_with(
+            &router,
+            "/files/a.txt",
+            &since("If-Modified-Since", &modified),
+        )
+        .await;
+        assert!(res.starts_with(b"HTTP/1.1 304 Not Modified\r\n"));
+        assert!(res.ends_with(b"\r\n\r\n"));
+        let res = get_with(
+            &router,
+            "/files/a.txt",
+            &since("If-Modified-Since", &earlier),
+        )
+        .await;
+        assert!(res.starts_with(b"HTTP/1.1 200 "));
+
+        let res = get_with(
+            &router,
+            "/files/a.txt",
+            &since("If-Unmodified-Since", &earlier),
+        )
+        .await;
+        assert!(res.starts_with(b"HTTP/1.1 412 "));
+        let res = get_with(
+            &router,
+            "/files/a.txt",
+            &since("If-Unmodified-Since", &later),
+        )
+        .await;
+        assert!(res.starts_with(b"HTTP/1.1 200 "));
+
+        let headers = since("If-Modified-Since", &modified) + "If-None-Match: \"x\"\r\n";
+        let res = get_with(&router, "/files/a.txt", &headers).await;
+        assert!(res.starts_with(b"HTTP/1.1 200 "));
+    }
+
+    #[tokio::test]
+    async fn test_directories() {
+        let dir = TempDir::new("static-files-directories");
+        std::fs::create_dir_all(dir.0.join("site/assets")).unwrap();
+        std::fs::write(dir.0.join("site/index.html"), "<p>home</p>").unwrap();
+        std::fs::write(dir.0.join("site/assets/<b>.css"), "b {}").unwrap();
+
+        let files = StaticFiles::new(&dir.0);
+        let mut router = Router::default();
+        router.add_route(files.mount("/files"));
+        let res = get(&router, "/files/site/").await;
+        assert!(res.ends_with(b"\r\n\r\n<p>home</p>"));
+        let res = String::from_utf8(get(&router, "/files/site?x=1").await).unwrap();
+        assert!(res.starts_with("HTTP/1.1 301 "));
+        assert!(res.contains("Location: /files/site/?x=1\r\n"));
+        let res = get(&router, "/files/site/assets/").await;
+        assert!(res.starts_with(b"HTTP/1.1 404 "));
+
+        let mut router = Router::default();
+        router.add_route(files.with_listing(true).mount("/files"));
+        let res = String::from_utf8(get(&router, "/files/site/assets/").await).unwrap();
+        assert!(res.contains("Content-Type: text/html; charset=utf-8\r\n"));
+        assert!(res.contains("<a href=\"../\">../</a>"));
+        assert!(res.contains("<a href=\"&lt;b&gt;.css\">&lt;b&gt;.css</a></td><td>4</td>"));
+        let res = String::from_utf8(get(&router, "/files/").await).unwrap();
+        assert!(res.contains("<a href=\"site/\">site/</a></td><td>-</td>"));
+        assert!(!res.contains("../"));
+    }
+
+    #[tokio::test]
+    async fn test_precompressed() {
+        let dir = TempDir::new("static-files-precompressed");
+        std::fs::write(dir.0.join("app.js"), "plain").unwrap();
+        std::fs::write(dir.0.join("app.js.gz"), "gzip").unwrap();
+        std::fs::write(dir.0.join("app.js.br"), "brotli").unwrap();
+        std::fs::write(dir.0.join("other.js"), "other").unwrap();
+        let mut router = Router::default();
+        router.add_route(StaticFiles::new(&dir.0).mount("/files"));
+
+        let send = |encoding: &'static str| {
+            let router = &router;
+            async move {
+                let headers = format!("Accept-Encoding: {}\r\n", encoding);
+                String::from_utf8(get_with(router, "/files/app.js", &headers).await).unwrap()
+            }
+        };
+        let res = send("gzip, br").await;
+        assert!(res.contains("Content-Encoding: br\r\n"));
+        assert!(res.contains("Content-Type: text/javascript"));
+        assert!(res.contains("Vary: Accept-Encoding\r\n"));
+        assert!(res.ends_with("\r\n\r\nbrotli"));
+        let res = send("gzip, br;q=0.5").await;
+        assert!(res.contains("Content-Encoding: gzip\r\n"));
+        assert!(res.ends_with("\r\n\r\ngzip"));
+        let res = send("identity").await;
+        assert!(!res.contains("Content-Encoding"));
+        assert!(res.contains("Vary: Accept-Encoding\r\n"));
+        assert!(res.ends_with("\r\n\r\nplain"));
+
+        let res = get_with(&router, "/files/other.js", "Accept-Encoding: gzip\r\n").await;
+        let res = String::from_utf8(res).unwrap();
+        assert!(!res.contains("Content-Encoding") && !res.contains("Vary"));
+    }
+
+    #[tokio::test]
+    async fn test_streaming() {
+        let dir = TempDir::new("static-files-streaming");
+        let content: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        std::fs::write(dir.0.join("big.bin"), &content).unwrap();
+        std::fs::write(dir.0.join("small.txt"), "small").unwrap();
+        let mut router = Router::default();
+        let files = StaticFiles::new(&dir.0).with_stream_threshold(1024);
+        router.add_route(files.mount("/files"));
+
+        let req = Request::parse(&mut RequestBuffer::from(
+            "GET /files/big.bin HTTP/1.1\r\n\r\n".bytes(),
+        ))
+        .unwrap();
+        let res = router.route(req).await;
+        assert!(res.is_stream() && res.content().is_empty());
+        assert_eq!(res.body_len(), Some(200_000));
+
+        let res = get(&router, "/files/big.bin").await;
+        let head = String::from_utf8_lossy(&res[..res.len() - content.len()]);
+        assert!(head.contains("Content-Length: 200000\r\n"));
+        assert!(res.ends_with(&content));
+        let res = get_with(&router, "/files/big.bin", "Range: bytes=1000-99999\r\n").await;
+        assert!(res.starts_with(b"HTTP/1.1 206 "));
+        assert!(res.ends_with(&content[1000..100_000]));
+        let res = get(&router, "/files/small.txt").await;
+        assert!(res.ends_with(b"\r\n\r\nsmall"));
+    }
+
+    #[tokio::test]
+    async fn test_file_cache() {
+        let dir = TempDir::new("static-files-cache");
+        std::fs::write(dir.0.join("a.css"), "body {}").unwrap();
+        let cache = FileCache::new(1024);
+        let mut router = Router::default();
+        router.add_route(
+            StaticFiles::new(&dir.0)
+                .with_cache(cache.clone())
+                .mount("/files"),
+        );
+
+        let res = get(&router, "/files/a.css").await;
+        assert!(res.ends_with(b"\r\n\r\nbody {}"));
+        assert_eq!(cache.len(), 1);
+        let res = get_with(&router, "/files/a.css", "Range: bytes=5-\r\n").await;
+        assert!(res.starts_with(b"HTTP/1.1 206 "));
+        assert!(res.ends_with(b"\r\n\r\n{}"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_symlink_policy() {
+        let dir = TempDir::new("static-files-symlinks");
+        std::fs::create_dir_all(dir.0.join("root/sub")).unwrap();
+        std::fs::write(dir.0.join("secret"), "secret").unwrap();
+        std::fs::write(dir.0.join("root/sub/a.txt"), "hello").unwrap();
+        std::os::unix::fs::symlink(dir.0.join("secret"), dir.0.join("root/out")).unwrap();
+        std::os::unix::fs::symlink("sub", dir.0.join("root/in")).unwrap();
+
+        let status = |files: &StaticFiles, path: &str| {
+            let mut router = Router::default();
+            router.add_route(files.mount("/files"));
+            let path = path.to_string();
+            async move { get(&router, &path).await[9..12].to_vec() }
+        };
+        let files = StaticFiles::new(dir.0.join("root"));
+        assert_eq!(status(&files, "/files/in/a.txt").await, b"200");
+        assert_eq!(status(&files, "/files/out").await, b"403");
+        assert!(files.resolve_new("out").await.is_err());
+
+        let files = files.with_symlinks(SymlinkPolicy::Never);
+        assert_eq!(status(&files, "/files/sub/a.txt").await, b"200");
+        assert_eq!(status(&files, "/files/in/a.txt").await, b"403");
+        assert!(files.resolve_new("in/b.txt").await.is_err());
+        assert!(files.resolve_new("in").await.is_err());
+
+        let files = files.with_symlinks(SymlinkPolicy::Always);
+        assert_eq!(status(&files, "/files/out").await, b"200");
+        assert_eq!(status(&files, "/files/../secret").await, b"403");
+    }
+}
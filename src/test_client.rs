@@ -0,0 +1,176 @@
+//! In-process client for handler tests: requests go through the middlewares and the
+//! router the way those read from a connection do, without any socket.
+//!
+//! ```no_run
+//! # async fn run(router: http_server_starter_rust::Router) {
+//! use http_server_starter_rust::TestClient;
+//!
+//! let client = TestClient::new(router);
+//! let res = client.get("/echo/hi").header("Accept", "text/plain").send().await;
+//! assert_eq!(res.status(), 200);
+//! assert_eq!(res.text(), "hi");
+//! # }
+//! ```
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use super::middleware::{self, Middleware, Middlewares, Next};
+use super::request::RequestBuilder;
+use super::router::BoxFuture;
+use super::{ByteStr, ConnectionInfo, Method, Request, Response, Router, StateMap};
+
+/// Sends requests to a router, through the middlewares and with the states a
+/// [`Server`](crate::Server) would give them.
+#[derive(Clone)]
+pub struct TestClient {
+    router: Arc<Router>,
+    middlewares: Middlewares,
+    states: StateMap,
+    info: ConnectionInfo,
+}
+
+impl TestClient {
+    pub fn new(router: Router) -> Self {
+        TestClient {
+            router: Arc::new(router),
+            middlewares: Middlewares::from([]),
+            states: StateMap::default(),
+            info: ConnectionInfo::default(),
+        }
+    }
+
+    /// Runs `middleware` around every request, after the middlewares added before it.
+    pub fn with_middleware<M: Middleware>(mut self, middleware: M) -> Self {
+        self.middlewares = middleware::push(&self.middlewares, middleware);
+        self
+    }
+
+    /// Makes `state` available to the handlers, as [`Server::with_state`] does.
+    ///
+    /// [`Server::with_state`]: crate::Server::with_state
+    pub fn with_state<T>(mut self, state: T) -> Self
+    where
+        T: Send + Sync + 'static,
+    {
+        self.states.insert(state);
+        self
+    }
+
+    /// Address the requests come from, unknown by default.
+    pub fn with_peer_addr(mut self, addr: SocketAddr) -> Self {
+        self.info.peer_addr = Some(addr);
+        self
+    }
+
+    pub fn get(&self, path: &str) -> TestRequest<'_> {
+        self.request(Method::Get, path)
+    }
+
+    pub fn post(&self, path: &str) -> TestRequest<'_> {
+        self.request(Method::Post, path)
+    }
+
+    pub fn put(&self, path: &str) -> TestRequest<'_> {
+        self.request(Method::Put, path)
+    }
+
+    pub fn delete(&self, path: &str) -> TestRequest<'_> {
+        self.request(Method::Delete, path)
+    }
+
+    /// Request of any method, `path` including the query if any.
+    pub fn request(&self, method: Method, path: &str) -> TestRequest<'_> {
+        TestRequest {
+            client: self,
+            builder: Request::builder().method(method).path(path),
+        }
+    }
+
+    fn route(&self, req: Request) -> BoxFuture<Response> {
+        let router = self.router.clone();
+        let endpoint = move |req| -> BoxFuture<Response> {
+            let router = router.clone();
+            Box::pin(async move { router.route(req).await })
+        };
+        Next::new(self.middlewares.clone(), endpoint).run(req)
+    }
+}
+
+/// Request being built by a [`TestClient`].
+#[must_use = "requests are only sent by `send`"]
+pub struct TestRequest<'a> {
+    client: &'a TestClient,
+    builder: RequestBuilder,
+}
+
+impl TestRequest<'_> {
+    /// Sets a header, replacing any value set under a differently cased name.
+    pub fn header<K, V>(mut self, key: K, value: V) -> Self
+    where
+        K: Into<ByteStr>,
+        V: Into<ByteStr>,
+    {
+        self.builder = self.builder.header(key, value);
+        self
+    }
+
+    /// Sets the body, along with its `Content-Length`.
+    pub fn body<B: Into<Bytes>>(mut self, body: B) -> Self {
+        self.builder = self.builder.body(body);
+        self
+    }
+
+    pub async fn send(self) -> Response {
+        let mut req = self.builder.build();
+        req.set_connection_info(self.client.info);
+        req.set_states(self.client.states.clone());
+        self.client.route(req).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ComparePath, HttpCode, Route};
+
+    #[tokio::test]
+    async fn test_client() {
+        let mut router = Router::default();
+        router.add_route(Route::get(
+            "/echo/{msg}",
+            |req: Request| Response::from(req.param("msg").unwrap_or_default().to_string()),
+            ComparePath::Exact,
+        ));
+        router.add_route(Route::new(
+            Method::Post,
+            "/greeting",
+            |req: Request| {
+                let greeting = req.state::<&str>().map(|greeting| *greeting);
+                let name = String::from_utf8_lossy(req.body()).to_string();
+                Response::from(format!("{} {}", greeting.unwrap_or_default(), name))
+            },
+            ComparePath::Exact,
+        ));
+        let client = TestClient::new(router).with_state("Hello").with_middleware(
+            |req: Request, next: Next| async move {
+                let mut res = next.run(req).await;
+                res.header("X-Test", "1");
+                res
+            },
+        );
+
+        let res = client.get("/echo/hi").send().await;
+        assert_eq!(res.code(), HttpCode::Ok);
+        assert_eq!(res.text(), "hi");
+        assert_eq!(res.header_value("X-Test"), Some("1"));
+
+        let res = client.post("/greeting").body("Ferris").send().await;
+        assert_eq!(res.text(), "Hello Ferris");
+
+        let res = client.get("/missing").send().await;
+        assert_eq!(res.code(), HttpCode::NotFound);
+    }
+}
@@ -0,0 +1,131 @@
+//! End-to-end test harness: a real [`Server`] on an ephemeral port of the loopback
+//! interface, for tests going through sockets, e.g. of keep-alive or timeouts.
+//!
+//! ```no_run
+//! # fn run(router: http_server_starter_rust::Router) -> std::io::Result<()> {
+//! use std::io::{Read, Write};
+//! use http_server_starter_rust::{test_server, Server};
+//!
+//! let server = test_server(Server::new(router))?;
+//! let mut stream = std::net::TcpStream::connect(server.addr())?;
+//! stream.write_all(b"GET /echo/hi HTTP/1.1\r\nConnection: close\r\n\r\n")?;
+//! let mut res = String::new();
+//! stream.read_to_string(&mut res)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+use std::thread::{self, JoinHandle};
+
+use tokio::runtime::Builder;
+
+use super::{Server, ServerHandle};
+
+/// Worker threads of the runtime a test server runs on.
+const WORKERS: usize = 2;
+
+/// Server started by [`test_server`], shut down once dropped.
+pub struct TestServer {
+    addr: SocketAddr,
+    handle: ServerHandle,
+    thread: Option<JoinHandle<io::Result<()>>>,
+}
+
+/// Starts `server` on a port of `127.0.0.1` picked by the system, from a thread of
+/// its own so that both sync and async tests can use it. The runtime flavor and the
+/// socket options of `server` are left out.
+pub fn test_server(server: Server) -> io::Result<TestServer> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let addr = listener.local_addr()?;
+    let handle = server.handle();
+    let runtime = Builder::new_multi_thread()
+        .worker_threads(WORKERS)
+        .enable_all()
+        .build()?;
+    let thread = thread::Builder::new()
+        .name(format!("test-server-{}", addr.port()))
+        .spawn(move || runtime.block_on(server.run_with_listener(listener)))?;
+
+    Ok(TestServer {
+        addr,
+        handle,
+        thread: Some(thread),
+    })
+}
+
+impl TestServer {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// URL of `path` on the server, e.g. `http://127.0.0.1:38211/echo/hi`.
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    /// Handle to swap the routes or read the metrics of the running server.
+    pub fn handle(&self) -> &ServerHandle {
+        &self.handle
+    }
+
+    /// Shuts the server down, waiting for its connections to be drained.
+    pub fn shutdown(mut self) -> io::Result<()> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        self.handle.shutdown();
+        match self.thread.take() {
+            Some(thread) => thread
+                .join()
+                .map_err(|_| io::Error::other("test server panicked"))?,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    use super::*;
+    use crate::{ComparePath, Request, Response, Route, Router};
+
+    #[test]
+    fn test_test_server() {
+        let mut router = Router::default();
+        router.add_route(Route::get(
+            "/echo/{msg}",
+            |req: Request| Response::from(req.param("msg").unwrap_or_default().to_string()),
+            ComparePath::Exact,
+        ));
+        let server = test_server(Server::new(router)).unwrap();
+        assert!(server.url("/echo/hi").starts_with("http://127.0.0.1:"));
+
+        // Both requests are answered on the same connection
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        stream
+            .write_all(
+                b"GET /echo/a HTTP/1.1\r\nHost: localhost\r\n\r\n\
+                  GET /echo/b HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).unwrap();
+        assert_eq!(res.matches("HTTP/1.1 200 OK").count(), 2, "{}", res);
+        assert!(res.ends_with("\r\n\r\nb"), "{}", res);
+
+        let addr = server.addr();
+        server.shutdown().unwrap();
+        assert!(TcpStream::connect(addr).is_err());
+    }
+}
@@ -0,0 +1,91 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+use tracing::warn;
+
+use super::middleware::{Middleware, Next};
+use super::router::BoxFuture;
+use super::{HttpCode, Request, Response};
+
+/// Instant by which the request must be answered, stored in its extensions so
+/// handlers can bound their own work.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deadline(pub Instant);
+
+/// Middleware answering 504 when the rest of the chain takes longer than the timeout.
+/// The handler future is dropped at that point, cancelling its work.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeout {
+    duration: Duration,
+    code: HttpCode,
+}
+
+impl Timeout {
+    pub fn new(duration: Duration) -> Self {
+        Timeout {
+            duration,
+            code: HttpCode::GatewayTimeout,
+        }
+    }
+
+    /// Status code of timed out requests, e.g. 503 when the server is the one overloaded.
+    pub fn with_code(mut self, code: HttpCode) -> Self {
+        self.code = code;
+        self
+    }
+}
+
+impl Middleware for Timeout {
+    fn handle(&self, mut req: Request, next: Next) -> BoxFuture<Response> {
+        let deadline = Instant::now() + self.duration;
+        let method = req.method().clone();
+        let path = req.path().to_string();
+        req.extensions_mut().insert(Deadline(deadline));
+
+        let code = self.code;
+        Box::pin(async move {
+            match tokio::time::timeout_at(deadline, next.run(req)).await {
+                Ok(res) => res,
+                Err(_) => {
+                    warn!("Request {} {} timed out", method.as_str(), path);
+                    Response::from(code)
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware;
+
+    async fn send(timeout: Timeout, delay: Duration) -> Response {
+        middleware::send(timeout, "GET / HTTP/1.1\r\n\r\n", move |req: Request| {
+            assert!(req.extensions().get::<Deadline>().is_some());
+            async move {
+                tokio::time::sleep(delay).await;
+                Response::from(HttpCode::Ok)
+            }
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let timeout = Timeout::new(Duration::from_millis(100));
+
+        let res = send(timeout, Duration::from_millis(10)).await;
+        assert!(res.into_bytes().starts_with(b"HTTP/1.1 200 "));
+        let res = send(timeout, Duration::from_secs(5)).await;
+        assert!(res
+            .into_bytes()
+            .starts_with(b"HTTP/1.1 504 Gateway Timeout\r\n"));
+
+        let timeout = timeout.with_code(HttpCode::ServiceUnavailable);
+        let res = send(timeout, Duration::from_secs(5)).await;
+        assert!(res
+            .into_bytes()
+            .starts_with(b"HTTP/1.1 503 Service Unavailable\r\n"));
+    }
+}
@@ -0,0 +1,221 @@
+//! W3C Trace Context: the `traceparent` and `tracestate` headers tying the requests of
+//! a distributed trace together.
+//!
+//! The trace of a request is continued from its headers, or started when it has
+//! none, and reported back on the response. Handlers calling other services carry
+//! it on by sending the headers of [`TraceContext::child`].
+
+use std::fmt;
+
+use tracing::Span;
+
+use super::middleware::{Middleware, Next};
+use super::request_id;
+use super::router::BoxFuture;
+use super::{Request, Response};
+
+const TRACEPARENT_HEADER: &str = "traceparent";
+const TRACESTATE_HEADER: &str = "tracestate";
+
+/// Trace context of a request, stored in its extensions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceContext {
+    /// Identifier of the whole trace, 32 lowercase hex digits.
+    pub trace_id: String,
+    /// Identifier of the server's part in the trace, 16 lowercase hex digits.
+    pub span_id: String,
+    /// Span of the caller, when the trace was continued.
+    pub parent_id: Option<String>,
+    /// Trace flags, `01` when the trace is sampled.
+    pub flags: u8,
+    /// Vendor specific data, passed on untouched.
+    pub tracestate: Option<String>,
+}
+
+impl TraceContext {
+    /// Starts a new sampled trace.
+    pub fn generate() -> Self {
+        TraceContext {
+            trace_id: random_id(2),
+            span_id: random_id(1),
+            parent_id: None,
+            flags: 1,
+            tracestate: None,
+        }
+    }
+
+    /// Continues the trace described by a `traceparent` header, `None` when it is
+    /// malformed. Versions after `00` are read as far as `00` goes.
+    pub fn parse(traceparent: &str, tracestate: Option<&str>) -> Option<Self> {
+        let mut parts = traceparent.trim().split('-');
+        let version = parts.next().filter(|v| is_hex(v, 2) && *v != "ff")?;
+        let trace_id = parts.next().filter(|id| is_id(id, 32))?;
+        let parent_id = parts.next().filter(|id| is_id(id, 16))?;
+        let flags = parts.next().filter(|f| is_hex(f, 2))?;
+        if version == "00" && parts.next().is_some() {
+            return None;
+        }
+
+        Some(TraceContext {
+            trace_id: trace_id.to_string(),
+            span_id: random_id(1),
+            parent_id: Some(parent_id.to_string()),
+            flags: u8::from_str_radix(flags, 16).ok()?,
+            tracestate: tracestate
+                .map(str::trim)
+                .filter(|state| !state.is_empty())
+                .map(String::from),
+        })
+    }
+
+    /// Context to send to a service called while handling the request, the server's
+    /// span becoming its parent.
+    pub fn child(&self) -> Self {
+        TraceContext {
+            trace_id: self.trace_id.clone(),
+            span_id: random_id(1),
+            parent_id: Some(self.span_id.clone()),
+            flags: self.flags,
+            tracestate: self.tracestate.clone(),
+        }
+    }
+
+    /// `traceparent` header value naming this context's span.
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-{:02x}", self.trace_id, self.span_id, self.flags)
+    }
+
+    /// Headers propagating this context.
+    pub fn headers(&self) -> Vec<(String, String)> {
+        let mut headers = vec![(TRACEPARENT_HEADER.to_string(), self.traceparent())];
+        if let Some(state) = &self.tracestate {
+            headers.push((TRACESTATE_HEADER.to_string(), state.clone()));
+        }
+        headers
+    }
+
+    pub fn is_sampled(&self) -> bool {
+        self.flags & 1 == 1
+    }
+}
+
+impl fmt::Display for TraceContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.traceparent())
+    }
+}
+
+/// `words` random 64-bit words in lowercase hex, never all zeros.
+fn random_id(words: usize) -> String {
+    (0..words)
+        .map(|_| format!("{:016x}", request_id::random_u64().max(1)))
+        .collect()
+}
+
+fn is_hex(s: &str, len: usize) -> bool {
+    s.len() == len && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Identifiers made of zeros only are invalid.
+fn is_id(s: &str, len: usize) -> bool {
+    is_hex(s, len) && s.bytes().any(|b| b != b'0')
+}
+
+/// Middleware continuing or starting the trace of every request. The trace id is
+/// recorded on the request span, and the context is sent back on the response so
+/// clients can find the server's span.
+#[derive(Clone, Copy, Default)]
+pub struct SetTraceContext;
+
+impl Middleware for SetTraceContext {
+    fn handle(&self, mut req: Request, next: Next) -> BoxFuture<Response> {
+        let context = req
+            .header(TRACEPARENT_HEADER)
+            .and_then(|parent| TraceContext::parse(parent, req.header(TRACESTATE_HEADER)))
+            .unwrap_or_else(TraceContext::generate);
+        Span::current().record("trace_id", context.trace_id.as_str());
+        req.extensions_mut().insert(context.clone());
+
+        Box::pin(async move {
+            let mut res = next.run(req).await;
+            for (name, value) in context.headers() {
+                res.header(name, value);
+            }
+            res
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{middleware, HttpCode};
+
+    const PARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn test_parse() {
+        let context = TraceContext::parse(PARENT, Some("congo=t61rcWkgMzE")).unwrap();
+        assert_eq!(context.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(context.parent_id.as_deref(), Some("00f067aa0ba902b7"));
+        assert_ne!(context.span_id, "00f067aa0ba902b7");
+        assert!(context.is_sampled());
+        assert_eq!(context.tracestate.as_deref(), Some("congo=t61rcWkgMzE"));
+
+        let child = context.child();
+        assert_eq!(child.trace_id, context.trace_id);
+        assert_eq!(child.parent_id, Some(context.span_id.clone()));
+
+        // Later versions may append fields
+        let future = "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00-extra";
+        assert!(!TraceContext::parse(future, None).unwrap().is_sampled());
+
+        for invalid in [
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "garbage",
+        ] {
+            assert_eq!(TraceContext::parse(invalid, None), None, "{}", invalid);
+        }
+    }
+
+    async fn send(req: &str) -> (Response, TraceContext) {
+        let context = Arc::new(std::sync::Mutex::new(None));
+        let seen = context.clone();
+        let res = middleware::send(SetTraceContext, req, move |req: Request| {
+            *seen.lock().unwrap() = req.extensions().get::<TraceContext>().cloned();
+            async { Response::from(HttpCode::Ok) }
+        })
+        .await;
+        let context = context.lock().unwrap().take().unwrap();
+        (res, context)
+    }
+
+    #[tokio::test]
+    async fn test_set_trace_context() {
+        let req = format!(
+            "GET / HTTP/1.1\r\ntraceparent: {}\r\ntracestate: a=1\r\n\r\n",
+            PARENT
+        );
+        let (res, context) = send(&req).await;
+        assert_eq!(context.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        let traceparent = format!("00-4bf92f3577b34da6a3ce929d0e0e4736-{}-01", context.span_id);
+        assert_eq!(res.header_value("traceparent"), Some(traceparent.as_str()));
+        assert_eq!(res.header_value("tracestate"), Some("a=1"));
+
+        let (res, context) = send("GET / HTTP/1.1\r\ntraceparent: nope\r\n\r\n").await;
+        assert_eq!(context.parent_id, None);
+        assert!(is_id(&context.trace_id, 32));
+        assert_eq!(
+            res.header_value("traceparent"),
+            Some(context.traceparent().as_str())
+        );
+        assert_eq!(res.header_value("tracestate"), None);
+    }
+}
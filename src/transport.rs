@@ -0,0 +1,182 @@
+//! Byte streams connections are served over, and a scripted one for tests.
+//!
+//! [`MockTransport`] hands the connection the bytes it was given read by read, so that
+//! requests split across reads or pauses between them are replayed exactly. Its pauses
+//! run on the tokio clock: with the clock paused, as in
+//! `#[tokio::test(start_paused = true)]`, timeouts fire without the test sleeping.
+//!
+//! ```no_run
+//! # async fn run(router: http_server_starter_rust::SharedRouter) {
+//! use std::time::Duration;
+//! use http_server_starter_rust::{Connection, ConnectionOptions, MockTransport};
+//!
+//! let transport = MockTransport::default()
+//!     .receive(b"GET / HTTP/1.1\r\nHo")
+//!     .receive(b"st: localhost\r\n\r\n")
+//!     .pause(Duration::from_secs(60));
+//! let written = transport.written();
+//! Connection::new(transport, ConnectionOptions::default())
+//!     .serve(&router)
+//!     .await;
+//! assert!(written.text().starts_with("HTTP/1.1 200 OK\r\n"));
+//! # }
+//! ```
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+/// Stream a [`Connection`](crate::Connection) reads requests from and writes responses
+/// to, e.g. a `TcpStream`, a TLS stream over one, or a [`MockTransport`].
+pub trait Transport: AsyncRead + AsyncWrite + Unpin {}
+
+impl<T> Transport for T where T: AsyncRead + AsyncWrite + Unpin {}
+
+/// What the peer of a [`MockTransport`] does next.
+#[derive(Debug)]
+enum Step {
+    Read(Vec<u8>),
+    Pause(Duration),
+    Fail(io::ErrorKind),
+}
+
+/// In-memory transport replaying a script of reads, then reaching the end of the
+/// stream. Everything written to it is kept, see [`MockTransport::written`].
+#[derive(Debug, Default)]
+pub struct MockTransport {
+    script: VecDeque<Step>,
+    sleep: Option<Pin<Box<Sleep>>>,
+    written: Written,
+}
+
+impl MockTransport {
+    /// Has the next read give `data`, or as much of it as the reader has room for.
+    pub fn receive(mut self, data: &[u8]) -> Self {
+        self.script.push_back(Step::Read(data.to_vec()));
+        self
+    }
+
+    /// Has the next read wait for `duration` before going on with the script.
+    pub fn pause(mut self, duration: Duration) -> Self {
+        self.script.push_back(Step::Pause(duration));
+        self
+    }
+
+    /// Has the next read fail, e.g. with `ConnectionReset` for a peer going away.
+    pub fn fail(mut self, kind: io::ErrorKind) -> Self {
+        self.script.push_back(Step::Fail(kind));
+        self
+    }
+
+    /// Handle on the bytes written, readable once the transport is moved away.
+    pub fn written(&self) -> Written {
+        self.written.clone()
+    }
+}
+
+impl AsyncRead for MockTransport {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if let Some(sleep) = self.sleep.as_mut() {
+                ready!(sleep.as_mut().poll(cx));
+                self.sleep = None;
+            }
+            match self.script.pop_front() {
+                // End of the stream
+                None => return Poll::Ready(Ok(())),
+                Some(Step::Pause(duration)) => {
+                    self.sleep = Some(Box::pin(tokio::time::sleep(duration)));
+                }
+                Some(Step::Fail(kind)) => return Poll::Ready(Err(kind.into())),
+                Some(Step::Read(mut data)) => {
+                    let len = data.len().min(buf.remaining());
+                    buf.put_slice(&data[..len]);
+                    if len < data.len() {
+                        data.drain(..len);
+                        self.script.push_front(Step::Read(data));
+                    }
+                    return Poll::Ready(Ok(()));
+                }
+            }
+        }
+    }
+}
+
+impl AsyncWrite for MockTransport {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.written.0.lock().unwrap().extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Bytes written to a [`MockTransport`].
+#[derive(Debug, Clone, Default)]
+pub struct Written(Arc<Mutex<Vec<u8>>>);
+
+impl Written {
+    pub fn bytes(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Bytes as text, invalid UTF-8 sequences replaced.
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::time::Instant;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_mock_transport() {
+        let mut transport = MockTransport::default()
+            .receive(b"hello")
+            .pause(Duration::from_secs(30))
+            .receive(b"world")
+            .fail(io::ErrorKind::ConnectionReset);
+        let written = transport.written();
+
+        let mut buf = [0; 3];
+        assert_eq!(transport.read(&mut buf).await.unwrap(), 3);
+        assert_eq!(transport.read(&mut buf).await.unwrap(), 2);
+        assert_eq!(&buf[..2], b"lo");
+
+        let start = Instant::now();
+        let mut buf = Vec::new();
+        let err = transport.read_to_end(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(buf, b"world");
+        assert_eq!(start.elapsed(), Duration::from_secs(30));
+        assert_eq!(transport.read(&mut [0; 8]).await.unwrap(), 0);
+
+        transport.write_all(b"response").await.unwrap();
+        drop(transport);
+        assert_eq!(written.text(), "response");
+    }
+}
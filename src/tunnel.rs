@@ -0,0 +1,191 @@
+//! CONNECT tunnels, relaying bytes between the client and the host it asked for, so
+//! the server can act as a forward proxy.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::net::TcpStream;
+use tracing::{debug, warn};
+
+use super::upgrade::Upgraded;
+use super::{AppError, ComparePath, HttpCode, Method, Request, Response, Route};
+
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+type TargetFilter = Arc<dyn Fn(&str, u16) -> bool + Send + Sync>;
+
+/// Answers CONNECT requests by opening a TCP connection to their `host:port` target,
+/// then relaying bytes both ways once the client got its 200.
+///
+/// Only port 443 may be reached by default, as an open tunnel lets clients reach any
+/// host the server can.
+#[derive(Clone)]
+pub struct Tunnel {
+    filter: TargetFilter,
+    connect_timeout: Duration,
+}
+
+impl Default for Tunnel {
+    fn default() -> Self {
+        Tunnel {
+            filter: Arc::new(|_, port| port == 443),
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+        }
+    }
+}
+
+impl Tunnel {
+    /// Targets the tunnel may reach, given their host and port. Others get a 403.
+    pub fn with_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&str, u16) -> bool + Send + Sync + 'static,
+    {
+        self.filter = Arc::new(filter);
+        self
+    }
+
+    /// Time allowed to connect to the target, after which the client gets a 504.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Route answering every CONNECT request with this tunnel.
+    pub fn route(&self) -> Route {
+        let tunnel = self.clone();
+        Route::new(
+            Method::Connect,
+            "",
+            move |req: Request| {
+                let tunnel = tunnel.clone();
+                async move { tunnel.open(&req).await }
+            },
+            ComparePath::Prefix,
+        )
+    }
+
+    /// Connects to the target of `req`, answering with the 200 switching the
+    /// connection to the tunnel.
+    pub async fn open(&self, req: &Request) -> Result<Response, AppError> {
+        let (host, port) = authority(req.path()).ok_or_else(|| {
+            AppError::BadRequest(format!("Invalid CONNECT target: {}", req.path()))
+        })?;
+        if !(self.filter)(host, port) {
+            return Err(AppError::Forbidden);
+        }
+
+        let upstream = match tokio::time::timeout(
+            self.connect_timeout,
+            TcpStream::connect((host, port)),
+        )
+        .await
+        {
+            Ok(Ok(upstream)) => upstream,
+            Ok(Err(e)) => {
+                warn!("Failed to open a tunnel to {}:{}: {}", host, port, e);
+                return Ok(Response::from(HttpCode::BadGateway));
+            }
+            Err(_) => return Ok(Response::from(HttpCode::GatewayTimeout)),
+        };
+        let _ = upstream.set_nodelay(true);
+
+        let mut res = Response::from(HttpCode::Ok);
+        res.on_upgrade(move |client| relay(client, upstream));
+        Ok(res)
+    }
+}
+
+impl fmt::Debug for Tunnel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tunnel")
+            .field("connect_timeout", &self.connect_timeout)
+            .finish()
+    }
+}
+
+/// Copies bytes both ways until either side closes.
+async fn relay(mut client: Upgraded, mut upstream: TcpStream) {
+    if let Err(e) = tokio::io::copy_bidirectional(&mut client, &mut upstream).await {
+        debug!("Tunnel closed: {}", e);
+    }
+}
+
+/// Host and port of an authority-form target, `example.com:443` or `[::1]:443`.
+fn authority(target: &str) -> Option<(&str, u16)> {
+    let (host, port) = target.rsplit_once(':')?;
+    let host = match host.strip_prefix('[') {
+        Some(ipv6) => ipv6.strip_suffix(']')?,
+        None => host,
+    };
+    let port = port.parse().ok().filter(|&port| port != 0)?;
+    (!host.is_empty() && !host.contains(['/', '@'])).then_some((host, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::connection::{Connection, ConnectionOptions};
+    use crate::{Router, SharedRouter};
+
+    #[test]
+    fn test_authority() {
+        assert_eq!(authority("example.com:443"), Some(("example.com", 443)));
+        assert_eq!(authority("[::1]:8080"), Some(("::1", 8080)));
+        assert_eq!(authority("example.com"), None);
+        assert_eq!(authority("example.com:0"), None);
+        assert_eq!(authority("/path:80"), None);
+        assert_eq!(authority(":443"), None);
+    }
+
+    #[tokio::test]
+    async fn test_tunnel() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = upstream.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let mut buf = [0; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf.to_ascii_uppercase()).await.unwrap();
+        });
+
+        let mut router = Router::default();
+        router.add_route(Tunnel::default().with_filter(|_, _| true).route());
+        let router = SharedRouter::from(router);
+        let (mut client, server) = tokio::io::duplex(1024);
+        tokio::spawn(async move {
+            Connection::new(server, ConnectionOptions::default())
+                .with_upgrades()
+                .serve(&router)
+                .await
+        });
+
+        let req = format!(
+            "CONNECT 127.0.0.1:{0} HTTP/1.1\r\nHost: 127.0.0.1:{0}\r\n\r\nping",
+            port
+        );
+        client.write_all(req.as_bytes()).await.unwrap();
+        let mut out = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut out))
+            .await
+            .unwrap()
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(!out.contains("Content-Length"));
+        assert!(out.ends_with("\r\n\r\nPING"));
+    }
+
+    #[tokio::test]
+    async fn test_forbidden_target() {
+        let req = "CONNECT 127.0.0.1:22 HTTP/1.1\r\n\r\n";
+        let req = Request::parse(&mut crate::RequestBuffer::from(req.bytes())).unwrap();
+        assert!(matches!(
+            Tunnel::default().open(&req).await,
+            Err(AppError::Forbidden)
+        ));
+    }
+}
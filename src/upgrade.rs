@@ -0,0 +1,277 @@
+//! Hand-over of a connection to another protocol after a 101 response.
+//!
+//! Handlers answer with [`upgrade`], or attach a callback to their own 101 response
+//! through [`Response::on_upgrade`]. Once the response is written, the callback
+//! takes over the connection, which is closed when it returns.
+
+use std::any::Any;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use bytes::{Buf, BytesMut};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::router::BoxFuture;
+use super::{HttpCode, Request, Response};
+
+/// Transports a connection can be upgraded from.
+pub trait Io: AsyncRead + AsyncWrite + Send + Unpin {
+    fn as_any(&self) -> &dyn Any;
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any>;
+}
+
+impl<T> Io for T
+where
+    T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+/// Whether `req` asks to switch to `protocol`, listed in its `Upgrade` header along
+/// with the `upgrade` option in its `Connection` header.
+pub fn requested(req: &Request, protocol: &str) -> bool {
+    let has_token = |name: &str, token: &str| {
+        req.header(name).is_some_and(|value| {
+            value.split(',').any(|v| {
+                // Protocols may carry a version, as in `h2c` or `foo/2`
+                let v = v.trim();
+                v.eq_ignore_ascii_case(token)
+                    || v.split_once('/')
+                        .is_some_and(|(name, _)| name.eq_ignore_ascii_case(token))
+            })
+        })
+    };
+    has_token("Upgrade", protocol) && has_token("Connection", "upgrade")
+}
+
+/// Switches the connection to `protocol` with a 101 response, `callback` taking over
+/// the connection once it is sent. Requests not asking for the protocol are answered
+/// with a 426 advertising it.
+pub fn upgrade<F, Fut>(req: &Request, protocol: &str, callback: F) -> Response
+where
+    F: FnOnce(Upgraded) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let mut res = match requested(req, protocol) {
+        true => {
+            let mut res = Response::from(HttpCode::SwitchingProtocols);
+            res.on_upgrade(callback);
+            res
+        }
+        false => Response::from(HttpCode::UpgradeRequired),
+    };
+    res.header("Upgrade", protocol);
+    res.header("Connection", "Upgrade");
+    res
+}
+
+/// Connection taken over after the 101 response was sent. Bytes the client sent
+/// right after its request are read first.
+pub struct Upgraded {
+    io: Box<dyn Io>,
+    buf: BytesMut,
+}
+
+impl Upgraded {
+    pub(crate) fn new(io: Box<dyn Io>, buf: BytesMut) -> Self {
+        Upgraded { io, buf }
+    }
+
+    /// Underlying transport, e.g. a `TcpStream`, along with the bytes already read
+    /// from it. Gives the connection back when it is of another type.
+    pub fn downcast<T>(self) -> Result<(T, BytesMut), Self>
+    where
+        T: Io + 'static,
+    {
+        // Boxes are transports too, the deref reaches the boxed one
+        if !(*self.io).as_any().is::<T>() {
+            return Err(self);
+        }
+        let io = self.io.into_any().downcast::<T>().unwrap();
+        Ok((*io, self.buf))
+    }
+}
+
+impl fmt::Debug for Upgraded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Upgraded")
+            .field("buffered", &self.buf.len())
+            .finish()
+    }
+}
+
+impl AsyncRead for Upgraded {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.buf.is_empty() {
+            return Pin::new(&mut self.io).poll_read(cx, buf);
+        }
+
+        let n = self.buf.len().min(buf.remaining());
+        buf.put_slice(&self.buf[..n]);
+        self.buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Upgraded {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+pub(crate) type UpgradeFn = Box<dyn FnOnce(Upgraded) -> BoxFuture<()> + Send>;
+
+/// Callback attached to a 101 response, run with the connection once the response
+/// is written. Clones share the callback, which runs at most once.
+#[derive(Clone)]
+pub(crate) struct OnUpgrade(Arc<Mutex<Option<UpgradeFn>>>);
+
+impl OnUpgrade {
+    pub(crate) fn new<F, Fut>(callback: F) -> Self
+    where
+        F: FnOnce(Upgraded) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let callback: UpgradeFn = Box::new(move |upgraded| Box::pin(callback(upgraded)));
+        OnUpgrade(Arc::new(Mutex::new(Some(callback))))
+    }
+
+    pub(crate) fn take(&self) -> Option<UpgradeFn> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    use super::*;
+    use crate::connection::{Connection, ConnectionOptions};
+    use crate::{ComparePath, Route, Router, SharedRouter};
+
+    #[test]
+    fn test_requested() {
+        let req = |headers: &str| {
+            let req = format!("GET / HTTP/1.1\r\n{}\r\n", headers);
+            Request::parse(&mut crate::RequestBuffer::from(req.bytes())).unwrap()
+        };
+        let upgrade = req("Upgrade: foo/2, bar\r\nConnection: keep-alive, Upgrade\r\n");
+        assert!(requested(&upgrade, "foo"));
+        assert!(requested(&upgrade, "BAR"));
+        assert!(!requested(&upgrade, "baz"));
+        assert!(!requested(&req("Upgrade: foo\r\n"), "foo"));
+
+        let res = super::upgrade(&req(""), "foo", |_| async {});
+        assert_eq!(res.code(), HttpCode::UpgradeRequired);
+        assert_eq!(res.header_value("Upgrade"), Some("foo"));
+    }
+
+    #[tokio::test]
+    async fn test_upgrade() {
+        let mut router = Router::default();
+        router.add_route(Route::get(
+            "/",
+            |req: Request| {
+                // Line based protocol shouting back what it reads, on the raw transport
+                super::upgrade(&req, "shout", |upgraded| async move {
+                    let (mut io, buf) = upgraded.downcast::<DuplexStream>().unwrap();
+                    // The client may have pipelined the whole line with its request
+                    let mut line = buf.to_vec();
+                    while !line.ends_with(b"\n") {
+                        line.push(io.read_u8().await.unwrap());
+                    }
+                    io.write_all(&line.to_ascii_uppercase()).await.unwrap();
+                })
+            },
+            ComparePath::Exact,
+        ));
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        let router = SharedRouter::from(router);
+        let handle = tokio::spawn(async move {
+            Connection::new(server, ConnectionOptions::default())
+                .with_upgrades()
+                .serve(&router)
+                .await
+        });
+
+        client
+            .write_all(
+                b"GET / HTTP/1.1\r\nHost: localhost\r\n\
+                  Upgrade: shout\r\nConnection: upgrade\r\n\r\nhel",
+            )
+            .await
+            .unwrap();
+        client.write_all(b"lo\n").await.unwrap();
+        let mut out = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut out))
+            .await
+            .unwrap()
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(out.contains("Upgrade: shout\r\n"));
+        assert!(out.ends_with("\r\n\r\nHELLO\n"));
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_transport() {
+        let mut router = Router::default();
+        router.add_route(Route::get(
+            "/",
+            |req: Request| super::upgrade(&req, "shout", |_| async {}),
+            ComparePath::Exact,
+        ));
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        let router = SharedRouter::from(router);
+        tokio::spawn(async move {
+            Connection::new(server, ConnectionOptions::default())
+                .serve(&router)
+                .await
+        });
+        client
+            .write_all(
+                b"GET / HTTP/1.1\r\nHost: localhost\r\n\
+                  Upgrade: shout\r\nConnection: upgrade\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut out = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut out))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(out.starts_with(b"HTTP/1.1 501 "));
+    }
+}
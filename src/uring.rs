@@ -0,0 +1,86 @@
+use std::future::Future;
+use std::io;
+use std::net::Shutdown;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{ready, Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_uring::net::TcpStream;
+
+type BufFuture = Pin<Box<dyn Future<Output = (io::Result<usize>, Vec<u8>)>>>;
+
+/// Adapts an io_uring socket to tokio's I/O traits, so that [`Connection`] can drive
+/// it like any other stream. Every read and write is submitted as a ring operation
+/// owning its buffer until completion.
+///
+/// [`Connection`]: crate::Connection
+pub struct UringStream {
+    stream: Rc<TcpStream>,
+    read: Option<BufFuture>,
+    write: Option<BufFuture>,
+}
+
+impl UringStream {
+    pub fn from_tokio(stream: tokio::net::TcpStream) -> io::Result<Self> {
+        let stream = stream.into_std()?;
+        stream.set_nonblocking(false)?;
+
+        Ok(UringStream {
+            stream: Rc::new(TcpStream::from_std(stream)),
+            read: None,
+            write: None,
+        })
+    }
+}
+
+impl AsyncRead for UringStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let read = this.read.get_or_insert_with(|| {
+            let stream = this.stream.clone();
+            let len = buf.remaining();
+            Box::pin(async move { stream.read(Vec::with_capacity(len)).await })
+        });
+
+        let (res, data) = ready!(read.as_mut().poll(cx));
+        this.read = None;
+
+        // A pending read is always resumed with the same buffer, so the data fits
+        let n = res?;
+        buf.put_slice(&data[..n]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for UringStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let write = this.write.get_or_insert_with(|| {
+            let stream = this.stream.clone();
+            let data = buf.to_vec();
+            Box::pin(async move { stream.write(data).await })
+        });
+
+        let (res, _) = ready!(write.as_mut().poll(cx));
+        this.write = None;
+        Poll::Ready(res)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Writes are complete once their operation resolved, nothing is buffered here
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.stream.shutdown(Shutdown::Write))
+    }
+}
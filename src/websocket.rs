@@ -0,0 +1,527 @@
+//! WebSocket connections (RFC 6455): the opening handshake, then a frame codec
+//! exchanging messages with the client.
+
+use std::future::Future;
+use std::io;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+use super::upgrade::{self, Upgraded};
+use super::{AppError, HttpCode, Method, Request, Response};
+
+/// Appended to the client key to compute `Sec-WebSocket-Accept`.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+/// Status codes of close frames.
+pub mod close_code {
+    pub const NORMAL: u16 = 1000;
+    pub const GOING_AWAY: u16 = 1001;
+    pub const PROTOCOL_ERROR: u16 = 1002;
+    pub const INVALID_DATA: u16 = 1007;
+    pub const TOO_BIG: u16 = 1009;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    /// Received pings are answered by the socket before being handed out.
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close(Option<CloseFrame>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseFrame {
+    pub code: u16,
+    pub reason: String,
+}
+
+/// Value of `Sec-WebSocket-Accept` for the client `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.trim().as_bytes());
+    sha1.update(GUID.as_bytes());
+    BASE64.encode(sha1.finalize())
+}
+
+/// Answers a websocket opening handshake with a 101 and runs `handler` with the
+/// socket once the response is sent.
+///
+/// Requests that are not a valid handshake are rejected with a 400, and those asking
+/// for another protocol version with a 426 listing the supported one.
+pub fn upgrade<F, Fut>(req: &Request, handler: F) -> Result<Response, AppError>
+where
+    F: FnOnce(WebSocket) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    if *req.method() != Method::Get || !upgrade::requested(req, "websocket") {
+        return Err(AppError::BadRequest("Not a websocket handshake".into()));
+    }
+
+    if req.header("Sec-WebSocket-Version").map(str::trim) != Some("13") {
+        let mut res = Response::from(HttpCode::UpgradeRequired);
+        res.header("Sec-WebSocket-Version", "13");
+        return Ok(res);
+    }
+    let key = req
+        .header("Sec-WebSocket-Key")
+        .filter(|key| {
+            BASE64
+                .decode(key.trim())
+                .is_ok_and(|nonce| nonce.len() == 16)
+        })
+        .ok_or_else(|| AppError::BadRequest("Invalid Sec-WebSocket-Key".into()))?;
+
+    let mut res = Response::from(HttpCode::SwitchingProtocols);
+    res.header("Upgrade", "websocket");
+    res.header("Connection", "Upgrade");
+    res.header("Sec-WebSocket-Accept", accept_key(key));
+    res.on_upgrade(move |io| handler(WebSocket::new(io)));
+    Ok(res)
+}
+
+/// Server end of a websocket connection.
+#[derive(Debug)]
+pub struct WebSocket {
+    io: Upgraded,
+    max_message_size: usize,
+    /// Opcode and payload received so far of a fragmented message.
+    fragment: Option<(u8, Vec<u8>)>,
+    close_sent: bool,
+    closed: bool,
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+enum ReadError {
+    Io(io::Error),
+    /// The client broke the protocol, the connection is closed with the code.
+    Protocol(u16, &'static str),
+}
+
+impl From<io::Error> for ReadError {
+    fn from(e: io::Error) -> Self {
+        ReadError::Io(e)
+    }
+}
+
+impl WebSocket {
+    fn new(io: Upgraded) -> Self {
+        WebSocket {
+            io,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            fragment: None,
+            close_sent: false,
+            closed: false,
+        }
+    }
+
+    /// Size of the largest message accepted, 16 MiB by default. Larger ones close
+    /// the connection.
+    pub fn set_max_message_size(&mut self, max_message_size: usize) {
+        self.max_message_size = max_message_size;
+    }
+
+    /// Next message from the client, or `None` once the connection is closed.
+    ///
+    /// A received close frame is echoed, ending the connection after being returned.
+    pub async fn recv(&mut self) -> Option<io::Result<Message>> {
+        if self.closed {
+            return None;
+        }
+
+        loop {
+            let frame = match read_frame(&mut self.io, self.max_message_size).await {
+                Ok(Some(frame)) => frame,
+                Ok(None) => {
+                    self.closed = true;
+                    return None;
+                }
+                Err(ReadError::Io(e)) => {
+                    self.closed = true;
+                    return Some(Err(e));
+                }
+                Err(ReadError::Protocol(code, reason)) => {
+                    return Some(Err(self.fail(code, reason).await))
+                }
+            };
+
+            // Control frames may come in the middle of a fragmented message
+            match frame.opcode {
+                OP_CLOSE => return Some(self.recv_close(frame.payload).await),
+                OP_PING => {
+                    if let Err(e) = self.write(OP_PONG, &frame.payload).await {
+                        return Some(Err(e));
+                    }
+                    return Some(Ok(Message::Ping(frame.payload)));
+                }
+                OP_PONG => return Some(Ok(Message::Pong(frame.payload))),
+                _ => {}
+            }
+
+            let (opcode, payload) = match (frame.opcode, self.fragment.take()) {
+                (OP_TEXT | OP_BINARY, None) => (frame.opcode, frame.payload),
+                (OP_CONTINUATION, Some((opcode, mut payload))) => {
+                    if payload.len() + frame.payload.len() > self.max_message_size {
+                        return Some(Err(self.fail(close_code::TOO_BIG, "message too big").await));
+                    }
+                    payload.extend_from_slice(&frame.payload);
+                    (opcode, payload)
+                }
+                (OP_TEXT | OP_BINARY, Some(_)) | (OP_CONTINUATION, None) => {
+                    let reason = "unexpected fragment";
+                    return Some(Err(self.fail(close_code::PROTOCOL_ERROR, reason).await));
+                }
+                _ => {
+                    let reason = "unknown opcode";
+                    return Some(Err(self.fail(close_code::PROTOCOL_ERROR, reason).await));
+                }
+            };
+
+            if !frame.fin {
+                self.fragment = Some((opcode, payload));
+                continue;
+            }
+            return Some(match opcode {
+                OP_TEXT => match String::from_utf8(payload) {
+                    Ok(text) => Ok(Message::Text(text)),
+                    Err(_) => Err(self.fail(close_code::INVALID_DATA, "invalid UTF-8").await),
+                },
+                _ => Ok(Message::Binary(payload)),
+            });
+        }
+    }
+
+    pub async fn send(&mut self, message: Message) -> io::Result<()> {
+        if self.close_sent {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "websocket already closed",
+            ));
+        }
+
+        match message {
+            Message::Text(text) => self.write(OP_TEXT, text.as_bytes()).await,
+            Message::Binary(data) => self.write(OP_BINARY, &data).await,
+            Message::Ping(data) => self.write(OP_PING, &data).await,
+            Message::Pong(data) => self.write(OP_PONG, &data).await,
+            Message::Close(frame) => {
+                self.close_sent = true;
+                self.write(OP_CLOSE, &close_payload(frame.as_ref())).await
+            }
+        }
+    }
+
+    /// Starts the closing handshake, the client answer being the last message received.
+    pub async fn close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+        self.send(Message::Close(Some(CloseFrame {
+            code,
+            reason: reason.to_string(),
+        })))
+        .await
+    }
+
+    async fn recv_close(&mut self, payload: Vec<u8>) -> io::Result<Message> {
+        let frame = match payload.len() {
+            0 => None,
+            1 => {
+                return Err(self
+                    .fail(close_code::PROTOCOL_ERROR, "invalid close frame")
+                    .await)
+            }
+            _ => match String::from_utf8(payload[2..].to_vec()) {
+                Ok(reason) => Some(CloseFrame {
+                    code: u16::from_be_bytes([payload[0], payload[1]]),
+                    reason,
+                }),
+                Err(_) => return Err(self.fail(close_code::INVALID_DATA, "invalid UTF-8").await),
+            },
+        };
+
+        if !self.close_sent {
+            self.close_sent = true;
+            let code = frame.as_ref().map(|frame| CloseFrame {
+                code: frame.code,
+                reason: String::new(),
+            });
+            self.write(OP_CLOSE, &close_payload(code.as_ref())).await?;
+        }
+        self.closed = true;
+        let _ = self.io.shutdown().await;
+        Ok(Message::Close(frame))
+    }
+
+    /// Closes the connection after a protocol violation of the client.
+    async fn fail(&mut self, code: u16, reason: &'static str) -> io::Error {
+        if !self.close_sent {
+            self.close_sent = true;
+            let frame = CloseFrame {
+                code,
+                reason: reason.to_string(),
+            };
+            let _ = self.write(OP_CLOSE, &close_payload(Some(&frame))).await;
+        }
+        self.closed = true;
+        let _ = self.io.shutdown().await;
+        io::Error::new(io::ErrorKind::InvalidData, reason)
+    }
+
+    async fn write(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = vec![0x80 | opcode];
+        match payload.len() {
+            len @ 0..=125 => frame.push(len as u8),
+            len @ 126..=0xFFFF => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+        self.io.write_all(&frame).await?;
+        self.io.flush().await
+    }
+}
+
+fn close_payload(frame: Option<&CloseFrame>) -> Vec<u8> {
+    let Some(frame) = frame else {
+        return Vec::new();
+    };
+    let mut payload = frame.code.to_be_bytes().to_vec();
+    // Control frames hold at most 125 bytes
+    let mut end = frame.reason.len().min(123);
+    while !frame.reason.is_char_boundary(end) {
+        end -= 1;
+    }
+    payload.extend_from_slice(&frame.reason.as_bytes()[..end]);
+    payload
+}
+
+/// Reads the next client frame, or `None` when the stream ends between frames.
+async fn read_frame<R>(reader: &mut R, max_size: usize) -> Result<Option<Frame>, ReadError>
+where
+    R: AsyncRead + Unpin,
+{
+    let first = match reader.read_u8().await {
+        Ok(byte) => byte,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let second = reader.read_u8().await?;
+
+    let fin = first & 0x80 != 0;
+    let opcode = first & 0x0F;
+    if first & 0x70 != 0 {
+        return Err(ReadError::Protocol(
+            close_code::PROTOCOL_ERROR,
+            "reserved bits set",
+        ));
+    }
+    if second & 0x80 == 0 {
+        return Err(ReadError::Protocol(
+            close_code::PROTOCOL_ERROR,
+            "unmasked client frame",
+        ));
+    }
+
+    let len = match second & 0x7F {
+        126 => reader.read_u16().await? as u64,
+        127 => reader.read_u64().await?,
+        len => len as u64,
+    };
+    if opcode & 0x8 != 0 && (!fin || len > 125) {
+        return Err(ReadError::Protocol(
+            close_code::PROTOCOL_ERROR,
+            "invalid control frame",
+        ));
+    }
+    if len > max_size as u64 {
+        return Err(ReadError::Protocol(close_code::TOO_BIG, "message too big"));
+    }
+
+    let mut mask = [0; 4];
+    reader.read_exact(&mut mask).await?;
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload).await?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+
+    Ok(Some(Frame {
+        fin,
+        opcode,
+        payload,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncWriteExt, DuplexStream};
+
+    use super::*;
+    use crate::connection::{Connection, ConnectionOptions};
+    use crate::{ComparePath, Route, Router, SharedRouter};
+
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [1, 2, 3, 4];
+        let mut frame = vec![(fin as u8) << 7 | opcode];
+        match payload.len() {
+            len @ 0..=125 => frame.push(0x80 | len as u8),
+            len => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    async fn read_server_frame(client: &mut DuplexStream) -> (u8, Vec<u8>) {
+        let first = client.read_u8().await.unwrap();
+        let len = match client.read_u8().await.unwrap() {
+            126 => client.read_u16().await.unwrap() as usize,
+            len => len as usize,
+        };
+        let mut payload = vec![0; len];
+        client.read_exact(&mut payload).await.unwrap();
+        (first, payload)
+    }
+
+    #[test]
+    fn test_accept_key() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handshake_errors() {
+        let send = |req: &str| {
+            let req = Request::parse(&mut crate::RequestBuffer::from(req.bytes())).unwrap();
+            upgrade(&req, |_| async {}).unwrap_or_else(Response::from)
+        };
+        let res = send("GET / HTTP/1.1\r\n\r\n");
+        assert_eq!(res.code(), HttpCode::BadRequest);
+        let res = send(
+            "GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\
+             Sec-WebSocket-Version: 8\r\n\r\n",
+        );
+        assert_eq!(res.code(), HttpCode::UpgradeRequired);
+        assert_eq!(res.header_value("Sec-WebSocket-Version"), Some("13"));
+        let res = send(
+            "GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: c2hvcnQ=\r\n\r\n",
+        );
+        assert_eq!(res.code(), HttpCode::BadRequest);
+    }
+
+    #[tokio::test]
+    async fn test_echo() {
+        let mut router = Router::default();
+        router.add_route(Route::get(
+            "/ws",
+            |req: Request| {
+                upgrade(&req, |mut ws| async move {
+                    while let Some(Ok(message)) = ws.recv().await {
+                        if let Message::Text(_) | Message::Binary(_) = message {
+                            ws.send(message).await.unwrap();
+                        }
+                    }
+                })
+            },
+            ComparePath::Exact,
+        ));
+
+        let (mut client, server) = tokio::io::duplex(1 << 16);
+        let router = SharedRouter::from(router);
+        let handle = tokio::spawn(async move {
+            Connection::new(server, ConnectionOptions::default())
+                .with_upgrades()
+                .serve(&router)
+                .await
+        });
+
+        // The first frame arrives along with the handshake
+        let mut handshake = b"GET /ws HTTP/1.1\r\nHost: localhost\r\n\
+                              Upgrade: websocket\r\nConnection: Upgrade\r\n\
+                              Sec-WebSocket-Version: 13\r\n\
+                              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n"
+            .to_vec();
+        handshake.extend(client_frame(true, OP_TEXT, b"hello"));
+        client.write_all(&handshake).await.unwrap();
+
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(client.read_u8().await.unwrap());
+        }
+        let head = String::from_utf8(head).unwrap();
+        assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        assert!(!head.contains("Content-Length"));
+        assert_eq!(
+            read_server_frame(&mut client).await,
+            (0x81, b"hello".to_vec())
+        );
+
+        // A fragmented message with a ping in between
+        let long = vec![7; 300];
+        client
+            .write_all(&client_frame(false, OP_BINARY, &long[..100]))
+            .await
+            .unwrap();
+        client
+            .write_all(&client_frame(true, OP_PING, b"p"))
+            .await
+            .unwrap();
+        client
+            .write_all(&client_frame(true, OP_CONTINUATION, &long[100..]))
+            .await
+            .unwrap();
+        assert_eq!(read_server_frame(&mut client).await, (0x8A, b"p".to_vec()));
+        assert_eq!(read_server_frame(&mut client).await, (0x82, long));
+
+        let close = client_frame(true, OP_CLOSE, &close_code::NORMAL.to_be_bytes());
+        client.write_all(&close).await.unwrap();
+        assert_eq!(
+            read_server_frame(&mut client).await,
+            (0x88, close_code::NORMAL.to_be_bytes().to_vec())
+        );
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_protocol_errors() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut ws = WebSocket::new(Upgraded::new(Box::new(server), Default::default()));
+        ws.set_max_message_size(4);
+
+        client.write_all(&[0x81, 0x01, b'x']).await.unwrap();
+        assert!(ws.recv().await.unwrap().is_err());
+        let (first, payload) = read_server_frame(&mut client).await;
+        assert_eq!(first, 0x88);
+        assert_eq!(payload[..2], close_code::PROTOCOL_ERROR.to_be_bytes());
+        assert!(ws.recv().await.is_none());
+        assert!(ws.send(Message::Text("late".into())).await.is_err());
+    }
+}